@@ -0,0 +1,104 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use git2::Repository;
+use git_snapshot::settings::{SettingLayers, SettingOverrides};
+use git_snapshot::Repo;
+use std::env::var_os;
+use std::fs::{create_dir, remove_file, write};
+use std::path::{Path, PathBuf};
+use tempfile::{tempdir, TempDir};
+
+const FILES_PER_DIR: usize = 100;
+// roughly 16KB per file so hashing dominates like in real source trees
+const FILE_REPEAT: usize = 4096;
+// set to also run the 50k and 500k file repos, creating them takes a while
+const LARGE_ENV: &str = "GIT_SNAPSHOT_BENCH_LARGE";
+
+fn sizes() -> Vec<usize> {
+    if var_os(LARGE_ENV).is_some() {
+        vec![1_000, 50_000, 500_000]
+    } else {
+        vec![1_000]
+    }
+}
+
+fn test_repo(files: usize) -> (TempDir, Repository) {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path();
+    let repo = Repository::init(path).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Bench").unwrap();
+    config.set_str("user.email", "bench@bench.bench").unwrap();
+    for i in 0..files {
+        let dir = path.join((i / FILES_PER_DIR).to_string());
+        if i % FILES_PER_DIR == 0 {
+            create_dir(&dir).unwrap();
+        }
+        write(dir.join(i.to_string()), i.to_string().repeat(FILE_REPEAT)).unwrap();
+    }
+    (temp_dir, repo)
+}
+
+fn cached_index(repo: &Repo) -> PathBuf {
+    repo.git_repo().path().join("snapshot-index")
+}
+
+fn full_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("full_index");
+    group.sample_size(10);
+    for files in sizes() {
+        let (_temp_dir, repo) = test_repo(files);
+        for threads in [1, 4] {
+            group.bench_with_input(
+                BenchmarkId::new(files.to_string(), threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_batched(
+                        // fresh handle without the cached index so every iteration hashes the whole tree
+                        || {
+                            let repo = Repo::new(Repository::open(repo.path()).unwrap())
+                                .with_settings(SettingLayers {
+                                    flags: SettingOverrides {
+                                        threads: Some(threads),
+                                        ..Default::default()
+                                    },
+                                    ..Default::default()
+                                });
+                            let _ = remove_file(cached_index(&repo));
+                            repo
+                        },
+                        |repo| repo.snapshot().unwrap(),
+                        BatchSize::PerIteration,
+                    );
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn changed_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("changed_path");
+    group.sample_size(10);
+    for files in sizes() {
+        let (temp_dir, repo) = test_repo(files);
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let rel = Path::new("0").join("0");
+        let mut i = 0;
+        group.bench_function(BenchmarkId::from_parameter(files), |b| {
+            b.iter_batched(
+                || {
+                    i += 1;
+                    write(temp_dir.path().join(&rel), i.to_string()).unwrap();
+                },
+                |_| repo.snapshot_paths(std::slice::from_ref(&rel)).unwrap(),
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, full_index, changed_path);
+criterion_main!(benches);
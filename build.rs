@@ -0,0 +1,8 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        // no protoc needs to be installed to build with grpc
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::compile_protos("proto/snapshot.proto").unwrap();
+    }
+}
@@ -0,0 +1,695 @@
+use std::{
+    convert::Infallible,
+    fmt::Display,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{
+    audit::AuditAction,
+    error::Error,
+    events::{EventSender, WatchEvent},
+    history::{Divergence, LogCommit},
+    logging::LogLevel,
+    metadata::Trigger,
+    pause::Pause,
+    repo_watcher::{open_config, save_config, WatchConfig},
+    secret::Secret,
+    state::PendingPush,
+    Repo,
+};
+
+/// HTTP API served by the watcher
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiConfig {
+    pub listen: SocketAddr,
+    /// Bearer token every request has to carry
+    pub token: Secret,
+}
+
+/// What the API shares with the watcher
+#[derive(Clone)]
+pub struct ApiState {
+    pub token: String,
+    /// Config file repos are added to and removed from, the watcher reloads it on change
+    pub config_path: Option<PathBuf>,
+    /// Repos watched with the currently loaded config
+    pub repos: Arc<Mutex<Vec<PathBuf>>>,
+    pub events: EventSender,
+    pub started: SystemTime,
+    /// Times the watchdog rebuilt the watcher
+    pub watcher_restarts: Arc<AtomicU64>,
+}
+
+/// Running API server, stopped when dropped
+pub struct ApiServer {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl ApiServer {
+    pub fn start(listen: SocketAddr, state: ApiState) -> Result<Self, Error> {
+        // bound up front so errors reach the caller
+        let listener = TcpListener::bind(listen)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let handle = tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, router(state)).await {
+                error!("api server error: {:?}", err);
+            }
+        });
+        Ok(Self { addr, handle })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/repos", get(list_repos).post(add_repo).delete(remove_repo))
+        .route("/snapshot", post(snapshot))
+        .route("/approvals", get(list_approvals).post(approve_remote))
+        .route("/events", get(events))
+        .route("/log-level", get(log_level).put(set_log_level))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+async fn authorize(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    if !state.is_authorized(header) {
+        return ApiError::new(ErrorKind::Unauthorized, "invalid token").into_response();
+    }
+    next.run(request).await
+}
+
+/// Kinds of failed requests, mapped to HTTP statuses and gRPC codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    Unauthorized,
+    NotFound,
+    Conflict,
+    BadRequest,
+    Internal,
+}
+
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(kind: ErrorKind, message: impl Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        Self::new(ErrorKind::Internal, err)
+    }
+}
+
+impl From<JoinError> for ApiError {
+    fn from(err: JoinError) -> Self {
+        Self::new(ErrorKind::Internal, err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.kind {
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Status {
+    pub version: &'static str,
+    #[serde(with = "humantime_serde")]
+    pub started: SystemTime,
+    pub repos: usize,
+    pub watcher_restarts: u64,
+    /// Set while the watcher's snapshots are paused
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub paused_until: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RepoStatus {
+    pub path: PathBuf,
+    pub branch: Option<String>,
+    pub last_snapshot: Option<SnapshotStatus>,
+    pub divergence: Option<Divergence>,
+    /// Set when the repo couldn't be read
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SnapshotStatus {
+    pub id: String,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+impl From<LogCommit> for SnapshotStatus {
+    fn from(commit: LogCommit) -> Self {
+        Self {
+            id: commit.id.to_string(),
+            time: commit.time,
+        }
+    }
+}
+
+/// Push held until its remote is approved, in one of the watched repos
+#[derive(Debug, Serialize)]
+pub(crate) struct PendingApproval {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub push: PendingPush,
+}
+
+fn repo_status(path: &Path) -> RepoStatus {
+    let status = Repo::from_path(path).and_then(|repo| {
+        let branch = repo.current_branch()?;
+        let last_snapshot = repo.list(None)?.into_iter().next();
+        Ok((branch, last_snapshot, repo.divergence(None)?))
+    });
+    match status {
+        Ok((branch, last_snapshot, divergence)) => RepoStatus {
+            path: path.to_owned(),
+            branch: Some(branch),
+            last_snapshot: last_snapshot.map(|(commit, _)| commit.into()),
+            divergence,
+            error: None,
+        },
+        Err(err) => RepoStatus {
+            path: path.to_owned(),
+            branch: None,
+            last_snapshot: None,
+            divergence: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+// Operations shared by the HTTP and gRPC interfaces
+impl ApiState {
+    /// Whether an authorization header carries the token
+    pub(crate) fn is_authorized(&self, header: Option<&str>) -> bool {
+        header
+            .and_then(|v| v.strip_prefix("Bearer "))
+            // compared in constant time so the token can't be guessed byte by byte from timings
+            .map(|token| token.as_bytes().ct_eq(self.token.as_bytes()).into())
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        Status {
+            version: env!("CARGO_PKG_VERSION"),
+            started: self.started,
+            repos: self.repos.lock().unwrap().len(),
+            watcher_restarts: self.watcher_restarts.load(Ordering::Relaxed),
+            paused_until: self
+                .config_path
+                .as_deref()
+                .and_then(|p| Pause::load(&Pause::path(p)).ok().flatten())
+                .map(|pause| pause.until),
+        }
+    }
+
+    pub(crate) async fn repo_statuses(&self) -> Result<Vec<RepoStatus>, ApiError> {
+        let repos = self.repos.lock().unwrap().clone();
+        Ok(
+            tokio::task::spawn_blocking(move || repos.iter().map(|p| repo_status(p)).collect())
+                .await?,
+        )
+    }
+
+    pub(crate) fn add_repo(&self, path: &Path) -> Result<(), ApiError> {
+        self.update_config(|config| config.add_repo(path))?;
+        audit(path, AuditAction::Watch);
+        Ok(())
+    }
+
+    pub(crate) fn remove_repo(&self, path: &Path) -> Result<(), ApiError> {
+        self.update_config(|config| config.remove_repo(path))?;
+        audit(path, AuditAction::Unwatch);
+        Ok(())
+    }
+
+    // The watcher picks up the change once the config file is written
+    fn update_config(
+        &self,
+        update: impl FnOnce(&mut WatchConfig) -> Result<(), Error>,
+    ) -> Result<(), ApiError> {
+        let config_path = self.config_path.as_ref().ok_or_else(|| {
+            ApiError::new(
+                ErrorKind::Conflict,
+                "watcher wasn't started from a config file",
+            )
+        })?;
+        let mut config = open_config(config_path)?;
+        update(&mut config).map_err(|err| ApiError::new(ErrorKind::BadRequest, err))?;
+        save_config(config_path, &config)?;
+        Ok(())
+    }
+
+    fn watched_path(&self, path: &Path) -> Result<PathBuf, ApiError> {
+        path.canonicalize()
+            .ok()
+            .filter(|p| self.repos.lock().unwrap().contains(p))
+            .ok_or_else(|| ApiError::new(ErrorKind::NotFound, "repo isn't watched"))
+    }
+
+    /// Snapshot a watched repo right away
+    pub(crate) async fn snapshot(&self, path: &Path) -> Result<(), ApiError> {
+        let path = self.watched_path(path)?;
+
+        let result = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                Repo::from_path(&path)?
+                    .with_trigger(Trigger::Api)
+                    .snapshot()
+            }
+        })
+        .await?;
+
+        let time = SystemTime::now();
+        let event = match &result {
+            Ok(snapshot) => WatchEvent::Snapshot {
+                repo: path,
+                changed_paths: Vec::new(),
+                stats: snapshot.and_then(|s| s.stats),
+                time,
+            },
+            Err(err) => WatchEvent::Failed {
+                repo: path,
+                error: err.to_string(),
+                code: err.code(),
+                hint: err.hint().map(str::to_owned),
+                time,
+            },
+        };
+        // no subscribers isn't an error
+        let _ = self.events.send(event);
+        result?;
+        Ok(())
+    }
+
+    /// Pushes held in the watched repos, repos that can't be read are left out
+    pub(crate) async fn pending_approvals(&self) -> Result<Vec<PendingApproval>, ApiError> {
+        let repos = self.repos.lock().unwrap().clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            let mut approvals = Vec::new();
+            for path in repos {
+                let pushes = Repo::from_path(&path).and_then(|repo| repo.pending_pushes());
+                for push in pushes.unwrap_or_default() {
+                    approvals.push(PendingApproval {
+                        path: path.clone(),
+                        push,
+                    });
+                }
+            }
+            approvals
+        })
+        .await?)
+    }
+
+    /// Approve a remote of a watched repo, pushing the snapshots held for it
+    pub(crate) async fn approve_remote(&self, path: &Path, remote: &str) -> Result<(), ApiError> {
+        let path = self.watched_path(path)?;
+        let remote = remote.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let repo = Repo::from_path(&path)?;
+            if repo.git_repo().find_remote(&remote).is_err() {
+                return Err(ApiError::new(ErrorKind::NotFound, "no such remote"));
+            }
+            Ok(repo.approve_remote(&remote)?)
+        })
+        .await?
+    }
+}
+
+async fn status(State(state): State<ApiState>) -> Json<Status> {
+    Json(state.status())
+}
+
+async fn list_repos(State(state): State<ApiState>) -> Result<Json<Vec<RepoStatus>>, ApiError> {
+    Ok(Json(state.repo_statuses().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoRequest {
+    path: PathBuf,
+}
+
+// Config changes are recorded in the audit log of the repo they concern
+fn audit(path: &Path, action: AuditAction) {
+    if let Ok(repo) = Repo::from_path(path) {
+        repo.audit(action);
+    }
+}
+
+async fn add_repo(
+    State(state): State<ApiState>,
+    Json(request): Json<RepoRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.add_repo(&request.path)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn remove_repo(
+    State(state): State<ApiState>,
+    Json(request): Json<RepoRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.remove_repo(&request.path)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn snapshot(
+    State(state): State<ApiState>,
+    Json(request): Json<RepoRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.snapshot(&request.path).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn list_approvals(
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<PendingApproval>>, ApiError> {
+    Ok(Json(state.pending_approvals().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct ApprovalRequest {
+    path: PathBuf,
+    remote: String,
+}
+
+async fn approve_remote(
+    State(state): State<ApiState>,
+    Json(request): Json<ApprovalRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.approve_remote(&request.path, &request.remote).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LogLevelBody {
+    level: LogLevel,
+}
+
+// The level is the process's, shared by the watchers of every config it runs
+async fn log_level() -> Json<LogLevelBody> {
+    Json(LogLevelBody {
+        level: LogLevel::current(),
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LogLevelChange {
+    level: LogLevel,
+    previous: LogLevel,
+}
+
+async fn set_log_level(Json(request): Json<LogLevelBody>) -> Json<LogLevelChange> {
+    Json(LogLevelChange {
+        level: request.level,
+        previous: request.level.change(),
+    })
+}
+
+async fn events(
+    State(state): State<ApiState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // events missed by a lagging client are skipped
+    let stream = BroadcastStream::new(state.events.subscribe())
+        .filter_map(|event| event.ok())
+        .map(|event| Ok(Event::default().json_data(&event).unwrap_or_default()));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{events, test_util::test_repo};
+
+    const TOKEN: &str = "secret";
+
+    fn start(config_path: Option<PathBuf>, repos: Vec<PathBuf>) -> (ApiServer, EventSender) {
+        let events = events::channel();
+        let server = ApiServer::start(
+            "127.0.0.1:0".parse().unwrap(),
+            ApiState {
+                token: TOKEN.to_owned(),
+                config_path,
+                repos: Arc::new(Mutex::new(repos)),
+                events: events.clone(),
+                started: SystemTime::now(),
+                watcher_restarts: Arc::default(),
+            },
+        )
+        .unwrap();
+        (server, events)
+    }
+
+    // ureq blocks, keep it off the runtime's threads
+    async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        tokio::task::spawn_blocking(f).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let (server, _) = start(None, Vec::new());
+        let url = format!("http://{}/status", server.local_addr());
+        let status = blocking(move || match ureq::get(&url).call() {
+            Err(ureq::Error::Status(code, _)) => code,
+            _ => 0,
+        })
+        .await;
+        assert_eq!(401, status);
+    }
+
+    #[tokio::test]
+    async fn repos_and_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, _config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("a"), "a").unwrap();
+        let path = temp_dir.path().canonicalize().unwrap();
+        let (server, events) = start(None, vec![path.clone()]);
+        let mut subscriber = events.subscribe();
+        let base = format!("http://{}", server.local_addr());
+        let auth = format!("Bearer {}", TOKEN);
+
+        let (snapshot, repos, add) = blocking({
+            let path = path.clone();
+            move || {
+                let snapshot = ureq::post(&format!("{}/snapshot", base))
+                    .set("Authorization", &auth)
+                    .send_json(json!({ "path": path }))
+                    .unwrap()
+                    .status();
+                let repos: serde_json::Value = ureq::get(&format!("{}/repos", base))
+                    .set("Authorization", &auth)
+                    .call()
+                    .unwrap()
+                    .into_json()
+                    .unwrap();
+                let add = match ureq::post(&format!("{}/repos", base))
+                    .set("Authorization", &auth)
+                    .send_json(json!({ "path": path }))
+                {
+                    Err(ureq::Error::Status(code, _)) => code,
+                    _ => 0,
+                };
+                (snapshot, repos, add)
+            }
+        })
+        .await;
+
+        assert_eq!(200, snapshot);
+        assert!(matches!(
+            subscriber.recv().await.unwrap(),
+            WatchEvent::Snapshot { repo, .. } if repo == path
+        ));
+        assert_eq!("master", repos[0]["branch"]);
+        assert!(repos[0]["lastSnapshot"]["id"].is_string());
+        // there's no config file to add the repo to
+        assert_eq!(409, add);
+    }
+
+    #[tokio::test]
+    async fn add_repo_to_config() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.json");
+        save_config(&config_path, &WatchConfig::default()).unwrap();
+        let (server, _) = start(Some(config_path.clone()), Vec::new());
+        let url = format!("http://{}/repos", server.local_addr());
+        let path = temp_dir.path().to_owned();
+
+        let status = blocking(move || {
+            ureq::post(&url)
+                .set("Authorization", &format!("Bearer {}", TOKEN))
+                .send_json(json!({ "path": path }))
+                .unwrap()
+                .status()
+        })
+        .await;
+        assert_eq!(202, status);
+        assert_eq!(1, open_config(&config_path).unwrap().repos.len());
+    }
+
+    #[tokio::test]
+    async fn approvals() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        repo.remote("origin", "file:///nowhere").unwrap();
+        config.set_bool("snapshot.confirmnewremotes", true).unwrap();
+        config
+            .set_bool("remote.origin.snapshotenabled", true)
+            .unwrap();
+        std::fs::write(temp_dir.path().join("a"), "a").unwrap();
+        let path = temp_dir.path().canonicalize().unwrap();
+        Repo::from_path(&path).unwrap().snapshot().unwrap();
+        let (server, _) = start(None, vec![path.clone()]);
+        let url = format!("http://{}/approvals", server.local_addr());
+        let auth = format!("Bearer {}", TOKEN);
+
+        let (pending, unknown) = blocking(move || {
+            let pending: serde_json::Value = ureq::get(&url)
+                .set("Authorization", &auth)
+                .call()
+                .unwrap()
+                .into_json()
+                .unwrap();
+            let unknown = match ureq::post(&url)
+                .set("Authorization", &auth)
+                .send_json(json!({ "path": path, "remote": "other" }))
+            {
+                Err(ureq::Error::Status(code, _)) => code,
+                _ => 0,
+            };
+            (pending, unknown)
+        })
+        .await;
+
+        assert_eq!("origin", pending[0]["remote"]);
+        assert_eq!("file:///nowhere", pending[0]["url"]);
+        assert_eq!("master", pending[0]["branch"]);
+        assert_eq!(404, unknown);
+    }
+
+    #[tokio::test]
+    async fn log_level() {
+        let (server, _) = start(None, Vec::new());
+        let url = format!("http://{}/log-level", server.local_addr());
+        let auth = format!("Bearer {}", TOKEN);
+        LogLevel::Info.apply();
+
+        let (changed, current) = blocking(move || {
+            let changed: serde_json::Value = ureq::put(&url)
+                .set("Authorization", &auth)
+                .send_json(json!({ "level": "debug" }))
+                .unwrap()
+                .into_json()
+                .unwrap();
+            let current: serde_json::Value = ureq::get(&url)
+                .set("Authorization", &auth)
+                .call()
+                .unwrap()
+                .into_json()
+                .unwrap();
+            (changed, current)
+        })
+        .await;
+        assert_eq!(json!({"level": "debug", "previous": "info"}), changed);
+        assert_eq!("debug", current["level"]);
+        assert_eq!(LogLevel::Debug, LogLevel::current());
+    }
+
+    #[tokio::test]
+    async fn event_stream() {
+        let (server, events) = start(None, Vec::new());
+        let url = format!("http://{}/events", server.local_addr());
+
+        let reader = blocking(move || {
+            let response = ureq::get(&url)
+                .set("Authorization", &format!("Bearer {}", TOKEN))
+                .call()
+                .unwrap();
+            BufReader::new(response.into_reader())
+        })
+        .await;
+        events
+            .send(WatchEvent::ConfigReloaded {
+                time: SystemTime::UNIX_EPOCH,
+            })
+            .unwrap();
+        let line = blocking(move || {
+            reader
+                .lines()
+                .map(|l| l.unwrap())
+                .find(|l| l.starts_with("data:"))
+                .unwrap()
+        })
+        .await;
+        assert_eq!(
+            r#"data: {"type":"configReloaded","time":"1970-01-01T00:00:00Z"}"#,
+            line
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, metadata::Trigger, secret::Secret};
+
+// kept in the git dir next to the repo state
+const AUDIT_FILE: &str = "snapshot-audit.jsonl";
+// previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// read back from the end of the log to find the last entry
+const TAIL_CHUNK_LEN: u64 = 4096;
+
+/// An operation recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AuditAction {
+    Snapshot {
+        commit: String,
+        branch: String,
+        trigger: Trigger,
+    },
+    Push {
+        remote: String,
+        #[serde(rename = "ref")]
+        ref_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Restore {
+        commit: String,
+        paths: Vec<PathBuf>,
+    },
+    UndoRestore {
+        commit: String,
+    },
+    /// Repo added to the watcher config
+    Watch,
+    /// Repo removed from the watcher config
+    Unwatch,
+}
+
+/// A line of the audit log, chained to the previous one by its hash
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+    pub action: AuditAction,
+    /// Hash of the previous entry
+    pub prev: String,
+    /// SHA-256 of this entry's fields above
+    pub hash: String,
+    /// HMAC-SHA256 of the hash with the audit key, when one is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> Result<String, Error> {
+        let time = humantime::format_rfc3339_nanos(self.time).to_string();
+        let body = serde_json::to_vec(&(self.seq, time, &self.action, &self.prev))?;
+        Ok(hex(&Sha256::digest(body)))
+    }
+}
+
+/// Outcome of verifying an audit log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuditSummary {
+    pub entries: u64,
+    /// Entries whose signature was checked against the key
+    pub signed: u64,
+}
+
+/// Append-only log of the snapshot operations on a repo
+pub struct AuditLog {
+    path: PathBuf,
+    key: Option<Secret>,
+}
+
+impl AuditLog {
+    pub fn new(git_dir: &Path, key: Option<Secret>) -> Self {
+        Self {
+            path: git_dir.join(AUDIT_FILE),
+            key,
+        }
+    }
+
+    pub fn append(&self, action: AuditAction) -> Result<AuditEntry, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        // the watcher and the command line may append at the same time
+        file.lock()?;
+
+        let last = match last_line(&mut file)? {
+            // a crash while appending leaves part of an entry, which no later one could follow
+            Some(last) if !last.complete => {
+                warn!(
+                    "dropping the partly written last entry of {}",
+                    self.path.display()
+                );
+                file.set_len(last.start)?;
+                last_line(&mut file)?
+            }
+            last => last,
+        };
+        let (seq, prev) = match last {
+            Some(last) => {
+                // anything else is for `verify` to look into, not for appending to
+                let last: AuditEntry = serde_json::from_str(&last.line).map_err(|err| {
+                    Error::InvalidAuditLog(format!(
+                        "last entry of {}: {}",
+                        self.path.display(),
+                        err
+                    ))
+                })?;
+                (last.seq + 1, last.hash)
+            }
+            None => (0, GENESIS_HASH.to_owned()),
+        };
+        let mut entry = AuditEntry {
+            seq,
+            time: SystemTime::now(),
+            action,
+            prev,
+            hash: String::new(),
+            signature: None,
+        };
+        entry.hash = entry.digest()?;
+        entry.signature = self
+            .key
+            .as_ref()
+            .map(|key| sign(key, &entry.hash))
+            .transpose()?;
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(entry)
+    }
+
+    /// Check that every entry hashes to its recorded hash, links to the one before it and, with a
+    /// key, carries a valid signature
+    pub fn verify(&self) -> Result<AuditSummary, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(AuditSummary::default()),
+            Err(err) => return Err(err.into()),
+        };
+        let key = self.key.as_ref().map(Secret::reveal).transpose()?;
+
+        let mut summary = AuditSummary::default();
+        let mut prev = GENESIS_HASH.to_owned();
+        for (line_number, line) in BufReader::new(file).lines().enumerate() {
+            let invalid = |reason: &str| {
+                Error::InvalidAuditLog(format!("line {}: {}", line_number + 1, reason))
+            };
+            let entry: AuditEntry =
+                serde_json::from_str(&line?).map_err(|err| invalid(&err.to_string()))?;
+            if entry.seq != summary.entries {
+                return Err(invalid("out of sequence"));
+            }
+            if entry.prev != prev {
+                return Err(invalid("doesn't follow the previous entry"));
+            }
+            if entry.digest()? != entry.hash {
+                return Err(invalid("hash mismatch"));
+            }
+            if let Some(key) = &key {
+                let signature = entry
+                    .signature
+                    .as_ref()
+                    .ok_or_else(|| invalid("not signed"))?;
+                if hmac(key.as_bytes(), &entry.hash) != *signature {
+                    return Err(invalid("invalid signature"));
+                }
+                summary.signed += 1;
+            }
+            summary.entries += 1;
+            prev = entry.hash;
+        }
+        Ok(summary)
+    }
+}
+
+// The last line of the log without its newline
+struct LastLine {
+    // offset of its first byte
+    start: u64,
+    line: String,
+    // ending with a newline, as every entry is written with one
+    complete: bool,
+}
+
+// None for an empty log
+fn last_line(file: &mut File) -> Result<Option<LastLine>, Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut start = len;
+    let mut tail = Vec::new();
+    while start > 0 {
+        let chunk_start = start.saturating_sub(TAIL_CHUNK_LEN);
+        let mut chunk = vec![0; (start - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut tail);
+        tail = chunk;
+        start = chunk_start;
+        let content = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if let Some(newline) = content.iter().rposition(|&b| b == b'\n') {
+            return Ok(Some(LastLine {
+                start: start + newline as u64 + 1,
+                line: String::from_utf8_lossy(&content[newline + 1..]).into_owned(),
+                complete: tail.ends_with(b"\n"),
+            }));
+        }
+    }
+    let content = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    Ok((!content.is_empty()).then(|| LastLine {
+        start: 0,
+        line: String::from_utf8_lossy(content).into_owned(),
+        complete: tail.ends_with(b"\n"),
+    }))
+}
+
+fn sign(key: &Secret, hash: &str) -> Result<String, Error> {
+    Ok(hmac(key.reveal()?.as_bytes(), hash))
+}
+
+fn hmac(key: &[u8], hash: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(hash.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn snapshot(commit: &str) -> AuditAction {
+        AuditAction::Snapshot {
+            commit: commit.to_owned(),
+            branch: "main".to_owned(),
+            trigger: Trigger::Watcher,
+        }
+    }
+
+    #[test]
+    fn chained() {
+        let temp_dir = tempdir().unwrap();
+        let log = AuditLog::new(temp_dir.path(), None);
+        assert_eq!(AuditSummary::default(), log.verify().unwrap());
+
+        let first = log.append(snapshot("a")).unwrap();
+        let second = log.append(AuditAction::Watch).unwrap();
+        assert_eq!(GENESIS_HASH, first.prev);
+        assert_eq!(first.hash, second.prev);
+        assert_eq!(1, second.seq);
+        assert_eq!(
+            AuditSummary {
+                entries: 2,
+                signed: 0
+            },
+            log.verify().unwrap()
+        );
+
+        // rewriting an entry breaks its hash
+        let path = temp_dir.path().join(AUDIT_FILE);
+        let content = read_to_string(&path).unwrap();
+        write(&path, content.replacen("\"a\"", "\"b\"", 1)).unwrap();
+        assert!(matches!(log.verify(), Err(Error::InvalidAuditLog(_))));
+
+        // dropping one breaks the chain
+        let lines: Vec<_> = content.lines().collect();
+        write(&path, format!("{}\n", lines[1])).unwrap();
+        assert!(matches!(log.verify(), Err(Error::InvalidAuditLog(_))));
+    }
+
+    #[test]
+    fn signed() {
+        let temp_dir = tempdir().unwrap();
+        let key = Some(Secret::Plain("key".to_owned()));
+        let log = AuditLog::new(temp_dir.path(), key);
+        log.append(snapshot("a")).unwrap();
+        log.append(snapshot("b")).unwrap();
+        assert_eq!(2, log.verify().unwrap().signed);
+
+        // entries signed with another key are rejected
+        let other = AuditLog::new(temp_dir.path(), Some(Secret::Plain("other".to_owned())));
+        assert!(matches!(other.verify(), Err(Error::InvalidAuditLog(_))));
+    }
+
+    #[test]
+    fn tail() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("log");
+        write(&path, format!("a\n{}\n", "b".repeat(10000))).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let last = last_line(&mut file).unwrap().unwrap();
+        assert_eq!("b".repeat(10000), last.line);
+        assert_eq!((2, true), (last.start, last.complete));
+
+        write(&path, "a\nb").unwrap();
+        let last = last_line(&mut file).unwrap().unwrap();
+        assert_eq!(
+            ("b", 2, false),
+            (last.line.as_str(), last.start, last.complete)
+        );
+
+        write(&path, "").unwrap();
+        assert!(last_line(&mut file).unwrap().is_none());
+    }
+
+    #[test]
+    fn torn() {
+        let temp_dir = tempdir().unwrap();
+        let log = AuditLog::new(temp_dir.path(), None);
+        let first = log.append(snapshot("a")).unwrap();
+        let path = temp_dir.path().join(AUDIT_FILE);
+        let content = read_to_string(&path).unwrap();
+
+        // an entry cut short is dropped, the next one follows the last complete one
+        write(&path, format!("{}{{\"seq\":1,\"ti", content)).unwrap();
+        let second = log.append(snapshot("b")).unwrap();
+        assert_eq!((1, first.hash), (second.seq, second.prev));
+        assert_eq!(2, log.verify().unwrap().entries);
+
+        // a complete line that isn't an entry is refused rather than built upon
+        let content = read_to_string(&path).unwrap();
+        write(&path, format!("{}garbage\n", content)).unwrap();
+        assert!(matches!(
+            log.append(snapshot("c")),
+            Err(Error::InvalidAuditLog(_))
+        ));
+        assert!(read_to_string(&path).unwrap().ends_with("garbage\n"));
+    }
+}
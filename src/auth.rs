@@ -0,0 +1,483 @@
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use git2::{Config, Cred, CredentialType, Direction, Remote, RemoteCallbacks, Repository};
+use globset::GlobBuilder;
+use log::debug;
+
+use crate::{error::Error, secret::Secret, util::ConfigValue};
+
+/// The `Host` sections of an ssh config, `snapshot.sshConfig` or `~/.ssh/config`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SshConfig {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Section {
+    // empty for `Match` sections, which are never applied
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+/// How ssh connects to a host alias, the first value of each option found applying
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SshHost {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+}
+
+impl SshConfig {
+    /// The ssh config in use, empty when there's none
+    pub fn from_config(config: &Config) -> Self {
+        let default_path = dirs::home_dir()
+            .map(|home| home.join(".ssh").join("config"))
+            .unwrap_or_default();
+        let path = PathBuf::from_config(config, &["snapshot.sshconfig"], default_path);
+        match read_to_string(&path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) => {
+                debug!("no ssh config at {}: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        // options before the first `Host` apply to every host
+        let mut sections = vec![Section {
+            patterns: vec!["*".to_owned()],
+            options: Vec::new(),
+        }];
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+                Some(i) => (&line[..i], line[i..].trim_start_matches([' ', '\t', '='])),
+                None => (line, ""),
+            };
+            let keyword = keyword.to_ascii_lowercase();
+            match keyword.as_str() {
+                "host" => sections.push(Section {
+                    patterns: value.split_whitespace().map(unquote).collect(),
+                    options: Vec::new(),
+                }),
+                "match" => sections.push(Section {
+                    patterns: Vec::new(),
+                    options: Vec::new(),
+                }),
+                _ => {
+                    let section = sections.last_mut().expect("sections start with one");
+                    section.options.push((keyword, unquote(value.trim())));
+                }
+            }
+        }
+        Self { sections }
+    }
+
+    /// The options applying to `alias`, with `~` and `%` tokens of identity files expanded
+    pub fn host(&self, alias: &str) -> SshHost {
+        let mut host = SshHost::default();
+        let mut identity_files = Vec::new();
+        for section in self.sections.iter().filter(|s| s.matches(alias)) {
+            for (keyword, value) in &section.options {
+                match keyword.as_str() {
+                    "hostname" if host.hostname.is_none() => {
+                        host.hostname = Some(value.replace("%h", alias))
+                    }
+                    "user" if host.user.is_none() => host.user = Some(value.clone()),
+                    "port" if host.port.is_none() => host.port = value.parse().ok(),
+                    "identityfile" => identity_files.push(value.clone()),
+                    _ => {}
+                }
+            }
+        }
+        let hostname = host.hostname.clone().unwrap_or_else(|| alias.to_owned());
+        host.identity_files = identity_files
+            .iter()
+            .map(|file| expand_tokens(file, &hostname, host.user.as_deref()))
+            .collect();
+        host
+    }
+}
+
+impl Section {
+    // any of the patterns matches and none of the negated ones does
+    fn matches(&self, alias: &str) -> bool {
+        let is_match = |pattern: &str| {
+            GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map(|glob| glob.compile_matcher().is_match(alias))
+                .unwrap_or(false)
+        };
+        let (negated, patterns): (Vec<_>, Vec<_>) =
+            self.patterns.iter().partition(|p| p.starts_with('!'));
+        patterns.iter().any(|p| is_match(p)) && !negated.iter().any(|p| is_match(&p[1..]))
+    }
+}
+
+fn unquote(value: &str) -> String {
+    value.trim_matches('"').to_owned()
+}
+
+// `~` and the `%d`, `%h` and `%r` tokens ssh expands in identity file paths
+fn expand_tokens(path: &str, hostname: &str, user: Option<&str>) -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    let mut expanded = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some(token @ ('d' | 'h' | 'r' | '%'))) => {
+                chars.next();
+                match token {
+                    'd' => expanded.push_str(&home.to_string_lossy()),
+                    'h' => expanded.push_str(hostname),
+                    'r' => expanded.push_str(user.unwrap_or_default()),
+                    _ => expanded.push('%'),
+                }
+            }
+            _ => expanded.push(c),
+        }
+    }
+    PathBuf::from(shellexpand::tilde(&expanded).as_ref())
+}
+
+// The parts of an `ssh://` or scp-like `user@host:path` url
+#[derive(Debug, PartialEq, Eq)]
+struct SshUrl<'a> {
+    user: Option<&'a str>,
+    host: &'a str,
+    port: Option<u16>,
+    path: &'a str,
+}
+
+fn parse_ssh_url(url: &str) -> Option<SshUrl<'_>> {
+    let (authority, path) = match ["ssh://", "git+ssh://", "ssh+git://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))
+    {
+        Some(rest) => {
+            let i = rest.find('/')?;
+            (&rest[..i], &rest[i..])
+        }
+        // scp-like, a colon before any slash unlike a local path or a windows drive
+        None if !url.contains("://") => {
+            let (authority, path) = url.split_once(':')?;
+            if authority.is_empty()
+                || authority.contains('/')
+                || (cfg!(windows) && authority.len() == 1)
+            {
+                return None;
+            }
+            (authority, path)
+        }
+        None => return None,
+    };
+    let (user, host) = match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, authority),
+    };
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) if url.contains("://") => (host, Some(port.parse().ok()?)),
+        _ => (host, None),
+    };
+    Some(SshUrl {
+        user,
+        host,
+        port,
+        path,
+    })
+}
+
+/// `url` with its host alias resolved to the host name, port and user ssh would connect with,
+/// for libgit2 which doesn't read the ssh config. `None` when the config changes nothing.
+pub fn resolve_url(url: &str, host: &SshHost) -> Option<String> {
+    let parts = parse_ssh_url(url)?;
+    let hostname = host.hostname.as_deref().unwrap_or(parts.host);
+    let user = parts.user.or(host.user.as_deref());
+    let port = parts.port.or(host.port);
+    if hostname == parts.host && user == parts.user && port == parts.port {
+        return None;
+    }
+    let user = user.map(|user| format!("{}@", user)).unwrap_or_default();
+    Some(match port {
+        // paths of scp-like urls are taken from the server's root, like hosting services have them
+        Some(port) => format!(
+            "ssh://{}{}:{}/{}",
+            user,
+            hostname,
+            port,
+            parts.path.trim_start_matches('/')
+        ),
+        None if url.contains("://") => format!("ssh://{}{}{}", user, hostname, parts.path),
+        None => format!("{}{}:{}", user, hostname, parts.path),
+    })
+}
+
+/// `remote` connecting through the ssh config's host alias of its url, and the ssh options of it
+pub fn connect<'r>(
+    repo: &'r Repository,
+    config: &Config,
+    remote: &str,
+    direction: Direction,
+) -> Result<(Remote<'r>, SshHost), Error> {
+    let named = repo.find_remote(remote)?;
+    let url = match direction {
+        Direction::Push => named.pushurl().or_else(|| named.url()),
+        Direction::Fetch => named.url(),
+    }
+    .unwrap_or_default()
+    .to_owned();
+    let Some(parts) = parse_ssh_url(&url) else {
+        return Ok((named, SshHost::default()));
+    };
+    let host = SshConfig::from_config(config).host(parts.host);
+    match resolve_url(&url, &host) {
+        Some(resolved) => {
+            debug!("connecting to {} at {} for {}", remote, resolved, url);
+            Ok((repo.remote_anonymous(&resolved)?, host))
+        }
+        None => Ok((named, host)),
+    }
+}
+
+/// Callbacks authenticating with `remote` non-interactively, with the identity files of the ssh
+/// config's `host` when the agent has no key
+pub fn remote_callbacks<'a>(
+    config: &'a Config,
+    remote: &'a str,
+    host: SshHost,
+) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    // Only allow non-interactive credentials
+    let mut tried_configured = false;
+    let mut tried_agent = false;
+    let mut identity_files = host
+        .identity_files
+        .into_iter()
+        .filter(|file| file.is_file());
+    callbacks.credentials(move |url, username, allowed_types| {
+        // libgit2 asks again after a rejected credential, configured ones are tried once
+        if !tried_configured {
+            tried_configured = true;
+            match configured_credentials(config, remote, username, allowed_types) {
+                Ok(Some(cred)) => return Ok(cred),
+                Ok(None) => {}
+                Err(err) => {
+                    return Err(git2::Error::new(
+                        git2::ErrorCode::Auth,
+                        git2::ErrorClass::Callback,
+                        err.to_string(),
+                    ))
+                }
+            }
+        }
+        if allowed_types.is_user_pass_plaintext() {
+            if let Ok(cred) = Cred::credential_helper(config, url, username) {
+                return Ok(cred);
+            }
+        }
+        if allowed_types.is_ssh_key() {
+            if let Some(username) = username.or(host.user.as_deref()) {
+                if !tried_agent {
+                    tried_agent = true;
+                    if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                        return Ok(cred);
+                    }
+                }
+                for file in identity_files.by_ref() {
+                    match Cred::ssh_key(username, None, &file, None) {
+                        Ok(cred) => return Ok(cred),
+                        Err(err) => debug!("skipping ssh key {}: {}", file.display(), err),
+                    }
+                }
+            }
+        }
+        Err(git2::Error::new(
+            git2::ErrorCode::Auth,
+            git2::ErrorClass::Callback,
+            "unable to authenticate, setup ssh key agent or credential helper for this remote and username",
+        ))
+    });
+    callbacks
+}
+
+/// Credentials from `remote.<name>.snapshotusername`/`snapshotpassword` or
+/// `remote.<name>.snapshotsshkey`/`snapshotsshpassphrase`, secrets are only revealed here
+pub fn configured_credentials(
+    config: &Config,
+    remote: &str,
+    username: Option<&str>,
+    allowed_types: CredentialType,
+) -> Result<Option<Cred>, Error> {
+    let value = |key: &str| {
+        let value = String::from_config(
+            config,
+            &[&format!("remote.{}.{}", remote, key)],
+            String::new(),
+        );
+        (!value.is_empty()).then_some(value)
+    };
+    let username = value("snapshotusername")
+        .or_else(|| username.map(str::to_owned))
+        .unwrap_or_else(|| "git".to_owned());
+
+    if allowed_types.is_user_pass_plaintext() {
+        if let Some(password) = value("snapshotpassword") {
+            let password = password.parse::<Secret>()?.reveal()?;
+            return Ok(Some(Cred::userpass_plaintext(&username, &password)?));
+        }
+    }
+    if allowed_types.is_ssh_key() {
+        if let Some(key) = value("snapshotsshkey") {
+            let passphrase = value("snapshotsshpassphrase")
+                .map(|p| p.parse::<Secret>()?.reveal())
+                .transpose()?;
+            return Ok(Some(Cred::ssh_key(
+                &username,
+                None,
+                Path::new(shellexpand::tilde(&key).as_ref()),
+                passphrase.as_deref(),
+            )?));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    const SSH_CONFIG: &str = "\
+# work account
+Host work-github
+    HostName github.com
+    User git
+    IdentityFile ~/.ssh/id_work
+
+Host *.internal !legacy.internal
+    Port=2222
+    IdentityFile \"/keys/%h_%r\"
+
+Match user nobody
+    User ignored
+
+Host *
+    User me
+    IdentityFile ~/.ssh/id_ed25519
+";
+
+    #[test]
+    fn hosts() {
+        let config = SshConfig::parse(SSH_CONFIG);
+        let home = dirs::home_dir().unwrap();
+
+        let work = config.host("work-github");
+        assert_eq!(Some("github.com"), work.hostname.as_deref());
+        assert_eq!(Some("git"), work.user.as_deref());
+        assert_eq!(None, work.port);
+        assert_eq!(
+            vec![home.join(".ssh/id_work"), home.join(".ssh/id_ed25519")],
+            work.identity_files
+        );
+
+        let internal = config.host("build.INTERNAL");
+        assert_eq!(Some(2222), internal.port);
+        assert_eq!(Some("me"), internal.user.as_deref());
+        assert_eq!(
+            PathBuf::from("/keys/build.INTERNAL_me"),
+            internal.identity_files[0]
+        );
+
+        let legacy = config.host("legacy.internal");
+        assert_eq!(None, legacy.port);
+        assert_eq!(Some("me"), legacy.user.as_deref());
+    }
+
+    #[test]
+    fn urls() {
+        let config = SshConfig::parse(SSH_CONFIG);
+        let resolve = |url: &str| {
+            let host = config.host(parse_ssh_url(url).unwrap().host);
+            resolve_url(url, &host)
+        };
+        assert_eq!(
+            Some("git@github.com:org/repo.git".to_owned()),
+            resolve("git@work-github:org/repo.git")
+        );
+        assert_eq!(
+            Some("ssh://me@build.internal:2222/srv/repo.git".to_owned()),
+            resolve("ssh://build.internal/srv/repo.git")
+        );
+        assert_eq!(
+            Some("ssh://git@build.internal:2222/org/repo.git".to_owned()),
+            resolve("git@build.internal:org/repo.git")
+        );
+        // the url's user and port win over the config's
+        assert_eq!(None, resolve("ssh://me@host.internal:2222/repo.git"));
+        assert_eq!(None, parse_ssh_url("https://github.com/org/repo.git"));
+        assert_eq!(None, parse_ssh_url("/srv/repo.git"));
+        assert_eq!(None, parse_ssh_url("./repo:name"));
+    }
+
+    #[test]
+    fn connect_alias() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let ssh_config = temp_dir.path().join("ssh_config");
+        std::fs::write(&ssh_config, SSH_CONFIG).unwrap();
+        config
+            .set_str("snapshot.sshconfig", ssh_config.to_str().unwrap())
+            .unwrap();
+        repo.remote("work", "git@work-github:org/repo.git").unwrap();
+        repo.remote("backup", "https://example.com/repo.git")
+            .unwrap();
+
+        let (remote, host) = connect(&repo, &config, "work", Direction::Push).unwrap();
+        assert_eq!(Some("git@github.com:org/repo.git"), remote.url());
+        assert_eq!(Some("git"), host.user.as_deref());
+        let (remote, host) = connect(&repo, &config, "backup", Direction::Fetch).unwrap();
+        assert_eq!(Some("backup"), remote.name());
+        assert_eq!(SshHost::default(), host);
+    }
+
+    #[test]
+    fn remote_configured_credentials() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+
+        let userpass = CredentialType::USER_PASS_PLAINTEXT;
+        assert!(configured_credentials(&config, "origin", None, userpass)
+            .unwrap()
+            .is_none());
+
+        config
+            .set_str("remote.origin.snapshotpassword", "token")
+            .unwrap();
+        let cred = configured_credentials(&config, "origin", None, userpass)
+            .unwrap()
+            .unwrap();
+        assert!(cred.has_username());
+        // no ssh key configured
+        assert!(
+            configured_credentials(&config, "origin", Some("git"), CredentialType::SSH_KEY)
+                .unwrap()
+                .is_none()
+        );
+
+        config
+            .set_str("remote.origin.snapshotpassword", "age:not base64")
+            .unwrap();
+        assert!(configured_credentials(&config, "origin", None, userpass).is_err());
+    }
+}
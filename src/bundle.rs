@@ -0,0 +1,158 @@
+use std::{
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+use git2::{Oid, Repository};
+use log::debug;
+
+use crate::error::Error;
+
+/// Write `refs` to a bundle at `path` with the system's `git`, leaving out the history of
+/// `exclude`. False without a commit to bundle, when no bundle is written.
+pub fn create(
+    repo: &Repository,
+    path: &Path,
+    refs: &[String],
+    exclude: &[Oid],
+) -> Result<bool, Error> {
+    let mut walk = repo.revwalk()?;
+    for ref_name in refs {
+        walk.push_ref(ref_name)?;
+    }
+    for oid in exclude {
+        // excluded commits may be gone since, after squashing sessions
+        if repo.find_commit(*oid).is_ok() {
+            walk.hide(*oid)?;
+        }
+    }
+    if walk.next().is_none() {
+        return Ok(false);
+    }
+    let mut args = vec!["bundle".to_owned(), "create".to_owned()];
+    args.push(path.to_string_lossy().into_owned());
+    args.extend(refs.iter().cloned());
+    args.extend(
+        exclude
+            .iter()
+            .filter(|oid| repo.find_commit(**oid).is_ok())
+            .map(|oid| format!("^{}", oid)),
+    );
+    git(repo, &args)?;
+    Ok(true)
+}
+
+/// Fetch the refs of the bundle at `path` into `repo`, replacing them. The bundle's excluded
+/// history has to be in the repo already, from the bundles before it.
+pub fn unbundle(repo: &Repository, path: &Path) -> Result<Vec<(String, Oid)>, Error> {
+    let path = path.to_string_lossy().into_owned();
+    git(repo, &["bundle", "verify", "--quiet", &path])?;
+    let heads = list_heads(&String::from_utf8_lossy(
+        &git(repo, &["bundle", "list-heads", &path])?.stdout,
+    ));
+    if heads.is_empty() {
+        return Ok(heads);
+    }
+    let mut args = vec!["fetch".to_owned(), "--no-tags".to_owned(), path];
+    args.extend(
+        heads
+            .iter()
+            .map(|(ref_name, _)| format!("+{}:{}", ref_name, ref_name)),
+    );
+    git(repo, &args)?;
+    Ok(heads)
+}
+
+// `<oid> <ref>` lines of `git bundle list-heads`
+fn list_heads(output: &str) -> Vec<(String, Oid)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (oid, ref_name) = line.split_once(' ')?;
+            Some((ref_name.to_owned(), Oid::from_str(oid).ok()?))
+        })
+        .filter(|(ref_name, _)| ref_name.starts_with("refs/"))
+        .collect()
+}
+
+fn git<S: AsRef<std::ffi::OsStr>>(repo: &Repository, args: &[S]) -> Result<Output, Error> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        debug!("git bundle command failed: {}", stderr);
+        return Err(Error::Bundle(match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => stderr.lines().last().unwrap_or(stderr).to_owned(),
+        }));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo_with_files;
+
+    #[test]
+    fn round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        let first = repo
+            .commit(
+                Some("refs/heads/snapshot/master"),
+                &signature,
+                &signature,
+                "first",
+                &tree,
+                &[],
+            )
+            .unwrap();
+        let refs = vec!["refs/heads/snapshot/master".to_owned()];
+        let full = temp_dir.path().join("full.bundle");
+        assert!(create(&repo, &full, &refs, &[]).unwrap());
+        // nothing new since
+        let empty = temp_dir.path().join("empty.bundle");
+        assert!(!create(&repo, &empty, &refs, &[first]).unwrap());
+        assert!(!empty.exists());
+
+        let second = repo
+            .commit(
+                Some("refs/heads/snapshot/master"),
+                &signature,
+                &signature,
+                "second",
+                &tree,
+                &[&repo.find_commit(first).unwrap()],
+            )
+            .unwrap();
+        let incremental = temp_dir.path().join("incremental.bundle");
+        assert!(create(&repo, &incremental, &refs, &[first]).unwrap());
+
+        let other_dir = tempdir().unwrap();
+        let other = Repository::init(other_dir.path()).unwrap();
+        // the history it builds on is missing
+        assert!(matches!(
+            unbundle(&other, &incremental),
+            Err(Error::Bundle(_))
+        ));
+        assert_eq!(
+            vec![(refs[0].clone(), first)],
+            unbundle(&other, &full).unwrap()
+        );
+        unbundle(&other, &incremental).unwrap();
+        assert_eq!(second, other.refname_to_id(&refs[0]).unwrap());
+    }
+}
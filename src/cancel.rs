@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{error::Error, util::panic_message};
+
+// phase of an operation that hasn't entered one yet
+const STARTING: &str = "start";
+
+/// Shared with an operation so it can be given up on, stopping it as it enters its next phase
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    phase: Mutex<Option<&'static str>>,
+}
+
+impl CancelToken {
+    /// Record that the operation entered `phase`, failing once it was given up on
+    pub fn enter(&self, phase: &'static str) -> Result<(), Error> {
+        *self.0.phase.lock().unwrap() = Some(phase);
+        match self.is_cancelled() {
+            true => Err(Error::Cancelled(phase)),
+            false => Ok(()),
+        }
+    }
+
+    /// The phase the operation is in
+    pub fn phase(&self) -> &'static str {
+        self.0.phase.lock().unwrap().unwrap_or(STARTING)
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// The repos an operation is running on, given up on or not, so another isn't started alongside
+/// one that's still hanging
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashMap<PathBuf, CancelToken>>>);
+
+impl InFlight {
+    /// Mark `path` busy until the returned operation ends, failing with the phase of the one
+    /// running on it already
+    pub fn start(&self, path: &Path) -> Result<Running, Error> {
+        let mut running = self.0.lock().unwrap();
+        if let Some(token) = running.get(path) {
+            return Err(Error::StillRunning(token.phase()));
+        }
+        let token = CancelToken::default();
+        running.insert(path.to_owned(), token.clone());
+        Ok(Running {
+            in_flight: self.clone(),
+            path: path.to_owned(),
+            token,
+        })
+    }
+}
+
+/// An operation marked in flight, until it's dropped
+#[derive(Debug)]
+pub struct Running {
+    in_flight: InFlight,
+    path: PathBuf,
+    token: CancelToken,
+}
+
+impl Running {
+    /// `run_within` keeping the repo busy until `op` returns, even after it was given up on
+    pub fn run_within<T, F>(self, timeout: Duration, op: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(CancelToken) -> T + Send + 'static,
+    {
+        let token = self.token.clone();
+        run(timeout, token, move |token| {
+            let _running = self;
+            op(token)
+        })
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Run `op` on a thread of its own, giving up on it after `timeout`, or on the current thread
+/// without a limit when `timeout` is zero. A call hanging in the filesystem or libgit2 can't be
+/// interrupted, the thread is left to stop once it enters its next phase.
+pub fn run_within<T, F>(timeout: Duration, op: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(CancelToken) -> T + Send + 'static,
+{
+    run(timeout, CancelToken::default(), op)
+}
+
+fn run<T, F>(timeout: Duration, token: CancelToken, op: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(CancelToken) -> T + Send + 'static,
+{
+    if timeout.is_zero() {
+        return Ok(op(token));
+    }
+    let (sender, receiver) = channel();
+    let worker = token.clone();
+    // the operation's spans stay within the caller's
+    let span = tracing::Span::current();
+    thread::Builder::new()
+        .name("timed operation".to_owned())
+        .spawn(move || {
+            let _entered = span.enter();
+            let result = catch_unwind(AssertUnwindSafe(|| op(worker)))
+                .map_err(|payload| Error::Panicked(panic_message(&*payload)));
+            // the receiver is gone when the operation was given up on
+            let _ = sender.send(result);
+        })?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Disconnected) => Err(Error::Panicked("timed operation".to_owned())),
+        Err(RecvTimeoutError::Timeout) => {
+            token.cancel();
+            Err(Error::TimedOut {
+                phase: token.phase(),
+                after: timeout,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout() {
+        let no_limit = run_within(Duration::ZERO, |token| token.enter("index build"));
+        assert!(no_limit.unwrap().is_ok());
+        assert_eq!(
+            2,
+            run_within(Duration::from_secs(5), |token| {
+                token.enter("index build").unwrap();
+                2
+            })
+            .unwrap()
+        );
+
+        let (sender, receiver) = channel();
+        let err = run_within(Duration::from_millis(100), move |token| {
+            token.enter("index build").unwrap();
+            thread::sleep(Duration::from_millis(300));
+            sender.send(token.enter("commit")).unwrap();
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::TimedOut {
+                phase: "index build",
+                ..
+            }
+        ));
+        // stopped before committing
+        assert!(matches!(
+            receiver.recv().unwrap(),
+            Err(Error::Cancelled("commit"))
+        ));
+
+        assert!(matches!(
+            run_within(Duration::from_secs(5), |_| -> u8 { panic!("boom") }),
+            Err(Error::Panicked(message)) if message == "boom"
+        ));
+    }
+
+    #[test]
+    fn in_flight() {
+        let in_flight = InFlight::default();
+        let path = Path::new("/repo");
+        let (sender, receiver) = channel();
+        let running = in_flight.start(path).unwrap();
+        let err = running
+            .run_within(Duration::from_millis(100), move |token| {
+                token.enter("push").unwrap();
+                thread::sleep(Duration::from_millis(300));
+                sender.send(()).unwrap();
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::TimedOut { phase: "push", .. }));
+        // busy until the operation given up on returns
+        assert!(matches!(
+            in_flight.start(path),
+            Err(Error::StillRunning("push"))
+        ));
+        assert!(in_flight.start(Path::new("/other")).is_ok());
+        receiver.recv().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(in_flight.start(path).is_ok());
+    }
+}
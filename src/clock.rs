@@ -0,0 +1,150 @@
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::TimeZone;
+use git2::{Repository, Signature, Time};
+
+use crate::error::Error;
+use crate::util::{expand, ConfigValue};
+
+pub(crate) const DEFAULT_AUTHOR_NAME: &str = "git-snapshot";
+pub(crate) const DEFAULT_AUTHOR_EMAIL: &str = "git-snapshot@localhost";
+
+/// Where a repo's snapshots, restores and state records take the current time from
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's clock, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock standing still until it's set or advanced, for reproducible snapshots
+#[derive(Debug)]
+pub struct FixedClock(Mutex<SystemTime>);
+
+impl FixedClock {
+    pub fn new(time: SystemTime) -> Self {
+        Self(Mutex::new(time))
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Who snapshots are committed as, at the time taken from the repo's clock
+pub trait SignatureProvider: Debug + Send + Sync {
+    fn signature(&self, repo: &Repository, time: SystemTime) -> Result<Signature<'static>, Error>;
+}
+
+/// `snapshot.authorName` and `snapshot.authorEmail`, falling back to `user.name` and
+/// `user.email`, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConfigSignature;
+
+impl SignatureProvider for ConfigSignature {
+    fn signature(&self, repo: &Repository, time: SystemTime) -> Result<Signature<'static>, Error> {
+        let config = repo.config()?;
+        let value = |keys: &[&str], default: &str| {
+            keys.iter()
+                .map(|key| expand(&String::from_config(&config, &[key], String::new()), &[]))
+                .find(|value| !value.trim().is_empty())
+                .unwrap_or_else(|| default.to_owned())
+        };
+        let name = value(&["snapshot.authorname", "user.name"], DEFAULT_AUTHOR_NAME);
+        let email = value(
+            &["snapshot.authoremail", "user.email"],
+            DEFAULT_AUTHOR_EMAIL,
+        );
+        new_signature(&name, &email, time)
+    }
+}
+
+/// The same name and email whatever the repo's config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedSignature {
+    pub name: String,
+    pub email: String,
+}
+
+impl SignatureProvider for FixedSignature {
+    fn signature(&self, _repo: &Repository, time: SystemTime) -> Result<Signature<'static>, Error> {
+        new_signature(&self.name, &self.email, time)
+    }
+}
+
+fn new_signature(name: &str, email: &str, time: SystemTime) -> Result<Signature<'static>, Error> {
+    Signature::new(name, email, &git_time(time))
+        .map_err(|err| Error::InvalidSignature(err.message().to_owned()))
+}
+
+// `time` in the local time zone, as `Signature::now` would have it
+fn git_time(time: SystemTime) -> Time {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let offset = chrono::Local
+        .timestamp_opt(seconds, 0)
+        .single()
+        .map(|local| local.offset().local_minus_utc() / 60)
+        .unwrap_or_default();
+    Time::new(seconds, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn fixed_clock() {
+        let clock = FixedClock::new(UNIX_EPOCH);
+        assert_eq!(UNIX_EPOCH, clock.now());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(60), clock.now());
+    }
+
+    #[test]
+    fn signatures() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let time = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+
+        let signature = ConfigSignature.signature(&repo, time).unwrap();
+        assert_eq!(Some("Test"), signature.name());
+        assert_eq!(1_650_000_000, signature.when().seconds());
+        config.set_str("snapshot.authorname", "Snapshots").unwrap();
+        let signature = ConfigSignature.signature(&repo, time).unwrap();
+        assert_eq!(Some("Snapshots"), signature.name());
+
+        let fixed = FixedSignature {
+            name: "Build".to_owned(),
+            email: "build@example.com".to_owned(),
+        };
+        let signature = fixed.signature(&repo, time).unwrap();
+        assert_eq!(Some("build@example.com"), signature.email());
+    }
+}
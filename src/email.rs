@@ -0,0 +1,89 @@
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    notify::{notification_error, Notification, Notifier},
+    secret::Secret,
+    util::run_blocking,
+};
+
+/// SMTP settings for email notifications
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    /// Defaults to the submission port with STARTTLS
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Emails notifications
+pub struct EmailNotifier {
+    config: EmailConfig,
+    transport: SmtpTransport,
+}
+
+impl EmailNotifier {
+    pub fn new(config: EmailConfig) -> Result<Self, Error> {
+        let mut transport =
+            SmtpTransport::starttls_relay(&config.smtp_host).map_err(notification_error)?;
+        if let Some(port) = config.smtp_port {
+            transport = transport.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            transport =
+                transport.credentials(Credentials::new(username.clone(), password.reveal()?));
+        }
+        Ok(Self {
+            config,
+            transport: transport.build(),
+        })
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let mut builder = Message::builder()
+            .from(
+                self.config
+                    .from
+                    .parse::<Mailbox>()
+                    .map_err(notification_error)?,
+            )
+            .subject(&notification.title);
+        for to in &self.config.to {
+            builder = builder.to(to.parse::<Mailbox>().map_err(notification_error)?);
+        }
+        let message = builder
+            .body(notification.text.clone())
+            .map_err(notification_error)?;
+        // notifications are sent from watcher tasks, SMTP blocks on the connection
+        run_blocking(|| self.transport.send(&message)).map_err(notification_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config: EmailConfig = from_str(
+            r#"{"smtp_host": "smtp.example.com", "from": "watcher@example.com", "to": ["me@example.com"]}"#,
+        )
+        .unwrap();
+        assert!(EmailNotifier::new(config).is_ok());
+    }
+}
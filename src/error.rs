@@ -1,15 +1,246 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
 use thiserror::Error as ThisError;
 
 #[derive(Debug, ThisError)]
 pub enum Error {
+    #[error("bundle error: {0}")]
+    Bundle(String),
+    #[error("cancelled before {0}")]
+    Cancelled(&'static str),
+    #[error("system clock is {behind:?} behind the previous snapshot")]
+    ClockSkew { behind: std::time::Duration },
+    #[error("commit message hook failed: {0}")]
+    CommitMsgHook(String),
+    #[error("glob error: {0:?}")]
+    Glob(#[from] globset::Error),
     #[error("git error: {0:?}")]
     Git(#[from] git2::Error),
+    #[error("invalid audit log: {0}")]
+    InvalidAuditLog(String),
+    #[error("invalid log level: {0}, one of off, error, warn, info and debug")]
+    InvalidLogLevel(String),
     #[error("invalid head")]
     InvalidHead,
+    #[error("invalid snapshot branch name: {0:?}")]
+    InvalidBranchName(String),
+    #[error("invalid settings archive: {0}")]
+    InvalidSettings(String),
+    #[error("invalid snapshot identity: {0}")]
+    InvalidSignature(String),
+    #[error("invalid event trace: {0}")]
+    InvalidTrace(String),
+    #[error("invalid time: {0}")]
+    InvalidTime(String),
     #[error("io error: {0:?}")]
     Io(#[from] std::io::Error),
     #[error("json error: {0:?}")]
     Json(#[from] serde_json::error::Error),
+    #[error("low disk space on {}: {available} bytes available, {required} required", path.display())]
+    LowDiskSpace {
+        path: std::path::PathBuf,
+        available: u64,
+        required: u64,
+    },
+    #[error(
+        "{0} change(s) in the working tree aren't in the latest snapshot, take a checkpoint first"
+    )]
+    NotCaptured(usize),
+    #[error("no restore to undo")]
+    NothingToUndo,
+    #[error("notification error: {0}")]
+    Notification(String),
     #[error("notify error: {0:?}")]
     Notify(#[from] notify::Error),
+    #[error("regex error: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("{} is a git repo already", .0.display())]
+    RepoExists(std::path::PathBuf),
+    #[error("panicked: {0}")]
+    Panicked(String),
+    #[error("remote {remote} at {url} isn't allowed by snapshot.push.allowUrls")]
+    RemoteNotAllowed { remote: String, url: String },
+    #[error("secret error: {0}")]
+    Secret(String),
+    #[error("no snapshot found")]
+    SnapshotNotFound,
+    #[error("still busy with an operation given up on, in {0}")]
+    StillRunning(&'static str),
+    #[error("object storage error: {0}")]
+    Storage(String),
+    #[error("unknown user: {0}")]
+    UnknownUser(String),
+    #[error("timed out during {phase} after {}", humantime::format_duration(*.after))]
+    TimedOut {
+        phase: &'static str,
+        after: std::time::Duration,
+    },
+    #[error("snapshot {commit} doesn't match the working tree in {mismatched} file(s)")]
+    VerificationFailed { commit: String, mismatched: usize },
+}
+
+/// Stable identifier of a kind of failure, for scripts and notifications to tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    Auth,
+    ClockSkew,
+    CommitMsgHook,
+    DetachedHead,
+    IndexLocked,
+    InvalidBranchName,
+    InvalidSignature,
+    LowDiskSpace,
+    Network,
+    NonFastForward,
+    Other,
+    Panic,
+    RemoteNotAllowed,
+    Timeout,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::ClockSkew => "clock-skew",
+            Self::CommitMsgHook => "commit-msg-hook",
+            Self::DetachedHead => "detached-head",
+            Self::IndexLocked => "index-locked",
+            Self::InvalidBranchName => "invalid-branch-name",
+            Self::InvalidSignature => "invalid-signature",
+            Self::LowDiskSpace => "low-disk-space",
+            Self::Network => "network",
+            Self::NonFastForward => "non-fast-forward",
+            Self::Other => "other",
+            Self::Panic => "panic",
+            Self::RemoteNotAllowed => "remote-not-allowed",
+            Self::Timeout => "timeout",
+        }
+    }
+
+    /// One line on how to fix the failure
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Auth => Some(
+                "add the remote's key to the ssh agent, set up a credential helper, or set \
+                 remote.<name>.snapshotUsername and snapshotPassword",
+            ),
+            Self::ClockSkew => Some(
+                "check the system clock, or set snapshot.clockSkew to clamp to commit snapshots at \
+                 the previous one's time",
+            ),
+            Self::CommitMsgHook => Some(
+                "check the repo's prepare-commit-msg hook runs outside of `git commit`, or unset \
+                 snapshot.prepareCommitMsg",
+            ),
+            Self::DetachedHead => {
+                Some("check out a branch, snapshots are taken of the current branch only")
+            }
+            Self::IndexLocked => Some(
+                "another git process is using the repo, remove the .lock file in the git dir if \
+                 none is running",
+            ),
+            Self::InvalidBranchName => Some(
+                "check the snapshot branch template with `git snapshot config check-template`, or \
+                 set snapshot.sanitizeBranch to replace what git doesn't allow",
+            ),
+            Self::InvalidSignature => Some(
+                "check snapshot.authorName and snapshot.authorEmail, or user.name and user.email",
+            ),
+            Self::LowDiskSpace => Some("free up disk space or lower snapshot.minFreeSpace"),
+            Self::Network => Some(
+                "check the connection to the remote, snapshot.retryAttempts sets how often pushes \
+                 are tried",
+            ),
+            Self::NonFastForward => Some(
+                "the remote snapshot branch has diverged, delete it on the remote or set \
+                 remote.<name>.snapshotBranch to push elsewhere",
+            ),
+            Self::Other => None,
+            Self::Panic => Some(
+                "a bug in git-snapshot, the watcher keeps running; please report it with the log",
+            ),
+            Self::RemoteNotAllowed => Some(
+                "check the remote's url, or add a pattern matching it to snapshot.push.allowUrls",
+            ),
+            Self::Timeout => Some(
+                "check the repo's filesystem responds, or raise handler_timeout in the watcher \
+                 config",
+            ),
+        }
+    }
+
+    /// Whether trying again later may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::IndexLocked | Self::Network)
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Git(err) => match err.code() {
+                git2::ErrorCode::Auth => ErrorCode::Auth,
+                git2::ErrorCode::Locked => ErrorCode::IndexLocked,
+                git2::ErrorCode::NotFastForward => ErrorCode::NonFastForward,
+                _ => match err.class() {
+                    git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssh => {
+                        ErrorCode::Network
+                    }
+                    _ => ErrorCode::Other,
+                },
+            },
+            Self::ClockSkew { .. } => ErrorCode::ClockSkew,
+            Self::CommitMsgHook(_) => ErrorCode::CommitMsgHook,
+            Self::InvalidHead => ErrorCode::DetachedHead,
+            Self::InvalidBranchName(_) => ErrorCode::InvalidBranchName,
+            Self::InvalidSignature(_) => ErrorCode::InvalidSignature,
+            Self::LowDiskSpace { .. } => ErrorCode::LowDiskSpace,
+            Self::Panicked(_) => ErrorCode::Panic,
+            Self::RemoteNotAllowed { .. } => ErrorCode::RemoteNotAllowed,
+            Self::Cancelled(_) | Self::StillRunning(_) | Self::TimedOut { .. } => {
+                ErrorCode::Timeout
+            }
+            _ => ErrorCode::Other,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        self.code().hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        let git = |code| Error::Git(git2::Error::new(code, git2::ErrorClass::None, "failed"));
+        assert_eq!(ErrorCode::Auth, git(git2::ErrorCode::Auth).code());
+        assert_eq!(ErrorCode::IndexLocked, git(git2::ErrorCode::Locked).code());
+        assert_eq!(
+            ErrorCode::NonFastForward,
+            git(git2::ErrorCode::NotFastForward).code()
+        );
+        assert_eq!(ErrorCode::Other, git(git2::ErrorCode::GenericError).code());
+        let unreachable = Error::Git(git2::Error::new(
+            git2::ErrorCode::GenericError,
+            git2::ErrorClass::Net,
+            "failed to resolve address",
+        ));
+        assert_eq!(ErrorCode::Network, unreachable.code());
+        assert!(unreachable.code().is_transient());
+        assert!(!ErrorCode::Auth.is_transient());
+        assert_eq!(None, Error::SnapshotNotFound.hint());
+        assert!(Error::InvalidHead.hint().is_some());
+    }
 }
@@ -0,0 +1,66 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{error::ErrorCode, metadata::DiffStats};
+
+// events are dropped for subscribers lagging further behind
+const EVENT_CAPACITY: usize = 256;
+
+/// Something the watcher did, streamed to API clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum WatchEvent {
+    #[serde(rename_all = "camelCase")]
+    Snapshot {
+        repo: PathBuf,
+        /// Paths refreshed for the snapshot, empty when the whole working tree was indexed
+        changed_paths: Vec<PathBuf>,
+        /// What changed since the previous snapshot, none when there wasn't a change to commit
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stats: Option<DiffStats>,
+        #[serde(with = "humantime_serde")]
+        time: SystemTime,
+    },
+    Failed {
+        repo: PathBuf,
+        error: String,
+        code: ErrorCode,
+        /// How to fix it, for the failures it's known for
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
+        #[serde(with = "humantime_serde")]
+        time: SystemTime,
+    },
+    /// Snapshot or push left out for now, e.g. with the disk almost full
+    Skipped {
+        repo: PathBuf,
+        reason: String,
+        #[serde(with = "humantime_serde")]
+        time: SystemTime,
+    },
+    /// Snapshot or push given up on after running for longer than the handler timeout
+    TimedOut {
+        repo: PathBuf,
+        /// What it was doing, e.g. `index build` or `push`
+        phase: String,
+        #[serde(with = "humantime_serde")]
+        after: Duration,
+        #[serde(with = "humantime_serde")]
+        time: SystemTime,
+    },
+    ConfigReloaded {
+        #[serde(with = "humantime_serde")]
+        time: SystemTime,
+    },
+}
+
+pub type EventSender = broadcast::Sender<WatchEvent>;
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_CAPACITY).0
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::error::ErrorCode;
+
+/// A snapshot of a watched repo that failed
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub repo: PathBuf,
+    pub error: String,
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+/// Failures collected between notifications, released at most once per interval
+#[derive(Debug, Default)]
+pub struct FailureLog {
+    pending: Vec<Failure>,
+    last_taken: Option<Instant>,
+}
+
+impl FailureLog {
+    pub fn push(&mut self, failure: Failure) {
+        self.pending.push(failure);
+    }
+
+    /// The pending failures, if there are any and `interval` passed since they were last taken
+    pub fn take_due(&mut self, interval: Duration) -> Option<FailureDigest> {
+        let due = self
+            .last_taken
+            .map(|last| last.elapsed() >= interval)
+            .unwrap_or(true);
+        if !due || self.pending.is_empty() {
+            return None;
+        }
+        self.last_taken = Some(Instant::now());
+        Some(FailureDigest(std::mem::take(&mut self.pending)))
+    }
+}
+
+/// Failures grouped by repo for a notification
+#[derive(Debug)]
+pub struct FailureDigest(pub Vec<Failure>);
+
+impl FailureDigest {
+    pub fn title(&self) -> String {
+        format!(
+            "git-snapshot: {} failed snapshot{}",
+            self.0.len(),
+            plural(self.0.len())
+        )
+    }
+}
+
+impl Display for FailureDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut repos: BTreeMap<&PathBuf, Vec<&Failure>> = BTreeMap::new();
+        for failure in &self.0 {
+            repos.entry(&failure.repo).or_default().push(failure);
+        }
+        for (repo, failures) in repos {
+            // failures are pushed in order, the last one is the latest
+            let last = failures[failures.len() - 1];
+            writeln!(
+                f,
+                "{}: {} failed snapshot{}, last at {}: {}",
+                repo.display(),
+                failures.len(),
+                plural(failures.len()),
+                humantime::format_rfc3339_seconds(last.time),
+                last.error
+            )?;
+            if let Some(hint) = &last.hint {
+                writeln!(f, "    hint: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(repo: &str) -> Failure {
+        Failure {
+            repo: PathBuf::from(repo),
+            error: "auth".to_owned(),
+            code: ErrorCode::Other,
+            hint: None,
+            time: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn take_due() {
+        let mut log = FailureLog::default();
+        assert!(log.take_due(Duration::from_secs(60)).is_none());
+
+        log.push(failure("/a"));
+        log.push(failure("/a"));
+        log.push(failure("/b"));
+        let digest = log.take_due(Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            "/a: 2 failed snapshots, last at 1970-01-01T00:00:00Z: auth\n/b: 1 failed snapshot, last at 1970-01-01T00:00:00Z: auth\n",
+            digest.to_string()
+        );
+
+        // rate limited until the interval passes
+        log.push(failure("/a"));
+        assert!(log.take_due(Duration::from_secs(60)).is_none());
+        assert_eq!(1, log.take_due(Duration::ZERO).unwrap().0.len());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashSet, path::Path};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+
+use crate::{
+    error::Error,
+    watcher::{EventKind, DEFAULT_EVENT_KINDS},
+};
+
+/// File name patterns of the temporary files editors write while saving
+pub const DEFAULT_IGNORE_PATTERNS: &[&str] = &[
+    // vim swap and backup files, and the file it creates to check directory permissions
+    "*.swp",
+    "*.swx",
+    "*~",
+    "4913",
+    // emacs lock and autosave files
+    ".#*",
+    "#*#",
+    // JetBrains safe write
+    "*___jb_tmp___",
+    "*___jb_old___",
+];
+
+/// Drops watcher events before they reach the debounce stage
+pub struct EventFilter {
+    kinds: HashSet<EventKind>,
+    ignore: GlobSet,
+}
+
+impl EventFilter {
+    /// `ignore_patterns` are globs matched against the file name of each event path
+    pub fn new(kinds: &[EventKind], ignore_patterns: &[impl AsRef<str>]) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in ignore_patterns {
+            builder.add(Glob::new(pattern.as_ref())?);
+        }
+        Ok(Self {
+            kinds: kinds.iter().copied().collect(),
+            ignore: builder.build()?,
+        })
+    }
+
+    pub fn is_match(&self, kind: EventKind, path: &Path) -> bool {
+        self.kinds.contains(&kind)
+            && !path
+                .file_name()
+                .map(|name| self.ignore.is_match(name))
+                .unwrap_or(false)
+    }
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_KINDS, DEFAULT_IGNORE_PATTERNS).unwrap()
+    }
+}
+
+/// Branches snapshots are taken on. With allow patterns a branch has to match one of them, and it
+/// mustn't match any deny pattern. `*` stays within a `/` separated component, `**` doesn't.
+#[derive(Debug, Clone, Default)]
+pub struct BranchFilter {
+    allow: Option<GlobSet>,
+    deny: GlobSet,
+}
+
+impl BranchFilter {
+    pub fn new(allow: &[impl AsRef<str>], deny: &[impl AsRef<str>]) -> Result<Self, Error> {
+        Ok(Self {
+            allow: match allow.is_empty() {
+                true => None,
+                false => Some(branch_globs(allow)?),
+            },
+            deny: branch_globs(deny)?,
+        })
+    }
+
+    pub fn is_match(&self, branch: &str) -> bool {
+        self.allow
+            .as_ref()
+            .map(|allow| allow.is_match(branch))
+            .unwrap_or(true)
+            && !self.deny.is_match(branch)
+    }
+}
+
+fn branch_globs(patterns: &[impl AsRef<str>]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(pattern.as_ref())
+                .literal_separator(true)
+                .build()?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+// marks a url pattern as a regular expression rather than a glob
+const REGEX_PREFIX: &str = "regex:";
+
+/// Remote urls snapshots may be pushed to, any when there are no patterns. Patterns are globs,
+/// where `*` also matches `/`, or regular expressions prefixed with `regex:`.
+#[derive(Debug, Clone)]
+pub struct UrlFilter {
+    globs: GlobSet,
+    regexes: RegexSet,
+    any: bool,
+}
+
+impl UrlFilter {
+    pub fn new(patterns: &[impl AsRef<str>]) -> Result<Self, Error> {
+        let mut globs = GlobSetBuilder::new();
+        let mut regexes = Vec::new();
+        for pattern in patterns {
+            match pattern.as_ref().strip_prefix(REGEX_PREFIX) {
+                Some(regex) => regexes.push(regex),
+                None => {
+                    globs.add(Glob::new(pattern.as_ref())?);
+                }
+            }
+        }
+        Ok(Self {
+            globs: globs.build()?,
+            regexes: RegexSet::new(regexes)?,
+            any: patterns.is_empty(),
+        })
+    }
+
+    pub fn is_match(&self, url: &str) -> bool {
+        self.any || self.globs.is_match(url) || self.regexes.is_match(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editor_temp_files() {
+        let filter = EventFilter::default();
+        for name in [
+            ".main.rs.swp",
+            "main.rs~",
+            "4913",
+            ".#main.rs",
+            "#main.rs#",
+            "main.rs___jb_tmp___",
+            "main.rs___jb_old___",
+        ] {
+            assert!(
+                !filter.is_match(EventKind::Modify, &Path::new("/repo/src").join(name)),
+                "{}",
+                name
+            );
+        }
+        assert!(filter.is_match(EventKind::Modify, Path::new("/repo/src/main.rs")));
+        assert!(filter.is_match(EventKind::Create, Path::new("/repo/src/4913.rs")));
+    }
+
+    #[test]
+    fn kinds() {
+        let filter = EventFilter::default();
+        assert!(filter.is_match(EventKind::Rename, Path::new("/repo/a")));
+        assert!(!filter.is_match(EventKind::Metadata, Path::new("/repo/a")));
+        assert!(!filter.is_match(EventKind::Access, Path::new("/repo/a")));
+    }
+
+    #[test]
+    fn override_patterns() {
+        let filter = EventFilter::new(DEFAULT_EVENT_KINDS, &["*.tmp"]).unwrap();
+        assert!(filter.is_match(EventKind::Modify, Path::new("/repo/a.swp")));
+        assert!(!filter.is_match(EventKind::Modify, Path::new("/repo/a.tmp")));
+
+        let filter = EventFilter::new(DEFAULT_EVENT_KINDS, &[] as &[&str]).unwrap();
+        assert!(filter.is_match(EventKind::Modify, Path::new("/repo/a~")));
+    }
+
+    #[test]
+    fn branches() {
+        let filter = BranchFilter::new(&["feature/*", "main"], &["feature/wip-*"]).unwrap();
+        assert!(filter.is_match("main"));
+        assert!(filter.is_match("feature/login"));
+        assert!(!filter.is_match("feature/wip-login"));
+        assert!(!filter.is_match("feature/login/part"));
+        assert!(!filter.is_match("release/1.0"));
+
+        let filter = BranchFilter::new(&[] as &[&str], &["release/**"]).unwrap();
+        assert!(filter.is_match("main"));
+        assert!(!filter.is_match("release/1.0/hotfix"));
+    }
+
+    #[test]
+    fn urls() {
+        let filter = UrlFilter::new(&[
+            "git@github.example.com:*",
+            r"regex:^https://git\.example\.com/(team|me)/",
+        ])
+        .unwrap();
+        assert!(filter.is_match("git@github.example.com:team/project.git"));
+        assert!(filter.is_match("https://git.example.com/me/project.git"));
+        assert!(!filter.is_match("git@github.com:someone/project.git"));
+        assert!(!filter.is_match("https://git.example.com/fork/project.git"));
+
+        assert!(UrlFilter::new(&[] as &[&str])
+            .unwrap()
+            .is_match("file:///anywhere"));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(EventFilter::new(DEFAULT_EVENT_KINDS, &["a[b"]).is_err());
+        assert!(UrlFilter::new(&["regex:a("]).is_err());
+    }
+}
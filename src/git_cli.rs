@@ -0,0 +1,170 @@
+use std::process::{Command, Stdio};
+
+use git2::{Config, ErrorClass, ErrorCode, Repository};
+use log::{debug, error};
+
+use crate::error::Error;
+
+/// How snapshots are pushed, `snapshot.push.backend`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PushBackend {
+    /// libgit2, with the credentials of the remote's snapshot config, the ssh agent or a
+    /// credential helper
+    #[default]
+    Libgit2,
+    /// The system's `git push`, with git's own credential helpers and ssh config
+    GitCli,
+    /// libgit2, then `git push` when it can't authenticate
+    Auto,
+}
+
+impl PushBackend {
+    /// `remote.<name>.snapshotPushBackend`, or `snapshot.push.backend` for every remote
+    pub fn from_config(config: &Config, remote: &str) -> Self {
+        let keys = [
+            format!("remote.{}.snapshotpushbackend", remote),
+            "snapshot.push.backend".to_owned(),
+        ];
+        let Some(value) = keys.iter().find_map(|key| config.get_string(key).ok()) else {
+            return Self::default();
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "libgit2" => Self::Libgit2,
+            "git-cli" => Self::GitCli,
+            "auto" => Self::Auto,
+            _ => {
+                error!("invalid snapshot.push.backend: {}", value);
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Push `refspecs` to `remote` with the system's `git`, failing instead of prompting for
+/// credentials
+pub fn push(repo: &Repository, remote: &str, refspecs: &[String]) -> Result<(), Error> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(["push", "--porcelain", remote])
+        .args(refspecs)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("git push to {}: {}{}", remote, stdout, stderr);
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(push_error(&stdout, &stderr).into()),
+    }
+}
+
+// Classify a failed `git push` like libgit2 would, so it's retried and reported the same way
+fn push_error(stdout: &str, stderr: &str) -> git2::Error {
+    // `--porcelain` reports each rejected ref as `!	<src>:<dst>	[rejected] (<reason>)`
+    let rejected = stdout
+        .lines()
+        .find(|line| line.starts_with('!'))
+        .map(|line| line.trim_start_matches(['!', '\t']).to_owned());
+    if let Some(rejected) = rejected {
+        let code = match rejected.contains("non-fast-forward") || rejected.contains("fetch first") {
+            true => ErrorCode::NotFastForward,
+            false => ErrorCode::GenericError,
+        };
+        return git2::Error::new(
+            code,
+            ErrorClass::Reference,
+            format!("rejected: {}", rejected),
+        );
+    }
+    let message = match stderr.trim() {
+        "" => "git push failed".to_owned(),
+        stderr => stderr.lines().last().unwrap_or(stderr).to_owned(),
+    };
+    let stderr = stderr.to_ascii_lowercase();
+    let (code, class) = if [
+        "authentication failed",
+        "permission denied",
+        "could not read username",
+        "could not read password",
+        "terminal prompts disabled",
+    ]
+    .iter()
+    .any(|needle| stderr.contains(needle))
+    {
+        (ErrorCode::Auth, ErrorClass::Net)
+    } else if [
+        "could not resolve host",
+        "connection refused",
+        "connection timed out",
+        "network is unreachable",
+        "unable to access",
+        "could not read from remote repository",
+    ]
+    .iter()
+    .any(|needle| stderr.contains(needle))
+    {
+        (ErrorCode::GenericError, ErrorClass::Net)
+    } else {
+        (ErrorCode::GenericError, ErrorClass::None)
+    };
+    git2::Error::new(code, class, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn backend_from_config() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        assert_eq!(
+            PushBackend::Libgit2,
+            PushBackend::from_config(&config, "origin")
+        );
+        config.set_str("snapshot.push.backend", "auto").unwrap();
+        config
+            .set_str("remote.work.snapshotpushbackend", "git-cli")
+            .unwrap();
+        assert_eq!(
+            PushBackend::Auto,
+            PushBackend::from_config(&config, "origin")
+        );
+        assert_eq!(
+            PushBackend::GitCli,
+            PushBackend::from_config(&config, "work")
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let code = |stdout: &str, stderr: &str| Error::from(push_error(stdout, stderr)).code();
+        assert_eq!(
+            crate::ErrorCode::Auth,
+            code(
+                "",
+                "git@host: Permission denied (publickey).\nfatal: Could not read from remote repository."
+            )
+        );
+        assert_eq!(
+            crate::ErrorCode::Network,
+            code(
+                "",
+                "fatal: unable to access 'https://host/': Could not resolve host: host"
+            )
+        );
+        assert_eq!(
+            crate::ErrorCode::NonFastForward,
+            code(
+                "To host:repo\n!\trefs/heads/a:refs/heads/a\t[rejected] (non-fast-forward)\nDone",
+                "error: failed to push some refs"
+            )
+        );
+        assert_eq!(crate::ErrorCode::Other, code("", "fatal: something else"));
+    }
+}
@@ -0,0 +1,346 @@
+// tonic dictates `Status` as the error type
+#![allow(clippy::result_large_err)]
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use tokio::task::JoinHandle;
+use tokio_stream::{
+    wrappers::{BroadcastStream, TcpListenerStream},
+    Stream, StreamExt,
+};
+use tonic::{transport::Server, Code, Request, Response, Status};
+
+use crate::{
+    api::{ApiError, ApiState, ErrorKind, RepoStatus},
+    error::Error,
+    events::WatchEvent,
+};
+
+/// Types generated from `proto/snapshot.proto`
+pub mod proto {
+    tonic::include_proto!("gitsnapshot.v1");
+}
+
+use proto::{
+    git_snapshot_server::{GitSnapshot, GitSnapshotServer},
+    watch_event::Event,
+};
+
+/// Running gRPC server, stopped when dropped
+pub struct GrpcServer {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl GrpcServer {
+    pub fn start(listen: SocketAddr, state: ApiState) -> Result<Self, Error> {
+        // bound up front so errors reach the caller
+        let listener = TcpListener::bind(listen)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+
+        let auth = state.clone();
+        let service = GitSnapshotServer::with_interceptor(Service(state), move |request| {
+            authorize(&auth, request)
+        });
+        let handle = tokio::spawn(async move {
+            if let Err(err) = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+            {
+                error!("grpc server error: {:?}", err);
+            }
+        });
+        Ok(Self { addr, handle })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+fn authorize(state: &ApiState, request: Request<()>) -> Result<Request<()>, Status> {
+    let header = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    if !state.is_authorized(header) {
+        return Err(Status::unauthenticated("invalid token"));
+    }
+    Ok(request)
+}
+
+impl From<ApiError> for Status {
+    fn from(err: ApiError) -> Self {
+        let code = match err.kind {
+            ErrorKind::Unauthorized => Code::Unauthenticated,
+            ErrorKind::NotFound => Code::NotFound,
+            ErrorKind::Conflict => Code::FailedPrecondition,
+            ErrorKind::BadRequest => Code::InvalidArgument,
+            ErrorKind::Internal => Code::Internal,
+        };
+        Status::new(code, err.message)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl From<RepoStatus> for proto::RepoStatus {
+    fn from(status: RepoStatus) -> Self {
+        Self {
+            path: status.path.to_string_lossy().into_owned(),
+            branch: status.branch,
+            last_snapshot: status.last_snapshot.map(|s| proto::Snapshot {
+                id: s.id,
+                time: unix_secs(s.time),
+            }),
+            error: status.error,
+        }
+    }
+}
+
+impl From<WatchEvent> for proto::WatchEvent {
+    fn from(event: WatchEvent) -> Self {
+        let (time, event) = match event {
+            WatchEvent::Snapshot {
+                repo,
+                changed_paths,
+                stats,
+                time,
+            } => (
+                time,
+                Event::Snapshot(proto::SnapshotEvent {
+                    repo: repo.to_string_lossy().into_owned(),
+                    changed_paths: changed_paths
+                        .iter()
+                        .map(|p| p.to_string_lossy().into_owned())
+                        .collect(),
+                    stats: stats.map(|stats| proto::DiffStats {
+                        files_changed: stats.files_changed as u64,
+                        insertions: stats.insertions as u64,
+                        deletions: stats.deletions as u64,
+                        bytes_written: stats.bytes_written,
+                    }),
+                }),
+            ),
+            WatchEvent::Failed {
+                repo,
+                error,
+                code,
+                hint,
+                time,
+            } => (
+                time,
+                Event::Failed(proto::FailedEvent {
+                    repo: repo.to_string_lossy().into_owned(),
+                    error,
+                    code: code.to_string(),
+                    hint: hint.unwrap_or_default(),
+                }),
+            ),
+            WatchEvent::Skipped { repo, reason, time } => (
+                time,
+                Event::Skipped(proto::SkippedEvent {
+                    repo: repo.to_string_lossy().into_owned(),
+                    reason,
+                }),
+            ),
+            WatchEvent::TimedOut {
+                repo,
+                phase,
+                after,
+                time,
+            } => (
+                time,
+                Event::TimedOut(proto::TimedOutEvent {
+                    repo: repo.to_string_lossy().into_owned(),
+                    phase,
+                    after_millis: after.as_millis() as i64,
+                }),
+            ),
+            WatchEvent::ConfigReloaded { time } => {
+                (time, Event::ConfigReloaded(proto::ConfigReloadedEvent {}))
+            }
+        };
+        Self {
+            time: unix_secs(time),
+            event: Some(event),
+        }
+    }
+}
+
+struct Service(ApiState);
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::WatchEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl GitSnapshot for Service {
+    async fn status(
+        &self,
+        _: Request<proto::StatusRequest>,
+    ) -> Result<Response<proto::StatusResponse>, Status> {
+        let status = self.0.status();
+        Ok(Response::new(proto::StatusResponse {
+            version: status.version.to_owned(),
+            started: unix_secs(status.started),
+            repos: status.repos as u32,
+            watcher_restarts: status.watcher_restarts,
+        }))
+    }
+
+    async fn list_repos(
+        &self,
+        _: Request<proto::ListReposRequest>,
+    ) -> Result<Response<proto::ListReposResponse>, Status> {
+        let repos = self.0.repo_statuses().await?;
+        Ok(Response::new(proto::ListReposResponse {
+            repos: repos.into_iter().map(From::from).collect(),
+        }))
+    }
+
+    async fn add_repo(
+        &self,
+        request: Request<proto::RepoRequest>,
+    ) -> Result<Response<proto::RepoResponse>, Status> {
+        self.0.add_repo(Path::new(&request.get_ref().path))?;
+        Ok(Response::new(proto::RepoResponse {}))
+    }
+
+    async fn remove_repo(
+        &self,
+        request: Request<proto::RepoRequest>,
+    ) -> Result<Response<proto::RepoResponse>, Status> {
+        self.0.remove_repo(Path::new(&request.get_ref().path))?;
+        Ok(Response::new(proto::RepoResponse {}))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<proto::RepoRequest>,
+    ) -> Result<Response<proto::RepoResponse>, Status> {
+        self.0.snapshot(Path::new(&request.get_ref().path)).await?;
+        Ok(Response::new(proto::RepoResponse {}))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    async fn stream_events(
+        &self,
+        _: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        // events missed by a lagging client are skipped
+        let stream = BroadcastStream::new(self.0.events.subscribe())
+            .filter_map(|event| event.ok())
+            .map(|event| Ok(event.into()));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tonic::{metadata::MetadataValue, transport::Channel};
+
+    use super::*;
+    use crate::events::{self, EventSender};
+    use proto::git_snapshot_client::GitSnapshotClient;
+
+    const TOKEN: &str = "secret";
+
+    fn start() -> (GrpcServer, EventSender) {
+        let events = events::channel();
+        let server = GrpcServer::start(
+            "127.0.0.1:0".parse().unwrap(),
+            ApiState {
+                token: TOKEN.to_owned(),
+                config_path: None,
+                repos: Arc::new(Mutex::new(Vec::new())),
+                events: events.clone(),
+                started: UNIX_EPOCH,
+                watcher_restarts: Arc::default(),
+            },
+        )
+        .unwrap();
+        (server, events)
+    }
+
+    async fn client(server: &GrpcServer) -> GitSnapshotClient<Channel> {
+        GitSnapshotClient::connect(format!("http://{}", server.local_addr()))
+            .await
+            .unwrap()
+    }
+
+    fn authorized<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(
+            "authorization",
+            MetadataValue::try_from(format!("Bearer {}", TOKEN)).unwrap(),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (server, _) = start();
+        let mut client = client(&server).await;
+
+        let err = client.status(proto::StatusRequest {}).await.unwrap_err();
+        assert_eq!(Code::Unauthenticated, err.code());
+
+        let status = client
+            .status(authorized(proto::StatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(env!("CARGO_PKG_VERSION"), status.version);
+        assert_eq!(0, status.repos);
+
+        // there's no config file to add the repo to
+        let err = client
+            .add_repo(authorized(proto::RepoRequest {
+                path: "/".to_owned(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::FailedPrecondition, err.code());
+    }
+
+    #[tokio::test]
+    async fn stream_events() {
+        let (server, events) = start();
+        let mut client = client(&server).await;
+        let mut stream = client
+            .stream_events(authorized(proto::StreamEventsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        events
+            .send(WatchEvent::ConfigReloaded {
+                time: UNIX_EPOCH + std::time::Duration::from_secs(5),
+            })
+            .unwrap();
+        let event = stream.message().await.unwrap().unwrap();
+        assert_eq!(5, event.time);
+        assert!(matches!(event.event, Some(Event::ConfigReloaded(_))));
+    }
+}
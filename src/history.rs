@@ -0,0 +1,635 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::Local;
+use git2::{Commit, Oid, Repository, Sort};
+use interim::{parse_date_string, Dialect};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Trailer recording the HEAD commit a snapshot was taken on top of
+pub const BASE_TRAILER: &str = "Snapshot-Base";
+/// Trailer marking a snapshot taken as a named checkpoint, never squashed away
+pub const CHECKPOINT_TRAILER: &str = "Snapshot-Checkpoint";
+/// Trailer tying together the snapshots a repo group took in one trigger
+pub const GROUP_TRAILER: &str = "Snapshot-Group";
+/// Trailer marking the first snapshot after the base branch was rewritten, holding the base the
+/// snapshot before it was taken on
+pub const REWRITE_TRAILER: &str = "Snapshot-Rewrite";
+/// Trailer with the diff stats of a snapshot, with `snapshot.statsTrailer` set
+pub const STATS_TRAILER: &str = "Snapshot-Stats";
+/// Trailer counting the snapshots squashed into a session's commit
+pub const SESSION_TRAILER: &str = "Snapshot-Session";
+
+// abbreviated commit ids in the rendered log
+const SHORT_ID_LEN: usize = 7;
+
+#[derive(Debug, Clone)]
+pub struct LogCommit {
+    pub id: Oid,
+    pub time: SystemTime,
+    pub summary: String,
+    pub checkpoint: bool,
+    /// The base branch was rewritten between this snapshot and the one before
+    pub rewrite: bool,
+}
+
+impl LogCommit {
+    pub(crate) fn new(commit: &Commit) -> Self {
+        Self {
+            id: commit.id(),
+            time: commit_time(commit),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+            checkpoint: is_checkpoint(commit),
+            rewrite: rewrite_base(commit).is_some(),
+        }
+    }
+
+    pub fn short_id(&self) -> String {
+        let mut id = self.id.to_string();
+        id.truncate(SHORT_ID_LEN);
+        id
+    }
+}
+
+impl Display for LogCommit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.short_id(),
+            humantime::format_rfc3339_seconds(self.time),
+            self.summary
+        )?;
+        if self.checkpoint {
+            write!(f, " [checkpoint]")?;
+        }
+        if self.rewrite {
+            write!(f, " [base rewritten]")?;
+        }
+        Ok(())
+    }
+}
+
+/// A commit of the base branch along with the snapshots taken on top of it, newest first
+#[derive(Debug)]
+pub struct BaseCommit {
+    pub commit: LogCommit,
+    pub snapshots: Vec<LogCommit>,
+}
+
+/// Snapshots interleaved with the commits of the branch they were taken on, newest first
+#[derive(Debug, Default)]
+pub struct SnapshotLog {
+    pub bases: Vec<BaseCommit>,
+    /// Snapshots whose base commit isn't recorded or no longer on the branch
+    pub unmatched: Vec<LogCommit>,
+}
+
+impl SnapshotLog {
+    /// Walk `snapshot_ref` and `base_ref`, skipping commits older than `since`. Without `since`
+    /// the base branch is walked back to the oldest commit a snapshot was taken on.
+    pub fn new(
+        repo: &Repository,
+        base_ref: &str,
+        snapshot_ref: &str,
+        since: Option<SystemTime>,
+    ) -> Result<Self, Error> {
+        let snapshots: Vec<(LogCommit, Option<Oid>)> = walk(repo, snapshot_ref, since)?
+            .iter()
+            .map(|c| (LogCommit::new(c), base_id(c)))
+            .collect();
+
+        let since = since.or_else(|| {
+            snapshots
+                .iter()
+                .map(|(snapshot, base)| {
+                    base.and_then(|id| repo.find_commit(id).ok())
+                        .map(|c| commit_time(&c))
+                        .unwrap_or(snapshot.time)
+                })
+                .min()
+        });
+        let mut bases: Vec<BaseCommit> = match since {
+            Some(_) => walk(repo, base_ref, since)?
+                .iter()
+                .map(|c| BaseCommit {
+                    commit: LogCommit::new(c),
+                    snapshots: Vec::new(),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let positions: HashMap<Oid, usize> = bases
+            .iter()
+            .enumerate()
+            .map(|(i, base)| (base.commit.id, i))
+            .collect();
+        let mut unmatched = Vec::new();
+        for (snapshot, base) in snapshots {
+            match base.and_then(|id| positions.get(&id)) {
+                Some(&i) => bases[i].snapshots.push(snapshot),
+                None => unmatched.push(snapshot),
+            }
+        }
+
+        Ok(Self { bases, unmatched })
+    }
+}
+
+impl Display for SnapshotLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for snapshot in &self.unmatched {
+            writeln!(f, "o {} (base unknown)", snapshot)?;
+        }
+        for base in &self.bases {
+            if !base.snapshots.is_empty() {
+                for snapshot in &base.snapshots {
+                    writeln!(f, "| o {}", snapshot)?;
+                }
+                writeln!(f, "|/")?;
+            }
+            writeln!(f, "* {}", base.commit)?;
+        }
+        Ok(())
+    }
+}
+
+/// How far a snapshot branch and the branch it's taken of moved apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Divergence {
+    /// Snapshots taken on top of the branch's tip, work not committed yet
+    pub ahead: usize,
+    /// Commits on the branch the latest snapshot wasn't taken on, e.g. pulled since
+    pub behind: usize,
+    /// Seconds the latest snapshot is newer than the branch's tip, negative when it's older
+    pub time_delta_secs: i64,
+}
+
+impl Divergence {
+    /// `snapshot_ref` compared with `base_ref`, `None` without snapshots or base commits
+    pub fn new(
+        repo: &Repository,
+        base_ref: &str,
+        snapshot_ref: &str,
+    ) -> Result<Option<Self>, Error> {
+        let (Ok(base_tip), Ok(snapshot_tip)) = (
+            repo.refname_to_id(base_ref),
+            repo.refname_to_id(snapshot_ref),
+        ) else {
+            return Ok(None);
+        };
+        let ahead = walk(repo, snapshot_ref, None)?
+            .iter()
+            .take_while(|c| base_id(c) == Some(base_tip))
+            .count();
+        let snapshot = repo.find_commit(snapshot_tip)?;
+        let behind = match base_id(&snapshot).filter(|base| repo.find_commit(*base).is_ok()) {
+            // counted from the merge base when the branch was rewritten since
+            Some(base) => repo.graph_ahead_behind(base_tip, base)?.0,
+            // taken before the branch's first commit
+            None => walk(repo, base_ref, None)?.len(),
+        };
+        let base = repo.find_commit(base_tip)?;
+        Ok(Some(Self {
+            ahead,
+            behind,
+            time_delta_secs: snapshot.time().seconds() - base.time().seconds(),
+        }))
+    }
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let delta =
+            humantime::format_duration(Duration::from_secs(self.time_delta_secs.unsigned_abs()));
+        write!(
+            f,
+            "{} snapshot(s) ahead, {} commit(s) behind, latest snapshot {} {}",
+            self.ahead,
+            self.behind,
+            delta,
+            match self.time_delta_secs < 0 {
+                true => "older",
+                false => "newer",
+            }
+        )
+    }
+}
+
+/// Which snapshot to operate on
+/// Consecutive snapshots without more than the session gap between them, newest first
+#[derive(Debug)]
+pub struct Session {
+    pub snapshots: Vec<LogCommit>,
+}
+
+impl Session {
+    pub fn start(&self) -> SystemTime {
+        self.snapshots.last().map(|c| c.time).unwrap_or(UNIX_EPOCH)
+    }
+
+    pub fn end(&self) -> SystemTime {
+        self.snapshots.first().map(|c| c.time).unwrap_or(UNIX_EPOCH)
+    }
+}
+
+impl Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} - {} {} snapshot(s)",
+            humantime::format_rfc3339_seconds(self.start()),
+            humantime::format_rfc3339_seconds(self.end()),
+            self.snapshots.len()
+        )
+    }
+}
+
+/// Split snapshots, newest first, into sessions wherever more than `gap` passed between two
+pub fn sessions(snapshots: Vec<LogCommit>, gap: Duration) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    for snapshot in snapshots {
+        match sessions.last_mut() {
+            Some(session) if !is_gap(snapshot.time, session.start(), gap) => {
+                session.snapshots.push(snapshot)
+            }
+            _ => sessions.push(Session {
+                snapshots: vec![snapshot],
+            }),
+        }
+    }
+    sessions
+}
+
+/// Whether more than `gap` passed from `earlier` to `later`
+pub(crate) fn is_gap(earlier: SystemTime, later: SystemTime, gap: Duration) -> bool {
+    later
+        .duration_since(earlier)
+        .map(|elapsed| elapsed > gap)
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotSpec {
+    /// Any revision git understands, e.g. a commit id
+    Rev(String),
+    /// The latest snapshot at or before the instant
+    At(SystemTime),
+    /// The snapshot taken with a repo group, or the latest one before it when the repo had no
+    /// changes then
+    Group(String),
+}
+
+/// Parse local times like `yesterday 17:00`, `2 hours ago` or `2022-06-01 12:00`
+pub fn parse_time(s: &str) -> Result<SystemTime, Error> {
+    parse_date_string(s, Local::now(), Dialect::Uk)
+        .map(SystemTime::from)
+        .map_err(|err| Error::InvalidTime(format!("{}: {}", s, err)))
+}
+
+/// Resolve `spec` against the snapshots on `snapshot_ref`, the latest one when unset
+pub(crate) fn find_snapshot<'r>(
+    repo: &'r Repository,
+    snapshot_ref: &str,
+    spec: Option<&SnapshotSpec>,
+) -> Result<Commit<'r>, Error> {
+    match spec {
+        Some(SnapshotSpec::Rev(rev)) => Ok(repo.revparse_single(rev)?.peel_to_commit()?),
+        Some(SnapshotSpec::At(at)) => walk(repo, snapshot_ref, None)?
+            .into_iter()
+            .find(|c| commit_time(c) <= *at)
+            .ok_or(Error::SnapshotNotFound),
+        Some(SnapshotSpec::Group(id)) => {
+            let commits = walk(repo, snapshot_ref, None)?;
+            if let Some(index) = commits
+                .iter()
+                .position(|c| trailer(c, GROUP_TRAILER).as_deref() == Some(id))
+            {
+                return Ok(commits.into_iter().nth(index).unwrap());
+            }
+            let (_, time) = parse_group_id(id).ok_or(Error::SnapshotNotFound)?;
+            commits
+                .into_iter()
+                .find(|c| commit_time(c) <= time)
+                .ok_or(Error::SnapshotNotFound)
+        }
+        None => walk(repo, snapshot_ref, None)?
+            .into_iter()
+            .next()
+            .ok_or(Error::SnapshotNotFound),
+    }
+}
+
+/// Id shared by the snapshots a repo group takes together, `<group>@<unix millis>`
+pub fn group_id(group: &str, time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!("{}@{}", group, millis)
+}
+
+/// The group name and time of a group id
+pub fn parse_group_id(id: &str) -> Option<(&str, SystemTime)> {
+    let (group, millis) = id.rsplit_once('@')?;
+    Some((
+        group,
+        UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+    ))
+}
+
+/// The base commit recorded in a snapshot commit's trailer
+pub fn base_id(commit: &Commit) -> Option<Oid> {
+    trailer(commit, BASE_TRAILER).and_then(|id| Oid::from_str(&id).ok())
+}
+
+pub fn is_checkpoint(commit: &Commit) -> bool {
+    trailer(commit, CHECKPOINT_TRAILER).is_some()
+}
+
+pub fn rewrite_base(commit: &Commit) -> Option<Oid> {
+    trailer(commit, REWRITE_TRAILER).and_then(|id| Oid::from_str(&id).ok())
+}
+
+// The value of the last `key` trailer of the commit message
+pub(crate) fn trailer(commit: &Commit, key: &str) -> Option<String> {
+    let prefix = format!("{}: ", key);
+    commit
+        .message()?
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map(|value| value.trim().to_owned())
+}
+
+pub(crate) fn commit_time(commit: &Commit) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64)
+}
+
+// Commits reachable from `reference` newest first, stopping at the first one older than `since`
+pub(crate) fn walk<'r>(
+    repo: &'r Repository,
+    reference: &str,
+    since: Option<SystemTime>,
+) -> Result<Vec<Commit<'r>>, Error> {
+    let id = match repo.refname_to_id(reference) {
+        Ok(id) => id,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(id)?;
+
+    let mut commits = Vec::new();
+    for id in revwalk {
+        let commit = repo.find_commit(id?)?;
+        if since
+            .map(|since| commit_time(&commit) < since)
+            .unwrap_or(false)
+        {
+            break;
+        }
+        commits.push(commit);
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use git2::{IndexAddOption, Signature};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{test_util::test_repo, Repo};
+
+    fn commit(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            parent.as_ref().as_slice(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn snapshots_grouped_by_base() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        write(temp_dir.path().join("a"), "a").unwrap();
+        let first = commit(&git_repo, "first");
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+
+        write(temp_dir.path().join("a"), "snapshot 1").unwrap();
+        repo.snapshot().unwrap();
+        write(temp_dir.path().join("a"), "snapshot 2").unwrap();
+        repo.snapshot().unwrap();
+        let second = commit(&git_repo, "second");
+        write(temp_dir.path().join("a"), "snapshot 3").unwrap();
+        repo.snapshot().unwrap();
+
+        let log = repo.log(None, None).unwrap();
+        assert!(log.unmatched.is_empty());
+        let bases: Vec<(Oid, usize)> = log
+            .bases
+            .iter()
+            .map(|b| (b.commit.id, b.snapshots.len()))
+            .collect();
+        assert_eq!(vec![(second, 1), (first, 2)], bases);
+
+        let rendered = log.to_string();
+        assert_eq!(7, rendered.lines().count());
+        assert!(rendered.ends_with("first\n"));
+    }
+
+    #[test]
+    fn divergence() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        // without a snapshot or a commit
+        assert_eq!(None, repo.divergence(None).unwrap());
+        write(temp_dir.path().join("a"), "a").unwrap();
+        commit(&git_repo, "first");
+        assert_eq!(None, repo.divergence(None).unwrap());
+
+        write(temp_dir.path().join("a"), "snapshot 1").unwrap();
+        repo.snapshot().unwrap();
+        write(temp_dir.path().join("a"), "snapshot 2").unwrap();
+        repo.snapshot().unwrap();
+        let divergence = repo.divergence(None).unwrap().unwrap();
+        assert_eq!((2, 0), (divergence.ahead, divergence.behind));
+        assert!(divergence.time_delta_secs >= 0);
+        assert!(divergence
+            .to_string()
+            .starts_with("2 snapshot(s) ahead, 0 commit(s) behind"));
+
+        // committed, and more commits since the last snapshot
+        commit(&git_repo, "second");
+        write(temp_dir.path().join("b"), "b").unwrap();
+        commit(&git_repo, "third");
+        let divergence = repo.divergence(None).unwrap().unwrap();
+        assert_eq!((0, 2), (divergence.ahead, divergence.behind));
+    }
+
+    #[test]
+    fn since() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        write(temp_dir.path().join("a"), "a").unwrap();
+        commit(&git_repo, "first");
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        write(temp_dir.path().join("a"), "snapshot").unwrap();
+        repo.snapshot().unwrap();
+
+        let log = repo
+            .log(None, Some(SystemTime::now() + Duration::from_secs(60)))
+            .unwrap();
+        assert!(log.bases.is_empty());
+        assert!(log.unmatched.is_empty());
+    }
+
+    #[test]
+    fn missing_base_trailer() {
+        let temp_dir = tempdir().unwrap();
+        let (_git_repo, _config) = test_repo(temp_dir.path());
+        write(temp_dir.path().join("a"), "a").unwrap();
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        // unborn branch, there is no base commit to record
+        repo.snapshot().unwrap();
+
+        let log = repo.log(None, None).unwrap();
+        assert!(log.bases.is_empty());
+        assert_eq!(1, log.unmatched.len());
+        assert!(log.to_string().contains("(base unknown)"));
+    }
+
+    #[test]
+    fn find_snapshot_at() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let tree = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+        let snapshot_ref = "refs/heads/snapshot/master";
+        let mut ids = Vec::new();
+        for secs in [1000, 2000, 3000] {
+            let signature = Signature::new("test", "test", &git2::Time::new(secs, 0)).unwrap();
+            let parent = ids.last().map(|id| repo.find_commit(*id).unwrap());
+            ids.push(
+                repo.commit(
+                    Some(snapshot_ref),
+                    &signature,
+                    &signature,
+                    "Snapshot",
+                    &tree,
+                    parent.as_ref().as_slice(),
+                )
+                .unwrap(),
+            );
+        }
+
+        let at = |secs| SnapshotSpec::At(UNIX_EPOCH + Duration::from_secs(secs));
+        let find =
+            |spec: Option<&SnapshotSpec>| find_snapshot(&repo, snapshot_ref, spec).map(|c| c.id());
+        assert_eq!(ids[1], find(Some(&at(2500))).unwrap());
+        assert_eq!(ids[1], find(Some(&at(2000))).unwrap());
+        assert_eq!(ids[2], find(None).unwrap());
+        assert_eq!(
+            ids[0],
+            find(Some(&SnapshotSpec::Rev(ids[0].to_string()))).unwrap()
+        );
+        assert!(matches!(find(Some(&at(500))), Err(Error::SnapshotNotFound)));
+    }
+
+    #[test]
+    fn find_by_group() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(git_repo);
+        let snapshot_ref = "refs/heads/snapshot/master";
+        write(temp_dir.path().join("a"), "a").unwrap();
+        repo.snapshot().unwrap();
+        let before = repo.find_snapshot(None).unwrap().id();
+        write(temp_dir.path().join("a"), "b").unwrap();
+        let id = group_id("app", SystemTime::now());
+        repo.snapshot_group(None, &id).unwrap();
+        let grouped = repo.find_snapshot(None).unwrap().id();
+        assert_ne!(before, grouped);
+
+        let find = |id: &str| {
+            find_snapshot(
+                repo.git_repo(),
+                snapshot_ref,
+                Some(&SnapshotSpec::Group(id.to_owned())),
+            )
+            .map(|c| c.id())
+        };
+        assert_eq!(grouped, find(&id).unwrap());
+        // a group the repo had no changes in resolves to the snapshot before it
+        let later = group_id("app", SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(grouped, find(&later).unwrap());
+        assert!(matches!(
+            find(&group_id("app", UNIX_EPOCH)),
+            Err(Error::SnapshotNotFound)
+        ));
+        assert_eq!(
+            Some(("app", UNIX_EPOCH + Duration::from_millis(5))),
+            parse_group_id("app@5")
+        );
+        assert_eq!(None, parse_group_id("app"));
+    }
+
+    #[test]
+    fn parse_times() {
+        let now = SystemTime::now();
+        let ago = parse_time("2 hours ago").unwrap();
+        let diff = now.duration_since(ago).unwrap();
+        assert!(
+            diff >= Duration::from_secs(2 * 3600 - 5) && diff <= Duration::from_secs(2 * 3600 + 5)
+        );
+        assert!(parse_time("yesterday 17:00").unwrap() < now);
+        assert!(parse_time("not a time").is_err());
+    }
+
+    #[test]
+    fn grouped_into_sessions() {
+        let snapshot = |secs| LogCommit {
+            id: Oid::zero(),
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+            summary: String::new(),
+            checkpoint: false,
+            rewrite: false,
+        };
+        let snapshots = vec![
+            snapshot(5000),
+            snapshot(1300),
+            snapshot(1000),
+            snapshot(100),
+        ];
+
+        let grouped = sessions(snapshots, Duration::from_secs(300));
+        let counts: Vec<_> = grouped.iter().map(|s| s.snapshots.len()).collect();
+        assert_eq!(vec![1, 2, 1], counts);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1000), grouped[1].start());
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1300), grouped[1].end());
+    }
+}
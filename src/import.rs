@@ -0,0 +1,216 @@
+use std::{
+    fs::{canonicalize, read_dir, read_to_string},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use git2::Repository;
+use serde_json::Value;
+use url::Url;
+
+use crate::error::Error;
+
+// ghq clones to <root>/<host>/<owner>/<name>
+const GHQ_DEPTH: usize = 3;
+
+/// Tool keeping a list of projects repos can be imported from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    /// Recently opened folders in `storage.json`, or the Project Manager extension's `projects.json`
+    Vscode,
+    /// The ghq root directory
+    Ghq,
+    /// gita's `repos.csv`
+    Gita,
+    /// projectile's `projectile-bookmarks.eld`
+    Projectile,
+}
+
+impl FromStr for Provider {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vscode" => Ok(Self::Vscode),
+            "ghq" => Ok(Self::Ghq),
+            "gita" => Ok(Self::Gita),
+            "projectile" => Ok(Self::Projectile),
+            _ => Err(format!("invalid provider: {}", s)),
+        }
+    }
+}
+
+/// Projects listed by a provider, split into the git working trees and everything else
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    pub repos: Vec<PathBuf>,
+    /// Projects that don't exist anymore or aren't the root of a working tree
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Read the projects `provider` lists in `path`
+pub fn discover(provider: Provider, path: &Path) -> Result<Discovered, Error> {
+    let projects = match provider {
+        Provider::Vscode => vscode_projects(&read_to_string(path)?)?,
+        Provider::Ghq => {
+            let mut repos = Vec::new();
+            find_repos(path, GHQ_DEPTH, &mut repos);
+            repos
+        }
+        Provider::Gita => gita_projects(&read_to_string(path)?),
+        Provider::Projectile => projectile_projects(&read_to_string(path)?),
+    };
+
+    let mut discovered = Discovered::default();
+    for project in projects {
+        match canonicalize(&project).ok().filter(|p| is_worktree_root(p)) {
+            Some(repo) if !discovered.repos.contains(&repo) => discovered.repos.push(repo),
+            Some(_) => {}
+            None => discovered.skipped.push(project),
+        }
+    }
+    Ok(discovered)
+}
+
+fn is_worktree_root(path: &Path) -> bool {
+    Repository::open(path)
+        .ok()
+        .and_then(|repo| repo.workdir().and_then(|w| canonicalize(w).ok()))
+        .is_some_and(|workdir| workdir == path)
+}
+
+fn expand_path(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).as_ref())
+}
+
+// Folders are `folderUri` file URIs in `storage.json` and `rootPath`s in `projects.json`
+fn vscode_projects(json: &str) -> Result<Vec<PathBuf>, Error> {
+    fn collect(value: &Value, projects: &mut Vec<PathBuf>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    match (key.as_str(), value.as_str()) {
+                        ("folderUri", Some(uri)) => {
+                            if let Some(path) = Url::parse(uri)
+                                .ok()
+                                .filter(|url| url.scheme() == "file")
+                                .and_then(|url| url.to_file_path().ok())
+                            {
+                                projects.push(path);
+                            }
+                        }
+                        ("rootPath", Some(path)) => projects.push(expand_path(path)),
+                        _ => collect(value, projects),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|v| collect(v, projects)),
+            _ => {}
+        }
+    }
+    let mut projects = Vec::new();
+    collect(&serde_json::from_str(json)?, &mut projects);
+    Ok(projects)
+}
+
+// A line per repo starting with its path, older versions listed bare paths
+fn gita_projects(csv: &str) -> Vec<PathBuf> {
+    csv.lines()
+        .filter_map(|line| line.split(',').next())
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(expand_path)
+        .collect()
+}
+
+// An elisp list of quoted directory names
+fn projectile_projects(eld: &str) -> Vec<PathBuf> {
+    eld.split('"')
+        .skip(1)
+        .step_by(2)
+        .filter(|path| !path.is_empty())
+        .map(expand_path)
+        .collect()
+}
+
+// Working trees at most `depth` directories below `dir`, not looking inside them
+fn find_repos(dir: &Path, depth: usize, repos: &mut Vec<PathBuf>) {
+    if dir.join(".git").exists() {
+        repos.push(dir.to_owned());
+        return;
+    }
+    if depth == 0 {
+        return;
+    }
+    if let Ok(entries) = read_dir(dir) {
+        let mut dirs: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir())
+            .collect();
+        dirs.sort();
+        for dir in dirs {
+            find_repos(&dir, depth - 1, repos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn providers() {
+        let temp_dir = tempdir().unwrap();
+        let root = canonicalize(temp_dir.path()).unwrap();
+        let a = root.join("github.com/me/a");
+        let b = root.join("github.com/me/b");
+        for repo in [&a, &b] {
+            create_dir_all(repo).unwrap();
+            test_repo(repo);
+        }
+        let missing = root.join("missing");
+        let plain = root.join("plain");
+        create_dir_all(&plain).unwrap();
+
+        let ghq = discover(Provider::Ghq, &root).unwrap();
+        assert_eq!(vec![a.clone(), b.clone()], ghq.repos);
+
+        let list = root.join("list");
+        write(
+            &list,
+            format!(
+                r#"{{"openedPathsList": {{"entries": [{{"folderUri": "{}"}}, {{"fileUri": "file:///x"}}]}},
+                   "projects": [{{"name": "b", "rootPath": "{}"}}, {{"rootPath": "{}"}}]}}"#,
+                Url::from_file_path(&a).unwrap(),
+                b.display(),
+                missing.display()
+            ),
+        )
+        .unwrap();
+        let vscode = discover(Provider::Vscode, &list).unwrap();
+        assert_eq!(vec![a.clone(), b.clone()], vscode.repos);
+        assert_eq!(vec![missing.clone()], vscode.skipped);
+
+        write(
+            &list,
+            format!("{},a,,\n{},plain\n", a.display(), plain.display()),
+        )
+        .unwrap();
+        let gita = discover(Provider::Gita, &list).unwrap();
+        assert_eq!(vec![a.clone()], gita.repos);
+        assert_eq!(vec![plain.clone()], gita.skipped);
+
+        write(
+            &list,
+            format!("(\"{}/\" \"{}/\")", b.display(), b.join("sub").display()),
+        )
+        .unwrap();
+        let projectile = discover(Provider::Projectile, &list).unwrap();
+        assert_eq!(vec![b.clone()], projectile.repos);
+        assert_eq!(1, projectile.skipped.len());
+    }
+}
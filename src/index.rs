@@ -0,0 +1,313 @@
+use std::{
+    ffi::OsStr,
+    fs::{read_dir, Metadata},
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use git2::{Index, IndexEntry, IndexTime, Oid, Repository};
+
+use crate::error::Error;
+
+const FILE_MODE: u32 = 0o100644;
+const EXECUTABLE_MODE: u32 = 0o100755;
+// the low bits of an index entry's flags hold the path length
+const INDEX_ENTRY_NAME_MASK: usize = 0x0fff;
+
+/// Whether blobs can be written straight from disk, skipping libgit2's workdir filters.
+/// Repos using line ending conversion or attributes fall back to the serial `add_all`, nested
+/// `.gitattributes` are only found by `add_all_parallel` walking the working tree.
+pub fn can_hash_parallel(repo: &Repository) -> bool {
+    let Ok(config) = repo.config() else {
+        return false;
+    };
+    let autocrlf = config
+        .get_string("core.autocrlf")
+        .map(|v| v == "false")
+        .unwrap_or(true);
+    // git reads `$XDG_CONFIG_HOME/git/attributes` without core.attributesFile
+    let global_attributes = config.get_path("core.attributesfile").ok().or_else(|| {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+            .map(|dir| dir.join("git").join("attributes"))
+    });
+    let attributes = [
+        repo.workdir().map(|w| w.join(".gitattributes")),
+        Some(repo.path().join("info").join("attributes")),
+        global_attributes,
+    ]
+    .into_iter()
+    .flatten()
+    .any(|path| path.exists());
+    autocrlf && !attributes && repo.workdir().is_some()
+}
+
+/// Hash every new or modified file in the working tree across `threads` workers, writing blobs to
+/// the object database of the repository at `objects_repo`, and add them to the index. Files whose
+/// size and mtime match their index entry are skipped. Nothing is added when a `.gitattributes`
+/// turns up, leaving every file to the serial `add_all`.
+pub fn add_all_parallel(
+    repo: &Repository,
+    index: &mut Index,
+    threads: usize,
+    objects_repo: &Path,
+) -> Result<(), Error> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir.to_owned(),
+        None => return Ok(()),
+    };
+
+    let mut files = Vec::new();
+    collect_files(repo, &workdir, Path::new(""), &mut files)?;
+    if files
+        .iter()
+        .any(|(rel, _)| rel.file_name() == Some(OsStr::new(".gitattributes")))
+    {
+        return Ok(());
+    }
+    files.retain(|(rel, metadata)| !is_unchanged(index, rel, metadata));
+    // with core.filemode off the executable bit isn't trusted, modes are kept as they were
+    let trust_mode = repo
+        .config()
+        .and_then(|c| c.get_bool("core.filemode"))
+        .unwrap_or(true);
+
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let chunk_size = files.len().div_ceil(threads);
+    let (tx, rx) = channel();
+
+    thread::scope(|s| -> Result<(), Error> {
+        for chunk in files.chunks(chunk_size) {
+            let tx = tx.clone();
+            let workdir = &workdir;
+            s.spawn(move || {
+                // Repository handles can't be shared between threads, open one per worker
+                let result = Repository::open(objects_repo)
+                    .map_err(Error::from)
+                    .and_then(|repo| {
+                        chunk
+                            .iter()
+                            .map(|(rel, _)| Ok(repo.blob_path(&workdir.join(rel))?))
+                            .collect::<Result<Vec<Oid>, Error>>()
+                    });
+                let _ = tx.send((chunk, result));
+            });
+        }
+        drop(tx);
+
+        for (chunk, result) in rx {
+            for ((rel, metadata), id) in chunk.iter().zip(result?) {
+                let mut entry = index_entry(rel, metadata, id);
+                if !trust_mode {
+                    entry.mode = index.get_path(rel, 0).map_or(FILE_MODE, |e| e.mode);
+                }
+                index.add(&entry)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn collect_files(
+    repo: &Repository,
+    workdir: &Path,
+    rel: &Path,
+    files: &mut Vec<(PathBuf, Metadata)>,
+) -> Result<(), Error> {
+    for entry in read_dir(workdir.join(rel))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        if rel == Path::new(".git") || repo.is_path_ignored(&rel)? {
+            continue;
+        }
+
+        // symlinks are left to add_all
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // nested repositories are left to add_all
+            if !workdir.join(&rel).join(".git").exists() {
+                collect_files(repo, workdir, &rel, files)?;
+            }
+        } else if file_type.is_file() {
+            files.push((rel, entry.metadata()?));
+        }
+    }
+    Ok(())
+}
+
+fn entry_path(rel: &Path) -> Vec<u8> {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+        .into_bytes()
+}
+
+fn index_time(time: std::io::Result<std::time::SystemTime>) -> IndexTime {
+    time.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| IndexTime::new(d.as_secs() as i32, d.subsec_nanos()))
+        .unwrap_or_else(|| IndexTime::new(0, 0))
+}
+
+fn is_unchanged(index: &Index, rel: &Path, metadata: &Metadata) -> bool {
+    index
+        .get_path(rel, 0)
+        .map(|entry| {
+            let mtime = index_time(metadata.modified());
+            entry.file_size == metadata.len() as u32
+                && entry.mtime.seconds() == mtime.seconds()
+                && entry.mtime.nanoseconds() == mtime.nanoseconds()
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn stat_fields(metadata: &Metadata) -> (IndexTime, u32, u32, u32, u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    // like git, only the owner's executable bit counts
+    let mode = if metadata.mode() & 0o100 != 0 {
+        EXECUTABLE_MODE
+    } else {
+        FILE_MODE
+    };
+    (
+        IndexTime::new(metadata.ctime() as i32, metadata.ctime_nsec() as u32),
+        metadata.dev() as u32,
+        metadata.ino() as u32,
+        mode,
+        metadata.uid(),
+        metadata.gid(),
+    )
+}
+
+#[cfg(not(unix))]
+fn stat_fields(metadata: &Metadata) -> (IndexTime, u32, u32, u32, u32, u32) {
+    (index_time(metadata.created()), 0, 0, FILE_MODE, 0, 0)
+}
+
+fn index_entry(rel: &Path, metadata: &Metadata, id: Oid) -> IndexEntry {
+    let path = entry_path(rel);
+    let (ctime, dev, ino, mode, uid, gid) = stat_fields(metadata);
+    IndexEntry {
+        ctime,
+        mtime: index_time(metadata.modified()),
+        dev,
+        ino,
+        mode,
+        uid,
+        gid,
+        file_size: metadata.len() as u32,
+        id,
+        flags: path.len().min(INDEX_ENTRY_NAME_MASK) as u16,
+        flags_extended: 0,
+        path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn add_all_parallel_matches_add_all() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        std::fs::create_dir(temp_dir.path().join("ignored")).unwrap();
+        std::fs::write(temp_dir.path().join(".gitignore"), "ignored\n").unwrap();
+        std::fs::write(temp_dir.path().join("ignored/a"), "a").unwrap();
+        for i in 0..10 {
+            std::fs::write(temp_dir.path().join(format!("dir/{}", i)), i.to_string()).unwrap();
+        }
+
+        let mut serial = Index::new().unwrap();
+        repo.set_index(&mut serial).unwrap();
+        serial
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let serial_tree = serial.write_tree().unwrap();
+
+        let mut parallel = Index::new().unwrap();
+        repo.set_index(&mut parallel).unwrap();
+        add_all_parallel(&repo, &mut parallel, 4, repo.path()).unwrap();
+
+        assert_eq!(serial_tree, parallel.write_tree().unwrap());
+    }
+
+    // tree of the working tree written by add_all, then by add_all_parallel
+    fn trees(repo: &Repository) -> (Oid, Oid) {
+        let mut serial = Index::new().unwrap();
+        repo.set_index(&mut serial).unwrap();
+        serial
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let mut parallel = Index::new().unwrap();
+        repo.set_index(&mut parallel).unwrap();
+        add_all_parallel(repo, &mut parallel, 2, repo.path()).unwrap();
+        (
+            serial.write_tree_to(repo).unwrap(),
+            parallel.write_tree_to(repo).unwrap(),
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn add_all_parallel_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let mode = |name: &str, mode: u32| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, name).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        mode("owner", 0o744);
+        mode("group", 0o654);
+        let (serial, parallel) = trees(&repo);
+        assert_eq!(serial, parallel);
+
+        // add_all on an index opened from a path trusts the mode, what build_index runs after
+        // add_all_parallel keeps it
+        config.set_bool("core.filemode", false).unwrap();
+        let mut index = Index::new().unwrap();
+        repo.set_index(&mut index).unwrap();
+        add_all_parallel(&repo, &mut index, 2, repo.path()).unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        assert_eq!(
+            index.get_path(Path::new("owner"), 0).unwrap().mode,
+            FILE_MODE
+        );
+    }
+
+    #[test]
+    fn add_all_parallel_nested_attributes() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        std::fs::write(
+            temp_dir.path().join("dir/.gitattributes"),
+            "*.txt eol=crlf\n",
+        )
+        .unwrap();
+        std::fs::write(temp_dir.path().join("dir/a.txt"), "a\r\n").unwrap();
+        assert!(can_hash_parallel(&repo));
+
+        let mut index = Index::new().unwrap();
+        add_all_parallel(&repo, &mut index, 2, repo.path()).unwrap();
+        // left to add_all to apply the attributes
+        assert!(index.is_empty());
+    }
+}
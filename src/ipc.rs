@@ -0,0 +1,305 @@
+use std::{
+    io::{BufRead, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_slice, from_value, to_value, to_vec, Value};
+
+use crate::{
+    error::Error,
+    history::{parse_time, Divergence, LogCommit, SnapshotSpec},
+    Repo,
+};
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+// error codes defined by JSON-RPC
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// A snapshot operation failed, the error's code is in the data
+pub const SNAPSHOT_ERROR: i64 = -32000;
+
+const CONTENT_LENGTH: &str = "Content-Length:";
+
+/// A call, or a notification without `id` that gets no response
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        Self {
+            code: SNAPSHOT_ERROR,
+            message: err.to_string(),
+            data: Some(serde_json::json!({ "code": err.code(), "hint": err.hint() })),
+        }
+    }
+}
+
+/// Params of `status` and `list`, the repo defaults to the one the server was started in
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RepoParams {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Most snapshots `list` returns, newest first
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct SnapshotParams {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Take a checkpoint with the message, even when nothing changed
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RestoreFileParams {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Absolute, or relative to the working tree
+    pub file: PathBuf,
+    /// Revision of the snapshot, the latest one without it or `at`
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// Local time of the snapshot, e.g. `10 minutes ago`
+    #[serde(default)]
+    pub at: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+    pub summary: String,
+    pub checkpoint: bool,
+}
+
+impl From<LogCommit> for SnapshotInfo {
+    fn from(commit: LogCommit) -> Self {
+        Self {
+            id: commit.id.to_string(),
+            time: commit.time,
+            summary: commit.summary,
+            checkpoint: commit.checkpoint,
+        }
+    }
+}
+
+/// Result of `snapshot`, without a snapshot when nothing changed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SnapshotResult {
+    pub snapshot: Option<SnapshotInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResult {
+    pub branch: String,
+    pub snapshot_branch: String,
+    pub last_snapshot: Option<SnapshotInfo>,
+    /// Why snapshots are off, e.g. a disable marker
+    pub disabled: Option<String>,
+    #[serde(default)]
+    pub divergence: Option<Divergence>,
+}
+
+/// Answers requests read from `input` on `output` until `shutdown` or the end of the input.
+/// Messages are framed by a `Content-Length` header like LSP's.
+pub fn serve(mut input: impl BufRead, mut output: impl Write, cwd: &Path) -> Result<(), Error> {
+    while let Some(message) = read_message(&mut input)? {
+        let (id, result, shutdown) = match from_slice::<Request>(&message) {
+            Ok(request) if request.jsonrpc == JSONRPC_VERSION => (
+                request.id.clone(),
+                handle(&request, cwd),
+                request.method == "shutdown",
+            ),
+            Ok(request) => (
+                request.id,
+                Err(RpcError::new(
+                    INVALID_REQUEST,
+                    "unsupported jsonrpc version",
+                )),
+                false,
+            ),
+            Err(err) => (
+                Some(Value::Null),
+                Err(RpcError::new(PARSE_ERROR, err)),
+                false,
+            ),
+        };
+        // notifications aren't answered
+        if let Some(id) = id {
+            let (result, error) = match result {
+                Ok(result) => (Some(result), None),
+                Err(error) => (None, Some(error)),
+            };
+            let response = Response {
+                jsonrpc: JSONRPC_VERSION.to_owned(),
+                id,
+                result,
+                error,
+            };
+            write_message(&mut output, &to_vec(&response)?)?;
+        }
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn handle(request: &Request, cwd: &Path) -> Result<Value, RpcError> {
+    let repo = |path: &Option<PathBuf>| Repo::from_path(path.as_deref().unwrap_or(cwd));
+    let result = match request.method.as_str() {
+        "snapshot" => {
+            let params: SnapshotParams = params(&request.params)?;
+            let repo = repo(&params.path)?;
+            let tip = |repo: &Repo| repo.find_snapshot(None).map(|c| c.id()).ok();
+            let before = tip(&repo);
+            match &params.message {
+                Some(message) => repo.checkpoint(message)?,
+                None => repo.snapshot()?,
+            };
+            let snapshot = match tip(&repo) != before {
+                true => Some(LogCommit::new(&repo.find_snapshot(None)?).into()),
+                false => None,
+            };
+            to_value(SnapshotResult { snapshot })
+        }
+        "status" => {
+            let params: RepoParams = params(&request.params)?;
+            let repo = repo(&params.path)?;
+            let branch = repo.current_branch()?;
+            let config = repo.git_repo().config().map_err(Error::from)?;
+            to_value(StatusResult {
+                snapshot_branch: Repo::snapshot_branch(&config, &branch, SystemTime::now()),
+                branch,
+                last_snapshot: repo.list(None)?.into_iter().next().map(|(c, _)| c.into()),
+                disabled: repo.disabled_reason(),
+                divergence: repo.divergence(None)?,
+            })
+        }
+        "list" => {
+            let params: RepoParams = params(&request.params)?;
+            let snapshots: Vec<SnapshotInfo> = repo(&params.path)?
+                .list(None)?
+                .into_iter()
+                .take(params.limit.unwrap_or(usize::MAX))
+                .map(|(c, _)| c.into())
+                .collect();
+            to_value(snapshots)
+        }
+        "restoreFile" => {
+            let params: RestoreFileParams = params(&request.params)?;
+            let repo = repo(&params.path)?;
+            let file = match params.file.is_absolute() {
+                true => repo.relative_path(&params.file).ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, "file isn't inside the working tree")
+                })?,
+                false => params.file,
+            };
+            let spec = match (params.snapshot, params.at) {
+                (Some(rev), _) => Some(SnapshotSpec::Rev(rev)),
+                (None, Some(at)) => Some(SnapshotSpec::At(parse_time(&at)?)),
+                (None, None) => None,
+            };
+            let restored = repo.restore(spec.as_ref(), &[file], None)?;
+            to_value(SnapshotResult {
+                snapshot: Some(LogCommit::new(&restored).into()),
+            })
+        }
+        "shutdown" => Ok(Value::Null),
+        method => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", method),
+            ))
+        }
+    };
+    result.map_err(|err| Error::from(err).into())
+}
+
+fn params<T: DeserializeOwned>(params: &Value) -> Result<T, RpcError> {
+    // omitted params are the same as empty ones
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params.clone(),
+    };
+    from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+/// The body of the next message, None at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<Vec<u8>>, Error> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            // a stray empty line between messages
+            if length.is_none() {
+                continue;
+            }
+            break;
+        }
+        if let Some(value) = line.strip_prefix(CONTENT_LENGTH) {
+            length = Some(value.trim().parse::<usize>().map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidData, "invalid Content-Length")
+            })?);
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+pub fn write_message(output: &mut impl Write, body: &[u8]) -> Result<(), Error> {
+    write!(output, "{} {}\r\n\r\n", CONTENT_LENGTH, body.len())?;
+    output.write_all(body)?;
+    output.flush()?;
+    Ok(())
+}
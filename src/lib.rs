@@ -1,7 +1,59 @@
+pub mod api;
+pub mod audit;
+mod auth;
+mod bundle;
+pub mod cancel;
+pub mod clock;
+#[cfg(feature = "email")]
+pub mod email;
 mod error;
+pub mod events;
+pub mod failures;
+pub mod filter;
+mod git_cli;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod history;
+pub mod import;
+mod index;
+pub mod ipc;
+pub mod logging;
+mod message;
+pub mod metadata;
+pub mod migrate;
+pub mod notify;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod overlay;
+pub mod pause;
+pub mod performance;
+mod placeholder;
+pub mod prompt;
+pub mod recorder;
 mod repo;
 pub mod repo_watcher;
+pub mod report;
+pub mod restore;
+mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod search;
+pub mod secret;
+pub mod settings;
+pub mod setup;
+pub mod spans;
+pub mod state;
+pub mod stream;
+#[cfg(unix)]
+pub mod system;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_remote;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod timeout;
+pub mod units;
 mod util;
+pub mod verify;
 pub mod watcher;
 pub use error::*;
 pub use repo::*;
@@ -0,0 +1,143 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// How much is logged, set on the command line and changed while running through the API or
+/// SIGUSR1
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Off => write!(f, "off"),
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(Error::InvalidLogLevel(s.to_owned())),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => Self::Off,
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+        }
+    }
+}
+
+impl LogLevel {
+    /// The level in effect in the process
+    pub fn current() -> Self {
+        match log::max_level() {
+            LevelFilter::Off => Self::Off,
+            LevelFilter::Error => Self::Error,
+            LevelFilter::Warn => Self::Warn,
+            LevelFilter::Info => Self::Info,
+            LevelFilter::Debug | LevelFilter::Trace => Self::Debug,
+        }
+    }
+
+    /// Make the level the one in effect. The logger has to be built to log at every level for
+    /// levels above the one it was built with to show.
+    pub fn apply(self) {
+        log::set_max_level(self.into());
+    }
+
+    /// Apply the level, logging the change at the new level so it shows, and return the previous
+    /// one
+    pub fn change(self) -> Self {
+        let previous = Self::current();
+        self.apply();
+        if let Some(level) = LevelFilter::from(self).to_level() {
+            log::log!(level, "log level changed from {} to {}", previous, self);
+        }
+        previous
+    }
+
+    /// The next more verbose level, going back to errors only after debug
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off | Self::Debug => Self::Error,
+            Self::Error => Self::Warn,
+            Self::Warn => Self::Info,
+            Self::Info => Self::Debug,
+        }
+    }
+}
+
+/// Move to the next log level on every SIGUSR1 for as long as the process runs
+#[cfg(unix)]
+pub fn cycle_on_sigusr1() {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+    // only async-signal-safe calls are allowed in the handler, the level is changed by the task
+    extern "C" fn handle(_: libc::c_int) {
+        RECEIVED.store(true, Ordering::Relaxed);
+    }
+    unsafe {
+        libc::signal(libc::SIGUSR1, handle as *const () as libc::sighandler_t);
+    }
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            if RECEIVED.swap(false, Ordering::Relaxed) {
+                LogLevel::current().next().change();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        assert_eq!(LogLevel::Debug, "debug".parse().unwrap());
+        assert!(matches!(
+            "trace".parse::<LogLevel>(),
+            Err(Error::InvalidLogLevel(_))
+        ));
+        assert_eq!(LogLevel::Debug, LogLevel::Info.next());
+        assert_eq!(LogLevel::Error, LogLevel::Debug.next());
+        assert_eq!("\"warn\"", serde_json::to_string(&LogLevel::Warn).unwrap());
+    }
+}
@@ -1,73 +1,47 @@
-use git_snapshot::repo_watcher::{RepoWatcher, WatchConfig};
+use git_snapshot::repo_watcher::{config_paths, explain, run_once, RepoWatcher, WatchConfig};
 
-use git_snapshot::Repo;
+use git2::DiffFormat;
+use git_snapshot::audit::AuditAction;
+use git_snapshot::history::{parse_group_id, parse_time, SnapshotSpec};
+use git_snapshot::import::{discover, Provider};
+use git_snapshot::ipc::serve;
+use git_snapshot::logging::LogLevel;
+use git_snapshot::migrate::SettingsArchive;
+use git_snapshot::overlay;
+use git_snapshot::pause::Pause;
+use git_snapshot::prompt::PromptStatus;
+use git_snapshot::recorder;
+use git_snapshot::report::{Period, Report, ReportFormat};
+use git_snapshot::restore::MergeStrategy;
+use git_snapshot::secret::Secret;
+use git_snapshot::settings::{SettingLayers, SettingOverrides};
+use git_snapshot::setup::{apply_defaults, apply_recommended_config, create_repo, ServiceUnit};
+use git_snapshot::state::{State, Suppression};
+#[cfg(unix)]
+use git_snapshot::system::SystemWatcher;
+#[cfg(feature = "test-util")]
+use git_snapshot::test_remote::{
+    write_ssh_container, RemoteKind, TestRemote, SSH_CONTAINER_URL, SSH_KEY_ENV, SSH_URL_ENV,
+};
+use git_snapshot::units::HumanDuration;
+use git_snapshot::verify::Parity;
+use git_snapshot::{ConfigScope, Repo};
 use log::{error, LevelFilter};
+use regex::Regex;
 use serde_json::{from_reader, to_writer};
 use structopt::StructOpt;
 
 use anyhow::{anyhow, Error};
 
-use std::env::current_dir;
-use std::fmt::Display;
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::ErrorKind;
-use std::str::FromStr;
+use std::env::{current_dir, current_exe};
+use std::fs::{create_dir_all, write, OpenOptions};
+use std::io::{stdin, stdout, ErrorKind, IsTerminal, Write};
+use std::net::Ipv4Addr;
 
 use pretty_env_logger::formatted_builder;
 use std::path::{Path, PathBuf};
 use std::thread::park;
-
-#[derive(Debug)]
-enum LogLevel {
-    Off,
-    Error,
-    Warn,
-    Info,
-    Debug,
-}
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Info
-    }
-}
-
-impl Display for LogLevel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
-            Self::Off => write!(f, "off"),
-            Self::Error => write!(f, "error"),
-            Self::Warn => write!(f, "warn"),
-            Self::Info => write!(f, "info"),
-            Self::Debug => write!(f, "debug"),
-        }
-    }
-}
-
-impl FromStr for LogLevel {
-    type Err = Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "off" => Ok(Self::Off),
-            "error" => Ok(Self::Error),
-            "warn" => Ok(Self::Warn),
-            "info" => Ok(Self::Info),
-            "debug" => Ok(Self::Debug),
-            _ => Err(anyhow!("Invalid log level: {}", s)),
-        }
-    }
-}
-
-impl From<&LogLevel> for LevelFilter {
-    fn from(level: &LogLevel) -> Self {
-        match level {
-            LogLevel::Off => Self::Off,
-            LogLevel::Error => Self::Error,
-            LogLevel::Warn => Self::Warn,
-            LogLevel::Info => Self::Info,
-            LogLevel::Debug => Self::Debug,
-        }
-    }
-}
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "git-snapshot", about = "Automate snapshots for git")]
@@ -82,16 +56,182 @@ struct App {
         about = "error,warn,info,debug"
     )]
     log_level: LogLevel,
+    #[structopt(
+        short,
+        long,
+        about = "Threads used to hash files, over GIT_SNAPSHOT_THREADS and snapshot.threads"
+    )]
+    threads: Option<usize>,
+    #[structopt(long, about = "Log the duration of each snapshot phase")]
+    timings: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct SnapshotArgs {
+    #[structopt(about = "Snapshot commit, defaults to the latest snapshot")]
+    snapshot: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with = "snapshot",
+        parse(try_from_str = parse_time),
+        about = "Use the latest snapshot at or before a time, e.g. \"yesterday 17:00\""
+    )]
+    at: Option<SystemTime>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["snapshot", "at"],
+        about = "Use the snapshot taken with a repo group, by its group id"
+    )]
+    group: Option<String>,
+}
+
+impl SnapshotArgs {
+    fn spec(self) -> Option<SnapshotSpec> {
+        self.at
+            .map(SnapshotSpec::At)
+            .or_else(|| self.group.map(SnapshotSpec::Group))
+            .or_else(|| self.snapshot.map(SnapshotSpec::Rev))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum ApprovalsCommands {
+    #[structopt(about = "List pushes held until their remote is approved")]
+    List {
+        #[structopt(long, about = "Repo path, defaults to the current directory")]
+        repo: Option<PathBuf>,
+    },
+    #[structopt(about = "Approve a remote and push the snapshots held for it")]
+    Approve {
+        #[structopt(about = "Remote name")]
+        remote: String,
+        #[structopt(long, about = "Repo path, defaults to the current directory")]
+        repo: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum DaemonCommands {
+    #[structopt(about = "Show or change the log level of the running watcher through its API")]
+    LogLevel {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(about = "off,error,warn,info,debug, shows the current one when left out")]
+        level: Option<LogLevel>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommands {
+    #[structopt(about = "Show the snapshot branch a template expands to and check git accepts it")]
+    CheckTemplate {
+        #[structopt(about = "Template, defaults to the configured one")]
+        template: Option<String>,
+        #[structopt(long, about = "Branch to expand it for, defaults to the current one")]
+        branch: Option<String>,
+    },
+    #[structopt(about = "Set the snapshot branch template, checking it expands to a valid name")]
+    SetBranch {
+        #[structopt(about = "Template, e.g. wip/${BRANCH}")]
+        template: String,
+        #[structopt(long, about = "In the user's git config, for every repo")]
+        global: bool,
+    },
+    #[structopt(about = "Push snapshots to a remote, or stop pushing them")]
+    Remote {
+        #[structopt(about = "Remote name")]
+        name: String,
+        #[structopt(long, about = "Stop pushing snapshots to the remote")]
+        disable: bool,
+    },
+}
+
+#[cfg(feature = "test-util")]
+#[derive(Debug, StructOpt)]
+enum DevCommands {
+    #[structopt(about = "Serve a bare repo to push snapshots to, for the ignored remote tests")]
+    TestRemote {
+        #[structopt(long, default_value = "daemon", about = "daemon,http,ssh")]
+        kind: RemoteKind,
+        #[structopt(about = "Directory of the repo, or of the container for ssh")]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            default_value = "snapshot",
+            about = "User allowed to push over http"
+        )]
+        username: String,
+        #[structopt(
+            long,
+            default_value = "secret",
+            about = "Password of the user over http"
+        )]
+        password: String,
+        #[structopt(long, about = "Public key allowed to push over ssh")]
+        public_key: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, StructOpt)]
 enum AppCommands {
+    #[structopt(
+        about = "Set up snapshots of the current repo: watcher config, git config, remote and service"
+    )]
+    Init {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(short, long, about = "Push snapshots to this remote")]
+        remote: Option<String>,
+        #[structopt(long, about = "Install a user service running the watcher")]
+        service: bool,
+        #[structopt(short, long, about = "Don't ask, only do what the flags say")]
+        yes: bool,
+    },
     #[structopt(about = "Add git repo to watcher config")]
     Watch {
         #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
         config: Option<PathBuf>,
         #[structopt(about = "Repo path")]
         path: PathBuf,
+        #[structopt(long, about = "Don't set the config defaults on the repo")]
+        no_apply_defaults: bool,
+        #[structopt(long, about = "Create the repo with an initial commit first")]
+        create: bool,
+        #[structopt(
+            long,
+            conflicts_with = "create",
+            about = "Snapshot a directory without a repo into one kept by git-snapshot"
+        )]
+        overlay: bool,
+    },
+    #[structopt(about = "Add the git repos another tool keeps a list of to the watcher config")]
+    Import {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(long, about = "vscode,ghq,gita,projectile")]
+        provider: Provider,
+        #[structopt(about = "The tool's project list, or for ghq its root directory")]
+        path: PathBuf,
+        #[structopt(long, about = "Only show the repos that would be added")]
+        dry_run: bool,
+    },
+    #[structopt(
+        about = "Write the watcher config and the settings of the watched repos to a file"
+    )]
+    ExportSettings {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(about = "Settings file to write")]
+        output: PathBuf,
+    },
+    #[structopt(about = "Apply settings written by export-settings, e.g. on a new machine")]
+    ImportSettings {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(about = "Settings file to read")]
+        input: PathBuf,
+        #[structopt(long, about = "Replace an existing watcher config")]
+        force: bool,
     },
     #[structopt(about = "Remove repo from watcher config")]
     Unwatch {
@@ -100,52 +240,958 @@ enum AppCommands {
         #[structopt(about = "repo path")]
         path: PathBuf,
     },
+    #[structopt(about = "Show snapshots interleaved with the commits they were taken on")]
+    Log {
+        #[structopt(short, long, about = "Branch, defaults to the current branch")]
+        branch: Option<String>,
+        #[structopt(long, about = "Only show commits newer than this, e.g. 2d")]
+        since: Option<HumanDuration>,
+    },
+    #[structopt(about = "Search the contents of snapshots of the current branch")]
+    Grep {
+        #[structopt(about = "Regular expression matched against each line")]
+        pattern: Regex,
+        #[structopt(
+            long,
+            about = "Only search content present this long ago or added since, e.g. 7d"
+        )]
+        since: Option<HumanDuration>,
+    },
+    #[structopt(about = "List snapshots along with their metadata")]
+    List {
+        #[structopt(short, long, about = "Branch, defaults to the current branch")]
+        branch: Option<String>,
+    },
+    #[structopt(about = "Snapshot the current branch as a named checkpoint, even without changes")]
+    Checkpoint {
+        #[structopt(short, long, about = "Checkpoint message")]
+        message: String,
+    },
+    #[structopt(about = "Push the snapshot branches that advanced since they were last pushed")]
+    Push,
+    #[cfg(feature = "s3")]
+    #[structopt(
+        about = "Upload a bundle of the snapshots taken since the last one to snapshot.s3.bucket"
+    )]
+    Backup,
+    #[structopt(
+        about = "Write the snapshots taken since the last bundle to a file, for another machine to unbundle"
+    )]
+    Bundle {
+        #[structopt(short, long, about = "Bundle file to write")]
+        output: PathBuf,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_time),
+            about = "Bundle the snapshots taken after a time instead, e.g. \"last monday\""
+        )]
+        since: Option<SystemTime>,
+    },
+    #[structopt(about = "Fetch the snapshot branches of a bundle file")]
+    Unbundle {
+        #[structopt(about = "Bundle file")]
+        path: PathBuf,
+    },
+    #[structopt(about = "List snapshots grouped by editing session")]
+    Sessions {
+        #[structopt(short, long, about = "Branch, defaults to the current branch")]
+        branch: Option<String>,
+    },
+    #[structopt(about = "Answer JSON-RPC requests of editor plugins")]
+    Serve {
+        #[structopt(long, about = "Over stdin and stdout, the only transport so far")]
+        stdio: bool,
+    },
+    #[structopt(about = "Print a short snapshot status of the current repo for a shell prompt")]
+    PromptStatus,
+    #[structopt(about = "Show the snapshot branch and latest snapshot of the current branch")]
+    Status {
+        #[structopt(
+            short,
+            long,
+            env = "GIT_SNAPSHOT_CONFIG",
+            about = "Watcher config path"
+        )]
+        config: Option<PathBuf>,
+    },
+    #[structopt(about = "Suspend the watcher's snapshots, resuming after a while")]
+    Pause {
+        #[structopt(long = "for", about = "Resume after this long, e.g. 1h")]
+        duration: HumanDuration,
+        #[structopt(
+            short,
+            long,
+            env = "GIT_SNAPSHOT_CONFIG",
+            about = "Watcher config path"
+        )]
+        config: Option<PathBuf>,
+    },
+    #[structopt(about = "Resume the watcher's snapshots before the pause is over")]
+    Resume {
+        #[structopt(
+            short,
+            long,
+            env = "GIT_SNAPSHOT_CONFIG",
+            about = "Watcher config path"
+        )]
+        config: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "Stop snapshotting a repo or one of its branches, for good or for a while"
+    )]
+    Disable {
+        #[structopt(short, long, about = "Only this branch")]
+        branch: Option<String>,
+        #[structopt(long, about = "Repo path, defaults to the current directory")]
+        repo: Option<PathBuf>,
+        #[structopt(long = "for", about = "Re-enable after this long, e.g. 2h")]
+        duration: Option<HumanDuration>,
+    },
+    #[structopt(about = "Resume snapshotting a repo or one of its branches")]
+    Enable {
+        #[structopt(short, long, about = "Only this branch")]
+        branch: Option<String>,
+        #[structopt(long, about = "Repo path, defaults to the current directory")]
+        repo: Option<PathBuf>,
+    },
+    #[structopt(about = "Show the rules deciding whether a change would be snapshotted now")]
+    Explain {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(long, about = "Repo path, defaults to the current directory")]
+        repo: Option<PathBuf>,
+    },
+    #[structopt(about = "Review pushes to remotes snapshots haven't been pushed to before")]
+    Approvals(ApprovalsCommands),
+    #[structopt(about = "Check the snapshot git config")]
+    Config(ConfigCommands),
+    #[structopt(about = "Control the running watcher")]
+    Daemon(DaemonCommands),
+    #[cfg(feature = "test-util")]
+    #[structopt(about = "Helpers for developing git-snapshot")]
+    Dev(DevCommands),
+    #[structopt(about = "Restore the working tree to a snapshot, removing files created since")]
+    Restore {
+        #[structopt(flatten)]
+        snapshot: SnapshotArgs,
+        #[structopt(short, long = "path", about = "Only restore these paths")]
+        paths: Vec<PathBuf>,
+        #[structopt(
+            short,
+            long,
+            about = "Merge the snapshot into the working tree, leaving conflict markers"
+        )]
+        merge: bool,
+        #[structopt(
+            long,
+            conflicts_with = "theirs",
+            about = "Merge, keeping the working tree's side of conflicts"
+        )]
+        ours: bool,
+        #[structopt(long, about = "Merge, taking the snapshot's side of conflicts")]
+        theirs: bool,
+        #[structopt(
+            long,
+            about = "Fetch the snapshot branches from a bundle file, or the bundles of an s3://<bucket>/<prefix> first"
+        )]
+        from_bundle: Option<String>,
+    },
+    #[structopt(about = "Restore every repo of a group to the snapshots taken together")]
+    RestoreGroup {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(about = "Group id, as recorded in the Snapshot-Group trailer")]
+        id: String,
+    },
+    #[structopt(about = "Put the working tree back to how it was before the last restore")]
+    UndoRestore,
+    #[structopt(
+        about = "Remove untracked and ignored files, once the latest snapshot is checked to hold every change"
+    )]
+    Clean {
+        #[structopt(short = "n", long, about = "Only list what would be removed")]
+        dry_run: bool,
+    },
+    #[structopt(about = "Show changes in the working tree since a snapshot")]
+    Diff {
+        #[structopt(flatten)]
+        snapshot: SnapshotArgs,
+    },
+    #[structopt(about = "Write the files of a snapshot to a directory")]
+    Export {
+        #[structopt(about = "Destination directory")]
+        dest: PathBuf,
+        #[structopt(flatten)]
+        snapshot: SnapshotArgs,
+    },
+    #[structopt(about = "Summarize snapshot activity of the watched repos")]
+    Report {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+        #[structopt(long, default_value = "daily", about = "daily,weekly")]
+        period: Period,
+        #[structopt(long, default_value = "markdown", about = "markdown,json")]
+        format: ReportFormat,
+        #[structopt(short, long, about = "Write the report to a file instead of stdout")]
+        output: Option<PathBuf>,
+    },
+    #[structopt(about = "Encrypt a credential read from stdin into a value for the configs")]
+    EncryptSecret {
+        #[structopt(long, about = "Store it in the OS keyring under this name instead")]
+        keyring: Option<String>,
+    },
+    #[structopt(about = "Check the audit log of the current repo hasn't been tampered with")]
+    VerifyAudit,
+    #[structopt(about = "Check the snapshots of a branch can be read back, and have been pushed")]
+    Verify {
+        #[structopt(short, long, about = "Branch, defaults to the current branch")]
+        branch: Option<String>,
+        #[structopt(long, about = "Compare with the snapshots on each remote pushed to")]
+        remote: bool,
+    },
+    #[structopt(
+        about = "Snapshot and push the changes of every watched repo once, e.g. from a timer"
+    )]
+    RunOnce {
+        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
+        config: Option<PathBuf>,
+    },
     #[structopt(about = "Runs the watcher in foreground")]
     StartWatcher {
-        #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "config path")]
-        config: Option<PathBuf>,
+        #[structopt(
+            short,
+            long,
+            env = "GIT_SNAPSHOT_CONFIG",
+            number_of_values = 1,
+            about = "config path or a directory of configs, repeat to watch several"
+        )]
+        config: Vec<PathBuf>,
+        #[structopt(long, about = "Append every event and what became of it to this file")]
+        record_events: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "Replay events recorded with start-watcher --record-events against the current repo"
+    )]
+    Replay {
+        #[structopt(about = "Event trace to replay")]
+        file: PathBuf,
+        #[structopt(
+            long,
+            about = "Recorded root to replay, defaults to the first one watched"
+        )]
+        root: Option<PathBuf>,
+    },
+    #[cfg(unix)]
+    #[structopt(about = "Runs a watcher for each configured user in foreground, usually as root")]
+    StartSystemWatcher {
+        #[structopt(
+            short,
+            long,
+            default_value = "/etc/git-snapshot/system.json",
+            about = "System config path"
+        )]
+        config: PathBuf,
     },
 }
 
 #[tokio::main]
 async fn main() {
     let app = App::from_args();
-    formatted_builder()
-        .filter_level((&app.log_level).into())
-        .init();
+    // every level is logged so the level can be raised while running
+    formatted_builder().filter_level(LevelFilter::Debug).init();
+    app.log_level.apply();
+    git_snapshot::spans::install();
     if let Err(err) = run(app) {
-        error!("{:?}", err)
+        match err.downcast_ref::<git_snapshot::Error>() {
+            Some(err) => {
+                error!("{} [{}]", err, err.code());
+                if let Some(hint) = err.hint() {
+                    eprintln!("hint: {}", hint);
+                }
+            }
+            None => error!("{:?}", err),
+        }
+        std::process::exit(1);
     }
 }
 
 fn run(app: App) -> Result<(), Error> {
     if let Some(cmds) = app.cmds {
         match cmds {
-            AppCommands::StartWatcher { config } => {
-                let _watcher = RepoWatcher::with_config(config.unwrap_or(default_config_path()?))?;
+            AppCommands::StartWatcher {
+                config,
+                record_events,
+            } => {
+                #[cfg(unix)]
+                git_snapshot::logging::cycle_on_sigusr1();
+                if let Some(path) = record_events {
+                    recorder::record_to(&path)?;
+                }
+                let config = match config.is_empty() {
+                    true => vec![default_config_path()?],
+                    false => config,
+                };
+                let config_paths = config_paths(&config)?;
+                if config_paths.is_empty() {
+                    return Err(anyhow!("no watcher config found"));
+                }
+                let _watchers = RepoWatcher::with_configs(&config_paths)?;
+                park();
+            }
+            AppCommands::Replay { file, root } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let entries = recorder::read_trace(&file)?;
+                for entry in recorder::replay(&entries, &repo, root.as_deref())? {
+                    println!("{}", serde_json::to_string(&entry)?);
+                }
+            }
+            #[cfg(unix)]
+            AppCommands::StartSystemWatcher { config } => {
+                let _watcher = SystemWatcher::with_config(config)?;
                 park();
             }
-            AppCommands::Watch { config, path } => {
+            AppCommands::RunOnce { config } => {
+                let p = config.unwrap_or(default_config_path()?);
+                let mut config = load_config(&p)?;
+                config.add_drop_ins(&p);
+                let summary = run_once(&config, Some(&Pause::path(&p)))?;
+                for (path, reason) in &summary.skipped {
+                    println!("skipped {}: {}", path.display(), reason);
+                }
+                for (path, err) in &summary.failed {
+                    println!("failed {}: {}", path.display(), err);
+                }
+                println!(
+                    "{} snapshotted, {} unchanged, {} skipped, {} failed",
+                    summary.snapshotted.len(),
+                    summary.unchanged.len(),
+                    summary.skipped.len(),
+                    summary.failed.len()
+                );
+                if !summary.is_ok() {
+                    return Err(anyhow!("{} repo(s) failed", summary.failed.len()));
+                }
+            }
+            AppCommands::Log { branch, since } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let since = since.map(|since| SystemTime::now() - since.0);
+                print!("{}", repo.log(branch.as_deref(), since)?);
+            }
+            AppCommands::Grep { pattern, since } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let since = since.map(|since| SystemTime::now() - since.0);
+                for m in repo.grep(&pattern, since)? {
+                    println!("{}", m);
+                }
+            }
+            AppCommands::List { branch } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                if let Some(divergence) = repo.divergence(branch.as_deref())? {
+                    println!("{}", divergence);
+                }
+                for (commit, metadata) in repo.list(branch.as_deref())? {
+                    println!("{}", commit);
+                    if let Some(metadata) = metadata {
+                        println!("    {}", metadata);
+                    }
+                }
+            }
+            AppCommands::Sessions { branch } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                for session in repo.sessions(branch.as_deref())? {
+                    println!("{}", session);
+                    for commit in &session.snapshots {
+                        println!("    {}", commit);
+                    }
+                }
+            }
+            AppCommands::Checkpoint { message } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                repo.checkpoint(&message)?;
+                println!("checkpoint: {}", repo.find_snapshot(None)?.id());
+            }
+            AppCommands::Push => {
+                Repo::from_path(current_dir()?)?.push_snapshots()?;
+            }
+            #[cfg(feature = "s3")]
+            AppCommands::Backup => {
+                let repo = Repo::from_path(current_dir()?)?;
+                match git_snapshot::s3::backup(&repo)? {
+                    Some(key) => println!("uploaded {}", key),
+                    None => println!("nothing to upload"),
+                }
+            }
+            AppCommands::Bundle { output, since } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                match repo.bundle(&output, since)? {
+                    Some(heads) => {
+                        for (ref_name, commit) in heads {
+                            println!("{} {}", commit, ref_name);
+                        }
+                    }
+                    None => println!("nothing new to bundle"),
+                }
+            }
+            AppCommands::Unbundle { path } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                for (ref_name, commit) in repo.unbundle(&path)? {
+                    println!("{} {}", commit, ref_name);
+                }
+            }
+            AppCommands::Serve { stdio } => {
+                if !stdio {
+                    return Err(anyhow!("Pass --stdio, the only transport supported"));
+                }
+                serve(stdin().lock(), stdout().lock(), &current_dir()?)?;
+            }
+            #[cfg(feature = "test-util")]
+            AppCommands::Dev(DevCommands::TestRemote {
+                kind,
+                dir,
+                username,
+                password,
+                public_key,
+            }) => {
+                create_dir_all(&dir)?;
+                let remote = match kind {
+                    RemoteKind::Daemon => TestRemote::daemon(&dir)?,
+                    RemoteKind::Http => TestRemote::http(&dir, &username, &password)?,
+                    RemoteKind::Ssh => {
+                        let public_key =
+                            public_key.ok_or_else(|| anyhow!("ssh needs --public-key"))?;
+                        write_ssh_container(&dir, &public_key)?;
+                        println!("docker build -t git-snapshot-test-remote {}", dir.display());
+                        println!("docker run --rm -p 2222:22 git-snapshot-test-remote");
+                        println!("export {}={}", SSH_URL_ENV, SSH_CONTAINER_URL);
+                        println!("export {}=<private key>", SSH_KEY_ENV);
+                        return Ok(());
+                    }
+                };
+                println!("serving {} at {}", remote.path.display(), remote.url);
+                park();
+            }
+            AppCommands::Config(ConfigCommands::CheckTemplate { template, branch }) => {
+                let repo = Repo::from_path(&current_dir()?)?;
+                let branch = match branch {
+                    Some(branch) => branch,
+                    None => repo.current_branch()?,
+                };
+                let config = repo.git_repo().config()?;
+                let check =
+                    Repo::check_template(&config, template.as_deref(), &branch, SystemTime::now());
+                println!("template: {}", check.template);
+                println!("expanded: {}", check.expanded);
+                match check.branch {
+                    Some(name) if name == check.expanded => println!("valid"),
+                    Some(name) => println!("sanitized: {}", name),
+                    None => {
+                        return Err(anyhow!(
+                            "invalid snapshot branch name: {:?}",
+                            check.expanded
+                        ))
+                    }
+                }
+            }
+            AppCommands::Config(ConfigCommands::SetBranch { template, global }) => {
+                let repo = Repo::from_path(&current_dir()?)?;
+                let scope = match global {
+                    true => ConfigScope::Global,
+                    false => ConfigScope::Local,
+                };
+                repo.set_snapshot_branch(scope, &template)?;
+            }
+            AppCommands::Config(ConfigCommands::Remote { name, disable }) => {
+                Repo::from_path(&current_dir()?)?.set_remote_enabled(&name, !disable)?;
+            }
+            AppCommands::PromptStatus => {
+                if let Some(status) = PromptStatus::read(&current_dir()?, SystemTime::now())? {
+                    println!("{}", status);
+                }
+            }
+            AppCommands::Status { config } => {
+                let now = SystemTime::now();
+                let pause_path = Pause::path(&config.unwrap_or(default_config_path()?));
+                if let Some(pause) = Pause::load(&pause_path)? {
+                    println!(
+                        "watcher paused until: {} ({} left)",
+                        humantime::format_rfc3339_seconds(pause.until),
+                        humantime::format_duration(Duration::from_secs(
+                            pause.remaining(now).as_secs()
+                        ))
+                    );
+                }
+                let repo = Repo::from_path(current_dir()?)?;
+                let branch = repo.current_branch()?;
+                let config = repo.git_repo().config()?;
+                println!("branch: {}", branch);
+                println!(
+                    "snapshot branch: {}",
+                    Repo::snapshot_branch(&config, &branch, SystemTime::now())
+                );
+                if let Some(reason) = repo.disabled_reason() {
+                    println!("disabled: {}", reason);
+                }
+                let state = State::load(repo.git_repo().path())?;
+                match state.suppression(&branch, now) {
+                    Some(Suppression {
+                        until: Some(until), ..
+                    }) => println!(
+                        "disabled until: {}",
+                        humantime::format_rfc3339_seconds(*until)
+                    ),
+                    Some(_) => println!("disabled"),
+                    None => {}
+                }
+                if !state.pending_pushes.is_empty() {
+                    println!(
+                        "pushes awaiting approval: {} (see git-snapshot approvals list)",
+                        state.pending_pushes.len()
+                    );
+                }
+                if let Some(verification) = &state.last_verification {
+                    println!("last verification: {}", verification);
+                }
+                match repo.list(None)?.into_iter().next() {
+                    Some((commit, metadata)) => {
+                        println!("last snapshot: {}", commit);
+                        if let Some(metadata) = metadata {
+                            println!("    {}", metadata);
+                        }
+                        if let Some(divergence) = repo.divergence(None)? {
+                            println!("divergence: {}", divergence);
+                        }
+                    }
+                    None => println!("last snapshot: none"),
+                }
+            }
+            AppCommands::Disable {
+                branch,
+                repo,
+                duration,
+            } => {
+                let repo = Repo::from_path(repo.map_or_else(current_dir, Ok)?)?;
+                let until = duration.map(|duration| SystemTime::now() + duration.0);
+                repo.disable(branch.as_deref(), until)?;
+            }
+            AppCommands::Enable { branch, repo } => {
+                let repo = Repo::from_path(repo.map_or_else(current_dir, Ok)?)?;
+                repo.enable(branch.as_deref())?;
+            }
+            AppCommands::Explain { config, repo } => {
+                let p = config.unwrap_or(default_config_path()?);
+                // the repo's own rules still apply without a watcher
+                let mut config = match p.exists() {
+                    true => load_config(&p)?,
+                    false => WatchConfig::default(),
+                };
+                config.add_drop_ins(&p);
+                let path = repo.map_or_else(current_dir, Ok)?;
+                let checks = explain(&config, Some(&Pause::path(&p)), &path)?;
+                for check in &checks {
+                    println!("{}", check);
+                }
+                match checks.iter().find(|check| check.stop.is_some()) {
+                    Some(check) => println!("a snapshot would be stopped by: {}", check.rule),
+                    None => println!("changes since the last snapshot would be snapshotted"),
+                }
+            }
+            AppCommands::Pause { duration, config } => {
+                let pause = Pause {
+                    until: SystemTime::now() + duration.0,
+                };
+                pause.save(&Pause::path(&config.unwrap_or(default_config_path()?)))?;
+                println!(
+                    "paused until: {}",
+                    humantime::format_rfc3339_seconds(pause.until)
+                );
+            }
+            AppCommands::Resume { config } => {
+                Pause::clear(&Pause::path(&config.unwrap_or(default_config_path()?)))?;
+            }
+            AppCommands::Daemon(DaemonCommands::LogLevel { config, level }) => {
+                let p = config.unwrap_or(default_config_path()?);
+                let api = load_config(&p)?.api.ok_or(anyhow!(
+                    "{} has no api for the running watcher to be reached through",
+                    p.display()
+                ))?;
+                let mut addr = api.listen;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+                let url = format!("http://{}/log-level", addr);
+                let auth = format!("Bearer {}", api.token.reveal()?);
+                match level {
+                    Some(level) => {
+                        let response: serde_json::Value = ureq::put(&url)
+                            .set("Authorization", &auth)
+                            .send_json(serde_json::json!({ "level": level }))?
+                            .into_json()?;
+                        println!(
+                            "log level changed from {} to {}",
+                            response["previous"].as_str().unwrap_or_default(),
+                            level
+                        );
+                    }
+                    None => {
+                        let response: serde_json::Value = ureq::get(&url)
+                            .set("Authorization", &auth)
+                            .call()?
+                            .into_json()?;
+                        println!("{}", response["level"].as_str().unwrap_or_default());
+                    }
+                }
+            }
+            AppCommands::Approvals(ApprovalsCommands::List { repo }) => {
+                let repo = Repo::from_path(repo.map_or_else(current_dir, Ok)?)?;
+                for push in repo.pending_pushes()? {
+                    println!("{}", push);
+                }
+            }
+            AppCommands::Approvals(ApprovalsCommands::Approve { remote, repo }) => {
+                let repo = Repo::from_path(repo.map_or_else(current_dir, Ok)?)?;
+                repo.approve_remote(&remote)?;
+                println!("approved remote {}", remote);
+            }
+            AppCommands::Restore {
+                snapshot,
+                paths,
+                merge,
+                ours,
+                theirs,
+                from_bundle,
+            } => {
+                let cwd = current_dir()?;
+                let repo = Repo::from_path(&cwd)?;
+                if let Some(from_bundle) = from_bundle {
+                    fetch_bundles(&repo, &from_bundle)?;
+                }
+                // paths are given relative to the current directory, checkout wants them relative to the working tree
+                let prefix = repo
+                    .relative_path(&cwd.canonicalize()?)
+                    .ok_or(anyhow!("Not inside a working tree"))?;
+                let paths: Vec<PathBuf> = paths.iter().map(|p| prefix.join(p)).collect();
+                let merge = if ours {
+                    Some(MergeStrategy::Ours)
+                } else if theirs {
+                    Some(MergeStrategy::Theirs)
+                } else if merge {
+                    Some(MergeStrategy::Markers)
+                } else {
+                    None
+                };
+                let restored = repo.restore(snapshot.spec().as_ref(), &paths, merge)?;
+                println!("restored snapshot {}", restored.id());
+            }
+            AppCommands::RestoreGroup { config, id } => {
+                let config = load_config(&config.unwrap_or(default_config_path()?))?;
+                let (name, _) = parse_group_id(&id).ok_or(anyhow!("Invalid group id: {}", id))?;
+                let group = config
+                    .groups
+                    .into_iter()
+                    .find(|g| g.name == name)
+                    .ok_or(anyhow!("No group named {}", name))?;
+                let spec = SnapshotSpec::Group(id.clone());
+                for path in group.repos {
+                    let repo = Repo::from_path(&path)?;
+                    let restored = repo.restore(Some(&spec), &[], None)?;
+                    println!("{}: restored snapshot {}", path.display(), restored.id());
+                }
+            }
+            AppCommands::UndoRestore => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let capture = repo.undo_restore()?;
+                println!("restored working tree captured at {}", capture.id());
+            }
+            AppCommands::Clean { dry_run } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                for path in repo.clean(dry_run)? {
+                    match dry_run {
+                        true => println!("would remove {}", path.display()),
+                        false => println!("removed {}", path.display()),
+                    }
+                }
+            }
+            AppCommands::Diff { snapshot } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                repo.diff(snapshot.spec().as_ref())?
+                    .print(DiffFormat::Patch, |_, _, line| {
+                        if matches!(line.origin(), '+' | '-' | ' ') {
+                            print!("{}", line.origin());
+                        }
+                        print!("{}", String::from_utf8_lossy(line.content()));
+                        true
+                    })?;
+            }
+            AppCommands::Export { dest, snapshot } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let exported = repo.export(snapshot.spec().as_ref(), &dest)?;
+                println!("exported snapshot {} to {}", exported.id(), dest.display());
+            }
+            AppCommands::Report {
+                config,
+                period,
+                format,
+                output,
+            } => {
                 let p = config.unwrap_or(default_config_path()?);
                 let mut config = load_config(&p)?;
-                config.add_repo(path)?;
+                config.add_drop_ins(&p);
+                let paths: Vec<PathBuf> = config.repos.into_iter().map(|r| r.path).collect();
+                let report = Report::new(period, &paths, SystemTime::now()).render(format)?;
+                match output {
+                    Some(output) => write(output, report)?,
+                    None => print!("{}", report),
+                }
+            }
+            AppCommands::EncryptSecret { keyring } => {
+                let mut value = String::new();
+                stdin().read_line(&mut value)?;
+                let value = value.trim_end_matches(['\r', '\n']);
+                let secret = match keyring {
+                    Some(name) => Secret::store(&name, value)?,
+                    None => Secret::encrypt(value)?,
+                };
+                println!("{}", secret);
+            }
+            AppCommands::VerifyAudit => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let summary = repo.verify_audit()?;
+                println!(
+                    "verified {} audit log entries, {} signed",
+                    summary.entries, summary.signed
+                );
+            }
+            AppCommands::Verify { branch, remote } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let report = repo.verify_objects(branch.as_deref())?;
+                println!("{}", report);
+                let mut problems = usize::from(!report.is_ok());
+                if remote {
+                    for check in repo.verify_remotes(branch.as_deref())? {
+                        println!("{}", check);
+                        problems += usize::from(check.parity != Parity::InSync);
+                    }
+                }
+                if problems > 0 {
+                    return Err(anyhow!("{} problem(s) found", problems));
+                }
+            }
+            AppCommands::Init {
+                config,
+                remote,
+                service,
+                yes,
+            } => {
+                let cwd = current_dir()?;
+                let repo = Repo::from_path(&cwd)?;
+                let path = repo
+                    .git_repo()
+                    .workdir()
+                    .ok_or(anyhow!("Not inside a working tree"))?
+                    .to_owned();
+                let interactive = !yes && stdin().is_terminal();
+
+                let p = config.unwrap_or(default_config_path()?);
+                let mut config = load_config(&p)?;
+                config.add_repo(&path)?;
+                save_config(&p, &config)?;
+                repo.audit(AuditAction::Watch);
+                println!("watching {} in {}", path.display(), p.display());
+
+                for key in apply_defaults(&repo, &config.defaults)? {
+                    println!("set {}", key);
+                }
+                for key in apply_recommended_config(&repo)? {
+                    println!("set {}", key);
+                }
+
+                let remote = match remote {
+                    Some(remote) => Some(remote),
+                    None if interactive => {
+                        let mut chosen = None;
+                        for name in repo.git_repo().remotes()?.iter().flatten() {
+                            if confirm(&format!("Push snapshots to remote {}?", name))? {
+                                chosen = Some(name.to_owned());
+                                break;
+                            }
+                        }
+                        chosen
+                    }
+                    None => None,
+                };
+                if let Some(remote) = remote {
+                    repo.set_remote_enabled(&remote, true)?;
+                    println!("pushing snapshots to {}", remote);
+                }
+
+                if service || (interactive && confirm("Install a service running the watcher?")?) {
+                    let home = dirs::home_dir().ok_or(anyhow!("Unable to get home directory"))?;
+                    let unit = ServiceUnit::new(&home, &current_exe()?, &p)?;
+                    unit.install()?;
+                    println!(
+                        "installed {}, start it with: {}",
+                        unit.path.display(),
+                        unit.enable_command
+                    );
+                }
+            }
+            AppCommands::Watch {
+                config,
+                path,
+                no_apply_defaults,
+                create,
+                overlay,
+            } => {
+                let p = config.unwrap_or(default_config_path()?);
+                let mut config = load_config(&p)?;
+                if create {
+                    create_repo(&path)?;
+                    println!("created {}", path.display());
+                }
+                if overlay {
+                    let repo = overlay::create(&path)?;
+                    println!(
+                        "snapshotting {} into {}",
+                        path.display(),
+                        repo.git_repo().path().display()
+                    );
+                    config.add_overlay(&path)?;
+                } else {
+                    config.add_repo(&path)?;
+                }
                 save_config(&p, &config)?;
+                if let Ok(repo) = Repo::from_path(&path) {
+                    repo.audit(AuditAction::Watch);
+                    if let Some(reason) = repo.disabled_reason() {
+                        println!("watching, but snapshots are disabled: {}", reason);
+                    }
+                    if !no_apply_defaults {
+                        for key in apply_defaults(&repo, &config.defaults)? {
+                            println!("set {}", key);
+                        }
+                    }
+                    if create {
+                        for key in apply_recommended_config(&repo)? {
+                            println!("set {}", key);
+                        }
+                    }
+                }
+            }
+            AppCommands::Import {
+                config,
+                provider,
+                path,
+                dry_run,
+            } => {
+                let p = config.unwrap_or(default_config_path()?);
+                let mut config = load_config(&p)?;
+                let discovered = discover(provider, &path)?;
+                for skipped in &discovered.skipped {
+                    println!("skipping {}, not a git working tree", skipped.display());
+                }
+                let mut added = Vec::new();
+                for repo in discovered.repos {
+                    if config.repos.iter().any(|r| r.path == repo) {
+                        continue;
+                    }
+                    match dry_run {
+                        true => println!("would watch {}", repo.display()),
+                        false => {
+                            config.add_repo(&repo)?;
+                            println!("watching {}", repo.display());
+                        }
+                    }
+                    added.push(repo);
+                }
+                if !dry_run {
+                    save_config(&p, &config)?;
+                    for path in added {
+                        if let Ok(repo) = Repo::from_path(&path) {
+                            repo.audit(AuditAction::Watch);
+                        }
+                    }
+                }
+            }
+            AppCommands::ExportSettings { config, output } => {
+                let archive = SettingsArchive::export(&config.unwrap_or(default_config_path()?))?;
+                archive.save(&output)?;
+                println!(
+                    "exported settings of {} repo(s) to {}",
+                    archive.repos.len(),
+                    output.display()
+                );
+            }
+            AppCommands::ImportSettings {
+                config,
+                input,
+                force,
+            } => {
+                let p = config.unwrap_or(default_config_path()?);
+                let archive = SettingsArchive::load(&input)?;
+                if archive.config.is_some() && p.exists() && !force {
+                    return Err(anyhow!(
+                        "{} already exists, pass --force to replace it",
+                        p.display()
+                    ));
+                }
+                let summary = archive.import(&p, dirs::home_dir().as_deref())?;
+                for path in &summary.applied {
+                    println!("applied settings to {}", path.display());
+                }
+                for path in &summary.missing {
+                    println!("{} not found, clone it and import again", path.display());
+                }
             }
             AppCommands::Unwatch { config, path } => {
                 let p = config.unwrap_or(default_config_path()?);
                 let mut config = load_config(&p)?;
-                config.remove_repo(path)?;
+                config.remove_repo(&path)?;
                 save_config(&p, &config)?;
+                if let Ok(repo) = Repo::from_path(&path) {
+                    repo.audit(AuditAction::Unwatch);
+                }
             }
         }
     } else {
         let cwd = current_dir()?;
-        let repo = Repo::from_path(cwd)?;
+        let repo = Repo::from_path(cwd)?.with_settings(SettingLayers {
+            flags: SettingOverrides {
+                threads: app.threads,
+                timings: app.timings.then_some(true),
+            },
+            ..Default::default()
+        });
         repo.snapshot()?;
     }
     Ok(())
 }
 
+// The snapshot branches of a bundle file, or of the bundles under an s3:// url
+fn fetch_bundles(repo: &Repo, from: &str) -> Result<(), Error> {
+    let heads = match from.starts_with("s3://") {
+        #[cfg(feature = "s3")]
+        true => {
+            let target = git_snapshot::s3::S3Target::from_url(&repo.git_repo().config()?, from)?;
+            git_snapshot::s3::restore_bundles(repo, &target)?
+        }
+        #[cfg(not(feature = "s3"))]
+        true => return Err(anyhow!("Build with --features s3 to restore from {}", from)),
+        false => repo.unbundle(Path::new(from))?,
+    };
+    for (ref_name, commit) in heads {
+        println!("{} {}", commit, ref_name);
+    }
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
 fn default_config_path() -> Result<PathBuf, Error> {
     let home = dirs::home_dir().ok_or(anyhow!("Unable to get home directory"))?;
     Ok(home.join(
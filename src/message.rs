@@ -0,0 +1,249 @@
+use std::{
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use git2::{Config, Repository};
+use log::debug;
+
+use crate::{error::Error, util::ConfigValue};
+
+const HOOK_NAME: &str = "prepare-commit-msg";
+// in the git dir like git's own COMMIT_EDITMSG, so a snapshot doesn't clobber a commit being edited
+const MESSAGE_FILE: &str = "SNAPSHOT_EDITMSG";
+// set for the hook so it can tell snapshots from commits
+const HOOK_ENV: &str = "GIT_SNAPSHOT";
+
+/// The text of `commit.template` without its comments, with `snapshot.commitTemplate` set and a
+/// template configured
+pub fn commit_template(repo: &Repository, config: &Config) -> Result<Option<String>, Error> {
+    if !bool::from_config(config, &["snapshot.committemplate"], false) {
+        return Ok(None);
+    }
+    let path = PathBuf::from_config(config, &["commit.template"], PathBuf::new());
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    let template = read_to_string(relative_to_workdir(repo, path))?;
+    Ok(Some(strip_comments(&template)).filter(|template| !template.is_empty()))
+}
+
+/// `message` as edited by the repo's `prepare-commit-msg` hook, with `snapshot.prepareCommitMsg`
+/// set. It's run like for `git commit -m`, failing the snapshot when it fails.
+pub fn prepare_commit_msg(
+    repo: &Repository,
+    config: &Config,
+    message: String,
+) -> Result<String, Error> {
+    if !bool::from_config(config, &["snapshot.preparecommitmsg"], false) {
+        return Ok(message);
+    }
+    let hook = hooks_dir(repo, config).join(HOOK_NAME);
+    if !is_executable(&hook) {
+        debug!("no {} hook at: {}", HOOK_NAME, hook.display());
+        return Ok(message);
+    }
+    let file = repo.path().join(MESSAGE_FILE);
+    // ending with a newline like messages git hands hooks
+    write(&file, format!("{}\n", message))?;
+    let output = Command::new(&hook)
+        .arg(&file)
+        .arg("message")
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .env("GIT_DIR", repo.path())
+        .env(HOOK_ENV, "1")
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::CommitMsgHook(match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => format!("{}: {}", output.status, stderr),
+        }));
+    }
+    Ok(strip_comments(&read_to_string(&file)?))
+}
+
+/// `message` with `trailers` added like `git interpret-trailers` does by default: to the message's
+/// last paragraph when it's made of trailers already, or in a paragraph of their own. `Key:value`
+/// is written `Key: value`, trailers without a value are dropped and one is only added when it
+/// differs from the trailer before it.
+pub fn append_trailers(message: &str, trailers: &[String]) -> String {
+    let message = message.trim_end();
+    let (body, mut block) = match message.rsplit_once("\n\n") {
+        Some((body, last)) if is_trailer_block(last) => (body, last.to_owned()),
+        _ => (message, String::new()),
+    };
+    for trailer in trailers
+        .iter()
+        .filter_map(|trailer| format_trailer(trailer))
+    {
+        if block.lines().last() == Some(trailer.as_str()) {
+            continue;
+        }
+        if !block.is_empty() {
+            block.push('\n');
+        }
+        block.push_str(&trailer);
+    }
+    match (body.is_empty(), block.is_empty()) {
+        (_, true) => body.to_owned(),
+        (true, false) => block,
+        (false, false) => format!("{}\n\n{}", body, block),
+    }
+}
+
+// `Key: value` of a trailer written with or without the space, a key being letters, digits and
+// dashes
+fn format_trailer(trailer: &str) -> Option<String> {
+    let (key, value) = trailer.split_once(':')?;
+    let (key, value) = (key.trim(), value.trim());
+    let valid = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-');
+    (valid && !value.is_empty()).then(|| format!("{}: {}", key, value))
+}
+
+// Lines that are all trailers, or indented continuations of the one before
+fn is_trailer_block(paragraph: &str) -> bool {
+    let mut lines = paragraph.lines();
+    lines.next().and_then(format_trailer).is_some()
+        && lines.all(|line| line.starts_with([' ', '\t']) || format_trailer(line).is_some())
+}
+
+// `core.hooksPath`, relative to the working tree like git has it, or the git dir's hooks
+fn hooks_dir(repo: &Repository, config: &Config) -> PathBuf {
+    match PathBuf::from_config(config, &["core.hookspath"], PathBuf::new()) {
+        path if path.as_os_str().is_empty() => repo.path().join("hooks"),
+        path => relative_to_workdir(repo, path),
+    }
+}
+
+fn relative_to_workdir(repo: &Repository, path: PathBuf) -> PathBuf {
+    match repo.workdir() {
+        Some(workdir) if path.is_relative() => workdir.join(path),
+        _ => path,
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// Drops `#` comment lines and surrounding blank lines, like git's default message cleanup
+fn strip_comments(message: &str) -> String {
+    message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn template() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        std::fs::write(
+            temp_dir.path().join(".gitmessage"),
+            "\n# Describe the change\nIssue: PROJ-1\n",
+        )
+        .unwrap();
+        config.set_str("commit.template", ".gitmessage").unwrap();
+        assert_eq!(None, commit_template(&repo, &config).unwrap());
+
+        config.set_bool("snapshot.committemplate", true).unwrap();
+        assert_eq!(
+            Some("Issue: PROJ-1".to_owned()),
+            commit_template(&repo, &config).unwrap()
+        );
+    }
+
+    #[test]
+    fn trailers() {
+        let trailers = |message: &str, trailers: &[&str]| {
+            let trailers: Vec<String> = trailers.iter().map(|t| t.to_string()).collect();
+            append_trailers(message, &trailers)
+        };
+        assert_eq!(
+            "Snapshot\n\nIssue: PROJ-1",
+            trailers("Snapshot\n", &["Issue:PROJ-1"])
+        );
+        // added to the template's trailers
+        assert_eq!(
+            "Snapshot\n\nIssue: PROJ-1\nHost: laptop",
+            trailers("Snapshot\n\nIssue: PROJ-1", &["Host: laptop"])
+        );
+        assert_eq!(
+            "Snapshot\n\nWhy: it changed\nand more\n\nHost: laptop",
+            trailers("Snapshot\n\nWhy: it changed\nand more", &["Host: laptop"])
+        );
+        // empty, invalid and repeated ones are left out
+        assert_eq!(
+            "Snapshot\n\nA: 1\nB: 2\nA: 1",
+            trailers(
+                "Snapshot",
+                &[
+                    "A: 1",
+                    "A: 1",
+                    "Empty: ",
+                    "no separator",
+                    "Has space: x",
+                    "B: 2",
+                    "A: 1"
+                ]
+            )
+        );
+        assert_eq!("Snapshot", trailers("Snapshot", &[]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hook() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("snapshot.preparecommitmsg", true).unwrap();
+        // no hook
+        assert_eq!(
+            "Snapshot",
+            prepare_commit_msg(&repo, &config, "Snapshot".to_owned()).unwrap()
+        );
+
+        let hook = repo.path().join("hooks").join(HOOK_NAME);
+        std::fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        std::fs::write(
+            &hook,
+            "#!/bin/sh\n[ \"$2\" = message ] && [ \"$GIT_SNAPSHOT\" = 1 ] \
+             && printf '\\nIssue: PROJ-2\\n# comment\\n' >> \"$1\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            "Snapshot\n\nIssue: PROJ-2",
+            prepare_commit_msg(&repo, &config, "Snapshot".to_owned()).unwrap()
+        );
+
+        std::fs::write(&hook, "#!/bin/sh\necho 'missing issue id' >&2\nexit 1\n").unwrap();
+        let err = prepare_commit_msg(&repo, &config, "Snapshot".to_owned()).unwrap_err();
+        assert!(err.to_string().contains("missing issue id"));
+        assert_eq!(crate::error::ErrorCode::CommitMsgHook, err.code());
+    }
+}
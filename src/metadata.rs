@@ -0,0 +1,201 @@
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use git2::{Delta, Diff, ErrorCode, Oid, Repository, Signature};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+
+use crate::error::Error;
+
+/// Notes ref holding the metadata of each snapshot commit
+pub const NOTES_REF: &str = "refs/notes/snapshot";
+
+/// What started a snapshot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Trigger {
+    #[default]
+    Manual,
+    Watcher,
+    Api,
+    /// `run-once`, e.g. from a timer
+    Scheduled,
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Manual => write!(f, "manual"),
+            Self::Watcher => write!(f, "watcher"),
+            Self::Api => write!(f, "api"),
+            Self::Scheduled => write!(f, "scheduled"),
+        }
+    }
+}
+
+/// Size of the change a snapshot recorded since the previous one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffStats {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Full size of the added and modified files, before compression, not only their changes
+    pub bytes_written: u64,
+}
+
+impl DiffStats {
+    /// Stats of the diff a snapshot checked for changes with, rather than diffing again
+    pub fn from_diff(repo: &Repository, diff: &Diff) -> Result<Self, Error> {
+        let stats = diff.stats()?;
+        let odb = repo.odb()?;
+        let mut bytes_written = 0;
+        for delta in diff.deltas() {
+            if matches!(delta.status(), Delta::Added | Delta::Modified) {
+                bytes_written += odb.read_header(delta.new_file().id())?.0 as u64;
+            }
+        }
+        Ok(Self {
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+            bytes_written,
+        })
+    }
+}
+
+impl Display for DiffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files changed, {} insertions(+), {} deletions(-), {} bytes written",
+            self.files_changed, self.insertions, self.deletions, self.bytes_written
+        )
+    }
+}
+
+/// Provenance of a snapshot, stored as a JSON note so commit messages stay clean
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotMetadata {
+    pub hostname: String,
+    pub version: String,
+    pub trigger: Trigger,
+    /// Paths refreshed for the snapshot, empty when the whole working tree was indexed
+    pub changed_paths: Vec<PathBuf>,
+    /// Time taken to build and commit the snapshot
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    /// When the snapshot was triggered by the system clock, the commit time may be adjusted
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub triggered: Option<SystemTime>,
+    /// What changed since the previous snapshot, only noted with snapshot.stats or its trailer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DiffStats>,
+}
+
+impl SnapshotMetadata {
+    pub fn new(
+        trigger: Trigger,
+        changed_paths: Vec<PathBuf>,
+        duration: Duration,
+        triggered: SystemTime,
+        stats: Option<DiffStats>,
+    ) -> Self {
+        Self {
+            hostname: hostname::get()
+                .map(|h| h.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            trigger,
+            changed_paths,
+            duration,
+            triggered: Some(triggered),
+            stats,
+        }
+    }
+
+    pub fn write(&self, repo: &Repository, signature: &Signature, id: Oid) -> Result<(), Error> {
+        repo.note(
+            signature,
+            signature,
+            Some(NOTES_REF),
+            id,
+            &to_string(self)?,
+            true,
+        )?;
+        Ok(())
+    }
+
+    /// The metadata noted on snapshot commit `id`, if any
+    pub fn read(repo: &Repository, id: Oid) -> Result<Option<Self>, Error> {
+        match repo.find_note(Some(NOTES_REF), id) {
+            Ok(note) => Ok(Some(from_str(note.message().unwrap_or_default())?)),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Display for SnapshotMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {} (git-snapshot {}), ",
+            self.trigger, self.hostname, self.version
+        )?;
+        if self.changed_paths.is_empty() {
+            write!(f, "full working tree")?;
+        } else {
+            write!(f, "{} changed paths", self.changed_paths.len())?;
+        }
+        if let Some(stats) = &self.stats {
+            write!(f, ", {}", stats)?;
+        }
+        let duration = Duration::from_millis(self.duration.as_millis() as u64);
+        write!(f, ", took {}", humantime::format_duration(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn write_read() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let signature = repo.signature().unwrap();
+        let tree = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+        let id = repo
+            .commit(None, &signature, &signature, "", &tree, &[])
+            .unwrap();
+        assert_eq!(None, SnapshotMetadata::read(&repo, id).unwrap());
+
+        let metadata = SnapshotMetadata::new(
+            Trigger::Watcher,
+            vec![PathBuf::from("a")],
+            Duration::from_millis(5),
+            SystemTime::UNIX_EPOCH,
+            Some(DiffStats {
+                files_changed: 1,
+                insertions: 2,
+                deletions: 0,
+                bytes_written: 4,
+            }),
+        );
+        metadata.write(&repo, &signature, id).unwrap();
+        assert_eq!(Some(metadata), SnapshotMetadata::read(&repo, id).unwrap());
+    }
+}
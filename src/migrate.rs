@@ -0,0 +1,338 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read, read_dir, write},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::{Config, ConfigLevel};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec_pretty, Value};
+
+use crate::{
+    error::Error,
+    repo_watcher::{drop_in_dir, load_config},
+    state::State,
+    Repo,
+};
+
+// bumped when an archive can't be read by older versions
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Everything that shapes how snapshots are taken on a machine, to carry it over to another one
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingsArchive {
+    pub version: u32,
+    #[serde(with = "humantime_serde")]
+    pub exported: SystemTime,
+    /// Home directory of the exporting user, paths under it are moved to the importing user's
+    #[serde(default)]
+    pub home: Option<PathBuf>,
+    /// The watcher config file as is
+    #[serde(default)]
+    pub config: Option<Value>,
+    /// Drop-in repo configs by file name
+    #[serde(default)]
+    pub drop_ins: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub repos: Vec<RepoSettings>,
+}
+
+/// The snapshot settings and state of a watched repo
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepoSettings {
+    pub path: PathBuf,
+    /// Snapshot keys of the repo's own git config, multi-valued keys once per value
+    pub git_config: Vec<(String, String)>,
+    pub state: State,
+}
+
+/// Repos whose settings were applied on import, and those not found on this machine
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub applied: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+}
+
+impl SettingsArchive {
+    /// Collect the watcher config at `config_path`, its drop-ins, and the settings of the repos
+    /// they watch
+    pub fn export(config_path: &Path) -> Result<Self, Error> {
+        let config = match read(config_path) {
+            Ok(data) => Some(from_slice(&data)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let mut drop_ins = BTreeMap::new();
+        if let Ok(entries) = read_dir(drop_in_dir(config_path)) {
+            for entry in entries {
+                let path = entry?.path();
+                if let (Some(name), Ok(data)) = (path.file_name(), read(&path)) {
+                    drop_ins.insert(name.to_string_lossy().into_owned(), from_slice(&data)?);
+                }
+            }
+        }
+        let mut repos = Vec::new();
+        if config.is_some() {
+            for repo_config in load_config(config_path)?.repos {
+                // repos that are gone have nothing to carry over
+                if let Ok(repo) = Repo::from_path(&repo_config.path) {
+                    let git_repo = repo.git_repo();
+                    repos.push(RepoSettings {
+                        path: repo_config.path,
+                        git_config: snapshot_config(
+                            &git_repo.config()?.open_level(ConfigLevel::Local)?,
+                        )?,
+                        state: State::load(git_repo.path())?,
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            version: ARCHIVE_VERSION,
+            exported: SystemTime::now(),
+            home: dirs::home_dir(),
+            config,
+            drop_ins,
+            repos,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let archive: Self = from_slice(&read(path)?)?;
+        if archive.version > ARCHIVE_VERSION {
+            return Err(Error::InvalidSettings(format!(
+                "version {} is newer than this git-snapshot supports",
+                archive.version
+            )));
+        }
+        Ok(archive)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        write(path, to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Write the watcher config and its drop-ins to `config_path` and apply the settings of the
+    /// repos that exist here, with paths under the exporting user's home moved to `home`
+    pub fn import(self, config_path: &Path, home: Option<&Path>) -> Result<ImportSummary, Error> {
+        let rehome = |path: &Path| match (&self.home, home) {
+            (Some(from), Some(to)) => path
+                .strip_prefix(from)
+                .map(|rest| to.join(rest))
+                .unwrap_or_else(|_| path.to_owned()),
+            _ => path.to_owned(),
+        };
+        let rehome_value = |mut value: Value| {
+            rehome_strings(&mut value, &rehome);
+            value
+        };
+
+        if let Some(config) = &self.config {
+            if let Some(parent) = config_path.parent() {
+                create_dir_all(parent)?;
+            }
+            write(config_path, to_vec_pretty(&rehome_value(config.clone()))?)?;
+        }
+        if !self.drop_ins.is_empty() {
+            let dir = drop_in_dir(config_path);
+            create_dir_all(&dir)?;
+            for (name, drop_in) in &self.drop_ins {
+                // only file names are taken, nothing outside the directory is written
+                let name = Path::new(name).file_name().ok_or_else(|| {
+                    Error::InvalidSettings(format!("invalid drop-in name: {}", name))
+                })?;
+                write(
+                    dir.join(name),
+                    to_vec_pretty(&rehome_value(drop_in.clone()))?,
+                )?;
+            }
+        }
+
+        let mut summary = ImportSummary::default();
+        for settings in &self.repos {
+            let path = rehome(&settings.path);
+            let repo = match Repo::from_path(&path) {
+                Ok(repo) => repo,
+                Err(_) => {
+                    summary.missing.push(path);
+                    continue;
+                }
+            };
+            let mut config = repo.git_repo().config()?.open_level(ConfigLevel::Local)?;
+            apply_config(&mut config, &settings.git_config)?;
+            settings.state.save(repo.git_repo().path())?;
+            summary.applied.push(path);
+        }
+        Ok(summary)
+    }
+}
+
+fn is_snapshot_key(key: &str) -> bool {
+    match key.split_once('.') {
+        Some(("snapshot", _)) => true,
+        Some(("remote" | "branch", rest)) => rest
+            .rsplit_once('.')
+            .is_some_and(|(_, name)| name.starts_with("snapshot")),
+        _ => false,
+    }
+}
+
+fn snapshot_config(config: &Config) -> Result<Vec<(String, String)>, Error> {
+    let mut entries = Vec::new();
+    for entry in &config.entries(None)? {
+        let entry = entry?;
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            if is_snapshot_key(name) {
+                entries.push((name.to_owned(), value.to_owned()));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Replace every value of the keys in `entries`, keeping the order of multi-valued ones
+fn apply_config(config: &mut Config, entries: &[(String, String)]) -> Result<(), Error> {
+    let mut replaced = Vec::new();
+    for (key, value) in entries {
+        if !replaced.contains(key) {
+            match config.remove_multivar(key, ".*") {
+                Err(err) if err.code() != git2::ErrorCode::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            replaced.push(key.clone());
+        }
+        // a pattern matching no value adds one
+        config.set_multivar(key, "^$", value)?;
+    }
+    Ok(())
+}
+
+fn rehome_strings(value: &mut Value, rehome: &impl Fn(&Path) -> PathBuf) {
+    match value {
+        Value::String(s) => {
+            let rehomed = rehome(Path::new(s.as_str()));
+            if let Some(rehomed) = rehomed.to_str() {
+                *s = rehomed.to_owned();
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| rehome_strings(v, rehome)),
+        Value::Object(fields) => fields.values_mut().for_each(|v| rehome_strings(v, rehome)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        repo_watcher::{save_config, RepoConfig, WatchConfig},
+        state::Suppression,
+        test_util::test_repo,
+        util::config_values,
+    };
+
+    #[test]
+    fn export_import() {
+        let old_home = tempdir().unwrap();
+        let old_home = old_home.path().canonicalize().unwrap();
+        let repo_path = old_home.join("project");
+        create_dir_all(&repo_path).unwrap();
+        let (repo, mut config) = test_repo(&repo_path);
+        config
+            .set_str("snapshot.snapshotbranch", "wip/${BRANCH}")
+            .unwrap();
+        config
+            .set_multivar("snapshot.branchdeny", "^$", "a")
+            .unwrap();
+        config
+            .set_multivar("snapshot.branchdeny", "^$", "b")
+            .unwrap();
+        config
+            .set_bool("remote.origin.snapshotenabled", true)
+            .unwrap();
+        config.set_str("core.editor", "vi").unwrap();
+        let mut state = State::default();
+        state.suppressions.push(Suppression {
+            branch: Some("main".to_owned()),
+            until: None,
+        });
+        state.save(repo.path()).unwrap();
+
+        let config_path = old_home.join("config.json");
+        save_config(
+            &config_path,
+            &WatchConfig {
+                repos: vec![RepoConfig {
+                    path: repo_path.clone(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let archive_path = old_home.join("settings.json");
+        SettingsArchive::export(&config_path)
+            .unwrap()
+            .save(&archive_path)
+            .unwrap();
+        let mut archive = SettingsArchive::load(&archive_path).unwrap();
+        archive.home = Some(old_home.clone());
+        assert_eq!(
+            vec![
+                "snapshot.snapshotbranch",
+                "snapshot.branchdeny",
+                "snapshot.branchdeny",
+                "remote.origin.snapshotenabled"
+            ],
+            archive.repos[0]
+                .git_config
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        // a new machine, with the project cloned to the same place under another home
+        let new_home = tempdir().unwrap();
+        let new_home = new_home.path().canonicalize().unwrap();
+        let new_repo_path = new_home.join("project");
+        create_dir_all(&new_repo_path).unwrap();
+        let (new_repo, mut new_config) = test_repo(&new_repo_path);
+        new_config.set_str("snapshot.branchdeny", "old").unwrap();
+        let new_config_path = new_home.join("config.json");
+
+        let summary = archive.import(&new_config_path, Some(&new_home)).unwrap();
+        assert_eq!(vec![new_repo_path.clone()], summary.applied);
+
+        let new_config = new_repo.config().unwrap().snapshot().unwrap();
+        assert_eq!(
+            vec!["a", "b"],
+            config_values(&new_config, "snapshot.branchdeny")
+        );
+        assert_eq!(
+            "wip/${BRANCH}",
+            new_config.get_string("snapshot.snapshotbranch").unwrap()
+        );
+        assert_eq!(1, State::load(new_repo.path()).unwrap().suppressions.len());
+        assert_eq!(
+            new_repo_path,
+            load_config(&new_config_path).unwrap().repos[0].path
+        );
+    }
+
+    #[test]
+    fn snapshot_keys() {
+        assert!(is_snapshot_key("snapshot.verify"));
+        assert!(is_snapshot_key("remote.origin.snapshotbranch"));
+        assert!(is_snapshot_key("branch.feature/a.b.snapshotenabled"));
+        assert!(!is_snapshot_key("remote.origin.url"));
+        assert!(!is_snapshot_key("user.name"));
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[cfg(feature = "email")]
+use crate::email::{EmailConfig, EmailNotifier};
+use crate::{
+    error::Error,
+    failures::{Failure, FailureDigest, FailureLog},
+    secret::Secret,
+};
+
+/// A message for the notification channels
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub title: String,
+    /// Plain text body
+    pub text: String,
+    /// Structured contents for the generic webhook, an object whose fields are added to the payload
+    pub data: Value,
+}
+
+impl From<&FailureDigest> for Notification {
+    fn from(digest: &FailureDigest) -> Self {
+        Self {
+            title: digest.title(),
+            text: digest.to_string(),
+            data: json!({ "failures": digest.0 }),
+        }
+    }
+}
+
+/// Delivers notifications to a channel
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+/// A notification channel and how often it may be notified
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChannelConfig {
+    #[serde(flatten)]
+    pub adapter: AdapterConfig,
+    /// Minimum time between two notifications, failures in between are sent together
+    #[serde(with = "humantime_serde", default = "default_interval")]
+    pub interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AdapterConfig {
+    /// JSON POST of the failures to any url
+    Webhook { url: String },
+    /// Slack incoming webhook, formatted as blocks
+    Slack { url: String },
+    /// Discord webhook, formatted as an embed
+    Discord { url: String },
+    /// Notice sent to a Matrix room
+    Matrix {
+        homeserver: String,
+        room_id: String,
+        access_token: Secret,
+    },
+    #[cfg(feature = "email")]
+    Email(EmailConfig),
+}
+
+impl AdapterConfig {
+    pub fn notifier(&self) -> Result<Box<dyn Notifier>, Error> {
+        Ok(match self {
+            Self::Webhook { url } => Box::new(Webhook::new(url, Format::Json)),
+            Self::Slack { url } => Box::new(Webhook::new(url, Format::Slack)),
+            Self::Discord { url } => Box::new(Webhook::new(url, Format::Discord)),
+            Self::Matrix {
+                homeserver,
+                room_id,
+                access_token,
+            } => Box::new(Matrix {
+                homeserver: homeserver.trim_end_matches('/').to_owned(),
+                room_id: room_id.clone(),
+                access_token: access_token.clone(),
+            }),
+            #[cfg(feature = "email")]
+            Self::Email(config) => Box::new(EmailNotifier::new(config.clone())?),
+        })
+    }
+}
+
+/// Rate limits a notifier, holding back failures until its interval has passed
+pub struct Channel {
+    notifier: Box<dyn Notifier>,
+    interval: Duration,
+    failures: Mutex<FailureLog>,
+}
+
+impl Channel {
+    pub fn new(notifier: Box<dyn Notifier>, interval: Duration) -> Self {
+        Self {
+            notifier,
+            interval,
+            failures: Mutex::new(FailureLog::default()),
+        }
+    }
+
+    /// Record a failure, notifying right away when the interval has passed
+    pub fn failed(&self, failure: Failure) {
+        self.failures.lock().unwrap().push(failure);
+        self.flush();
+    }
+
+    /// Send the failures held back by the interval once it has passed
+    pub fn flush(&self) {
+        let digest = self.failures.lock().unwrap().take_due(self.interval);
+        if let Some(digest) = digest {
+            self.send(&Notification::from(&digest));
+        }
+    }
+
+    /// Send right away, bypassing the interval
+    pub fn send(&self, notification: &Notification) {
+        if let Err(err) = self.notifier.notify(notification) {
+            error!("error sending notification: {:?}", err);
+        }
+    }
+}
+
+/// All configured channels
+#[derive(Default)]
+pub struct Notifications {
+    channels: Vec<Channel>,
+}
+
+impl Notifications {
+    pub fn new(configs: &[ChannelConfig]) -> Result<Self, Error> {
+        let channels = configs
+            .iter()
+            .map(|c| Ok(Channel::new(c.adapter.notifier()?, c.interval)))
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { channels })
+    }
+
+    pub fn failed(&self, failure: Failure) {
+        for channel in &self.channels {
+            channel.failed(failure.clone());
+        }
+    }
+
+    pub fn flush(&self) {
+        for channel in &self.channels {
+            channel.flush();
+        }
+    }
+
+    pub fn send(&self, notification: &Notification) {
+        for channel in &self.channels {
+            channel.send(notification);
+        }
+    }
+
+    /// How often held back failures need to be flushed
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.channels.iter().map(|c| c.interval).min()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Slack,
+    Discord,
+}
+
+struct Webhook {
+    url: String,
+    format: Format,
+}
+
+impl Webhook {
+    fn new(url: &str, format: Format) -> Self {
+        Self {
+            url: url.to_owned(),
+            format,
+        }
+    }
+
+    fn payload(&self, notification: &Notification) -> Value {
+        let Notification { title, text, data } = notification;
+        match self.format {
+            Format::Json => {
+                let mut payload = json!({ "title": title, "text": text });
+                if let (Some(payload), Some(data)) = (payload.as_object_mut(), data.as_object()) {
+                    payload.extend(data.clone());
+                }
+                payload
+            }
+            Format::Slack => json!({
+                "text": title,
+                "blocks": [
+                    {"type": "header", "text": {"type": "plain_text", "text": title}},
+                    {"type": "section", "text": {"type": "mrkdwn", "text": text}},
+                ],
+            }),
+            Format::Discord => json!({
+                "embeds": [{"title": title, "description": text}],
+            }),
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        ureq::post(&self.url)
+            .send_json(self.payload(notification))
+            .map_err(notification_error)?;
+        Ok(())
+    }
+}
+
+struct Matrix {
+    homeserver: String,
+    room_id: String,
+    access_token: Secret,
+}
+
+impl Matrix {
+    fn url(&self, txn_id: u128) -> String {
+        format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver,
+            encode_path_segment(&self.room_id),
+            txn_id
+        )
+    }
+
+    fn payload(notification: &Notification) -> Value {
+        json!({
+            "msgtype": "m.notice",
+            "body": format!("{}\n\n{}", notification.title, notification.text),
+        })
+    }
+}
+
+impl Notifier for Matrix {
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        // the transaction id only has to be unique per access token
+        let txn_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        ureq::put(&self.url(txn_id))
+            .set(
+                "Authorization",
+                &format!("Bearer {}", self.access_token.reveal()?),
+            )
+            .send_json(Self::payload(notification))
+            .map_err(notification_error)?;
+        Ok(())
+    }
+}
+
+// Room ids contain `!` and `:`, aliases `#`
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub(crate) fn notification_error(err: impl Display) -> Error {
+    Error::Notification(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use serde_json::from_str;
+
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn digest() -> FailureDigest {
+        FailureDigest(vec![Failure {
+            repo: PathBuf::from("/a"),
+            error: "auth".to_owned(),
+            code: ErrorCode::Auth,
+            hint: None,
+            time: UNIX_EPOCH,
+        }])
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<usize>>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, notification: &Notification) -> Result<(), Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(notification.data["failures"].as_array().unwrap().len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn config() {
+        let configs: Vec<ChannelConfig> = from_str(
+            r#"[
+                {"type": "slack", "url": "https://hooks.slack.com/services/x"},
+                {"type": "matrix", "homeserver": "https://matrix.org", "room_id": "!a:matrix.org", "access_token": "t", "interval": "5m"}
+            ]"#,
+        )
+        .unwrap();
+        assert!(matches!(configs[0].adapter, AdapterConfig::Slack { .. }));
+        assert_eq!(default_interval(), configs[0].interval);
+        assert_eq!(Duration::from_secs(300), configs[1].interval);
+
+        let notifications = Notifications::new(&configs).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(300)),
+            notifications.flush_interval()
+        );
+    }
+
+    #[test]
+    fn payloads() {
+        let notification = Notification::from(&digest());
+        let slack = Webhook::new("", Format::Slack).payload(&notification);
+        assert_eq!("git-snapshot: 1 failed snapshot", slack["text"]);
+        assert_eq!("header", slack["blocks"][0]["type"]);
+        assert_eq!(
+            "/a: 1 failed snapshot, last at 1970-01-01T00:00:00Z: auth\n",
+            slack["blocks"][1]["text"]["text"]
+        );
+
+        let discord = Webhook::new("", Format::Discord).payload(&notification);
+        assert_eq!(
+            "git-snapshot: 1 failed snapshot",
+            discord["embeds"][0]["title"]
+        );
+
+        let webhook = Webhook::new("", Format::Json).payload(&notification);
+        assert_eq!("/a", webhook["failures"][0]["repo"]);
+        assert_eq!("1970-01-01T00:00:00Z", webhook["failures"][0]["time"]);
+
+        let matrix = Matrix {
+            homeserver: "https://matrix.org".to_owned(),
+            room_id: "!a:matrix.org".to_owned(),
+            access_token: Secret::Plain("t".to_owned()),
+        };
+        assert_eq!(
+            "https://matrix.org/_matrix/client/v3/rooms/%21a%3Amatrix.org/send/m.room.message/1",
+            matrix.url(1)
+        );
+        assert_eq!("m.notice", Matrix::payload(&notification)["msgtype"]);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let recorder = Recorder::default();
+        let channel = Channel::new(Box::new(recorder.clone()), Duration::from_secs(60));
+        let failure = digest().0.remove(0);
+        channel.failed(failure.clone());
+        channel.failed(failure.clone());
+        channel.failed(failure);
+        channel.flush();
+        // the first failure goes out right away, the rest wait for the interval
+        assert_eq!(vec![1], *recorder.0.lock().unwrap());
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    env,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::spans::{Export, FinishedSpan};
+
+// spans are sent at most this far apart, and at most this many at once
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_BATCH: usize = 512;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends ended spans to an OTLP/HTTP collector as JSON, in batches on a thread of its own
+pub struct OtlpExport {
+    sender: Sender<FinishedSpan>,
+}
+
+impl OtlpExport {
+    /// The exporter for `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, or the traces path of
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT`, named `OTEL_SERVICE_NAME`. None without an endpoint.
+    pub fn from_env() -> Option<Self> {
+        let endpoint = match env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => format!(
+                "{}/v1/traces",
+                env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .ok()?
+                    .trim_end_matches('/')
+            ),
+        };
+        let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "git-snapshot".to_owned());
+        Some(Self::new(endpoint, service))
+    }
+
+    pub fn new(endpoint: String, service: String) -> Self {
+        let (sender, receiver) = channel();
+        thread::Builder::new()
+            .name("otlp export".to_owned())
+            .spawn(move || send_batches(receiver, &endpoint, &service))
+            .expect("failed to spawn the OTLP export thread");
+        Self { sender }
+    }
+}
+
+impl Export for OtlpExport {
+    fn export(&self, span: FinishedSpan) {
+        // the thread only stops once the sender is gone
+        let _ = self.sender.send(span);
+    }
+}
+
+fn send_batches(receiver: Receiver<FinishedSpan>, endpoint: &str, service: &str) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + BATCH_INTERVAL;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let disconnected = match receiver.recv_timeout(timeout) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if disconnected || batch.len() >= MAX_BATCH || Instant::now() >= deadline {
+            if !batch.is_empty() {
+                let body = traces_body(service, &batch);
+                if let Err(err) = ureq::post(endpoint).timeout(SEND_TIMEOUT).send_json(body) {
+                    warn!(
+                        "failed to export {} spans to {}: {}",
+                        batch.len(),
+                        endpoint,
+                        err
+                    );
+                }
+                batch.clear();
+            }
+            deadline = Instant::now() + BATCH_INTERVAL;
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+// An OTLP ExportTraceServiceRequest in its JSON encoding, where ids are hex and 64 bit integers
+// strings
+fn traces_body(service: &str, spans: &[FinishedSpan]) -> Value {
+    let nanos = |time: std::time::SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+    };
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut attributes = vec![attribute("code.namespace", span.target)];
+            if let Some(repo) = &span.repo {
+                attributes.push(attribute("repo", repo));
+            }
+            for (name, value) in span.fields.iter().filter(|(name, _)| *name != "repo") {
+                attributes.push(attribute(name, value));
+            }
+            json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "parentSpanId": span.parent_id.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+                "name": span.name,
+                // internal
+                "kind": 1,
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.start + span.duration),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute("service.name", service)]},
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        time::SystemTime,
+    };
+
+    use super::*;
+
+    fn span(parent_id: Option<u64>) -> FinishedSpan {
+        FinishedSpan {
+            name: "snapshot",
+            target: "git_snapshot::repo",
+            repo: Some("repo".to_owned()),
+            trace_id: 0xab,
+            span_id: 0xcd,
+            parent_id,
+            start: UNIX_EPOCH + Duration::from_secs(1),
+            duration: Duration::from_millis(5),
+            fields: vec![("repo", "repo".to_owned()), ("commit", "abc".to_owned())],
+        }
+    }
+
+    #[test]
+    fn encoded() {
+        let body = traces_body("agent", &[span(Some(0xef))]);
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            "agent",
+            resource["resource"]["attributes"][0]["value"]["stringValue"]
+        );
+        let encoded = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!("000000000000000000000000000000ab", encoded["traceId"]);
+        assert_eq!("00000000000000cd", encoded["spanId"]);
+        assert_eq!("00000000000000ef", encoded["parentSpanId"]);
+        assert_eq!("1000000000", encoded["startTimeUnixNano"]);
+        assert_eq!("1005000000", encoded["endTimeUnixNano"]);
+        let attributes: Vec<_> = encoded["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| {
+                (
+                    a["key"].as_str().unwrap(),
+                    a["value"]["stringValue"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("code.namespace", "git_snapshot::repo"),
+                ("repo", "repo"),
+                ("commit", "abc")
+            ],
+            attributes
+        );
+        let root = traces_body("agent", &[span(None)]);
+        assert_eq!(
+            "",
+            root["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["parentSpanId"]
+        );
+    }
+
+    #[test]
+    fn sent_once_the_exporter_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let export = OtlpExport::new(endpoint, "agent".to_owned());
+        let mut span = span(None);
+        span.start = SystemTime::now();
+        export.export(span);
+        drop(export);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut length = 0;
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+
+        assert_eq!("POST /v1/traces HTTP/1.1\r\n", request_line);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!("snapshot", spans[0]["name"]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    fs::{canonicalize, create_dir_all, remove_file},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use git2::{Repository, RepositoryInitOptions};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, setup::initial_commit, util::canonicalize_existing, Repo};
+
+/// Overrides the directory git-snapshot keeps its data in
+pub const DATA_DIR_ENV: &str = "GIT_SNAPSHOT_DATA_DIR";
+
+/// Where the repos of directories snapshotted without a repo of their own are kept, `overlays` in
+/// `$GIT_SNAPSHOT_DATA_DIR` or `git-snapshot` in the user's data directory
+pub fn overlays_dir() -> Option<PathBuf> {
+    let data_dir = match std::env::var_os(DATA_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::data_dir()?.join("git-snapshot"),
+    };
+    Some(data_dir.join("overlays"))
+}
+
+/// Git dir under `dir` of the overlay repo of the canonical `target`, named after it and told
+/// apart from others of the same name by a hash of its path
+pub fn git_dir(dir: &Path, target: &Path) -> PathBuf {
+    let digest = Sha256::digest(target.to_string_lossy().as_bytes());
+    let hash: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().trim_start_matches('.').to_owned())
+        .unwrap_or_default();
+    dir.join(format!("{}-{}.git", name, hash))
+}
+
+/// Create the overlay repo of `target` in the overlays directory, see `create_in`
+pub fn create(target: &Path) -> Result<Repo, Error> {
+    let dir = overlays_dir().ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, "no data directory for overlay repos")
+    })?;
+    create_in(&dir, target)
+}
+
+/// Create the overlay repo of `target` under `dir`, its working tree at `target` without a `.git`
+/// in it, or open it when there's one already. A `target` in a repo is left to that repo.
+pub fn create_in(dir: &Path, target: &Path) -> Result<Repo, Error> {
+    let target = canonicalize(target)?;
+    if let Ok(repo) = Repository::discover(&target) {
+        let root = repo.workdir().unwrap_or(repo.path());
+        return Err(Error::RepoExists(root.to_owned()));
+    }
+    let git_dir = git_dir(dir, &target);
+    if let Ok(git_repo) = Repository::open(&git_dir) {
+        return Ok(Repo::new(git_repo));
+    }
+    create_dir_all(dir)?;
+    let git_repo = Repository::init_opts(
+        &git_dir,
+        RepositoryInitOptions::new()
+            .workdir_path(&target)
+            .no_dotgit_dir(true),
+    )?;
+    // libgit2 links the working tree to the git dir with a `.git` file, making it a repo to git
+    match remove_file(target.join(".git")) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    initial_commit(&git_repo)?;
+    Ok(Repo::new(git_repo))
+}
+
+/// The overlay repo of `path` or the directory it's in, from the overlays directory
+pub fn find(path: &Path) -> Option<Repository> {
+    find_in(&overlays_dir()?, path)
+}
+
+/// The overlay repo under `dir` of `path` or the directory it's in
+pub fn find_in(dir: &Path, path: &Path) -> Option<Repository> {
+    canonicalize_existing(path)
+        .ancestors()
+        .find_map(|ancestor| Repository::open(git_dir(dir, ancestor)).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::{check_snapshot_exists, test_repo};
+
+    #[test]
+    fn overlay() {
+        let data_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let target = canonicalize(target_dir.path()).unwrap().join(".nvim");
+        create_dir_all(target.join("lua")).unwrap();
+        write(target.join("init.lua"), "vim.opt.number = true\n").unwrap();
+
+        let repo = create_in(data_dir.path(), &target).unwrap();
+        assert!(!target.join(".git").exists());
+        assert!(repo.git_repo().path().starts_with(data_dir.path()));
+        assert_eq!(Some(target.as_path()), repo.git_repo().workdir());
+        assert_eq!(".nvim", repo.name());
+        // opened again rather than created
+        create_in(data_dir.path(), &target).unwrap();
+
+        let found = find_in(data_dir.path(), &target.join("lua")).unwrap();
+        assert_eq!(repo.git_repo().path(), found.path());
+        assert!(find_in(data_dir.path(), target_dir.path()).is_none());
+        let repo = Repo::new(found);
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+
+        // directories in a repo are snapshotted by it
+        let repo_dir = tempdir().unwrap();
+        test_repo(repo_dir.path());
+        assert!(matches!(
+            create_in(data_dir.path(), repo_dir.path()),
+            Err(Error::RepoExists(_))
+        ));
+    }
+}
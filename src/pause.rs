@@ -0,0 +1,90 @@
+use std::{
+    fs::{create_dir_all, read, remove_file, write},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+
+use crate::error::Error;
+
+/// The watcher's snapshots suspended until a time, kept in a file next to the watcher config so a
+/// running watcher can be paused from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Pause {
+    #[serde(with = "humantime_serde")]
+    pub until: SystemTime,
+}
+
+impl Pause {
+    /// `config.paused.json` for `config.json`
+    pub fn path(config_path: &Path) -> PathBuf {
+        config_path.with_extension("paused.json")
+    }
+
+    /// The pause in effect, None once it's over
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        let pause: Self = match read(path) {
+            Ok(data) => from_slice(&data)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok((pause.until > SystemTime::now()).then_some(pause))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(path, to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Resume right away
+    pub fn clear(path: &Path) -> Result<(), Error> {
+        match remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn remaining(&self, now: SystemTime) -> Duration {
+        self.until.duration_since(now).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn load_save() {
+        let temp_dir = tempdir().unwrap();
+        let path = Pause::path(&temp_dir.path().join("config.json"));
+        assert_eq!(temp_dir.path().join("config.paused.json"), path);
+        assert_eq!(None, Pause::load(&path).unwrap());
+
+        let pause = Pause {
+            until: SystemTime::now() + Duration::from_secs(60),
+        };
+        pause.save(&path).unwrap();
+        assert!(Pause::load(&path).unwrap().is_some());
+        assert!(pause.remaining(SystemTime::now()) > Duration::from_secs(50));
+
+        Pause::clear(&path).unwrap();
+        Pause::clear(&path).unwrap();
+        assert_eq!(None, Pause::load(&path).unwrap());
+
+        // over once the time has passed
+        Pause {
+            until: SystemTime::now() - Duration::from_secs(1),
+        }
+        .save(&path)
+        .unwrap();
+        assert_eq!(None, Pause::load(&path).unwrap());
+    }
+}
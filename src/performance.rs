@@ -0,0 +1,98 @@
+use libgit2_sys as raw;
+use serde::{Deserialize, Serialize};
+use std::os::raw::c_int;
+
+use crate::{error::Error, units::ByteSize};
+
+/// Process wide libgit2 memory settings, unset values keep libgit2's defaults
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct PerformanceConfig {
+    /// Maximum bytes held by the object cache of each repository
+    pub cache_max_size: Option<ByteSize>,
+    /// Disable to stop caching parsed objects altogether
+    pub enable_caching: Option<bool>,
+    /// Size of each window mapped from pack files
+    pub mwindow_size: Option<ByteSize>,
+    /// Maximum bytes of pack files mapped at once
+    pub mwindow_mapped_limit: Option<ByteSize>,
+    /// Maximum number of pack files kept open at once
+    pub mwindow_file_limit: Option<usize>,
+}
+
+impl PerformanceConfig {
+    pub fn apply(&self) -> Result<(), Error> {
+        raw::init();
+        unsafe {
+            if let Some(size) = self.cache_max_size {
+                check(raw::git_libgit2_opts(
+                    raw::GIT_OPT_SET_CACHE_MAX_SIZE as c_int,
+                    size.0 as isize,
+                ))?;
+            }
+            if let Some(enabled) = self.enable_caching {
+                check(raw::git_libgit2_opts(
+                    raw::GIT_OPT_ENABLE_CACHING as c_int,
+                    enabled as c_int,
+                ))?;
+            }
+            if let Some(size) = self.mwindow_size {
+                check(raw::git_libgit2_opts(
+                    raw::GIT_OPT_SET_MWINDOW_SIZE as c_int,
+                    size.0 as usize,
+                ))?;
+            }
+            if let Some(limit) = self.mwindow_mapped_limit {
+                check(raw::git_libgit2_opts(
+                    raw::GIT_OPT_SET_MWINDOW_MAPPED_LIMIT as c_int,
+                    limit.0 as usize,
+                ))?;
+            }
+            if let Some(limit) = self.mwindow_file_limit {
+                check(raw::git_libgit2_opts(
+                    raw::GIT_OPT_SET_MWINDOW_FILE_LIMIT as c_int,
+                    limit,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check(code: c_int) -> Result<(), Error> {
+    if code < 0 {
+        return Err(git2::Error::last_error(code)
+            .unwrap_or_else(|| git2::Error::from_str("failed to set libgit2 option"))
+            .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let config = PerformanceConfig {
+            mwindow_file_limit: Some(128),
+            ..PerformanceConfig::default()
+        };
+        config.apply().unwrap();
+
+        let mut limit: usize = 0;
+        unsafe {
+            raw::git_libgit2_opts(
+                raw::GIT_OPT_GET_MWINDOW_FILE_LIMIT as c_int,
+                &mut limit as *mut usize,
+            );
+        }
+        assert_eq!(128, limit);
+
+        PerformanceConfig {
+            mwindow_file_limit: Some(0),
+            ..PerformanceConfig::default()
+        }
+        .apply()
+        .unwrap();
+    }
+}
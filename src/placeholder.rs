@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use git2::{Config, ObjectType, Oid, Repository, Sort};
+use log::debug;
+
+use crate::{error::Error, units::ByteSize, util::ConfigValue};
+
+// local refs of the rewritten snapshots, pushed in place of the snapshot refs
+const FILTERED_REF_PREFIX: &str = "refs/snapshots-filtered/";
+
+/// Blobs larger than `remote.<name>.snapshotMaxBlobSize` or `snapshot.push.maxBlobSize` are
+/// replaced in the snapshots pushed to `remote`, `None` pushing them as they are
+pub fn max_blob_size(config: &Config, remote: &str) -> Option<u64> {
+    let size = ByteSize::from_config(
+        config,
+        &[
+            &format!("remote.{}.snapshotmaxblobsize", remote),
+            "snapshot.push.maxblobsize",
+        ],
+        ByteSize(0),
+    );
+    (size.0 > 0).then_some(size.0)
+}
+
+/// Local ref holding the rewritten snapshots pushed to `remote_ref` of `remote`
+pub fn filtered_ref(remote: &str, remote_ref: &str) -> String {
+    format!(
+        "{}{}/{}",
+        FILTERED_REF_PREFIX,
+        remote,
+        remote_ref.trim_start_matches("refs/")
+    )
+}
+
+/// The text a blob is replaced with
+pub fn placeholder(oid: Oid, size: usize) -> String {
+    format!(
+        "git-snapshot placeholder\noid {}\nsize {}\nThe file was too large to push, it's kept in \
+         the local snapshot.\n",
+        oid, size
+    )
+}
+
+/// Rewrites commits so that their trees hold placeholders instead of blobs above a size, leaving
+/// commits without any as they are
+pub struct BlobFilter<'r> {
+    repo: &'r Repository,
+    max_size: u64,
+    commits: HashMap<Oid, Oid>,
+    trees: HashMap<Oid, Oid>,
+}
+
+impl<'r> BlobFilter<'r> {
+    pub fn new(repo: &'r Repository, max_size: u64) -> Self {
+        Self {
+            repo,
+            max_size,
+            commits: HashMap::new(),
+            trees: HashMap::new(),
+        }
+    }
+
+    /// Take `commit` as rewritten to `filtered` already, so its history isn't rewritten again
+    pub fn known(&mut self, commit: Oid, filtered: Oid) {
+        self.commits.insert(commit, filtered);
+    }
+
+    /// `tip` and its history rewritten, oldest first so parents are rewritten before children
+    pub fn rewrite(&mut self, tip: Oid) -> Result<Oid, Error> {
+        if let Some(filtered) = self.commits.get(&tip) {
+            return Ok(*filtered);
+        }
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.push(tip)?;
+        for known in self.commits.keys() {
+            walk.hide(*known)?;
+        }
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            let mut parents = Vec::new();
+            for parent in commit.parent_ids() {
+                let filtered = match self.commits.get(&parent) {
+                    Some(filtered) => *filtered,
+                    // hidden by a known commit, but not known itself
+                    None => self.rewrite(parent)?,
+                };
+                parents.push(self.repo.find_commit(filtered)?);
+            }
+            let tree = self.rewrite_tree(commit.tree_id())?;
+            let unchanged =
+                tree == commit.tree_id() && parents.iter().map(|p| p.id()).eq(commit.parent_ids());
+            let filtered = match unchanged {
+                true => commit.id(),
+                false => self.repo.commit(
+                    None,
+                    &commit.author(),
+                    &commit.committer(),
+                    &String::from_utf8_lossy(commit.message_bytes()),
+                    &self.repo.find_tree(tree)?,
+                    &parents.iter().collect::<Vec<_>>(),
+                )?,
+            };
+            self.commits.insert(commit.id(), filtered);
+        }
+        Ok(self.commits[&tip])
+    }
+
+    fn rewrite_tree(&mut self, oid: Oid) -> Result<Oid, Error> {
+        if let Some(filtered) = self.trees.get(&oid) {
+            return Ok(*filtered);
+        }
+        let tree = self.repo.find_tree(oid)?;
+        let mut builder = None;
+        for entry in tree.iter() {
+            let replacement = match entry.kind() {
+                Some(ObjectType::Tree) => Some(self.rewrite_tree(entry.id())?),
+                Some(ObjectType::Blob) => {
+                    let (size, _) = self.repo.odb()?.read_header(entry.id())?;
+                    match size as u64 > self.max_size {
+                        true => {
+                            debug!(
+                                "replacing {} ({} bytes) with a placeholder",
+                                entry.name().unwrap_or_default(),
+                                size
+                            );
+                            Some(self.repo.blob(placeholder(entry.id(), size).as_bytes())?)
+                        }
+                        false => None,
+                    }
+                }
+                _ => None,
+            };
+            let Some(replacement) = replacement.filter(|r| *r != entry.id()) else {
+                continue;
+            };
+            let builder = match &mut builder {
+                Some(builder) => builder,
+                None => builder.insert(self.repo.treebuilder(Some(&tree))?),
+            };
+            builder.insert(entry.name_bytes(), replacement, entry.filemode())?;
+        }
+        let filtered = match builder {
+            Some(builder) => builder.write()?,
+            None => oid,
+        };
+        self.trees.insert(oid, filtered);
+        Ok(filtered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    fn commit(repo: &Repository, files: &[(&str, &[u8])], parent: Option<Oid>) -> Oid {
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            let full_path = repo.workdir().unwrap().join(path);
+            std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+            std::fs::write(full_path, content).unwrap();
+            index.add_path(std::path::Path::new(path)).unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        let parents: Vec<_> = parent
+            .map(|p| repo.find_commit(p).unwrap())
+            .into_iter()
+            .collect();
+        repo.commit(
+            None,
+            &signature,
+            &signature,
+            "snapshot",
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    fn blob(repo: &Repository, commit: Oid, path: &str) -> Vec<u8> {
+        let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+        let entry = tree.get_path(std::path::Path::new(path)).unwrap();
+        repo.find_blob(entry.id()).unwrap().content().to_vec()
+    }
+
+    #[test]
+    fn rewrite() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let small = commit(&repo, &[("a.txt", b"small")], None);
+        let large = commit(&repo, &[("data/big.bin", &[0; 64])], Some(small));
+        let tip = commit(&repo, &[("a.txt", b"changed")], Some(large));
+
+        let mut filter = BlobFilter::new(&repo, 32);
+        let filtered = filter.rewrite(tip).unwrap();
+        assert_ne!(tip, filtered);
+        assert_eq!(b"changed".to_vec(), blob(&repo, filtered, "a.txt"));
+        let big_id = repo
+            .find_commit(large)
+            .unwrap()
+            .tree()
+            .unwrap()
+            .get_path(std::path::Path::new("data/big.bin"))
+            .unwrap()
+            .id();
+        assert_eq!(
+            placeholder(big_id, 64).into_bytes(),
+            blob(&repo, filtered, "data/big.bin")
+        );
+        // history without large blobs is left as it is
+        let parent = repo.find_commit(filtered).unwrap().parent_id(0).unwrap();
+        assert_eq!(
+            small,
+            repo.find_commit(parent).unwrap().parent_id(0).unwrap()
+        );
+
+        // rewriting on top of a known rewrite, without rewriting it again
+        let next = commit(&repo, &[("b.txt", b"new")], Some(tip));
+        let mut filter = BlobFilter::new(&repo, 32);
+        filter.known(tip, filtered);
+        let filtered_next = filter.rewrite(next).unwrap();
+        assert_eq!(
+            filtered,
+            repo.find_commit(filtered_next)
+                .unwrap()
+                .parent_id(0)
+                .unwrap()
+        );
+        assert_eq!(small, BlobFilter::new(&repo, 32).rewrite(small).unwrap());
+    }
+
+    #[test]
+    fn max_size_from_config() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        assert_eq!(None, max_blob_size(&config, "origin"));
+        config
+            .set_str("snapshot.push.maxblobsize", "100MB")
+            .unwrap();
+        config
+            .set_str("remote.backup.snapshotmaxblobsize", "0")
+            .unwrap();
+        assert_eq!(Some(100_000_000), max_blob_size(&config, "origin"));
+        assert_eq!(None, max_blob_size(&config, "backup"));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    fmt::{self, Display},
+    fs::{metadata, read_to_string},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use git2::Config;
+
+use crate::{
+    error::Error,
+    repo::{disabled_by_env, DISABLE_MARKER_FILE},
+    state::State,
+};
+
+/// Snapshot state of a repo condensed for a shell prompt, read from files in the git dir without
+/// opening the repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptStatus {
+    /// `✓3m`, the age of the last snapshot
+    Fresh(Duration),
+    /// `!push-failed`, pushing the last snapshot failed
+    PushFailed,
+    /// `!push-held`, pushes wait for a remote to be approved
+    PushHeld,
+    /// `✗disabled`
+    Disabled,
+    /// `-`, no snapshot taken yet
+    None,
+}
+
+impl PromptStatus {
+    /// Status of the repo containing `dir`, None outside of one
+    pub fn read(dir: &Path, now: SystemTime) -> Result<Option<Self>, Error> {
+        let Some(git_dir) = find_git_dir(dir) else {
+            return Ok(None);
+        };
+        let state = State::load(&git_dir)?;
+        let branch = head_branch(&git_dir).unwrap_or_default();
+        if disabled_by_env()
+            || metadata(git_dir.join(DISABLE_MARKER_FILE)).is_ok()
+            || config_files(&git_dir).iter().any(|file| disabled_in(file))
+            || state.suppression(&branch, now).is_some()
+        {
+            return Ok(Some(Self::Disabled));
+        }
+        let Some(last_snapshot) = state.last_snapshot else {
+            return Ok(Some(Self::None));
+        };
+        // a later snapshot pushes what the failed push didn't
+        if state
+            .push_failures
+            .last()
+            .is_some_and(|failure| failure.time >= last_snapshot)
+        {
+            return Ok(Some(Self::PushFailed));
+        }
+        if !state.pending_pushes.is_empty() {
+            return Ok(Some(Self::PushHeld));
+        }
+        Ok(Some(Self::Fresh(
+            now.duration_since(last_snapshot).unwrap_or_default(),
+        )))
+    }
+}
+
+impl Display for PromptStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fresh(age) => {
+                let secs = age.as_secs();
+                match secs {
+                    0..=59 => write!(f, "✓{}s", secs),
+                    60..=3599 => write!(f, "✓{}m", secs / 60),
+                    3600..=86399 => write!(f, "✓{}h", secs / 3600),
+                    _ => write!(f, "✓{}d", secs / 86400),
+                }
+            }
+            Self::PushFailed => write!(f, "!push-failed"),
+            Self::PushHeld => write!(f, "!push-held"),
+            Self::Disabled => write!(f, "✗disabled"),
+            Self::None => write!(f, "-"),
+        }
+    }
+}
+
+// The git dir of the working tree containing `dir`, following the `gitdir:` file of worktrees
+// and submodules
+fn find_git_dir(dir: &Path) -> Option<PathBuf> {
+    for dir in dir.ancestors() {
+        let dot_git = dir.join(".git");
+        let Ok(metadata) = metadata(&dot_git) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            return Some(dot_git);
+        }
+        let gitdir = read_to_string(&dot_git).ok()?;
+        return Some(dir.join(gitdir.strip_prefix("gitdir:")?.trim()));
+    }
+    None
+}
+
+// The git config files of the repo, with the shared one of the common dir for linked worktrees
+fn config_files(git_dir: &Path) -> Vec<PathBuf> {
+    let common_dir = match read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim()),
+        Err(_) => git_dir.to_owned(),
+    };
+    let mut files = vec![common_dir.join("config"), git_dir.join("config.worktree")];
+    files.extend(Config::find_global().ok());
+    files.extend(Config::find_xdg().ok());
+    files.extend(Config::find_system().ok());
+    files
+}
+
+// Whether `file` sets `snapshot.enabled` to false, which wins at any level as it does for
+// snapshots. Parsed by hand to keep the prompt fast, includes aren't followed.
+fn disabled_in(file: &Path) -> bool {
+    let Ok(content) = read_to_string(file) else {
+        return false;
+    };
+    let mut in_snapshot = false;
+    for line in content.lines() {
+        let mut line = line.trim();
+        if let Some(section) = line.strip_prefix('[') {
+            let Some((name, rest)) = section.split_once(']') else {
+                continue;
+            };
+            in_snapshot = name.trim().eq_ignore_ascii_case("snapshot");
+            // a key may follow the section on its line
+            line = rest.trim();
+        }
+        if !in_snapshot || line.starts_with(['#', ';']) {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        if !key.eq_ignore_ascii_case("enabled") {
+            continue;
+        }
+        // `enabled` without a value is true
+        let value = value.map(|value| value.split(['#', ';']).next().unwrap_or_default().trim());
+        if value.is_some_and(|value| !Config::parse_bool(value.trim_matches('"')).unwrap_or(true)) {
+            return true;
+        }
+    }
+    false
+}
+
+// The branch HEAD points at, None when detached
+fn head_branch(git_dir: &Path) -> Option<String> {
+    let head = read_to_string(git_dir.join("HEAD")).ok()?;
+    head.trim()
+        .strip_prefix("ref: refs/heads/")
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        state::{PushFailure, Suppression},
+        test_util::{create_temp_file, test_repo},
+        Repo,
+    };
+
+    #[test]
+    fn status() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let sub = temp_dir.path().join("sub");
+        create_dir_all(&sub).unwrap();
+        let now = SystemTime::now();
+        let read = || PromptStatus::read(&sub, now).unwrap().unwrap();
+        assert_eq!(PromptStatus::None, read());
+
+        create_temp_file(temp_dir.path());
+        Repo::new(repo).snapshot().unwrap();
+        let git_dir = temp_dir.path().join(".git");
+        let mut state = State::load(&git_dir).unwrap();
+        state.last_snapshot = Some(now - Duration::from_secs(190));
+        state.save(&git_dir).unwrap();
+        assert_eq!("✓3m", read().to_string());
+
+        state.push_failures.push(PushFailure {
+            error: "network".to_owned(),
+            time: now,
+        });
+        state.save(&git_dir).unwrap();
+        assert_eq!(PromptStatus::PushFailed, read());
+
+        state.suppressions.push(Suppression {
+            branch: None,
+            until: None,
+        });
+        state.save(&git_dir).unwrap();
+        assert_eq!(PromptStatus::Disabled, read());
+
+        state.suppressions.clear();
+        state.save(&git_dir).unwrap();
+        assert_eq!(PromptStatus::PushFailed, read());
+        config.set_bool("snapshot.enabled", false).unwrap();
+        assert_eq!(PromptStatus::Disabled, read());
+        config.set_bool("snapshot.enabled", true).unwrap();
+        assert_eq!(PromptStatus::PushFailed, read());
+        write(
+            git_dir.join("config.worktree"),
+            "[snapshot] enabled = no # for now\n",
+        )
+        .unwrap();
+        assert_eq!(PromptStatus::Disabled, read());
+
+        // a linked worktree's .git file points to its git dir
+        let linked = temp_dir.path().join("linked");
+        create_dir_all(&linked).unwrap();
+        write(
+            linked.join(".git"),
+            format!("gitdir: {}\n", git_dir.display()),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(PromptStatus::Disabled),
+            PromptStatus::read(&linked, now).unwrap()
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeSet,
+    fs::{canonicalize, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    filter::EventFilter,
+    metadata::DiffStats,
+    repo_watcher::{snapshot_changes, SnapshottedPaths},
+    util::path_starts_with,
+    watcher::EventKind,
+    Repo,
+};
+
+/// A step of the watch pipeline, from the raw events of the backend to what became of them
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Record {
+    /// The watcher was built, or rebuilt, watching these roots
+    #[serde(rename_all = "camelCase")]
+    Watching {
+        roots: Vec<PathBuf>,
+        #[serde(with = "humantime_serde")]
+        debounce_period: Duration,
+        event_kinds: Vec<EventKind>,
+        ignore_patterns: Vec<String>,
+    },
+    /// A path of a raw event of the watch backend
+    Event {
+        kind: EventKind,
+        path: PathBuf,
+    },
+    /// Dropped by the configured event kinds or ignore patterns
+    Filtered {
+        kind: EventKind,
+        path: PathBuf,
+    },
+    /// Outside every watched root
+    Unwatched {
+        path: PathBuf,
+    },
+    /// Handed to the root's snapshot handler once the debounce period passed
+    #[serde(rename_all = "camelCase")]
+    Debounced {
+        root: PathBuf,
+        changed_paths: Vec<PathBuf>,
+    },
+    /// Held back for a snapshot later, e.g. while paused
+    Held {
+        root: PathBuf,
+        reason: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Snapshotted {
+        repo: PathBuf,
+        changed_paths: Vec<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<DiffStats>,
+    },
+    Skipped {
+        repo: PathBuf,
+        reason: String,
+    },
+    Failed {
+        repo: PathBuf,
+        error: String,
+    },
+}
+
+impl Record {
+    /// What became of a snapshot of the repo at `repo`
+    pub(crate) fn outcome(repo: &Path, result: &Result<Option<SnapshottedPaths>, Error>) -> Self {
+        let repo = repo.to_owned();
+        match result {
+            Ok(Some((changed_paths, stats))) => Self::Snapshotted {
+                repo,
+                changed_paths: changed_paths.clone(),
+                stats: *stats,
+            },
+            Ok(None) => Self::Skipped {
+                repo,
+                reason: "nothing to snapshot".to_owned(),
+            },
+            Err(err @ (Error::LowDiskSpace { .. } | Error::StillRunning(_))) => Self::Skipped {
+                repo,
+                reason: err.to_string(),
+            },
+            Err(err) => Self::Failed {
+                repo,
+                error: err.to_string(),
+            },
+        }
+    }
+}
+
+/// A line of an event trace
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+/// Appends records to an event trace
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, record: Record) -> Result<(), Error> {
+        let entry = Entry {
+            time: SystemTime::now(),
+            record,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // a single write keeps the lines of the watcher's threads whole
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// Record the watch pipeline of the process to the event trace at `path`
+pub fn record_to(path: &Path) -> Result<(), Error> {
+    let recorder = Recorder::new(path)?;
+    if RECORDER.set(recorder).is_err() {
+        warn!("events are recorded already, not to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Append a record to the process's event trace, built only when it's recording
+pub fn record(record: impl FnOnce() -> Record) {
+    if let Some(recorder) = RECORDER.get() {
+        if let Err(err) = recorder.write(record()) {
+            warn!("failed to record event: {}", err);
+        }
+    }
+}
+
+/// Read the entries of the event trace at `path`
+pub fn read_trace(path: &Path) -> Result<Vec<Entry>, Error> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(line_number, line)| {
+            serde_json::from_str(&line?)
+                .map_err(|err| Error::InvalidTrace(format!("line {}: {}", line_number + 1, err)))
+        })
+        .collect()
+}
+
+/// Run the recorded events under `root` through the pipeline again, with `repo` standing in for
+/// the repo they were recorded in. Time is taken from the trace so the debounce periods pass as
+/// they did. Returns the records of the run, the decisions of which can be compared to the trace.
+pub fn replay(entries: &[Entry], repo: &Repo, root: Option<&Path>) -> Result<Vec<Entry>, Error> {
+    let workdir = repo
+        .git_repo()
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("no working tree to replay against"))?;
+    let mut replay = Replay {
+        repo,
+        target: canonicalize(workdir)?,
+        root: root.map(Path::to_owned),
+        filter: None,
+        debounce_period: Duration::ZERO,
+        pending: BTreeSet::new(),
+        last: None,
+        records: Vec::new(),
+    };
+    for entry in entries {
+        match &entry.record {
+            Record::Watching {
+                roots,
+                debounce_period,
+                event_kinds,
+                ignore_patterns,
+            } => {
+                replay.flush();
+                if replay.root.is_none() {
+                    replay.root = roots.first().cloned();
+                }
+                replay.filter = Some(EventFilter::new(event_kinds, ignore_patterns)?);
+                replay.debounce_period = *debounce_period;
+            }
+            Record::Event { kind, path } => replay.event(entry.time, *kind, path),
+            _ => {}
+        }
+    }
+    replay.flush();
+    Ok(replay.records)
+}
+
+struct Replay<'a> {
+    repo: &'a Repo,
+    target: PathBuf,
+    root: Option<PathBuf>,
+    // none until the trace says how the watcher was configured
+    filter: Option<EventFilter>,
+    debounce_period: Duration,
+    pending: BTreeSet<PathBuf>,
+    // time of the last event added to the pending paths
+    last: Option<SystemTime>,
+    records: Vec<Entry>,
+}
+
+impl Replay<'_> {
+    fn push(&mut self, time: SystemTime, record: Record) {
+        self.records.push(Entry { time, record });
+    }
+
+    fn event(&mut self, time: SystemTime, kind: EventKind, path: &Path) {
+        if let Some(last) = self.last {
+            if time.duration_since(last).unwrap_or_default() >= self.debounce_period {
+                self.flush();
+            }
+        }
+        let path = path.to_owned();
+        if let Some(filter) = &self.filter {
+            if !filter.is_match(kind, &path) {
+                self.push(time, Record::Filtered { kind, path });
+                return;
+            }
+        }
+        let relative = self
+            .root
+            .as_ref()
+            .filter(|root| path_starts_with(&path, root))
+            .and_then(|root| path.strip_prefix(root).ok());
+        match relative {
+            Some(relative) => {
+                self.pending.insert(self.target.join(relative));
+                self.last = Some(time);
+            }
+            None => self.push(time, Record::Unwatched { path }),
+        }
+    }
+
+    fn flush(&mut self) {
+        let Some(last) = self.last.take() else {
+            return;
+        };
+        let time = last + self.debounce_period;
+        let changed_paths: Vec<PathBuf> = std::mem::take(&mut self.pending).into_iter().collect();
+        self.push(
+            time,
+            Record::Debounced {
+                root: self.target.clone(),
+                changed_paths: changed_paths.clone(),
+            },
+        );
+        let result = snapshot_changes(self.repo, &changed_paths, None);
+        self.push(time, Record::outcome(&self.target, &result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo_with_files;
+
+    #[test]
+    fn replay_trace() {
+        let temp_dir = tempdir().unwrap();
+        let trace = temp_dir.path().join("trace.jsonl");
+        let recorded = Path::new("/home/user/project");
+        let recorder = Recorder::new(&trace).unwrap();
+        recorder
+            .write(Record::Watching {
+                roots: vec![recorded.to_owned()],
+                debounce_period: Duration::from_secs(1),
+                event_kinds: vec![EventKind::Create, EventKind::Modify],
+                ignore_patterns: vec!["**/*.swp".to_owned()],
+            })
+            .unwrap();
+        let events = [
+            (EventKind::Modify, "a"),
+            (EventKind::Create, ".a.swp"),
+            (EventKind::Access, "a"),
+            (EventKind::Modify, "b"),
+        ];
+        for (kind, name) in events {
+            recorder
+                .write(Record::Event {
+                    kind,
+                    path: recorded.join(name),
+                })
+                .unwrap();
+        }
+        recorder
+            .write(Record::Event {
+                kind: EventKind::Modify,
+                path: PathBuf::from("/tmp/elsewhere"),
+            })
+            .unwrap();
+        let entries = read_trace(&trace).unwrap();
+        assert_eq!(6, entries.len());
+
+        let repo_dir = tempdir().unwrap();
+        let target = canonicalize(repo_dir.path()).unwrap();
+        test_repo_with_files(&target);
+        write(target.join("a"), "a").unwrap();
+        write(target.join("b"), "b").unwrap();
+        let repo = Repo::from_path(&target).unwrap();
+        let records: Vec<Record> = replay(&entries, &repo, None)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.record)
+            .collect();
+        assert_eq!(
+            vec![
+                Record::Filtered {
+                    kind: EventKind::Create,
+                    path: recorded.join(".a.swp"),
+                },
+                Record::Filtered {
+                    kind: EventKind::Access,
+                    path: recorded.join("a"),
+                },
+                Record::Unwatched {
+                    path: PathBuf::from("/tmp/elsewhere"),
+                },
+                Record::Debounced {
+                    root: target.clone(),
+                    changed_paths: vec![target.join("a"), target.join("b")],
+                },
+                Record::Snapshotted {
+                    repo: target.clone(),
+                    changed_paths: vec![PathBuf::from("a"), PathBuf::from("b")],
+                    stats: None,
+                },
+            ],
+            records
+        );
+
+        write(&trace, "{\"type\":\"event\"}\n").unwrap();
+        assert!(matches!(read_trace(&trace), Err(Error::InvalidTrace(_))));
+    }
+}
@@ -1,28 +1,396 @@
+use crate::audit::{AuditAction, AuditLog, AuditSummary};
+use crate::auth;
+use crate::bundle;
+use crate::cancel::CancelToken;
+use crate::clock::{Clock, ConfigSignature, SignatureProvider, SystemClock};
 use crate::error::Error;
+use crate::filter::{BranchFilter, UrlFilter};
+use crate::git_cli::{self, PushBackend};
+use crate::history::{
+    base_id, commit_time, find_snapshot, is_checkpoint, is_gap, rewrite_base, sessions, walk,
+    Divergence, LogCommit, Session, SnapshotLog, SnapshotSpec, BASE_TRAILER, CHECKPOINT_TRAILER,
+    GROUP_TRAILER, REWRITE_TRAILER, SESSION_TRAILER, STATS_TRAILER,
+};
+use crate::index::{add_all_parallel, can_hash_parallel};
+use crate::message::{append_trailers, commit_template, prepare_commit_msg};
+use crate::metadata::{DiffStats, SnapshotMetadata, Trigger, NOTES_REF};
+use crate::overlay;
+use crate::placeholder::{filtered_ref, max_blob_size, BlobFilter};
+use crate::report::RepoReport;
+use crate::restore::{export, export_linked, merge, restore, MergeStrategy};
+use crate::retry::RetryPolicy;
+use crate::search::{grep, GrepMatch};
+use crate::secret::Secret;
+use crate::settings::{SettingLayers, Settings};
+use crate::state::{
+    BundledRef, PendingPush, PushFailure, PushedRef, RemoteApproval, RestoreState, State,
+    Suppression,
+};
+use crate::stream::SnapshotStream;
+use crate::timeout::RemoteTimeouts;
+use crate::units::{ByteSize, HumanDuration};
+use crate::verify::{check_objects, verify_worktree, IntegrityReport, Parity, RemoteCheck};
 
-use crate::util::{branch_ref_shorthand, expand, ConfigValue, BRANCH_REF_PREFIX};
+use crate::util::{
+    available_space, branch_ref_shorthand, date_pattern, expand, expand_at, expand_dated,
+    is_valid_branch_name, mask_time_tokens, sanitize_branch_name, strip_path_prefix, ConfigValue,
+    Timings, BRANCH_REF_PREFIX,
+};
+use chrono::{FixedOffset, TimeZone};
 use git2::{
-    Config, Cred, ErrorCode, Index, IndexAddOption, PushOptions, RemoteCallbacks, Repository,
+    BranchType, Commit, Config, ConfigLevel, Delta, Diff, DiffDelta, DiffOptions, Direction,
+    ErrorCode, FetchOptions, Index, IndexAddOption, Oid, Patch, PushOptions, Repository, Signature,
+    Sort, StatusOptions, Time, Tree,
 };
-use log::{debug, error, info};
-use std::path::Path;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{self, Display};
+use std::fs::{canonicalize, create_dir_all, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{field, info_span};
+
+/// Environment variable turning snapshots of every repo off when set to anything but `0`
+pub const DISABLE_ENV: &str = "GIT_SNAPSHOT_DISABLE";
+
+const BRANCH_SUB_KEY: &str = "BRANCH";
+const HOST_SUB_KEY: &str = "HOST";
+const DEFAULT_SNAPSHOT_BRANCH: &str = "snapshot/${BRANCH}";
+const DEFAULT_SNAPSHOT_COMMIT_MESSAGE: &str = "Snapshot";
+// file in the git dir keeping the repo from being snapshotted while it exists
+pub(crate) const DISABLE_MARKER_FILE: &str = "snapshot-disable";
+// refs following each branch's snapshots with a reflog, with `snapshot.reflog` set
+const META_REF_PREFIX: &str = "refs/snapshot-meta/";
+// namespace snapshot branches are moved to when they're retired
+const ARCHIVE_BRANCH_PREFIX: &str = "snapshot-archive/";
+// index kept in the git dir between snapshots so unchanged files aren't re-hashed
+const SNAPSHOT_INDEX_FILE: &str = "snapshot-index";
+// keeps the working tree captured before the last restore reachable
+const PRE_RESTORE_REF: &str = "refs/snapshot/pre-restore";
+const PRE_RESTORE_MESSAGE: &str = "Pre-restore capture";
+// refs of the shared object store keeping what each repo's snapshots reach from being pruned
+const SHARED_KEEP_REFS: &str = "refs/keep";
+// snapshot refs with `snapshot.refnamespace` set to `snapshots`
+const SNAPSHOTS_REF_PREFIX: &str = "refs/snapshots/";
+// what `git snapshot bundle` files are tracked as in the state, apart from bundles uploaded
+const FILE_BUNDLE_TARGET: &str = "file";
+// mask for the conflict stage bits of an index entry's flags
+const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+// inactivity ending an editing session
+const DEFAULT_SESSION_GAP: Duration = Duration::from_secs(30 * 60);
+// how long the snapshot branch of a deleted branch is kept before being deleted
+const DEFAULT_DELETED_BRANCH_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Untracked files captured by snapshots, `snapshot.includeuntracked`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum UntrackedFiles {
+    /// Including the ones git ignores
+    All,
+    /// Only files in the repo's index are captured
+    None,
+    /// All but the ones git ignores
+    #[default]
+    NotIgnored,
+}
+
+impl UntrackedFiles {
+    fn from_config(config: &Config) -> Self {
+        match config.get_string("snapshot.includeuntracked") {
+            Ok(value) => match value.to_ascii_lowercase().as_str() {
+                "all" => Self::All,
+                "none" => Self::None,
+                "gitignorednever" => Self::NotIgnored,
+                _ => {
+                    error!("invalid snapshot.includeUntracked: {}", value);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// Preset snapshot branch template, `snapshot.layout`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// `snapshot/<branch>`
+    #[default]
+    PerBranch,
+    /// `snapshot/<host>/<branch>`, for repos synced between machines
+    PerHost,
+    /// One `snapshots` branch for every branch
+    Flat,
+    /// `snapshot/<host>/<branch>/<date>`, a new branch every day
+    Dated,
+}
+
+impl Layout {
+    fn from_config(config: &Config) -> Self {
+        match config.get_string("snapshot.layout") {
+            Ok(value) => match value.to_ascii_lowercase().as_str() {
+                "per-branch" => Self::PerBranch,
+                "per-host" => Self::PerHost,
+                "flat" => Self::Flat,
+                "dated" => Self::Dated,
+                _ => {
+                    error!("invalid snapshot.layout: {}", value);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn template(self, namespace: RefNamespace) -> &'static str {
+        let template = match self {
+            Self::PerBranch => DEFAULT_SNAPSHOT_BRANCH,
+            Self::PerHost => "snapshot/${HOST}/${BRANCH}",
+            Self::Flat => "snapshots",
+            Self::Dated => "snapshot/${HOST}/${BRANCH}/%Y-%m-%d",
+        };
+        match namespace {
+            RefNamespace::Heads => template,
+            // refs/snapshots/ sets them apart already
+            RefNamespace::Snapshots => template.strip_prefix("snapshot/").unwrap_or(template),
+        }
+    }
+}
+
+/// Where snapshot refs are kept, `snapshot.refNamespace`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum RefNamespace {
+    /// `refs/heads/`, snapshot branches are branches like any other
+    #[default]
+    Heads,
+    /// `refs/snapshots/`, out of the way of `git branch`
+    Snapshots,
+}
+
+impl RefNamespace {
+    fn from_config(config: &Config) -> Self {
+        match config.get_string("snapshot.refnamespace") {
+            Ok(value) => match value.to_ascii_lowercase().as_str() {
+                "heads" => Self::Heads,
+                "snapshots" => Self::Snapshots,
+                _ => {
+                    error!("invalid snapshot.refnamespace: {}", value);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Heads => BRANCH_REF_PREFIX,
+            Self::Snapshots => SNAPSHOTS_REF_PREFIX,
+        }
+    }
+
+    /// Full ref of `snapshot_branch`
+    fn snapshot_ref(self, snapshot_branch: &str) -> String {
+        [self.prefix(), snapshot_branch].concat()
+    }
+
+    /// Snapshot branch of a full ref in the namespace
+    fn shorthand(self, ref_name: &str) -> &str {
+        ref_name.strip_prefix(self.prefix()).unwrap_or(ref_name)
+    }
+}
+
+/// A snapshot commit that was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshotted {
+    pub commit: Oid,
+    /// What changed since the previous snapshot, with snapshot.stats or its trailer
+    pub stats: Option<DiffStats>,
+}
+
+/// A snapshot branch template expanded for a branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateCheck {
+    pub template: String,
+    pub expanded: String,
+    /// Branch snapshots would be taken on, None when the name is rejected
+    pub branch: Option<String>,
+}
+
+/// What became of the snapshot branch of a deleted branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetiredBranch {
+    Archived {
+        snapshot_branch: String,
+        archive: String,
+    },
+    Deleted {
+        snapshot_branch: String,
+    },
+}
+
+impl Display for RetiredBranch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Archived {
+                snapshot_branch,
+                archive,
+            } => write!(f, "archived {} to {}", snapshot_branch, archive),
+            Self::Deleted { snapshot_branch } => write!(f, "deleted {}", snapshot_branch),
+        }
+    }
+}
+
+/// A rule a snapshot has to pass, and why it stops one when it does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub rule: String,
+    pub stop: Option<String>,
+}
+
+impl Check {
+    pub fn new(rule: impl Into<String>, stop: Option<String>) -> Self {
+        Self {
+            rule: rule.into(),
+            stop,
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.stop {
+            Some(reason) => write!(f, "stop  {}: {}", self.rule, reason),
+            None => write!(f, "ok    {}", self.rule),
+        }
+    }
+}
+
+// What pushes are for, deciding whether remotes with a push interval are pushed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PushTiming {
+    /// Right after a snapshot, remotes with an interval only once it has passed
+    Snapshot,
+    /// Only remotes with an interval that has passed
+    Due,
+    /// Every remote, whatever its interval
+    Now,
+}
 
-const BRANCH_SUB_KEY: &'static str = "BRANCH";
-const DEFAULT_SNAPSHOT_BRANCH: &'static str = "snapshot/${BRANCH}";
-const DEFAULT_SNAPSHOT_COMMIT_MESSAGE: &'static str = "Snapshot";
+/// Config file the `Repo` config setters write to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigScope {
+    /// The repo's own `.git/config`
+    #[default]
+    Local,
+    /// The user's git config, applying to every repo without a value of its own
+    Global,
+}
 
 pub struct Repo {
     git_repo: Repository,
+    settings: SettingLayers,
+    trigger: Trigger,
+    branch_filter: BranchFilter,
+    stream: Option<SnapshotStream>,
+    clock: Arc<dyn Clock>,
+    signature: Arc<dyn SignatureProvider>,
+    cancel: CancelToken,
+    // the shared object store the handle's object database was pointed at
+    shared_objects: OnceLock<PathBuf>,
+    config: Mutex<Option<CachedConfig>>,
 }
 
-// TODO: add config setter helper functions
+/// Modification time and size of each git config file of the repo that exists, telling when the
+/// config has to be parsed again
+pub(crate) type ConfigStamp = Vec<Option<(SystemTime, u64)>>;
+
+/// Parsed git config kept between snapshots
+struct CachedConfig {
+    stamp: ConfigStamp,
+    config: Config,
+}
+
+// SAFETY: `Config` isn't `Send` as libgit2 objects can't be used by two threads at once, but can
+// move between threads, which is what git2 relies on for `Repository`. The cached one is a read
+// only snapshot that's only touched behind the repo's mutex, and hands out snapshots of its own
+// that share its entries through libgit2's atomic reference counts and stay on the caller's thread.
+unsafe impl Send for CachedConfig {}
+
 impl Repo {
     pub fn new(repo: Repository) -> Self {
-        Repo { git_repo: repo }
+        Repo {
+            git_repo: repo,
+            settings: SettingLayers::default(),
+            trigger: Trigger::default(),
+            branch_filter: BranchFilter::default(),
+            stream: None,
+            clock: Arc::new(SystemClock),
+            signature: Arc::new(ConfigSignature),
+            cancel: CancelToken::default(),
+            shared_objects: OnceLock::new(),
+            config: Mutex::new(None),
+        }
+    }
+
+    /// Settings of the layers above git config, e.g. the number of threads hashing files
+    pub fn with_settings(mut self, settings: SettingLayers) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// What started the snapshots, recorded in their metadata notes
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Only snapshot branches matching the filter, on top of the repo's own branch patterns
+    pub fn with_branch_filter(mut self, branch_filter: BranchFilter) -> Self {
+        self.branch_filter = branch_filter;
+        self
+    }
+
+    /// Only snapshot the stream's subdirectory, on the stream's own snapshot branch
+    pub fn with_stream(mut self, stream: Option<SnapshotStream>) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Take the time of snapshots, restores and state records from `clock` instead of the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Commit snapshots as the identity `signature` gives instead of the configured one
+    pub fn with_signature(mut self, signature: Arc<dyn SignatureProvider>) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    /// Stop snapshots and pushes at their next phase once `cancel` is cancelled
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // For operations of other modules to stop at their phases like snapshots and pushes
+    #[cfg(feature = "s3")]
+    pub(crate) fn enter_phase(&self, phase: &'static str) -> Result<(), Error> {
+        self.cancel.enter(phase)
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let git_repo = Repository::discover(path)?;
+        let path = path.as_ref();
+        let git_repo = match Repository::discover(path) {
+            Ok(git_repo) => git_repo,
+            // a directory snapshotted without a repo of its own
+            Err(err) => overlay::find(path).ok_or(err)?,
+        };
         Ok(Self::new(git_repo))
     }
 
@@ -30,7 +398,54 @@ impl Repo {
         &self.git_repo
     }
 
+    pub(crate) fn config_stamp(&self) -> ConfigStamp {
+        let git_dir = self.git_repo.path();
+        // a linked worktree shares the config of the common dir, and may have one of its own
+        let common_dir = match read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => git_dir.join(common_dir.trim()),
+            Err(_) => git_dir.to_owned(),
+        };
+        let files = [
+            Some(common_dir.join("config")),
+            Some(git_dir.join("config.worktree")),
+            Config::find_global().ok(),
+            Config::find_xdg().ok(),
+        ];
+        files
+            .into_iter()
+            .map(|file| {
+                let metadata = std::fs::metadata(file?).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+
+    // A snapshot of the git config, only parsed again once one of the repo's config files changes
+    fn cached_config(&self) -> Result<Config, Error> {
+        let stamp = self.config_stamp();
+        let mut cached = self.config.lock().unwrap_or_else(PoisonError::into_inner);
+        match cached.as_mut() {
+            Some(cached) if cached.stamp == stamp => Ok(cached.config.snapshot()?),
+            _ => {
+                let mut config = self.git_repo.config()?.snapshot()?;
+                let snapshot = config.snapshot()?;
+                *cached = Some(CachedConfig { stamp, config });
+                Ok(snapshot)
+            }
+        }
+    }
+
     pub fn name(&self) -> &str {
+        // linked worktrees and overlays keep their git dir away from the working tree
+        if let Some(name) = self
+            .git_repo
+            .workdir()
+            .filter(|workdir| !self.git_repo.path().starts_with(workdir))
+            .and_then(Path::file_name)
+            .and_then(|n| n.to_str())
+        {
+            return name;
+        }
         let mut components = self.git_repo.path().components();
         components.next_back();
         components
@@ -39,55 +454,288 @@ impl Repo {
             .unwrap_or("unknown")
     }
 
-    pub fn snapshot_branch(config: &Config, current_branch: &str) -> String {
-        let snapshot_branch = String::from_config(
-            &config,
+    /// Snapshot branch of `current_branch`, dated by `time` when the template has dates
+    pub fn snapshot_branch(config: &Config, current_branch: &str, time: SystemTime) -> String {
+        let template = Self::branch_template(config, current_branch);
+        branch_name(
+            config,
+            expand_branch_template(&template, current_branch, time),
+        )
+    }
+
+    fn branch_template(config: &Config, current_branch: &str) -> String {
+        String::from_config(
+            config,
             &[
                 &format!("branch.{}.snapshotbranch", current_branch),
                 "snapshot.snapshotbranch",
             ],
-            DEFAULT_SNAPSHOT_BRANCH.to_owned(),
-        );
-        expand(&snapshot_branch, &[(BRANCH_SUB_KEY, &current_branch)])
+            Layout::from_config(config)
+                .template(RefNamespace::from_config(config))
+                .to_owned(),
+        )
     }
 
-    pub fn snapshot(&self) -> Result<(), Error> {
-        let current_branch = self.current_branch()?;
-        let config = self.git_repo.config()?;
+    /// Expand `template`, or the configured snapshot branch template, for `current_branch` at
+    /// `time`
+    pub fn check_template(
+        config: &Config,
+        template: Option<&str>,
+        current_branch: &str,
+        time: SystemTime,
+    ) -> TemplateCheck {
+        let template = template
+            .map(str::to_owned)
+            .unwrap_or_else(|| Self::branch_template(config, current_branch));
+        let expanded = expand_branch_template(&template, current_branch, time);
+        let branch = branch_name(config, expanded.clone());
+        TemplateCheck {
+            template,
+            expanded,
+            branch: is_valid_branch_name(&branch).then_some(branch),
+        }
+    }
 
-        // Check if snapshotting is enabled for the current branch
-        let enabled = bool::from_config(
-            &config,
-            &[&format!("branch.{}.snapshotenabled", current_branch)],
-            true,
+    /// Set `key` to `value` in the config file of `scope`, retrying while another git process
+    /// holds its lock
+    pub fn set_config(&self, scope: ConfigScope, key: &str, value: &str) -> Result<(), Error> {
+        let mut config = self.config_file(scope)?;
+        self.retry_locked(|| Ok(config.set_str(key, value)?))?;
+        debug!(target: self.name(), "set {:?} {} to: {}", scope, key, value);
+        Ok(())
+    }
+
+    /// Remove every value of `key` from the config file of `scope`, if it has any
+    pub fn unset_config(&self, scope: ConfigScope, key: &str) -> Result<(), Error> {
+        let mut config = self.config_file(scope)?;
+        self.retry_locked(|| match config.remove_multivar(key, ".*") {
+            Err(err) if err.code() != ErrorCode::NotFound => Err(err.into()),
+            _ => Ok(()),
+        })
+    }
+
+    /// Set the snapshot branch template, `snapshot.snapshotBranch`, refusing one that doesn't
+    /// expand to a valid branch name for the current branch
+    pub fn set_snapshot_branch(&self, scope: ConfigScope, template: &str) -> Result<(), Error> {
+        let branch = self.current_branch().unwrap_or_else(|_| "main".to_owned());
+        let check = Self::check_template(
+            &self.git_repo.config()?,
+            Some(template),
+            &branch,
+            self.clock.now(),
         );
+        if check.branch.is_none() {
+            return Err(Error::InvalidBranchName(check.expanded));
+        }
+        self.set_config(scope, "snapshot.snapshotbranch", template)
+    }
 
-        if !enabled {
-            info!(
-                target: self.name(),
-                "snapshots disabled for branch: {}",
-                current_branch
-            );
-            return Ok(());
+    /// Push snapshots to `remote` or stop pushing them, `remote.<name>.snapshotEnabled`
+    pub fn set_remote_enabled(&self, remote: &str, enabled: bool) -> Result<(), Error> {
+        self.git_repo.find_remote(remote)?;
+        self.set_config(
+            ConfigScope::Local,
+            &format!("remote.{}.snapshotenabled", remote),
+            &enabled.to_string(),
+        )
+    }
+
+    /// Snapshot `branch` or stop snapshotting it, `branch.<name>.snapshotEnabled`
+    pub fn set_branch_enabled(&self, branch: &str, enabled: bool) -> Result<(), Error> {
+        self.set_config(
+            ConfigScope::Local,
+            &format!("branch.{}.snapshotenabled", branch),
+            &enabled.to_string(),
+        )
+    }
+
+    // The single config file of `scope`. A global config is created in the home directory when
+    // there's none yet, like `git config --global` does.
+    fn config_file(&self, scope: ConfigScope) -> Result<Config, Error> {
+        match scope {
+            ConfigScope::Local => Ok(self.git_repo.config()?.open_level(ConfigLevel::Local)?),
+            ConfigScope::Global => {
+                let path = match Config::find_global().or_else(|_| Config::find_xdg()) {
+                    Ok(path) => path,
+                    Err(_) => dirs::home_dir()
+                        .ok_or_else(|| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::NotFound,
+                                "unable to get home directory",
+                            )
+                        })?
+                        .join(".gitconfig"),
+                };
+                Ok(Config::open(&path)?)
+            }
+        }
+    }
+
+    // Config writes fail right away while another process holds the file's lock, e.g. a
+    // concurrent `git config`
+    fn retry_locked(&self, op: impl FnMut() -> Result<(), Error>) -> Result<(), Error> {
+        let policy = RetryPolicy::from_config(&self.git_repo.config()?);
+        policy.run(|err| err.code() == crate::error::ErrorCode::IndexLocked, op)
+    }
+
+    // The stream's snapshot branch when snapshotting one, dated by `time`
+    fn own_snapshot_branch(
+        &self,
+        config: &Config,
+        current_branch: &str,
+        time: SystemTime,
+    ) -> String {
+        match &self.stream {
+            Some(stream) => branch_name(config, stream.snapshot_branch(current_branch)),
+            None => Self::snapshot_branch(config, current_branch, time),
+        }
+    }
+
+    /// Snapshot the whole working tree, returning the commit when one was made
+    pub fn snapshot(&self) -> Result<Option<Snapshotted>, Error> {
+        self.snapshot_with(None, None, None)
+    }
+
+    /// Snapshot only refreshing the given paths, relative to the working tree, in the cached snapshot index
+    pub fn snapshot_paths(&self, changed_paths: &[PathBuf]) -> Result<Option<Snapshotted>, Error> {
+        self.snapshot_with(Some(changed_paths), None, None)
+    }
+
+    /// Snapshot as part of a repo group, recording the group id shared with the other repos'
+    /// snapshots in a trailer. The whole working tree is indexed without changed paths.
+    pub fn snapshot_group(
+        &self,
+        changed_paths: Option<&[PathBuf]>,
+        group_id: &str,
+    ) -> Result<Option<Snapshotted>, Error> {
+        self.snapshot_with(changed_paths, Some(group_id), None)
+    }
+
+    /// Snapshot the whole working tree with `message`, even when nothing changed since the last
+    /// snapshot. Checkpoints are kept when squashing sessions.
+    pub fn checkpoint(&self, message: &str) -> Result<Option<Snapshotted>, Error> {
+        self.snapshot_with(None, None, Some(message))
+    }
+
+    fn snapshot_with(
+        &self,
+        changed_paths: Option<&[PathBuf]>,
+        group_id: Option<&str>,
+        checkpoint: Option<&str>,
+    ) -> Result<Option<Snapshotted>, Error> {
+        let span = info_span!(
+            "snapshot",
+            repo = self.name(),
+            trigger = ?self.trigger,
+            group = group_id,
+            checkpoint = checkpoint.is_some(),
+            commit = field::Empty,
+            error = field::Empty,
+        )
+        .entered();
+        let mut timings = Timings::new();
+        // a snapshot whose push failed goes out with the next one's, only another git process
+        // holding a lock reruns the snapshot
+        let locked = |err: &Error| err.code() == crate::error::ErrorCode::IndexLocked;
+        let config = self.cached_config()?;
+        let settings = Settings::resolve(&self.settings, &config);
+        debug!(target: self.name(), "settings: {:?}", settings);
+        // read once so the commit, its notes and the state agree on the time
+        let now = self.clock.now();
+        let result = RetryPolicy::from_config(&config).run(locked, || {
+            self.snapshot_timed(
+                changed_paths,
+                group_id,
+                checkpoint,
+                now,
+                &settings,
+                &mut timings,
+            )
+        });
+        if !timings.phases().is_empty() {
+            if settings.timings.value {
+                info!(target: self.name(), "snapshot timings: {}", timings);
+            } else {
+                debug!(target: self.name(), "snapshot timings: {}", timings);
+            }
+        }
+        match &result {
+            Ok(Some(snapshotted)) => {
+                span.record("commit", field::display(snapshotted.commit));
+            }
+            Ok(None) => {}
+            Err(err) => {
+                span.record("error", field::display(err));
+            }
+        }
+        result
+    }
+
+    fn snapshot_timed(
+        &self,
+        changed_paths: Option<&[PathBuf]>,
+        group_id: Option<&str>,
+        checkpoint: Option<&str>,
+        now: SystemTime,
+        settings: &Settings,
+        timings: &mut Timings,
+    ) -> Result<Option<Snapshotted>, Error> {
+        self.cancel.enter("checks")?;
+        if let Some(reason) = self.disabled_reason() {
+            info!(target: self.name(), "snapshots disabled: {}", reason);
+            return Ok(None);
+        }
+        let current_branch = self.current_branch()?;
+        let config = self.cached_config()?;
+        let branch_checks = self.branch_checks(&config, &current_branch, now)?;
+        if let Some(Check {
+            rule,
+            stop: Some(reason),
+        }) = branch_checks.into_iter().find(|check| check.stop.is_some())
+        {
+            info!(target: self.name(), "not snapshotting, {}: {}", rule, reason);
+            return Ok(None);
         }
 
-        let snapshot_branch = Self::snapshot_branch(&config, &current_branch);
+        let snapshot_branch = self.own_snapshot_branch(&config, &current_branch, now);
+        // rejected before anything is written, rather than failing to update the ref
+        if !is_valid_branch_name(&snapshot_branch) {
+            return Err(Error::InvalidBranchName(snapshot_branch));
+        }
+        self.follow_rename(&config, &current_branch)?;
 
-        // create full branch ref name, e.g. refs/heads/snapshot/main
-        let snapshot_ref_name = [BRANCH_REF_PREFIX, &snapshot_branch].concat();
+        // create full ref name, e.g. refs/heads/snapshot/main or refs/snapshots/main
+        let snapshot_ref_name = snapshot_ref(&config, &snapshot_branch);
 
         // Build the index with the current local changes and write to repo
-        let mut index = Index::new()?;
-        self.git_repo.set_index(&mut index)?;
-        index.add_all(&["*"], IndexAddOption::DEFAULT, None)?;
+        let objects_repo = self.snapshot_objects_repo(&config)?;
+        self.check_free_space(&config, self.git_repo.path())?;
+        if objects_repo != self.git_repo.path() {
+            self.check_free_space(&config, &objects_repo)?;
+        }
+        let untracked = UntrackedFiles::from_config(&config);
+        self.cancel.enter("index build")?;
+        let mut index = self.build_index(
+            changed_paths,
+            &objects_repo,
+            untracked,
+            settings.threads.value,
+        )?;
+        timings.lap("index build");
 
+        self.cancel.enter("tree write")?;
         let tree = index.write_tree()?;
-        let tree = self.git_repo.find_tree(tree)?;
+        let mut tree = self.git_repo.find_tree(tree)?;
+        if let Some(stream) = &self.stream {
+            tree = stream.limit_tree(&self.git_repo, &tree)?;
+        }
+        timings.lap("tree write");
 
         // Get the current reference to the destination snapshot branch for diffing and the commit parent
         let snapshot_ref = self.git_repo.find_reference(&snapshot_ref_name).ok();
 
         // Diff the current index to the previous snapshot commit tree to check for changes
+        self.cancel.enter("diff")?;
         let diff = self.git_repo.diff_tree_to_tree(
             snapshot_ref
                 .as_ref()
@@ -96,377 +744,4447 @@ impl Repo {
             Some(&tree),
             None,
         )?;
-        if diff.deltas().next().is_none() {
-            info!(target: self.name(), "No changes from previous snapshot, aborting snapshot");
-            return Ok(());
+        let has_changes = self.has_changes(&diff, &config)?;
+        if !has_changes && checkpoint.is_none() {
+            info!(
+                target: self.name(),
+                "No changes from previous snapshot, aborting snapshot"
+            );
+            return Ok(None);
         }
-
-        // Default signature from config
-        let signature = self.git_repo.signature()?;
+        // generating the patches for stats is left out unless they're kept
+        let stats_trailer = bool::from_config(&config, &["snapshot.statstrailer"], false);
+        let stats = if stats_trailer || bool::from_config(&config, &["snapshot.stats"], false) {
+            Some(DiffStats::from_diff(&self.git_repo, &diff)?)
+        } else {
+            None
+        };
+        timings.lap("diff");
 
         let parent = snapshot_ref.and_then(|r| r.peel_to_commit().ok());
+        let signature = self.signature(now)?;
+        let time = snapshot_time(&config, parent.as_ref(), signature.when())?;
+        let signature = Signature::new(
+            signature.name().unwrap_or_default(),
+            signature.email().unwrap_or_default(),
+            &time,
+        )?;
 
-        let message = String::from_config(
-            &config,
-            &[
-                &format!("branch.{}.snapshotmessage", current_branch),
-                "snapshot.snapshotmessage",
-            ],
-            DEFAULT_SNAPSHOT_COMMIT_MESSAGE.to_owned(),
+        // snapshots taken on history that was rebased, reset or filtered away since are archived,
+        // or kept with the next snapshot marking where the history changed
+        let rewritten = parent.as_ref().and_then(|p| self.rewritten_base(p));
+        let parent = match rewritten {
+            Some(_)
+                if String::from_config(&config, &["snapshot.onrewrite"], String::new())
+                    == "archive" =>
+            {
+                let archived = self.archive_snapshot_branch(&config, &snapshot_branch)?;
+                warn!(
+                    target: self.name(),
+                    "base branch rewritten, archived snapshots to: {}", archived
+                );
+                None
+            }
+            Some(base) => {
+                warn!(
+                    target: self.name(),
+                    "base branch rewritten, previous snapshot was taken on: {}", base
+                );
+                parent
+            }
+            None => parent,
+        };
+
+        // the previous session closed, its snapshots are squashed before this one starts the next
+        let parent = match parent {
+            Some(parent) if bool::from_config(&config, &["snapshot.squashsessions"], false) => {
+                Some(self.squash_session(&snapshot_ref_name, parent, &config, now)?)
+            }
+            parent => parent,
+        };
+
+        self.cancel.enter("commit message")?;
+        let message = match checkpoint {
+            Some(message) => message.to_owned(),
+            None => expand_message(
+                &String::from_config(
+                    &config,
+                    &[
+                        &format!("branch.{}.snapshotmessage", current_branch),
+                        "snapshot.snapshotmessage",
+                    ],
+                    DEFAULT_SNAPSHOT_COMMIT_MESSAGE.to_owned(),
+                ),
+                &current_branch,
+                time,
+            ),
+        };
+        let message = match commit_template(&self.git_repo, &config)? {
+            Some(template) => format!("{}\n\n{}", message, template),
+            None => message,
+        };
+        let mut trailers = Vec::new();
+        if checkpoint.is_some() {
+            trailers.push(format!("{}: true", CHECKPOINT_TRAILER));
+        }
+        if let Some(group_id) = group_id {
+            trailers.push(format!("{}: {}", GROUP_TRAILER, group_id));
+        }
+        if let (true, Some(stats)) = (stats_trailer, &stats) {
+            trailers.push(format!("{}: {}", STATS_TRAILER, stats));
+        }
+        if let (Some(base), Some(_)) = (rewritten, &parent) {
+            trailers.push(format!("{}: {}", REWRITE_TRAILER, base));
+        }
+        // record the commit the snapshot was taken on top of, an unborn branch has none
+        if let Some(base) = self.git_repo.head().ok().and_then(|h| h.target()) {
+            trailers.push(format!("{}: {}", BASE_TRAILER, base));
+        }
+        trailers.extend(
+            Vec::<String>::from_config(
+                &config,
+                &[
+                    &format!("branch.{}.snapshottrailers", current_branch),
+                    "snapshot.trailers",
+                ],
+                Vec::new(),
+            )
+            .iter()
+            .map(|trailer| expand_message(trailer, &current_branch, time)),
         );
-        self.git_repo.commit(
+        if bool::from_config(&config, &["snapshot.signoff"], false) {
+            trailers.push(format!(
+                "Signed-off-by: {} <{}>",
+                signature.name().unwrap_or_default(),
+                signature.email().unwrap_or_default()
+            ));
+        }
+        let message = append_trailers(&message, &trailers);
+        let message = prepare_commit_msg(&self.git_repo, &config, message)?;
+        self.cancel.enter("commit")?;
+        let commit = self.git_repo.commit(
             Some(&snapshot_ref_name),
             &signature,
             &signature,
             &message,
             &tree,
-            parent
-                .as_ref()
-                .as_ref()
-                .map(std::slice::from_ref)
-                .unwrap_or_default(),
+            parent.as_ref().as_slice(),
         )?;
+        timings.lap("commit");
 
-        info!(
-            target: self.name(),
-            "snapshotted branch: {}", current_branch
-        );
-
-        self.push(&snapshot_ref_name, &current_branch, &config)
-    }
-
-    fn push(&self, ref_name: &str, current_branch: &str, config: &Config) -> Result<(), Error> {
-        let remotes = self.git_repo.remotes()?;
-
-        for remote in &remotes {
-            let remote = remote.unwrap();
-
-            // Check remote config if snapshots are enabled, disabled by default
-            let enabled = bool::from_config(
-                &config,
-                &[&format!("remote.{}.snapshotenabled", remote)],
-                false,
+        // the snapshot is committed, failing to note it shouldn't hold back its push
+        let noted = State::update(self.git_repo.path(), |state| {
+            state.track(&current_branch, &snapshot_branch);
+            state.last_snapshot = Some(now);
+            Ok(())
+        });
+        if let Err(err) = noted {
+            error!(
+                target: self.name(),
+                "error writing snapshot state: {:?}", err
             );
-
-            if !enabled {
-                debug!(
+        }
+        if bool::from_config(&config, &["snapshot.reflog"], false) {
+            let summary = message.lines().next().unwrap_or_default();
+            if let Err(err) = self.log_snapshot(&current_branch, commit, summary) {
+                error!(
                     target: self.name(),
-                    "snapshots disabled for remote: {}",
-                    remote
+                    "error writing snapshot reflog: {:?}", err
                 );
-                continue;
             }
+        }
 
-            // Get remote snapshot branch from remote config or default to the local snapshot branch
-            let snapshot_branch = String::from_config(
-                &config,
-                &[&format!("remote.{}.snapshotbranch", remote)],
-                branch_ref_shorthand(ref_name).to_owned(),
+        let metadata = SnapshotMetadata::new(
+            self.trigger,
+            changed_paths.map(|p| p.to_vec()).unwrap_or_default(),
+            timings.total(),
+            now,
+            stats,
+        );
+        if let Err(err) = metadata.write(&self.git_repo, &signature, commit) {
+            error!(
+                target: self.name(),
+                "error writing snapshot metadata: {:?}", err
             );
+        }
+        if let Err(err) = self.keep_shared(&objects_repo, &[&snapshot_ref_name, NOTES_REF]) {
+            error!(
+                target: self.name(),
+                "error keeping snapshot objects in the shared store: {:?}", err
+            );
+        }
 
-            let snapshot_ref_name = [BRANCH_REF_PREFIX, &snapshot_branch].concat();
-
-            let snapshot_ref_name = expand(&snapshot_ref_name, &[(BRANCH_SUB_KEY, current_branch)]);
-
-            let mut remote = self.git_repo.find_remote(&remote)?;
-
-            let config = config.clone();
-
-            let mut callbacks = RemoteCallbacks::new();
-
-            // Only allow non-interactive credentials
-            // TODO: Look into using default ssh key
-            callbacks.credentials(move |url, username, allowed_types| {
-                if allowed_types.is_user_pass_plaintext() {
-                    if let Ok(cred) = Cred::credential_helper(&config, url, username) {
-                        return Ok(cred);
-                    }
-                }
-                if allowed_types.is_ssh_key() {
-                    if let Some(username) = username {
-                        if let Ok(cred) = Cred::ssh_key_from_agent(username) {
-                            return Ok(cred);
-                        }
-                    }
-                }
-                Err(git2::Error::new(
-                    git2::ErrorCode::Auth,
-                    git2::ErrorClass::Callback,
-                    "unable to authenticate, setup ssh key agent or credential helper for this remote and username",
-                ))
-            });
-
-            let mut opts = PushOptions::new();
-            opts.remote_callbacks(callbacks);
-            if let Err(err) =
-                remote.push(&[[ref_name, &snapshot_ref_name].join(":")], Some(&mut opts))
+        let time_machine =
+            PathBuf::from_config(&config, &["snapshot.timemachinedir"], PathBuf::new());
+        if !time_machine.as_os_str().is_empty() {
+            if let Err(err) = self.export_time_machine(&time_machine, &current_branch, commit, time)
             {
                 error!(
                     target: self.name(),
-                    "error pushing snapshot branch to remote: {:?}",
+                    "error exporting snapshot to {}: {:?}",
+                    time_machine.display(),
                     err
                 );
-            } else {
-                info!(
-                    target: self.name(),
-                    "pushed snapshot branch to remote: {}",
-                    remote.name().unwrap_or("unknown")
-                );
             }
+            timings.lap("export");
         }
-        Ok(())
-    }
 
-    pub fn current_branch(&self) -> Result<String, Error> {
-        match self.git_repo.head() {
-            Ok(reference) => {
-                if !reference.is_branch() || reference.is_remote() {
-                    return Err(Error::InvalidHead);
-                }
-                reference
-                    .shorthand()
-                    .map(|r| r.to_owned())
-                    .ok_or(Error::InvalidHead)
-            }
-            Err(err) => {
-                if err.code() == ErrorCode::UnbornBranch {
-                    let reference = self.git_repo.find_reference("HEAD")?;
-                    let target = reference.symbolic_target().ok_or(Error::InvalidHead)?;
-                    return Ok(branch_ref_shorthand(target).to_owned());
-                }
-                Err(Error::InvalidHead)
-            }
+        if bool::from_config(&config, &["snapshot.verify"], false) {
+            // a snapshot that can't be restored isn't pushed, the next one is
+            self.verify_snapshot(commit, &config)?;
+            timings.lap("verify");
         }
-    }
-
-    pub fn is_ignored(&self, path: &Path) -> Result<bool, Error> {
-        Ok(self.git_repo.is_path_ignored(path)?)
-    }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use std::path::Path;
+        info!(target: self.name(), "snapshotted branch: {}", current_branch);
+        self.audit(AuditAction::Snapshot {
+            commit: commit.to_string(),
+            branch: current_branch.clone(),
+            trigger: self.trigger,
+        });
+
+        let result = self.push(
+            &[(snapshot_ref_name, current_branch.clone())],
+            &config,
+            PushTiming::Snapshot,
+        );
+        timings.lap("push");
+        if let Err(err) = &result {
+            self.record_push_failure(err);
+        }
+        result.map(|_| Some(Snapshotted { commit, stats }))
+    }
+
+    // Compare the snapshot with the working tree it was taken of, recording the outcome in the
+    // repo state
+    fn verify_snapshot(&self, commit: Oid, config: &Config) -> Result<(), Error> {
+        let sample = usize::try_from(i64::from_config(config, &["snapshot.verifysample"], -1)).ok();
+        let commit = self.git_repo.find_commit(commit)?;
+        let verification = verify_worktree(&self.git_repo, &commit, sample)?;
+        State::update(self.git_repo.path(), |state| {
+            state.last_verification = Some(verification.clone());
+            Ok(())
+        })?;
+        if !verification.is_ok() {
+            error!(
+                target: self.name(),
+                "snapshot doesn't match the working tree at: {:?}", verification.mismatched
+            );
+            return Err(Error::VerificationFailed {
+                commit: verification.commit,
+                mismatched: verification.mismatched.len(),
+            });
+        }
+        debug!(target: self.name(), "verified snapshot: {}", verification);
+        Ok(())
+    }
+
+    /// Replace the snapshots of the session ending with `last` by a single commit of its tree, unless
+    /// the session is still ongoing. Returns the new tip of the snapshot branch.
+    fn squash_session<'r>(
+        &'r self,
+        ref_name: &str,
+        last: Commit<'r>,
+        config: &Config,
+        now: SystemTime,
+    ) -> Result<Commit<'r>, Error> {
+        let gap = session_gap(config);
+        if !is_gap(commit_time(&last), now, gap) || is_checkpoint(&last) {
+            return Ok(last);
+        }
+        let mut session = vec![last.clone()];
+        while let Ok(previous) = session[session.len() - 1].parent(0) {
+            // checkpoints are kept, the session starts after the last one, and doesn't reach back
+            // across a rewrite of the base branch
+            if is_checkpoint(&previous)
+                || rewrite_base(&session[session.len() - 1]).is_some()
+                || is_gap(
+                    commit_time(&previous),
+                    commit_time(&session[session.len() - 1]),
+                    gap,
+                )
+            {
+                break;
+            }
+            session.push(previous);
+        }
+        if session.len() == 1 {
+            return Ok(last);
+        }
+
+        let before = session[session.len() - 1].parent(0).ok();
+        // the last snapshot's message and trailers, with the stats of the whole session
+        let stats_trailer = bool::from_config(config, &["snapshot.statstrailer"], false);
+        let stats = if stats_trailer || bool::from_config(config, &["snapshot.stats"], false) {
+            let before_tree = before.as_ref().map(|c| c.tree()).transpose()?;
+            let diff =
+                self.git_repo
+                    .diff_tree_to_tree(before_tree.as_ref(), Some(&last.tree()?), None)?;
+            Some(DiffStats::from_diff(&self.git_repo, &diff)?)
+        } else {
+            None
+        };
+        let stats_prefix = format!("{}: ", STATS_TRAILER);
+        let message: Vec<&str> = last
+            .message()
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.starts_with(&stats_prefix))
+            .collect();
+        let mut trailers = vec![format!("{}: {}", SESSION_TRAILER, session.len())];
+        if let (None, Some(base)) = (
+            rewrite_base(&last),
+            rewrite_base(&session[session.len() - 1]),
+        ) {
+            trailers.push(format!("{}: {}", REWRITE_TRAILER, base));
+        }
+        if let (true, Some(stats)) = (stats_trailer, &stats) {
+            trailers.push(format!("{}: {}", STATS_TRAILER, stats));
+        }
+        let message = append_trailers(&message.join("\n"), &trailers);
+        let squashed = self.git_repo.commit(
+            None,
+            &last.author(),
+            &last.committer(),
+            &message,
+            &last.tree()?,
+            before.as_ref().as_slice(),
+        )?;
+        // a snapshot taken meanwhile isn't squashed away
+        self.git_repo.reference_matching(
+            ref_name,
+            squashed,
+            true,
+            last.id(),
+            "snapshot: squash session",
+        )?;
+
+        if let Err(err) = self.squash_metadata(&session, squashed, stats) {
+            error!(
+                target: self.name(),
+                "error writing snapshot metadata: {:?}", err
+            );
+        }
+        info!(
+            target: self.name(),
+            "squashed {} snapshots of session into: {}",
+            session.len(),
+            squashed
+        );
+        Ok(self.git_repo.find_commit(squashed)?)
+    }
+
+    // The base commit `previous` was taken on when HEAD's history no longer contains it
+    fn rewritten_base(&self, previous: &Commit) -> Option<Oid> {
+        let base = base_id(previous)?;
+        let head = self.git_repo.head().ok()?.target()?;
+        // a base that was garbage collected isn't contained either
+        let contained = head == base
+            || self
+                .git_repo
+                .graph_descendant_of(head, base)
+                .unwrap_or(false);
+        (!contained).then_some(base)
+    }
+
+    /// Move `snapshot_branch` to `snapshot-archive/<snapshot_branch>/<unix time>`, returning the
+    /// archive's branch name
+    fn archive_snapshot_branch(
+        &self,
+        config: &Config,
+        snapshot_branch: &str,
+    ) -> Result<String, Error> {
+        let secs = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let archived = format!("{}{}/{}", ARCHIVE_BRANCH_PREFIX, snapshot_branch, secs);
+        self.git_repo
+            .find_reference(&snapshot_ref(config, snapshot_branch))?
+            .rename(&snapshot_ref(config, &archived), false, "snapshot: archive")?;
+        Ok(archived)
+    }
+
+    // Point `refs/snapshot-meta/<branch>` at the snapshot, so its reflog lists every snapshot of
+    // the branch, including ones squashed away since
+    fn log_snapshot(&self, branch: &str, commit: Oid, summary: &str) -> Result<(), Error> {
+        let meta_ref = [META_REF_PREFIX, branch].concat();
+        // refs outside refs/heads are only logged when asked for
+        self.git_repo.reference_ensure_log(&meta_ref)?;
+        self.git_repo.reference(
+            &meta_ref,
+            commit,
+            true,
+            &format!("snapshot ({}): {}", self.trigger, summary),
+        )?;
+        Ok(())
+    }
+
+    // Write the snapshot's files to `<dir>/<repo>/<branch>/<time>_<commit>`, hard linked to the
+    // files of all other snapshots exported to `dir` that have the same content
+    fn export_time_machine(
+        &self,
+        dir: &Path,
+        branch: &str,
+        commit: Oid,
+        time: Time,
+    ) -> Result<(), Error> {
+        let branch_dir = dir.join(self.name()).join(branch);
+        let stamp = FixedOffset::east_opt(time.offset_minutes() * 60)
+            .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+            .map(|time| time.format("%Y-%m-%dT%H%M%S").to_string())
+            .unwrap_or_else(|| time.seconds().to_string());
+        let name = format!("{}_{:.7}", stamp, commit.to_string());
+        let dest = branch_dir.join(&name);
+        // an export cut short is left as partial, never mistaken for a complete snapshot
+        let partial = branch_dir.join(format!("{}.partial", name));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        let tree = self.git_repo.find_commit(commit)?.tree()?;
+        export_linked(&self.git_repo, &tree, &partial, &dir.join(".objects"))?;
+        std::fs::rename(&partial, &dest)?;
+        #[cfg(unix)]
+        {
+            let latest = branch_dir.join("latest");
+            let next = branch_dir.join("latest.partial");
+            let _ = std::fs::remove_file(&next);
+            std::os::unix::fs::symlink(&name, &next)?;
+            std::fs::rename(&next, &latest)?;
+        }
+        debug!(
+            target: self.name(),
+            "exported snapshot to: {}",
+            dest.display()
+        );
+        Ok(())
+    }
+
+    // Moves the snapshot branch of a branch renamed to `current_branch` along, when
+    // `current_branch` has none yet and the old one's last snapshot was taken on the same tree.
+    // Returns the old name.
+    fn follow_rename(
+        &self,
+        config: &Config,
+        current_branch: &str,
+    ) -> Result<Option<String>, Error> {
+        if !bool::from_config(config, &["snapshot.renamefollow"], true) {
+            return Ok(None);
+        }
+        let now = self.clock.now();
+        let snapshot_branch = self.own_snapshot_branch(config, current_branch, now);
+        let snapshot_ref = snapshot_ref(config, &snapshot_branch);
+        if self.git_repo.find_reference(&snapshot_ref).is_ok() {
+            return Ok(None);
+        }
+        let Some(head_tree) = self
+            .git_repo
+            .head()
+            .ok()
+            .and_then(|h| h.peel_to_tree().ok())
+        else {
+            return Ok(None);
+        };
+        let base_tree = |snapshot_branch: &str| {
+            let snapshot = self
+                .git_repo
+                .find_reference(&self::snapshot_ref(config, snapshot_branch))
+                .ok()?
+                .peel_to_commit()
+                .ok()?;
+            Some(
+                self.git_repo
+                    .find_commit(base_id(&snapshot)?)
+                    .ok()?
+                    .tree_id(),
+            )
+        };
+
+        let renamed = State::update(self.git_repo.path(), |state| {
+            let Some(tracked) = state.snapshot_branches.iter_mut().find(|tracked| {
+                tracked.branch != current_branch
+                    && tracked.snapshot_branch
+                        == self.own_snapshot_branch(config, &tracked.branch, now)
+                    && self
+                        .git_repo
+                        .find_branch(&tracked.branch, BranchType::Local)
+                        .is_err()
+                    && base_tree(&tracked.snapshot_branch) == Some(head_tree.id())
+            }) else {
+                return Ok(None);
+            };
+            self.git_repo
+                .find_reference(&self::snapshot_ref(config, &tracked.snapshot_branch))?
+                .rename(&snapshot_ref, false, "snapshot: follow branch rename")?;
+            let renamed = std::mem::replace(&mut tracked.branch, current_branch.to_owned());
+            tracked.snapshot_branch = snapshot_branch;
+            tracked.deleted = None;
+            Ok(Some(renamed))
+        })?;
+        let Some(renamed) = renamed else {
+            return Ok(None);
+        };
+        info!(
+            target: self.name(),
+            "branch {} renamed to {}, moved its snapshots along", renamed, current_branch
+        );
+        Ok(Some(renamed))
+    }
+
+    /// Archive or delete, per `snapshot.deletedbranches`, the snapshot branches of branches deleted
+    /// since they were snapshotted. Deleted ones are kept for `snapshot.deletedbranchgrace` first.
+    pub fn retire_deleted_branches(&self, now: SystemTime) -> Result<Vec<RetiredBranch>, Error> {
+        let config = self.git_repo.config()?;
+        let policy = String::from_config(&config, &["snapshot.deletedbranches"], String::new());
+        if policy != "archive" && policy != "delete" {
+            return Ok(Vec::new());
+        }
+        let grace = HumanDuration::from_config(
+            &config,
+            &["snapshot.deletedbranchgrace"],
+            DEFAULT_DELETED_BRANCH_GRACE.into(),
+        )
+        .0;
+        // an unborn branch is the current one without being a branch yet
+        let current_branch = self.current_branch().ok();
+        // a renamed branch isn't a deleted one
+        if let Some(current_branch) = &current_branch {
+            self.follow_rename(&config, current_branch)?;
+        }
+
+        let retired = State::update(self.git_repo.path(), |state| {
+            let mut retired = Vec::new();
+            let mut kept = Vec::new();
+            for mut tracked in std::mem::take(&mut state.snapshot_branches) {
+                if current_branch.as_deref() == Some(tracked.branch.as_str())
+                    || self
+                        .git_repo
+                        .find_branch(&tracked.branch, BranchType::Local)
+                        .is_ok()
+                {
+                    tracked.deleted = None;
+                    kept.push(tracked);
+                    continue;
+                }
+                let snapshot_ref = snapshot_ref(&config, &tracked.snapshot_branch);
+                let Ok(mut reference) = self.git_repo.find_reference(&snapshot_ref) else {
+                    // removed by hand, nothing left to track
+                    continue;
+                };
+                if policy == "archive" {
+                    let archive =
+                        self.archive_snapshot_branch(&config, &tracked.snapshot_branch)?;
+                    retired.push(RetiredBranch::Archived {
+                        snapshot_branch: tracked.snapshot_branch,
+                        archive,
+                    });
+                } else if now >= *tracked.deleted.get_or_insert(now) + grace {
+                    reference.delete()?;
+                    retired.push(RetiredBranch::Deleted {
+                        snapshot_branch: tracked.snapshot_branch,
+                    });
+                } else {
+                    kept.push(tracked);
+                }
+            }
+            state.snapshot_branches = kept;
+            Ok(retired)
+        })?;
+        for branch in &retired {
+            info!(target: self.name(), "branch deleted, {}", branch);
+        }
+        Ok(retired)
+    }
+
+    // The last snapshot's metadata with the paths changed over the whole session
+    fn squash_metadata(
+        &self,
+        session: &[Commit],
+        squashed: Oid,
+        stats: Option<DiffStats>,
+    ) -> Result<(), Error> {
+        let Some(mut metadata) = SnapshotMetadata::read(&self.git_repo, session[0].id())? else {
+            return Ok(());
+        };
+        let mut changed_paths = BTreeSet::new();
+        for commit in session {
+            match SnapshotMetadata::read(&self.git_repo, commit.id())? {
+                Some(m) if !m.changed_paths.is_empty() => changed_paths.extend(m.changed_paths),
+                // the whole working tree was indexed
+                _ => {
+                    changed_paths.clear();
+                    break;
+                }
+            }
+        }
+        metadata.changed_paths = changed_paths.into_iter().collect();
+        // what the session changed as a whole isn't the sum of its snapshots' stats
+        metadata.stats = stats;
+        metadata.write(&self.git_repo, &session[0].committer(), squashed)
+    }
+
+    /// Whether a diff has changes worth a snapshot. Mode only changes from chmod sweeps don't count
+    /// with `snapshot.ignoremodechanges` set, whitespace only changes from formatters resaving files
+    /// don't with `snapshot.ignorewhitespaceonly`.
+    fn has_changes(&self, diff: &Diff, config: &Config) -> Result<bool, Error> {
+        let ignore_modes = bool::from_config(config, &["snapshot.ignoremodechanges"], false);
+        let ignore_whitespace =
+            bool::from_config(config, &["snapshot.ignorewhitespaceonly"], false);
+        for delta in diff.deltas() {
+            let same_mode = ignore_modes || delta.old_file().mode() == delta.new_file().mode();
+            let ignored = (ignore_modes && is_mode_change(&delta))
+                || (ignore_whitespace && same_mode && self.is_whitespace_change(&delta)?);
+            if !ignored {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn is_whitespace_change(&self, delta: &DiffDelta) -> Result<bool, Error> {
+        if delta.status() != Delta::Modified {
+            return Ok(false);
+        }
+        let old = self.git_repo.find_blob(delta.old_file().id())?;
+        let new = self.git_repo.find_blob(delta.new_file().id())?;
+        if old.is_binary() || new.is_binary() {
+            return Ok(false);
+        }
+        let mut options = DiffOptions::new();
+        options.ignore_whitespace(true).ignore_blank_lines(true);
+        let patch = Patch::from_blobs(&old, None, &new, None, Some(&mut options))?;
+        Ok(patch.num_hunks() == 0)
+    }
+
+    /// Stop snapshotting `branch`, or every branch when unset, until `until` passes or snapshots
+    /// are enabled again. A branch disabled for good gets its `snapshotenabled` key set instead.
+    pub fn disable(&self, branch: Option<&str>, until: Option<SystemTime>) -> Result<(), Error> {
+        if let (Some(branch), None) = (branch, until) {
+            return self.set_branch_enabled(branch, false);
+        }
+        State::update(self.git_repo.path(), |state| {
+            state.suppress(
+                Suppression {
+                    branch: branch.map(str::to_owned),
+                    until,
+                },
+                self.clock.now(),
+            );
+            Ok(())
+        })
+    }
+
+    /// Undo `disable` of `branch`, or of the whole repo when unset
+    pub fn enable(&self, branch: Option<&str>) -> Result<(), Error> {
+        if let Some(branch) = branch {
+            let key = format!("branch.{}.snapshotenabled", branch);
+            if self.git_repo.config()?.get_entry(&key).is_ok() {
+                self.set_branch_enabled(branch, true)?;
+            }
+        }
+        State::update(self.git_repo.path(), |state| {
+            state.unsuppress(branch, self.clock.now());
+            Ok(())
+        })
+    }
+
+    // Kept in the repo state so reports can count failed pushes
+    fn record_push_failure(&self, err: &Error) {
+        let result = State::update(self.git_repo.path(), |state| {
+            state.push_failed(PushFailure {
+                error: err.to_string(),
+                time: self.clock.now(),
+            });
+            Ok(())
+        });
+        if let Err(err) = result {
+            error!(
+                target: self.name(),
+                "error recording push failure: {:?}", err
+            );
+        }
+    }
+
+    /// Record an operation in the audit log when `snapshot.audit` is set, signed with
+    /// `snapshot.auditkey` when that is set too
+    pub fn audit(&self, action: AuditAction) {
+        let result = self.audit_log().and_then(|log| match log {
+            Some(log) => log.append(action).map(drop),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            error!(target: self.name(), "error writing audit log: {:?}", err);
+        }
+    }
+
+    /// Check the audit log hasn't been tampered with
+    pub fn verify_audit(&self) -> Result<AuditSummary, Error> {
+        let config = self.git_repo.config()?;
+        AuditLog::new(self.git_repo.path(), audit_key(&config)?).verify()
+    }
+
+    fn audit_log(&self) -> Result<Option<AuditLog>, Error> {
+        let config = self.git_repo.config()?;
+        if !bool::from_config(&config, &["snapshot.audit"], false) {
+            return Ok(None);
+        }
+        Ok(Some(AuditLog::new(
+            self.git_repo.path(),
+            audit_key(&config)?,
+        )))
+    }
+
+    // Identity of snapshot commits from `snapshot.authorname` and `authoremail`, then `user.name` and
+    // `user.email`, falling back to a generic one so snapshots don't fail for a missing identity
+    fn signature(&self, now: SystemTime) -> Result<Signature<'static>, Error> {
+        self.signature.signature(&self.git_repo, now)
+    }
+
+    // Refuses to write to a filesystem with less free space than `snapshot.minfreespace`, running
+    // out of space halfway through writing objects can corrupt the object store
+    fn check_free_space(&self, config: &Config, path: &Path) -> Result<(), Error> {
+        let required = match ByteSize::from_config(config, &["snapshot.minfreespace"], ByteSize(0))
+        {
+            ByteSize(0) => return Ok(()),
+            ByteSize(required) => required,
+        };
+        match available_space(path)? {
+            Some(available) if available < required => Err(Error::LowDiskSpace {
+                path: path.to_owned(),
+                available,
+                required,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Point the object database at the shared store from `snapshot.sharedobjects` when set,
+    /// returning the path of the repository new snapshot objects are written to. The store is
+    /// opened once per handle.
+    fn snapshot_objects_repo(&self, config: &Config) -> Result<PathBuf, Error> {
+        let objects_dir = self.git_repo.path().join("objects");
+        let shared = PathBuf::from_config(config, &["snapshot.sharedobjects"], PathBuf::new());
+        if shared.as_os_str().is_empty() {
+            return Ok(self.git_repo.path().to_owned());
+        }
+        if let Some(path) = self.shared_objects.get() {
+            return Ok(path.clone());
+        }
+
+        let shared_repo = open_shared_store(&shared)?;
+        let shared_objects_dir = shared_repo.path().join("objects");
+
+        // so git can read snapshot objects outside of git-snapshot too
+        add_alternate(&objects_dir, &shared_objects_dir)?;
+
+        let odb = shared_repo.odb()?;
+        odb.add_disk_alternate(path_str(&objects_dir)?)?;
+        self.git_repo.set_odb(&odb)?;
+
+        Ok(self
+            .shared_objects
+            .get_or_init(|| shared_repo.path().to_owned())
+            .clone())
+    }
+
+    // Point refs of the shared store at the targets of `refs`, so a gc of the store keeps the
+    // objects they reach. Refs gone from the repo are dropped from the store too.
+    fn keep_shared(&self, objects_repo: &Path, refs: &[&str]) -> Result<(), Error> {
+        if objects_repo == self.git_repo.path() {
+            return Ok(());
+        }
+        let store = Repository::open_bare(objects_repo)?;
+        let digest = Sha256::digest(self.git_repo.path().to_string_lossy().as_bytes());
+        let id: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+        for ref_name in refs {
+            let keep_ref = format!(
+                "{}/{}/{}",
+                SHARED_KEEP_REFS,
+                id,
+                ref_name.trim_start_matches("refs/")
+            );
+            match self.git_repo.refname_to_id(ref_name) {
+                Ok(target) => {
+                    store.reference(&keep_ref, target, true, "snapshot: keep")?;
+                }
+                Err(err) if err.code() == ErrorCode::NotFound => {
+                    if let Ok(mut reference) = store.find_reference(&keep_ref) {
+                        reference.delete()?;
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot_index_path(&self) -> PathBuf {
+        // streams keep their own index so their snapshots don't race on it
+        match &self.stream {
+            Some(stream) => self
+                .git_repo
+                .path()
+                .join(format!("{}-{}", SNAPSHOT_INDEX_FILE, stream.name)),
+            None => self.git_repo.path().join(SNAPSHOT_INDEX_FILE),
+        }
+    }
+
+    fn build_index(
+        &self,
+        changed_paths: Option<&[PathBuf]>,
+        objects_repo: &Path,
+        untracked: UntrackedFiles,
+        threads: usize,
+    ) -> Result<Index, Error> {
+        let index_path = self.snapshot_index_path();
+        let cached = index_path.exists();
+
+        let mut index = Index::open(&index_path)?;
+        let repo_index_path = self.git_repo.path().join("index");
+        // the files the repo tracks, when limited to them
+        let tracked: Option<HashSet<String>> = match untracked {
+            UntrackedFiles::None if repo_index_path.exists() => Some(
+                Index::open(&repo_index_path)?
+                    .iter()
+                    .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+                    .collect(),
+            ),
+            UntrackedFiles::None => Some(HashSet::new()),
+            _ => None,
+        };
+        if !cached {
+            // Seed from the repo index so its stat cache can be reused
+            for entry in self.git_repo.index()?.iter() {
+                if entry.flags & INDEX_ENTRY_STAGE_MASK == 0 {
+                    index.add(&entry)?;
+                }
+            }
+        }
+        self.git_repo.set_index(&mut index)?;
+
+        let all = match &self.stream {
+            Some(stream) => stream.pathspec()?,
+            None => "*".to_owned(),
+        };
+        let pathspecs = match changed_paths {
+            Some(paths) if cached => paths
+                .iter()
+                .filter(|p| self.stream.as_ref().is_none_or(|s| s.contains(p)))
+                .filter_map(|p| p.to_str())
+                // the working tree root itself changed
+                .map(|p| {
+                    if p.is_empty() {
+                        all.clone()
+                    } else {
+                        p.to_owned()
+                    }
+                })
+                .collect(),
+            _ => {
+                if self.stream.is_none()
+                    && untracked == UntrackedFiles::NotIgnored
+                    && threads > 1
+                    && can_hash_parallel(&self.git_repo)
+                {
+                    add_all_parallel(&self.git_repo, &mut index, threads, objects_repo)?;
+                }
+                vec![all]
+            }
+        };
+
+        if !pathspecs.is_empty() {
+            // update_all drops entries for deleted files, add_all picks up new ones
+            index.update_all(&pathspecs, None)?;
+            match &tracked {
+                Some(tracked) => {
+                    let is_tracked =
+                        |path: &Path| path.to_str().is_some_and(|p| tracked.contains(p));
+                    // callbacks return 0 to act on a path and 1 to skip it
+                    index.add_all(
+                        &pathspecs,
+                        IndexAddOption::DEFAULT,
+                        Some(&mut |path: &Path, _: &[u8]| !is_tracked(path) as i32),
+                    )?;
+                    // files the repo stopped tracking, or captured before limiting to tracked ones
+                    index.remove_all(
+                        &pathspecs,
+                        Some(&mut |path: &Path, _: &[u8]| is_tracked(path) as i32),
+                    )?;
+                }
+                None => {
+                    let flags = match untracked {
+                        UntrackedFiles::All => IndexAddOption::FORCE,
+                        _ => IndexAddOption::DEFAULT,
+                    };
+                    index.add_all(&pathspecs, flags, None)?;
+                }
+            }
+            index.write()?;
+        }
+
+        Ok(index)
+    }
+
+    /// Push every snapshot branch that advanced since it was last pushed, all of them in one push
+    /// per remote
+    pub fn push_snapshots(&self) -> Result<(), Error> {
+        let config = self.git_repo.config()?;
+        self.push(&self.tracked_refs()?, &config, PushTiming::Now)
+    }
+
+    /// Squash the sessions that closed with `snapshot.squashSessions`, pushing them like
+    /// snapshots, and push the snapshots held back by a remote's `snapshotPushInterval` once it
+    /// has passed
+    pub fn push_due(&self) -> Result<(), Error> {
+        let config = self.git_repo.config()?;
+        let refs = self.tracked_refs()?;
+        let timing = match self.squash_closed_sessions(&refs, &config)? {
+            true => PushTiming::Snapshot,
+            false => PushTiming::Due,
+        };
+        self.push(&refs, &config, timing)
+    }
+
+    // Squash the sessions of `refs` whose gap passed since their last snapshot, rather than
+    // waiting for the next snapshot to. Whether any was.
+    fn squash_closed_sessions(
+        &self,
+        refs: &[(String, String)],
+        config: &Config,
+    ) -> Result<bool, Error> {
+        if !bool::from_config(config, &["snapshot.squashsessions"], false) {
+            return Ok(false);
+        }
+        let now = self.clock.now();
+        let mut squashed = false;
+        for (ref_name, _) in refs {
+            let last = self.git_repo.find_reference(ref_name)?.peel_to_commit()?;
+            let id = last.id();
+            squashed |= self.squash_session(ref_name, last, config, now)?.id() != id;
+        }
+        Ok(squashed)
+    }
+
+    /// Write the snapshot branches to a bundle at `path`, leaving out the snapshots bundled for
+    /// `target` before, or the ones taken before `since`. The refs bundled and their commits,
+    /// `None` without anything new.
+    pub fn create_bundle(
+        &self,
+        path: &Path,
+        target: &str,
+        since: Option<SystemTime>,
+    ) -> Result<Option<Vec<(String, Oid)>>, Error> {
+        let refs: Vec<String> = self
+            .tracked_refs()?
+            .into_iter()
+            .map(|(ref_name, _)| ref_name)
+            .collect();
+        if refs.is_empty() {
+            return Ok(None);
+        }
+        let exclude: Vec<Oid> = match since {
+            Some(since) => self.snapshots_before(&refs, since)?,
+            None => State::load(self.git_repo.path())?
+                .last_bundled(target)
+                .iter()
+                .filter_map(|commit| Oid::from_str(commit).ok())
+                .collect(),
+        };
+        if !bundle::create(&self.git_repo, path, &refs, &exclude)? {
+            debug!(target: self.name(), "nothing new to bundle for {}", target);
+            return Ok(None);
+        }
+        let heads = refs
+            .into_iter()
+            .map(|ref_name| {
+                let commit = self.git_repo.refname_to_id(&ref_name)?;
+                Ok((ref_name, commit))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Some(heads))
+    }
+
+    /// Write the snapshots taken since the last bundle file, or since `since`, to a bundle at
+    /// `path` for another machine to `unbundle`. The refs bundled and their commits, `None`
+    /// without anything new.
+    pub fn bundle(
+        &self,
+        path: &Path,
+        since: Option<SystemTime>,
+    ) -> Result<Option<Vec<(String, Oid)>>, Error> {
+        let heads = self.create_bundle(path, FILE_BUNDLE_TARGET, since)?;
+        if let Some(heads) = &heads {
+            self.record_bundle(FILE_BUNDLE_TARGET, heads)?;
+            info!(
+                target: self.name(),
+                "bundled {} snapshot branch(es) to {}",
+                heads.len(),
+                path.display()
+            );
+        }
+        Ok(heads)
+    }
+
+    // The latest snapshot of each of `refs` taken before `since`, the ones they have any
+    fn snapshots_before(&self, refs: &[String], since: SystemTime) -> Result<Vec<Oid>, Error> {
+        let mut before = Vec::new();
+        for ref_name in refs {
+            let mut walk = self.git_repo.revwalk()?;
+            walk.set_sorting(Sort::TIME)?;
+            walk.push_ref(ref_name)?;
+            for oid in walk {
+                let commit = self.git_repo.find_commit(oid?)?;
+                if commit_time(&commit) < since {
+                    before.push(commit.id());
+                    break;
+                }
+            }
+        }
+        Ok(before)
+    }
+
+    /// Remember `heads` as bundled for `target`, once their bundle is stored safely
+    pub fn record_bundle(&self, target: &str, heads: &[(String, Oid)]) -> Result<(), Error> {
+        State::update(self.git_repo.path(), |state| {
+            for (ref_name, commit) in heads {
+                state.record_bundle(BundledRef {
+                    target: target.to_owned(),
+                    ref_name: ref_name.clone(),
+                    commit: commit.to_string(),
+                    time: Some(self.clock.now()),
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether `interval` passed since a bundle was last written for `target`
+    pub fn bundle_due(&self, target: &str, interval: Duration) -> Result<bool, Error> {
+        let last = State::load(self.git_repo.path())?.last_bundle_time(target);
+        Ok(last.is_none_or(|last| last + interval <= self.clock.now()))
+    }
+
+    /// Fetch the snapshot branches of the bundle at `path`, replacing the ones of the same name
+    pub fn unbundle(&self, path: &Path) -> Result<Vec<(String, Oid)>, Error> {
+        let heads = bundle::unbundle(&self.git_repo, path)?;
+        info!(
+            target: self.name(),
+            "fetched {} snapshot branch(es) from {}",
+            heads.len(),
+            path.display()
+        );
+        Ok(heads)
+    }
+
+    // Local refs of the snapshot branches of branches that still exist, with the branch each is
+    // taken of
+    fn tracked_refs(&self) -> Result<Vec<(String, String)>, Error> {
+        let config = self.git_repo.config()?;
+        let state = State::load(self.git_repo.path())?;
+        Ok(state
+            .snapshot_branches
+            .iter()
+            .filter(|tracked| tracked.deleted.is_none())
+            .map(|tracked| {
+                (
+                    snapshot_ref(&config, &tracked.snapshot_branch),
+                    tracked.branch.clone(),
+                )
+            })
+            .filter(|(ref_name, _)| self.git_repo.find_reference(ref_name).is_ok())
+            .collect())
+    }
+
+    // Push local snapshot `refs`, each with the branch it's taken of, to every remote enabled
+    fn push(
+        &self,
+        refs: &[(String, String)],
+        config: &Config,
+        timing: PushTiming,
+    ) -> Result<(), Error> {
+        self.cancel.enter("push")?;
+        let remotes = self.git_repo.remotes()?;
+        // the remaining remotes are still pushed to when one fails, the last error is returned
+        let mut result = Ok(());
+
+        for remote in &remotes {
+            let remote = remote.unwrap();
+
+            // Check remote config if snapshots are enabled, disabled by default
+            let enabled = bool::from_config(
+                config,
+                &[&format!("remote.{}.snapshotenabled", remote)],
+                false,
+            );
+
+            if !enabled {
+                debug!(
+                    target: self.name(),
+                    "snapshots disabled for remote: {}", remote
+                );
+                continue;
+            }
+
+            if !self.push_scheduled(config, remote, timing)? {
+                continue;
+            }
+
+            if let Err(err) = self.check_remote_allowed(config, remote) {
+                error!(target: self.name(), "not pushing snapshot branch: {}", err);
+                result = Err(err);
+                continue;
+            }
+
+            if bool::from_config(config, &["snapshot.confirmnewremotes"], false)
+                && !self.remote_approved(remote, refs)?
+            {
+                continue;
+            }
+
+            if let Err(err) = self.push_remote(remote, refs, config) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    // Whether to push to `remote` now given its push interval, `snapshot.push.interval` unless
+    // the remote has its own
+    fn push_scheduled(
+        &self,
+        config: &Config,
+        remote: &str,
+        timing: PushTiming,
+    ) -> Result<bool, Error> {
+        let interval = HumanDuration::from_config(
+            config,
+            &[
+                &format!("remote.{}.snapshotpushinterval", remote),
+                "snapshot.push.interval",
+            ],
+            HumanDuration::default(),
+        )
+        .0;
+        if timing == PushTiming::Now || interval.is_zero() {
+            return Ok(timing != PushTiming::Due);
+        }
+        let last = State::load(self.git_repo.path())?.last_push_time(remote);
+        let due = last.is_none_or(|last| last + interval <= self.clock.now());
+        if !due {
+            debug!(
+                target: self.name(),
+                "holding back push to {} for its push interval", remote
+            );
+        }
+        Ok(due)
+    }
+
+    // Snapshots only go to remotes matching `snapshot.push.allowurls`, whatever else is configured
+    fn check_remote_allowed(&self, config: &Config, remote: &str) -> Result<(), Error> {
+        let filter = UrlFilter::new(&Vec::from_config(
+            config,
+            &["snapshot.push.allowurls"],
+            Vec::new(),
+        ))?;
+        let url = self.remote_url(remote)?;
+        match filter.is_match(&url) {
+            true => Ok(()),
+            false => Err(Error::RemoteNotAllowed {
+                remote: remote.to_owned(),
+                url,
+            }),
+        }
+    }
+
+    // Whether snapshots may be pushed to `remote`, holding the push of `refs` when they may not
+    fn remote_approved(&self, remote: &str, refs: &[(String, String)]) -> Result<bool, Error> {
+        let url = self.remote_url(remote)?;
+        if State::load(self.git_repo.path())?.is_approved(remote, &url) {
+            return Ok(true);
+        }
+        State::update(self.git_repo.path(), |state| {
+            for (ref_name, branch) in refs {
+                state.hold(PendingPush {
+                    remote: remote.to_owned(),
+                    url: url.clone(),
+                    ref_name: ref_name.clone(),
+                    branch: branch.clone(),
+                    time: self.clock.now(),
+                });
+            }
+            Ok(())
+        })?;
+        info!(
+            target: self.name(),
+            "holding push to new remote {} ({}) until approved", remote, url
+        );
+        Ok(false)
+    }
+
+    // Where snapshots are pushed to, `pushurl` overriding `url` like it does for git push
+    fn remote_url(&self, remote: &str) -> Result<String, Error> {
+        let remote = self.git_repo.find_remote(remote)?;
+        Ok(remote
+            .pushurl()
+            .or_else(|| remote.url())
+            .unwrap_or_default()
+            .to_owned())
+    }
+
+    /// Why snapshots of the repo are off altogether, for tools needing it left alone for a
+    /// while, e.g. while rewriting history, or by `snapshot.enabled`
+    pub fn disabled_reason(&self) -> Option<String> {
+        if disabled_by_env() {
+            return Some(format!("{} is set", DISABLE_ENV));
+        }
+        if let Some(level) = self.disabled_level() {
+            let level = format!("{:?}", level).to_lowercase();
+            return Some(format!("snapshot.enabled is false in the {} config", level));
+        }
+        let worktree_enabled = self
+            .worktree_config()
+            .and_then(|config| config.get_bool("snapshot.enabled").ok());
+        if worktree_enabled == Some(false) {
+            return Some("snapshot.enabled is false in the worktree config".to_owned());
+        }
+        let marker = self.git_repo.path().join(DISABLE_MARKER_FILE);
+        marker
+            .exists()
+            .then(|| format!("{} exists", marker.display()))
+    }
+
+    // Rules of the current branch stopping a snapshot before anything is written, in the order
+    // snapshots check them
+    fn branch_checks(
+        &self,
+        config: &Config,
+        current_branch: &str,
+        now: SystemTime,
+    ) -> Result<Vec<Check>, Error> {
+        let branch_key = format!("branch.{}.snapshotenabled", current_branch);
+        let branch_enabled = bool::from_config(config, &[&branch_key], true);
+        let branch_patterns = BranchFilter::new(
+            &Vec::from_config(config, &["snapshot.branchallow"], Vec::new()),
+            &Vec::from_config(config, &["snapshot.branchdeny"], Vec::new()),
+        )?;
+        let disabled_branch = || Some(format!("snapshots disabled for {}", current_branch));
+        // a checked out snapshot branch would otherwise get snapshots of its own, e.g. on
+        // snapshot/snapshot/main
+        let on_snapshot_branch = is_snapshot_branch(config, current_branch)
+            && !bool::from_config(config, &["snapshot.allowsnapshotbranches"], false);
+        let state = State::load(self.git_repo.path())?;
+        let suppressed =
+            state
+                .suppression(current_branch, now)
+                .map(|suppression| match suppression.until {
+                    Some(until) => format!(
+                        "disabled until {}",
+                        humantime::format_rfc3339_seconds(until)
+                    ),
+                    None => "disabled until enabled again".to_owned(),
+                });
+        Ok(vec![
+            Check::new(
+                format!("branch.{}.snapshotEnabled", current_branch),
+                (!branch_enabled).then(disabled_branch).flatten(),
+            ),
+            Check::new(
+                "watcher branch filter",
+                (!self.branch_filter.is_match(current_branch))
+                    .then(disabled_branch)
+                    .flatten(),
+            ),
+            Check::new(
+                "snapshot.branchAllow and snapshot.branchDeny",
+                (!branch_patterns.is_match(current_branch))
+                    .then(disabled_branch)
+                    .flatten(),
+            ),
+            Check::new(
+                "not a snapshot branch",
+                on_snapshot_branch.then(|| format!("{} is a snapshot branch", current_branch)),
+            ),
+            Check::new("git snapshot disable", suppressed),
+        ])
+    }
+
+    /// Every rule a snapshot of the working tree would have to pass now, with the ones that would
+    /// stop it, short of building the snapshot to find whether anything changed
+    pub fn explain(&self) -> Result<Vec<Check>, Error> {
+        let mut checks = vec![Check::new("snapshots enabled", self.disabled_reason())];
+        let current_branch = match self.current_branch() {
+            Ok(branch) => branch,
+            Err(err) => {
+                checks.push(Check::new("on a branch", Some(err.to_string())));
+                return Ok(checks);
+            }
+        };
+        let config = self.git_repo.config()?;
+        checks.extend(self.branch_checks(&config, &current_branch, self.clock.now())?);
+
+        let snapshot_branch = self.own_snapshot_branch(&config, &current_branch, self.clock.now());
+        checks.push(Check::new(
+            "valid snapshot branch name",
+            (!is_valid_branch_name(&snapshot_branch))
+                .then(|| Error::InvalidBranchName(snapshot_branch.clone()).to_string()),
+        ));
+        let objects_repo = self.snapshot_objects_repo(&config)?;
+        let free_space = self
+            .check_free_space(&config, self.git_repo.path())
+            .and_then(|_| match objects_repo != self.git_repo.path() {
+                true => self.check_free_space(&config, &objects_repo),
+                false => Ok(()),
+            });
+        checks.push(Check::new(
+            "snapshot.minFreeSpace",
+            match free_space {
+                Err(err @ Error::LowDiskSpace { .. }) => Some(err.to_string()),
+                result => result.map(|_| None)?,
+            },
+        ));
+        // left by another git process, snapshots retry for a while then fail
+        let snapshot_ref_name = snapshot_ref(&config, &snapshot_branch);
+        let mut index_lock = self.snapshot_index_path().into_os_string();
+        index_lock.push(".lock");
+        let locks = [
+            PathBuf::from(index_lock),
+            self.git_repo
+                .path()
+                .join(format!("{}.lock", snapshot_ref_name)),
+        ];
+        checks.push(Check::new(
+            "no git lock held",
+            locks
+                .iter()
+                .find(|lock| lock.exists())
+                .map(|lock| format!("{} exists", lock.display())),
+        ));
+        Ok(checks)
+    }
+
+    // The worktree's own config written by `git config --worktree`, which libgit2 doesn't read
+    fn worktree_config(&self) -> Option<Config> {
+        let path = self.git_repo.path().join("config.worktree");
+        path.is_file().then(|| Config::open(&path).ok()).flatten()
+    }
+
+    // The config level setting `snapshot.enabled` to false. Unlike other keys, a false anywhere
+    // wins over true at a more specific level, so it can be enforced from the system config.
+    fn disabled_level(&self) -> Option<ConfigLevel> {
+        let config = self.git_repo.config().ok()?;
+        let entries = config.entries(Some("snapshot.enabled")).ok()?;
+        let mut disabled = None;
+        for entry in &entries {
+            let Ok(entry) = entry else { continue };
+            // `[snapshot] enabled` without a value is true
+            let enabled = entry
+                .value()
+                .is_none_or(|v| Config::parse_bool(v).unwrap_or(true));
+            if !enabled {
+                disabled = Some(entry.level());
+            }
+        }
+        disabled
+    }
+
+    /// Pushes held until their remote is approved
+    pub fn pending_pushes(&self) -> Result<Vec<PendingPush>, Error> {
+        Ok(State::load(self.git_repo.path())?.pending_pushes)
+    }
+
+    /// Approve pushing snapshots to `remote` at its current url and push the snapshots held for it
+    pub fn approve_remote(&self, remote: &str) -> Result<(), Error> {
+        let url = self.remote_url(remote)?;
+        let config = self.git_repo.config()?;
+        self.check_remote_allowed(&config, remote)?;
+        let held = State::update(self.git_repo.path(), |state| {
+            Ok(state.approve(RemoteApproval {
+                remote: remote.to_owned(),
+                url,
+                time: self.clock.now(),
+            }))
+        })?;
+        info!(target: self.name(), "approved remote: {}", remote);
+
+        // the snapshot branch may have gone since
+        let refs: Vec<(String, String)> = held
+            .into_iter()
+            .filter(|push| self.git_repo.find_reference(&push.ref_name).is_ok())
+            .map(|push| (push.ref_name, push.branch))
+            .collect();
+        self.push_remote(remote, &refs, &config)
+    }
+
+    // Push the `refs` that advanced since they were last pushed to the remote, in one push
+    fn push_remote(
+        &self,
+        remote_name: &str,
+        refs: &[(String, String)],
+        config: &Config,
+    ) -> Result<(), Error> {
+        let _span = info_span!(
+            "push",
+            repo = self.name(),
+            remote = remote_name,
+            refs = refs.len()
+        )
+        .entered();
+        let url = self.remote_url(remote_name)?;
+        let state = State::load(self.git_repo.path())?;
+        let mut updates: Vec<(&str, String, Oid)> = Vec::new();
+        for (ref_name, current_branch) in refs {
+            let snapshot_ref_name = remote_snapshot_ref(
+                config,
+                remote_name,
+                ref_name,
+                current_branch,
+                self.clock.now(),
+            );
+            let commit = self.git_repo.refname_to_id(ref_name)?;
+            if state.last_pushed(remote_name, &url, &snapshot_ref_name) == Some(&commit.to_string())
+            {
+                debug!(
+                    target: self.name(),
+                    "{} already pushed to {}", ref_name, remote_name
+                );
+                continue;
+            }
+            // several snapshot branches can map to one remote ref, the first one wins
+            if updates
+                .iter()
+                .any(|(_, remote_ref, _)| *remote_ref == snapshot_ref_name)
+            {
+                warn!(
+                    target: self.name(),
+                    "not pushing {}, another snapshot branch is pushed to {}",
+                    ref_name,
+                    snapshot_ref_name
+                );
+                continue;
+            }
+            updates.push((ref_name, snapshot_ref_name, commit));
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        // packing for the push needs room as well
+        if let Err(err) = self.check_free_space(config, self.git_repo.path()) {
+            warn!(
+                target: self.name(),
+                "skipping push to {}: {}", remote_name, err
+            );
+            return Err(err);
+        }
+
+        // only snapshots replaced since they were pushed, by squashing sessions or restarting after
+        // the base branch was rewritten, are force pushed. A pushed snapshot gone since was replaced.
+        let force = |snapshot_ref_name: &str, commit: Oid| {
+            let replaced = state
+                .last_pushed(remote_name, &url, snapshot_ref_name)
+                .and_then(|pushed| Oid::from_str(pushed).ok())
+                .is_some_and(|pushed| {
+                    !self
+                        .git_repo
+                        .graph_descendant_of(commit, pushed)
+                        .unwrap_or(false)
+                });
+            if replaced {
+                "+"
+            } else {
+                ""
+            }
+        };
+        let filtered = self.filter_oversized(config, remote_name, &state, &url, &updates)?;
+        let refspecs: Vec<String> = updates
+            .iter()
+            .zip(&filtered)
+            .map(
+                |((ref_name, snapshot_ref_name, commit), filtered)| match filtered {
+                    Some(_) => format!(
+                        "{}{}:{}",
+                        force(snapshot_ref_name, *commit),
+                        filtered_ref(remote_name, snapshot_ref_name),
+                        snapshot_ref_name
+                    ),
+                    None => format!(
+                        "{}{}:{}",
+                        force(snapshot_ref_name, *commit),
+                        ref_name,
+                        snapshot_ref_name
+                    ),
+                },
+            )
+            .collect();
+
+        // on a repo and callbacks of its own for every attempt, so a remote that stopped
+        // responding can be given up on
+        let timeouts = RemoteTimeouts::from_config(config, remote_name);
+        let libgit2_push = || -> Result<(), Error> {
+            let git_dir = self.git_repo.path().to_owned();
+            let name = remote_name.to_owned();
+            let refspecs = refspecs.clone();
+            timeouts.run(remote_name, move |transfer| {
+                let repo = Repository::open(git_dir)?;
+                let config = repo.config()?;
+                let (mut remote, ssh_host) = auth::connect(&repo, &config, &name, Direction::Push)?;
+                let mut callbacks = auth::remote_callbacks(&config, &name, ssh_host);
+                transfer.watch(&mut callbacks);
+
+                // refs rejected by the remote only show up here
+                callbacks.push_update_reference(move |ref_name, status| {
+                    if !transfer.respond() {
+                        return Err(git2::Error::from_str("push cancelled"));
+                    }
+                    match status {
+                        Some(status) => {
+                            let code = match status.contains("non-fast-forward")
+                                || status.contains("fetch first")
+                            {
+                                true => ErrorCode::NotFastForward,
+                                false => ErrorCode::GenericError,
+                            };
+                            Err(git2::Error::new(
+                                code,
+                                git2::ErrorClass::Reference,
+                                format!("{} rejected: {}", ref_name, status),
+                            ))
+                        }
+                        None => Ok(()),
+                    }
+                });
+
+                let mut opts = PushOptions::new();
+                opts.remote_callbacks(callbacks);
+                remote.push(&refspecs, Some(&mut opts))?;
+                Ok(())
+            })
+        };
+        let backend = PushBackend::from_config(config, remote_name);
+        let push_once = || match backend {
+            PushBackend::Libgit2 => libgit2_push(),
+            PushBackend::GitCli => git_cli::push(&self.git_repo, remote_name, &refspecs),
+            PushBackend::Auto => match libgit2_push() {
+                Err(err) if err.code() == crate::error::ErrorCode::Auth => {
+                    warn!(
+                        target: self.name(),
+                        "couldn't authenticate with {}, pushing with git instead: {}",
+                        remote_name,
+                        err
+                    );
+                    git_cli::push(&self.git_repo, remote_name, &refspecs)
+                }
+                pushed => pushed,
+            },
+        };
+        let pushed =
+            RetryPolicy::from_config(config).run(|err| err.code().is_transient(), push_once);
+        for (_, snapshot_ref_name, _) in &updates {
+            self.audit(AuditAction::Push {
+                remote: remote_name.to_owned(),
+                ref_name: snapshot_ref_name.clone(),
+                error: pushed.as_ref().err().map(ToString::to_string),
+            });
+        }
+        if let Err(err) = &pushed {
+            error!(
+                target: self.name(),
+                "error pushing snapshot branch to remote: {:?}", err
+            );
+            return pushed;
+        }
+        info!(
+            target: self.name(),
+            "pushed {} snapshot branch(es) to remote: {}",
+            updates.len(),
+            remote_name
+        );
+        State::update(self.git_repo.path(), |state| {
+            for ((_, snapshot_ref_name, commit), filtered) in updates.into_iter().zip(filtered) {
+                state.record_push(PushedRef {
+                    remote: remote_name.to_owned(),
+                    url: url.clone(),
+                    ref_name: snapshot_ref_name,
+                    commit: commit.to_string(),
+                    time: Some(self.clock.now()),
+                    filtered: filtered.map(|oid| oid.to_string()),
+                });
+            }
+            Ok(())
+        })
+    }
+
+    // With `snapshot.push.maxBlobSize`, the snapshots of `updates` rewritten with placeholders for
+    // oversized blobs to refs of their own, picking up from the ones pushed last
+    fn filter_oversized(
+        &self,
+        config: &Config,
+        remote_name: &str,
+        state: &State,
+        url: &str,
+        updates: &[(&str, String, Oid)],
+    ) -> Result<Vec<Option<Oid>>, Error> {
+        let Some(max_size) = max_blob_size(config, remote_name) else {
+            return Ok(vec![None; updates.len()]);
+        };
+        let mut filter = self.blob_filter(
+            max_size,
+            state,
+            remote_name,
+            url,
+            updates
+                .iter()
+                .map(|(_, snapshot_ref_name, _)| snapshot_ref_name.as_str()),
+        );
+        let mut filtered = Vec::new();
+        for (_, snapshot_ref_name, commit) in updates {
+            let oid = filter.rewrite(*commit)?;
+            self.git_repo.reference(
+                &filtered_ref(remote_name, snapshot_ref_name),
+                oid,
+                true,
+                "snapshot: replace oversized blobs",
+            )?;
+            filtered.push(Some(oid));
+        }
+        Ok(filtered)
+    }
+
+    // Rewrites on top of the commits last pushed in place of the snapshots of `remote_refs`
+    fn blob_filter<'a>(
+        &self,
+        max_size: u64,
+        state: &State,
+        remote_name: &str,
+        url: &str,
+        remote_refs: impl Iterator<Item = &'a str>,
+    ) -> BlobFilter<'_> {
+        let mut filter = BlobFilter::new(&self.git_repo, max_size);
+        for remote_ref in remote_refs {
+            let Some(pushed) = state.pushed_ref(remote_name, url, remote_ref) else {
+                continue;
+            };
+            let known = pushed.filtered.as_deref().and_then(|filtered| {
+                Some((
+                    Oid::from_str(&pushed.commit).ok()?,
+                    Oid::from_str(filtered).ok()?,
+                ))
+            });
+            // squashed or pruned snapshots may be gone since
+            if let Some((commit, filtered)) = known.filter(|(commit, filtered)| {
+                self.git_repo.find_commit(*commit).is_ok()
+                    && self.git_repo.find_commit(*filtered).is_ok()
+            }) {
+                filter.known(commit, filtered);
+            }
+        }
+        filter
+    }
+
+    pub fn current_branch(&self) -> Result<String, Error> {
+        match self.git_repo.head() {
+            Ok(reference) => {
+                if !reference.is_branch() || reference.is_remote() {
+                    return Err(Error::InvalidHead);
+                }
+                reference
+                    .shorthand()
+                    .map(|r| r.to_owned())
+                    .ok_or(Error::InvalidHead)
+            }
+            Err(err) => {
+                if err.code() == ErrorCode::UnbornBranch {
+                    // the branch the first commit will create, from init.defaultBranch or an
+                    // orphan checkout
+                    let reference = self.git_repo.find_reference("HEAD")?;
+                    return reference
+                        .symbolic_target()
+                        .and_then(|target| target.strip_prefix(BRANCH_REF_PREFIX))
+                        .filter(|branch| !branch.is_empty())
+                        .map(str::to_owned)
+                        .ok_or(Error::InvalidHead);
+                }
+                Err(Error::InvalidHead)
+            }
+        }
+    }
+
+    /// Snapshots of `branch`, the current branch by default, interleaved with its commits
+    pub fn log(
+        &self,
+        branch: Option<&str>,
+        since: Option<SystemTime>,
+    ) -> Result<SnapshotLog, Error> {
+        let (branch_ref, snapshot_ref) = self.branch_refs(branch)?;
+        SnapshotLog::new(&self.git_repo, &branch_ref, &snapshot_ref, since)
+    }
+
+    /// How far the snapshots of `branch`, the current branch by default, and the branch moved
+    /// apart, `None` while either has no commits
+    pub fn divergence(&self, branch: Option<&str>) -> Result<Option<Divergence>, Error> {
+        let (branch_ref, snapshot_ref) = self.branch_refs(branch)?;
+        Divergence::new(&self.git_repo, &branch_ref, &snapshot_ref)
+    }
+
+    /// Snapshots of `branch`, the current branch by default, newest first along with their metadata
+    pub fn list(
+        &self,
+        branch: Option<&str>,
+    ) -> Result<Vec<(LogCommit, Option<SnapshotMetadata>)>, Error> {
+        let (_, snapshot_ref) = self.branch_refs(branch)?;
+        walk(&self.git_repo, &snapshot_ref, None)?
+            .iter()
+            .map(|c| {
+                let metadata = SnapshotMetadata::read(&self.git_repo, c.id())?;
+                Ok((LogCommit::new(c), metadata))
+            })
+            .collect()
+    }
+
+    /// Read back every object of the snapshots of `branch`, the current branch when unset
+    pub fn verify_objects(&self, branch: Option<&str>) -> Result<IntegrityReport, Error> {
+        let (_, snapshot_ref) = self.branch_refs(branch)?;
+        let tip = self
+            .git_repo
+            .refname_to_id(&snapshot_ref)
+            .map_err(|_| Error::SnapshotNotFound)?;
+        check_objects(&self.git_repo, tip)
+    }
+
+    /// Compare the snapshot tip of `branch`, the current branch when unset, with the one on each
+    /// remote snapshots are pushed to
+    pub fn verify_remotes(&self, branch: Option<&str>) -> Result<Vec<RemoteCheck>, Error> {
+        let (branch_ref, snapshot_ref) = self.branch_refs(branch)?;
+        let local = self
+            .git_repo
+            .refname_to_id(&snapshot_ref)
+            .map_err(|_| Error::SnapshotNotFound)?;
+        let config = self.git_repo.config()?;
+        let mut checks = Vec::new();
+        for remote_name in self.git_repo.remotes()?.iter().flatten() {
+            let enabled = bool::from_config(
+                &config,
+                &[&format!("remote.{}.snapshotenabled", remote_name)],
+                false,
+            );
+            if !enabled {
+                continue;
+            }
+            let ref_name = remote_snapshot_ref(
+                &config,
+                remote_name,
+                &snapshot_ref,
+                branch_ref_shorthand(&branch_ref),
+                self.clock.now(),
+            );
+            // fetched like `git fetch` would, so the remote's snapshots can be compared locally
+            let tracking_ref = format!(
+                "refs/remotes/{}/{}",
+                remote_name,
+                // kept apart from the remote's branches unless snapshots are branches themselves
+                match RefNamespace::from_config(&config) {
+                    RefNamespace::Heads => branch_ref_shorthand(&ref_name),
+                    RefNamespace::Snapshots => ref_name.trim_start_matches("refs/"),
+                }
+            );
+            // left from an earlier check, the remote may have lost its snapshots since
+            if let Ok(mut reference) = self.git_repo.find_reference(&tracking_ref) {
+                reference.delete()?;
+            }
+            let timeouts = RemoteTimeouts::from_config(&config, remote_name);
+            RetryPolicy::from_config(&config).run(
+                |err| err.code().is_transient(),
+                || {
+                    let git_dir = self.git_repo.path().to_owned();
+                    let name = remote_name.to_owned();
+                    let refspec = format!("+{}:{}", ref_name, tracking_ref);
+                    timeouts.run(remote_name, move |transfer| {
+                        let repo = Repository::open(git_dir)?;
+                        let config = repo.config()?;
+                        let (mut remote, ssh_host) =
+                            auth::connect(&repo, &config, &name, Direction::Fetch)?;
+                        let mut callbacks = auth::remote_callbacks(&config, &name, ssh_host);
+                        transfer.watch(&mut callbacks);
+                        let mut opts = FetchOptions::new();
+                        opts.remote_callbacks(callbacks);
+                        remote.fetch(&[refspec], Some(&mut opts), None)?;
+                        Ok(())
+                    })
+                },
+            )?;
+            let tip = self.git_repo.refname_to_id(&tracking_ref).ok();
+            // compared as it would be pushed
+            let local = match max_blob_size(&config, remote_name) {
+                Some(max_size) => {
+                    let url = self.remote_url(remote_name)?;
+                    let state = State::load(self.git_repo.path())?;
+                    self.blob_filter(
+                        max_size,
+                        &state,
+                        remote_name,
+                        &url,
+                        [ref_name.as_str()].into_iter(),
+                    )
+                    .rewrite(local)?
+                }
+                None => local,
+            };
+            checks.push(RemoteCheck {
+                remote: remote_name.to_owned(),
+                parity: Parity::new(&self.git_repo, local, tip)?,
+                ref_name,
+            });
+        }
+        Ok(checks)
+    }
+
+    /// Snapshots of `branch`, the current branch when unset, grouped by editing session
+    pub fn sessions(&self, branch: Option<&str>) -> Result<Vec<Session>, Error> {
+        let (_, snapshot_ref) = self.branch_refs(branch)?;
+        let snapshots = walk(&self.git_repo, &snapshot_ref, None)?
+            .iter()
+            .map(LogCommit::new)
+            .collect();
+        Ok(sessions(snapshots, session_gap(&self.git_repo.config()?)))
+    }
+
+    /// Lines matching `pattern` in the file versions introduced by snapshots of the current branch,
+    /// and with `since` in the tree of the last snapshot before it
+    pub fn grep(
+        &self,
+        pattern: &Regex,
+        since: Option<SystemTime>,
+    ) -> Result<Vec<GrepMatch>, Error> {
+        let (_, snapshot_ref) = self.branch_refs(None)?;
+        grep(&self.git_repo, &snapshot_ref, pattern, since)
+    }
+
+    /// The snapshot of the current branch selected by `spec`, the latest one when unset
+    pub fn find_snapshot(&self, spec: Option<&SnapshotSpec>) -> Result<Commit<'_>, Error> {
+        let (_, snapshot_ref) = self.branch_refs(None)?;
+        find_snapshot(&self.git_repo, &snapshot_ref, spec)
+    }
+
+    /// Restore the working tree, or only `paths` in it, to a snapshot, removing files the snapshot
+    /// doesn't hold but ignored ones. With `merge` set the snapshot's changes since the commit it was
+    /// taken on are merged into the working tree instead of replacing it. The working tree is
+    /// captured first so the restore can be undone.
+    pub fn restore(
+        &self,
+        spec: Option<&SnapshotSpec>,
+        paths: &[PathBuf],
+        merge: Option<MergeStrategy>,
+    ) -> Result<Commit<'_>, Error> {
+        let snapshot = self.find_snapshot(spec)?;
+        // a stream's snapshots don't hold the rest of the working tree
+        let paths = match &self.stream {
+            Some(stream) if paths.is_empty() => vec![stream.path.clone()],
+            _ => paths.to_vec(),
+        };
+        let paths = paths.as_slice();
+        self.restore_commit(&snapshot, paths, merge)?;
+        info!(target: self.name(), "restored snapshot: {}", snapshot.id());
+        self.audit(AuditAction::Restore {
+            commit: snapshot.id().to_string(),
+            paths: paths.to_vec(),
+        });
+        Ok(snapshot)
+    }
+
+    /// Put the working tree back to how it was before the last restore, which can be undone again
+    pub fn undo_restore(&self) -> Result<Commit<'_>, Error> {
+        let last_restore = State::load(self.git_repo.path())?
+            .last_restore
+            .ok_or(Error::NothingToUndo)?;
+        let capture = self
+            .git_repo
+            .find_commit(Oid::from_str(&last_restore.capture)?)?;
+        self.restore_commit(&capture, &last_restore.paths, None)?;
+        info!(
+            target: self.name(),
+            "undid restore of: {}", last_restore.restored
+        );
+        self.audit(AuditAction::UndoRestore {
+            commit: capture.id().to_string(),
+        });
+        Ok(capture)
+    }
+
+    fn restore_commit(
+        &self,
+        commit: &Commit,
+        paths: &[PathBuf],
+        merge_strategy: Option<MergeStrategy>,
+    ) -> Result<(), Error> {
+        let capture = self.capture_worktree()?;
+        State::update(self.git_repo.path(), |state| {
+            state.last_restore = Some(RestoreState {
+                capture: capture.to_string(),
+                restored: commit.id().to_string(),
+                paths: paths.to_vec(),
+                time: self.clock.now(),
+            });
+            Ok(())
+        })?;
+
+        match merge_strategy {
+            Some(strategy) => {
+                let base = match base_id(commit) {
+                    Some(base) => Some(self.git_repo.find_commit(base)?.tree_id()),
+                    None => None,
+                };
+                let ours = self.git_repo.find_commit(capture)?.tree_id();
+                merge(
+                    &self.git_repo,
+                    base,
+                    ours,
+                    commit.tree_id(),
+                    paths,
+                    strategy,
+                )
+            }
+            None => restore(&self.git_repo, commit.tree_id(), paths),
+        }
+    }
+
+    // Commit the working tree as is, outside of the snapshot branch
+    fn capture_worktree(&self) -> Result<Oid, Error> {
+        let config = self.git_repo.config()?;
+        let objects_repo = self.snapshot_objects_repo(&config)?;
+        self.check_free_space(&config, self.git_repo.path())?;
+        if objects_repo != self.git_repo.path() {
+            self.check_free_space(&config, &objects_repo)?;
+        }
+        // whatever snapshots capture, as the restore removes the untracked files
+        let threads = Settings::resolve(&self.settings, &config).threads.value;
+        let mut index =
+            self.build_index(None, &objects_repo, UntrackedFiles::NotIgnored, threads)?;
+        let tree = self.git_repo.find_tree(index.write_tree()?)?;
+        let signature = self.signature(self.clock.now())?;
+        let capture = self.git_repo.commit(
+            None,
+            &signature,
+            &signature,
+            PRE_RESTORE_MESSAGE,
+            &tree,
+            &[],
+        )?;
+        self.git_repo
+            .reference(PRE_RESTORE_REF, capture, true, PRE_RESTORE_MESSAGE)?;
+        self.keep_shared(&objects_repo, &[PRE_RESTORE_REF])?;
+        Ok(capture)
+    }
+
+    /// Changes in the working tree since a snapshot
+    pub fn diff(&self, spec: Option<&SnapshotSpec>) -> Result<Diff<'_>, Error> {
+        let tree = self.find_snapshot(spec)?.tree()?;
+        Ok(self.git_repo.diff_tree_to_workdir(
+            Some(&tree),
+            Some(
+                DiffOptions::new()
+                    .include_untracked(true)
+                    .recurse_untracked_dirs(true)
+                    .show_untracked_content(true),
+            ),
+        )?)
+    }
+
+    /// Write the files of a snapshot into `dest`
+    pub fn export(&self, spec: Option<&SnapshotSpec>, dest: &Path) -> Result<Commit<'_>, Error> {
+        let snapshot = self.find_snapshot(spec)?;
+        export(&self.git_repo, &snapshot.tree()?, dest)?;
+        Ok(snapshot)
+    }
+
+    /// Remove untracked files, and ignored ones like `git clean -fdx`, once the latest snapshot of
+    /// the current branch is checked to hold every change snapshots capture. Ignored files are only
+    /// removed when the snapshot holds them, with `snapshot.includeUntracked` set to `all`. The
+    /// paths removed, or the ones that would be with `dry_run`.
+    pub fn clean(&self, dry_run: bool) -> Result<Vec<PathBuf>, Error> {
+        let workdir = self
+            .git_repo
+            .workdir()
+            .ok_or_else(|| git2::Error::from_str("no working tree to clean"))?
+            .to_owned();
+        let config = self.git_repo.config()?;
+        let untracked = UntrackedFiles::from_config(&config);
+        // listed before the snapshot index replaces the repo's, with a handle of its own
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(true)
+            .include_ignored(true)
+            .recurse_untracked_dirs(false)
+            .recurse_ignored_dirs(false);
+        let mut paths = Vec::new();
+        let mut uncaptured = 0;
+        for entry in Repository::open(self.git_repo.path())?
+            .statuses(Some(&mut options))?
+            .iter()
+        {
+            let status = entry.status();
+            if !status.is_wt_new() && !status.is_ignored() {
+                continue;
+            }
+            if status.is_wt_new() && untracked == UntrackedFiles::None {
+                uncaptured += 1;
+            }
+            if let Some(path) = entry.path() {
+                paths.push((PathBuf::from(path), status.is_ignored()));
+            }
+        }
+
+        let snapshot = self.find_snapshot(None)?;
+        let settings = Settings::resolve(&self.settings, &config);
+        let objects_repo = self.snapshot_objects_repo(&config)?;
+        let mut index = self.build_index(None, &objects_repo, untracked, settings.threads.value)?;
+        let tree = self.git_repo.find_tree(index.write_tree()?)?;
+        if tree.id() != snapshot.tree_id() {
+            let diff =
+                self.git_repo
+                    .diff_tree_to_tree(Some(&snapshot.tree()?), Some(&tree), None)?;
+            for delta in diff.deltas() {
+                if let Some(path) = delta.new_file().path() {
+                    debug!(
+                        target: self.name(),
+                        "not in the latest snapshot: {}",
+                        path.display()
+                    );
+                }
+            }
+            uncaptured += diff.deltas().len();
+        }
+        if uncaptured > 0 {
+            return Err(Error::NotCaptured(uncaptured));
+        }
+
+        // nothing is removed that isn't in the snapshot, whatever the index built above held
+        let snapshot_tree = snapshot.tree()?;
+        let mut removable = Vec::new();
+        for (path, ignored) in paths {
+            let full_path = workdir.join(&path);
+            let Ok(metadata) = full_path.symlink_metadata() else {
+                continue;
+            };
+            // nested repos are left alone, like git clean does without -ff
+            if metadata.is_dir() && full_path.join(".git").exists() {
+                continue;
+            }
+            match (held_by(&snapshot_tree, &workdir, &path)?, ignored) {
+                (true, _) => removable.push((path, metadata)),
+                (false, true) => debug!(
+                    target: self.name(),
+                    "keeping ignored path not in the latest snapshot: {}",
+                    path.display()
+                ),
+                (false, false) => uncaptured += 1,
+            }
+        }
+        if uncaptured > 0 {
+            return Err(Error::NotCaptured(uncaptured));
+        }
+
+        let mut removed = Vec::new();
+        for (path, metadata) in removable {
+            let full_path = workdir.join(&path);
+            if !dry_run {
+                match metadata.is_dir() {
+                    true => std::fs::remove_dir_all(&full_path)?,
+                    false => std::fs::remove_file(&full_path)?,
+                }
+            }
+            removed.push(path);
+        }
+        if !dry_run {
+            info!(
+                target: self.name(),
+                "cleaned {} path(s), snapshot {} holds the working tree",
+                removed.len(),
+                snapshot.id()
+            );
+        }
+        Ok(removed)
+    }
+
+    /// Snapshot activity between `since` and `until` on the snapshot branches of every local
+    /// branch, along with the repo's failed pushes
+    pub fn report(&self, since: SystemTime, until: SystemTime) -> Result<RepoReport, Error> {
+        let config = self.git_repo.config()?;
+        // the current branch isn't listed while it's unborn
+        let mut branches: Vec<String> = self.current_branch().into_iter().collect();
+        for branch in self.git_repo.branches(Some(BranchType::Local))? {
+            if let Some(name) = branch?.0.name()? {
+                branches.push(name.to_owned());
+            }
+        }
+        let mut snapshot_refs = Vec::new();
+        for branch in branches {
+            let snapshot_branch = Self::snapshot_branch(&config, &branch, self.clock.now());
+            let snapshot_ref = snapshot_ref(&config, &snapshot_branch);
+            if !snapshot_refs.contains(&snapshot_ref)
+                && self.git_repo.find_reference(&snapshot_ref).is_ok()
+            {
+                snapshot_refs.push(snapshot_ref);
+            }
+        }
+        let state = State::load(self.git_repo.path())?;
+        RepoReport::new(
+            &self.git_repo,
+            &snapshot_refs,
+            &state.push_failures,
+            since,
+            until,
+        )
+    }
+
+    // Full ref names of `branch`, or the current branch, and its snapshot branch
+    fn branch_refs(&self, branch: Option<&str>) -> Result<(String, String), Error> {
+        let branch = match branch {
+            Some(branch) => branch.to_owned(),
+            None => self.current_branch()?,
+        };
+        let config = self.git_repo.config()?;
+        let snapshot_branch = self.own_snapshot_branch(&config, &branch, self.clock.now());
+        Ok((
+            [BRANCH_REF_PREFIX, &branch].concat(),
+            snapshot_ref(&config, &snapshot_branch),
+        ))
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> Result<bool, Error> {
+        Ok(self.git_repo.is_path_ignored(path)?)
+    }
+
+    /// Path relative to the working tree, if the path is inside it
+    pub fn relative_path(&self, path: &Path) -> Option<PathBuf> {
+        let workdir = canonicalize(self.git_repo.workdir()?).ok()?;
+        strip_path_prefix(path, &workdir)
+    }
+}
+
+// Same contents, only the mode differs
+fn is_mode_change(delta: &DiffDelta) -> bool {
+    delta.status() == Delta::Modified && delta.old_file().id() == delta.new_file().id()
+}
+
+// `snapshot.sessionGap`, e.g. 30m
+fn session_gap(config: &Config) -> Duration {
+    HumanDuration::from_config(config, &["snapshot.sessiongap"], DEFAULT_SESSION_GAP.into()).0
+}
+
+// Whether every file at `path`, relative to `workdir`, has an entry in `tree`
+fn held_by(tree: &Tree, workdir: &Path, path: &Path) -> Result<bool, Error> {
+    let full_path = workdir.join(path);
+    if !full_path.symlink_metadata()?.is_dir() {
+        return Ok(tree.get_path(path).is_ok());
+    }
+    for entry in std::fs::read_dir(&full_path)? {
+        if !held_by(tree, workdir, &path.join(entry?.file_name()))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Commit time of a snapshot taken at `now`, in UTC with `snapshot.utc` set. A clock set back
+/// since the `previous` snapshot is an error with `snapshot.clockskew` set to `error`, with `clamp`
+/// the previous snapshot's time is used instead.
+fn snapshot_time(config: &Config, previous: Option<&Commit>, now: Time) -> Result<Time, Error> {
+    let offset = match bool::from_config(config, &["snapshot.utc"], false) {
+        true => 0,
+        false => now.offset_minutes(),
+    };
+    let mut seconds = now.seconds();
+    if let Some(previous) = previous
+        .map(|p| p.time().seconds())
+        .filter(|&p| p > seconds)
+    {
+        match String::from_config(config, &["snapshot.clockskew"], String::new()).as_str() {
+            "clamp" => seconds = previous,
+            "error" => {
+                return Err(Error::ClockSkew {
+                    behind: Duration::from_secs((previous - seconds) as u64),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(Time::new(seconds, offset))
+}
+
+pub(crate) fn disabled_by_env() -> bool {
+    std::env::var(DISABLE_ENV).is_ok_and(|value| !matches!(value.as_str(), "" | "0" | "false"))
+}
+
+/// Whether `branch` is a snapshot branch itself, going by the snapshot branch template with any
+/// other variables expanded, or an archived one
+fn is_snapshot_branch(config: &Config, branch: &str) -> bool {
+    // stands in for the branch while splitting the template around it
+    const MARKER: &str = "\0";
+    let namespace = RefNamespace::from_config(config);
+    // snapshots outside refs/heads can't be checked out as a branch
+    if namespace != RefNamespace::Heads {
+        return false;
+    }
+    let template = String::from_config(
+        config,
+        &["snapshot.snapshotbranch"],
+        Layout::from_config(config).template(namespace).to_owned(),
+    );
+    let expanded = expand(
+        &mask_time_tokens(&template),
+        &[(BRANCH_SUB_KEY, MARKER), (HOST_SUB_KEY, &hostname())],
+    );
+    let pattern = match expanded.split_once(MARKER) {
+        // a template without a prefix or suffix matches every branch
+        Some((prefix, suffix)) if !(prefix.is_empty() && suffix.is_empty()) => Some(format!(
+            "^{}.+{}$",
+            date_pattern(prefix),
+            date_pattern(suffix)
+        )),
+        Some(_) => None,
+        None => Some(format!("^{}$", date_pattern(&expanded))),
+    };
+    let in_namespace = pattern
+        .and_then(|pattern| Regex::new(&pattern).ok())
+        .is_some_and(|pattern| pattern.is_match(branch));
+    in_namespace || branch.starts_with(ARCHIVE_BRANCH_PREFIX)
+}
+
+/// Expanded snapshot branch name, sanitized unless `snapshot.sanitizebranch` is false
+fn branch_name(config: &Config, name: String) -> String {
+    match bool::from_config(config, &["snapshot.sanitizebranch"], true) {
+        true => sanitize_branch_name(&name),
+        false => name,
+    }
+}
+
+/// Snapshot branch from `template`, with the branch, host name and the local date of `time`
+fn expand_branch_template(template: &str, current_branch: &str, time: SystemTime) -> String {
+    expand_dated(
+        template,
+        &[
+            (BRANCH_SUB_KEY, current_branch),
+            (HOST_SUB_KEY, &hostname()),
+        ],
+        &chrono::DateTime::<chrono::Local>::from(time),
+    )
+}
+
+/// Snapshot message from `template`, with dates and times as of the snapshot's `time`
+fn expand_message(template: &str, current_branch: &str, time: Time) -> String {
+    let context = [
+        (BRANCH_SUB_KEY, current_branch),
+        (HOST_SUB_KEY, &hostname()),
+    ];
+    match FixedOffset::east_opt(time.offset_minutes() * 60)
+        .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+    {
+        Some(time) => expand_at(template, &context, &time),
+        None => expand(template, &context),
+    }
+}
+
+fn hostname() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn audit_key(config: &Config) -> Result<Option<Secret>, Error> {
+    let key = String::from_config(config, &["snapshot.auditkey"], String::new());
+    (!key.is_empty()).then(|| key.parse()).transpose()
+}
+
+/// Ref snapshots are pushed to on `remote`, from the remote config or defaulting to the local
+/// snapshot branch
+fn remote_snapshot_ref(
+    config: &Config,
+    remote: &str,
+    ref_name: &str,
+    current_branch: &str,
+    time: SystemTime,
+) -> String {
+    let namespace = RefNamespace::from_config(config);
+    let snapshot_branch = String::from_config(
+        config,
+        &[&format!("remote.{}.snapshotbranch", remote)],
+        namespace.shorthand(ref_name).to_owned(),
+    );
+    let snapshot_branch = expand_branch_template(&snapshot_branch, current_branch, time);
+    namespace.snapshot_ref(&branch_name(config, snapshot_branch))
+}
+
+/// Local ref of `snapshot_branch`, in the namespace of `snapshot.refnamespace`
+fn snapshot_ref(config: &Config, snapshot_branch: &str) -> String {
+    RefNamespace::from_config(config).snapshot_ref(snapshot_branch)
+}
+
+fn path_str(path: &Path) -> Result<&str, Error> {
+    path.to_str()
+        .ok_or_else(|| git2::Error::from_str("invalid path").into())
+}
+
+// Open the shared object store at `path`, creating it when missing. A lock next to it keeps the
+// first snapshots of several repos from creating it at once.
+fn open_shared_store(path: &Path) -> Result<Repository, Error> {
+    if let Ok(store) = Repository::open_bare(path) {
+        return Ok(store);
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(format!("{}.lock", path.display()))?;
+    lock.lock()?;
+    match Repository::open_bare(path) {
+        Ok(store) => Ok(store),
+        Err(_) => Ok(Repository::init_bare(path)?),
+    }
+}
+
+// Add `alternate` to the objects/info/alternates file of `objects_dir` if it isn't listed yet
+fn add_alternate(objects_dir: &Path, alternate: &Path) -> Result<(), Error> {
+    let alternates_path = objects_dir.join("info").join("alternates");
+    let alternate = path_str(alternate)?;
+    let alternates = read_to_string(&alternates_path).unwrap_or_default();
+    if alternates.lines().any(|l| l == alternate) {
+        return Ok(());
+    }
+
+    create_dir_all(objects_dir.join("info"))?;
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(alternates_path)?;
+    writeln!(f, "{}", alternate)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::Path};
 
     use git2::Signature;
+    use proptest::prelude::*;
     use tempfile::{tempdir, NamedTempFile};
 
-    use super::*;
+    use super::*;
+
+    use crate::clock::{FixedClock, FixedSignature, DEFAULT_AUTHOR_NAME};
+    use crate::settings::SettingOverrides;
+    use crate::test_util::*;
+
+    #[test]
+    fn snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
+
+        NamedTempFile::new_in(temp_dir.path())
+            .unwrap()
+            .keep()
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_empty_branch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo))
+    }
+
+    #[test]
+    fn snapshot_no_changes() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
+
+        NamedTempFile::new_in(temp_dir.path())
+            .unwrap()
+            .keep()
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, SystemTime::now());
+        let snapshot_ref = repo
+            .git_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .unwrap();
+
+        let first_commit = snapshot_ref.peel_to_commit().unwrap();
+
+        repo.snapshot().unwrap();
+
+        let second_commit = snapshot_ref.peel_to_commit().unwrap();
+
+        assert_eq!(first_commit.id(), second_commit.id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_ignore_mode_changes() {
+        use std::fs::{set_permissions, write, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let file = temp_dir.path().join("a");
+        write(&file, "a").unwrap();
+        repo.snapshot().unwrap();
+        let snapshot_id = || repo.find_snapshot(None).unwrap().id();
+        let first = snapshot_id();
+
+        config.set_bool("snapshot.ignoremodechanges", true).unwrap();
+        set_permissions(&file, Permissions::from_mode(0o755)).unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(first, snapshot_id());
+
+        config
+            .set_bool("snapshot.ignoremodechanges", false)
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert_ne!(first, snapshot_id());
+    }
+
+    #[test]
+    fn snapshot_ignore_whitespace_only() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let file = temp_dir.path().join("a");
+        std::fs::write(&file, "fn a() {}\n").unwrap();
+        repo.snapshot().unwrap();
+        let snapshot_id = || repo.find_snapshot(None).unwrap().id();
+        let first = snapshot_id();
+
+        config
+            .set_bool("snapshot.ignorewhitespaceonly", true)
+            .unwrap();
+        std::fs::write(&file, "fn a() {  }\n\n").unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(first, snapshot_id());
+
+        std::fs::write(&file, "fn b() {}\n").unwrap();
+        repo.snapshot().unwrap();
+        assert_ne!(first, snapshot_id());
+    }
+
+    #[test]
+    fn snapshot_branch_patterns() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let branch = repo.current_branch().unwrap();
+        create_temp_file(temp_dir.path());
+
+        for pattern in ["release/*", &branch] {
+            config
+                .set_multivar("snapshot.branchdeny", "^$", pattern)
+                .unwrap();
+        }
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+        config.remove_multivar("snapshot.branchdeny", ".*").unwrap();
+
+        let repo = repo.with_branch_filter(BranchFilter::new(&[] as &[&str], &[&branch]).unwrap());
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+
+        let repo = repo.with_branch_filter(BranchFilter::default());
+        config
+            .set_str("snapshot.branchallow", "feature/**")
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+
+        config
+            .set_multivar("snapshot.branchallow", "^$", &branch)
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn explain() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let branch = repo.current_branch().unwrap();
+        let stops = |repo: &Repo| -> Vec<String> {
+            repo.explain()
+                .unwrap()
+                .into_iter()
+                .filter(|check| check.stop.is_some())
+                .map(|check| check.rule)
+                .collect()
+        };
+        assert!(stops(&repo).is_empty());
+
+        config.set_str("snapshot.branchdeny", &branch).unwrap();
+        let lock = repo.snapshot_index_path().with_extension("lock");
+        std::fs::write(&lock, "").unwrap();
+        assert_eq!(
+            vec![
+                "snapshot.branchAllow and snapshot.branchDeny".to_owned(),
+                "no git lock held".to_owned()
+            ],
+            stops(&repo)
+        );
+        std::fs::remove_file(lock).unwrap();
+        config.remove("snapshot.branchdeny").unwrap();
+
+        repo.disable(
+            Some(&branch),
+            Some(SystemTime::now() + Duration::from_secs(60)),
+        )
+        .unwrap();
+        assert_eq!(vec!["git snapshot disable".to_owned()], stops(&repo));
+    }
+
+    #[test]
+    fn snapshot_stream() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        for dir in ["services/a", "services/b"] {
+            std::fs::create_dir_all(temp_dir.path().join(dir)).unwrap();
+            std::fs::write(temp_dir.path().join(dir).join("main.rs"), "1").unwrap();
+        }
+        let repo = Repo::new(repo).with_stream(Some(SnapshotStream {
+            name: "a".to_owned(),
+            path: PathBuf::from("services/a"),
+            branch: None,
+        }));
+        repo.snapshot().unwrap();
+
+        let branch = repo.current_branch().unwrap();
+        let snapshot_ref = format!("refs/heads/snapshot/a/{}", branch);
+        let tip = || repo.git_repo.refname_to_id(&snapshot_ref).unwrap();
+        let first = tip();
+        let tree = repo.git_repo.find_commit(first).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("services/a/main.rs")).is_ok());
+        assert!(tree.get_path(Path::new("services/b")).is_err());
+        assert!(!check_snapshot_exists(&Repo::new(
+            Repository::open(temp_dir.path()).unwrap()
+        )));
+
+        // changes outside the stream aren't snapshotted
+        std::fs::write(temp_dir.path().join("services/b/main.rs"), "2").unwrap();
+        repo.snapshot_paths(&[PathBuf::from("services/b/main.rs")])
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(first, tip());
+
+        std::fs::write(temp_dir.path().join("services/a/main.rs"), "2").unwrap();
+        repo.snapshot_paths(&[PathBuf::from("services/a/main.rs")])
+            .unwrap();
+        assert_ne!(first, tip());
+    }
+
+    #[test]
+    fn cached_config() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let config_path = temp_dir.path().join(".git/config");
+        let value = || {
+            repo.cached_config()
+                .unwrap()
+                .get_string("snapshot.snapshotbranch")
+                .ok()
+        };
+
+        config.set_str("snapshot.snapshotbranch", "a").unwrap();
+        let modified =
+            filetime::FileTime::from_last_modification_time(&config_path.metadata().unwrap());
+        assert_eq!(Some("a".to_owned()), value());
+
+        // parsed again on a change of size, even within the mtime's granularity
+        config.set_str("snapshot.snapshotbranch", "ab").unwrap();
+        filetime::set_file_mtime(&config_path, modified).unwrap();
+        assert_eq!(Some("ab".to_owned()), value());
+
+        // and on a change of mtime keeping the size
+        config.set_str("snapshot.snapshotbranch", "ba").unwrap();
+        filetime::set_file_mtime(&config_path, filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        assert_eq!(Some("ba".to_owned()), value());
+    }
+
+    #[test]
+    fn cached_config_worktree() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, mut config) = test_repo(temp_dir.path());
+        commit_all(&git_repo);
+        let worktree_dir = tempdir().unwrap();
+        let worktree_path = worktree_dir.path().join("feature");
+        git_repo.worktree("feature", &worktree_path, None).unwrap();
+        let repo = Repo::from_path(&worktree_path).unwrap();
+        let value = || {
+            repo.cached_config()
+                .unwrap()
+                .get_string("snapshot.snapshotbranch")
+                .ok()
+        };
+        assert!(repo.config_stamp()[0].is_some());
+
+        config.set_str("snapshot.snapshotbranch", "a").unwrap();
+        assert_eq!(Some("a".to_owned()), value());
+        config.set_str("snapshot.snapshotbranch", "ab").unwrap();
+        assert_eq!(Some("ab".to_owned()), value());
+    }
+
+    #[test]
+    fn config_setters() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let value = |key: &str| {
+            let config = repo.git_repo.config().unwrap().snapshot().unwrap();
+            config.get_string(key).ok()
+        };
+
+        repo.set_snapshot_branch(ConfigScope::Local, "wip/${BRANCH}")
+            .unwrap();
+        assert_eq!(
+            Some("wip/${BRANCH}".to_owned()),
+            value("snapshot.snapshotbranch")
+        );
+        assert!(matches!(
+            repo.set_snapshot_branch(ConfigScope::Local, ""),
+            Err(Error::InvalidBranchName(_))
+        ));
+        repo.unset_config(ConfigScope::Local, "snapshot.snapshotbranch")
+            .unwrap();
+        repo.unset_config(ConfigScope::Local, "snapshot.snapshotbranch")
+            .unwrap();
+        assert_eq!(None, value("snapshot.snapshotbranch"));
+
+        assert!(repo.set_remote_enabled("origin", true).is_err());
+        repo.git_repo
+            .remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.set_remote_enabled("origin", true).unwrap();
+        assert_eq!(
+            Some("true".to_owned()),
+            value("remote.origin.snapshotenabled")
+        );
+
+        // written once the lock held by another process is released
+        repo.set_config(ConfigScope::Local, "snapshot.retrydelay", "100ms")
+            .unwrap();
+        let lock = repo.git_repo.path().join("config.lock");
+        std::fs::write(&lock, "").unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            std::fs::remove_file(lock).unwrap();
+        });
+        repo.set_branch_enabled("master", false).unwrap();
+        release.join().unwrap();
+        assert_eq!(
+            Some("false".to_owned()),
+            value("branch.master.snapshotenabled")
+        );
+    }
+
+    #[test]
+    fn snapshot_disable_enable() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let branch = repo.current_branch().unwrap();
+        create_temp_file(temp_dir.path());
+
+        repo.disable(None, None).unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+        repo.enable(None).unwrap();
+
+        repo.disable(Some(&branch), None).unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+        repo.enable(Some(&branch)).unwrap();
+
+        // re-enabled once the time passes
+        let until = SystemTime::now() + std::time::Duration::from_millis(200);
+        repo.disable(Some(&branch), Some(until)).unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_low_disk_space() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+
+        config
+            .set_str("snapshot.minfreespace", "1000000000g")
+            .unwrap();
+        assert!(matches!(repo.snapshot(), Err(Error::LowDiskSpace { .. })));
+        assert!(!check_snapshot_exists(&repo));
+
+        config.set_str("snapshot.minfreespace", "1k").unwrap();
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_master_switch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        let branch = repo.current_branch().unwrap();
+        config
+            .set_bool(&format!("branch.{}.snapshotenabled", branch), true)
+            .unwrap();
+
+        // wins over the branch's key
+        config.set_bool("snapshot.enabled", false).unwrap();
+        assert_eq!(
+            Some("snapshot.enabled is false in the local config".to_owned()),
+            repo.disabled_reason()
+        );
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+
+        // and over a later true
+        config
+            .set_multivar("snapshot.enabled", "^$", "true")
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+
+        config.remove_multivar("snapshot.enabled", ".*").unwrap();
+        config.set_bool("snapshot.enabled", true).unwrap();
+        config
+            .set_bool(&format!("branch.{}.snapshotenabled", branch), false)
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+        config
+            .set_bool(&format!("branch.{}.snapshotenabled", branch), true)
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_disable_marker() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+
+        let marker = repo.git_repo.path().join(DISABLE_MARKER_FILE);
+        std::fs::write(&marker, "").unwrap();
+        assert!(repo.disabled_reason().is_some());
+        repo.snapshot().unwrap();
+        assert!(!check_snapshot_exists(&repo));
+
+        std::fs::remove_file(&marker).unwrap();
+        assert!(repo.disabled_reason().is_none());
+        repo.snapshot().unwrap();
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_base_rewritten() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        commit_all(&repo.git_repo);
+        let amend = |message| {
+            let head = repo.git_repo.head().unwrap().peel_to_commit().unwrap();
+            head.amend(Some("HEAD"), None, None, None, Some(message), None)
+                .unwrap()
+        };
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let first = repo.find_snapshot(None).unwrap();
+        let base = base_id(&first).unwrap();
+
+        // appended with a marker by default
+        amend("amended");
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let second = repo.find_snapshot(None).unwrap();
+        assert_eq!(first.id(), second.parent_id(0).unwrap());
+        assert_eq!(Some(base), rewrite_base(&second));
+
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let third = repo.find_snapshot(None).unwrap();
+        assert_eq!(None, rewrite_base(&third));
+
+        config.set_str("snapshot.onrewrite", "archive").unwrap();
+        amend("amended again");
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let restarted = repo.find_snapshot(None).unwrap();
+        assert_eq!(0, restarted.parent_count());
+        assert_eq!(None, rewrite_base(&restarted));
+        let archived: Vec<_> = repo
+            .git_repo
+            .branches(Some(BranchType::Local))
+            .unwrap()
+            .map(|b| b.unwrap().0)
+            .filter(|b| {
+                b.name()
+                    .unwrap()
+                    .unwrap()
+                    .starts_with(ARCHIVE_BRANCH_PREFIX)
+            })
+            .collect();
+        assert_eq!(1, archived.len());
+        assert_eq!(third.id(), archived[0].get().target().unwrap());
+    }
+
+    #[test]
+    fn retire_deleted_branches() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        commit_all(&repo.git_repo);
+        let main = repo.git_repo.head().unwrap().name().unwrap().to_owned();
+        let head = repo.git_repo.head().unwrap().peel_to_commit().unwrap();
+        let snapshot_deleted_branch = |name: &str| {
+            repo.git_repo.branch(name, &head, false).unwrap();
+            repo.git_repo
+                .set_head(&[BRANCH_REF_PREFIX, name].concat())
+                .unwrap();
+            create_temp_file(temp_dir.path());
+            repo.snapshot().unwrap();
+            repo.git_repo.set_head(&main).unwrap();
+            repo.git_repo
+                .find_branch(name, BranchType::Local)
+                .unwrap()
+                .delete()
+                .unwrap();
+        };
+        let now = SystemTime::now();
+        // without snapshots of its own the current branch would look renamed from a deleted one
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+
+        snapshot_deleted_branch("a");
+        // kept without a policy
+        assert!(repo.retire_deleted_branches(now).unwrap().is_empty());
+        config
+            .set_str("snapshot.deletedbranches", "archive")
+            .unwrap();
+        let retired = repo.retire_deleted_branches(now).unwrap();
+        assert!(matches!(
+            &retired[..],
+            [RetiredBranch::Archived { snapshot_branch, archive }]
+                if snapshot_branch == "snapshot/a" && archive.starts_with("snapshot-archive/snapshot/a/")
+        ));
+        assert!(repo
+            .git_repo
+            .find_branch("snapshot/a", BranchType::Local)
+            .is_err());
+
+        config
+            .set_str("snapshot.deletedbranches", "delete")
+            .unwrap();
+        config.set_str("snapshot.deletedbranchgrace", "1h").unwrap();
+        snapshot_deleted_branch("b");
+        assert!(repo.retire_deleted_branches(now).unwrap().is_empty());
+        assert_eq!(
+            vec![RetiredBranch::Deleted {
+                snapshot_branch: "snapshot/b".to_owned()
+            }],
+            repo.retire_deleted_branches(now + Duration::from_secs(3600))
+                .unwrap()
+        );
+        assert!(repo
+            .git_repo
+            .find_branch("snapshot/b", BranchType::Local)
+            .is_err());
+        assert!(State::load(repo.git_repo.path())
+            .unwrap()
+            .snapshot_branches
+            .iter()
+            .all(|t| t.branch != "b"));
+    }
+
+    #[test]
+    fn snapshot_follows_rename() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        commit_all(&repo.git_repo);
+        let rename = |from: &str, to: &str| {
+            repo.git_repo
+                .find_branch(from, BranchType::Local)
+                .unwrap()
+                .rename(to, false)
+                .unwrap();
+            repo.git_repo
+                .set_head(&[BRANCH_REF_PREFIX, to].concat())
+                .unwrap();
+        };
+        let main = repo.current_branch().unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let first = repo.find_snapshot(None).unwrap();
+
+        rename(&main, "renamed");
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let second = repo.find_snapshot(None).unwrap();
+        assert_eq!(first.id(), second.parent_id(0).unwrap());
+        assert!(repo
+            .git_repo
+            .find_branch(&format!("snapshot/{}", main), BranchType::Local)
+            .is_err());
+
+        config.set_bool("snapshot.renamefollow", false).unwrap();
+        rename("renamed", "again");
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        assert_eq!(0, repo.find_snapshot(None).unwrap().parent_count());
+        assert!(repo
+            .git_repo
+            .find_branch("snapshot/renamed", BranchType::Local)
+            .is_ok());
+    }
+
+    #[test]
+    fn snapshot_reflog() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("snapshot.reflog", true).unwrap();
+        let repo = Repo::new(repo);
+        for _ in 0..2 {
+            create_temp_file(temp_dir.path());
+            repo.snapshot().unwrap();
+        }
+
+        let meta_ref = [META_REF_PREFIX, &repo.current_branch().unwrap()].concat();
+        let reflog = repo.git_repo.reflog(&meta_ref).unwrap();
+        assert_eq!(2, reflog.len());
+        let latest = reflog.get(0).unwrap();
+        assert_eq!(repo.find_snapshot(None).unwrap().id(), latest.id_new());
+        assert_eq!(Some("snapshot (manual): Snapshot"), latest.message());
+    }
+
+    #[test]
+    fn snapshot_time_machine() {
+        let temp_dir = tempdir().unwrap();
+        let export_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config
+            .set_str(
+                "snapshot.timemachinedir",
+                &export_dir.path().to_string_lossy(),
+            )
+            .unwrap();
+        let repo = Repo::new(repo);
+        std::fs::write(temp_dir.path().join("kept.txt"), "kept").unwrap();
+        repo.snapshot().unwrap();
+        std::fs::write(temp_dir.path().join("changed.txt"), "changed").unwrap();
+        repo.snapshot().unwrap();
+
+        let branch_dir = export_dir
+            .path()
+            .join(repo.name())
+            .join(repo.current_branch().unwrap());
+        let mut exports: Vec<_> = std::fs::read_dir(&branch_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "latest")
+            .collect();
+        // taken within the same second, they're told apart by the latest snapshot's commit
+        let latest = format!("{:.7}", repo.find_snapshot(None).unwrap().id().to_string());
+        exports.sort_by_key(|path| path.to_string_lossy().ends_with(&latest));
+        assert_eq!(2, exports.len());
+        assert!(!exports[0].join("changed.txt").exists());
+        assert_eq!(
+            "changed",
+            std::fs::read_to_string(exports[1].join("changed.txt")).unwrap()
+        );
+        // unchanged files are the same file in every export
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: &Path| std::fs::metadata(path.join("kept.txt")).unwrap().ino();
+            assert_eq!(inode(&exports[0]), inode(&exports[1]));
+            assert_eq!(
+                std::fs::canonicalize(branch_dir.join("latest")).unwrap(),
+                std::fs::canonicalize(&exports[1]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn time_machine_concurrent_exports() {
+        let temp_dir = tempdir().unwrap();
+        let export_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        for i in 0..20 {
+            std::fs::write(temp_dir.path().join(format!("{}.txt", i)), "same").unwrap();
+        }
+        let repo = Repo::new(repo);
+        let commit = repo.snapshot().unwrap().unwrap().commit;
+
+        // every export writes the shared objects it finds missing
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let (repo_path, barrier) = (temp_dir.path().to_owned(), barrier.clone());
+                let (dest, objects) = (
+                    export_dir.path().join(i.to_string()),
+                    export_dir.path().join(".objects"),
+                );
+                std::thread::spawn(move || {
+                    let repo = Repository::open(repo_path).unwrap();
+                    let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+                    barrier.wait();
+                    export_linked(&repo, &tree, &dest, &objects)
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        for i in 0..4 {
+            let dest = export_dir.path().join(i.to_string());
+            assert_eq!("same", std::fs::read_to_string(dest.join("0.txt")).unwrap());
+        }
+    }
+
+    #[test]
+    fn snapshot_branch_namespace() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        assert!(is_snapshot_branch(&config, "snapshot/main"));
+        assert!(is_snapshot_branch(&config, "snapshot-archive/snapshot/a/1"));
+        assert!(!is_snapshot_branch(&config, "main"));
+        assert!(!is_snapshot_branch(&config, "snapshot/"));
+
+        config
+            .set_str(
+                "snapshot.snapshotbranch",
+                "wip/${GIT_SNAPSHOT_TEST_USER}/${BRANCH}-snap",
+            )
+            .unwrap();
+        std::env::set_var("GIT_SNAPSHOT_TEST_USER", "me");
+        assert!(is_snapshot_branch(&config, "wip/me/main-snap"));
+        assert!(!is_snapshot_branch(&config, "snapshot/main"));
+        assert!(!is_snapshot_branch(&config, "wip/other"));
+
+        config
+            .set_str("snapshot.snapshotbranch", "snapshots")
+            .unwrap();
+        assert!(is_snapshot_branch(&config, "snapshots"));
+        config
+            .set_str("snapshot.snapshotbranch", "${BRANCH}")
+            .unwrap();
+        assert!(!is_snapshot_branch(&config, "main"));
+    }
+
+    #[test]
+    fn snapshot_layout() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let clock = Arc::new(FixedClock::new(UNIX_EPOCH + START));
+        let repo = Repo::new(repo).with_clock(clock.clone());
+        let now = clock.now();
+        create_temp_file(temp_dir.path());
+        let main = repo.current_branch().unwrap();
+        let host = hostname();
+
+        config.set_str("snapshot.layout", "per-host").unwrap();
+        assert_eq!(
+            format!("snapshot/{}/{}", host, main),
+            Repo::snapshot_branch(&config, &main, now)
+        );
+        config.set_str("snapshot.layout", "flat").unwrap();
+        assert_eq!("snapshots", Repo::snapshot_branch(&config, &main, now));
+
+        config.set_str("snapshot.layout", "dated").unwrap();
+        // dated by the repo's clock
+        let date = chrono::DateTime::<chrono::Local>::from(now).format("%Y-%m-%d");
+        let dated = format!("snapshot/{}/{}/{}", host, main, date);
+        assert_eq!(dated, Repo::snapshot_branch(&config, &main, now));
+        repo.snapshot().unwrap();
+        assert!(repo.git_repo.find_branch(&dated, BranchType::Local).is_ok());
+        assert!(is_snapshot_branch(&config, &dated));
+        assert!(is_snapshot_branch(
+            &config,
+            &format!("snapshot/{}/{}/2022-06-01", host, main)
+        ));
+        assert!(!is_snapshot_branch(&config, &format!("snapshot/{}", main)));
+        config
+            .set_str("snapshot.snapshotbranch", "wip/${BRANCH}@${DATE}")
+            .unwrap();
+        assert!(is_snapshot_branch(&config, "wip/main@2022-06-01"));
+        assert!(!is_snapshot_branch(&config, "wip/main"));
+
+        // an explicit template wins
+        config
+            .set_str("snapshot.snapshotbranch", "wip/${BRANCH}")
+            .unwrap();
+        assert_eq!(
+            format!("wip/{}", main),
+            Repo::snapshot_branch(&config, &main, now)
+        );
+        config.set_str("snapshot.layout", "nested").unwrap();
+        config.remove("snapshot.snapshotbranch").unwrap();
+        assert_eq!(
+            format!("snapshot/{}", main),
+            Repo::snapshot_branch(&config, &main, now)
+        );
+    }
+
+    #[test]
+    fn snapshot_message_template() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        let main = repo.current_branch().unwrap();
+        config
+            .set_str(
+                "snapshot.snapshotmessage",
+                "Snapshot of ${BRANCH} on ${DATE} ${DATE:%Q} 100%",
+            )
+            .unwrap();
+        repo.snapshot().unwrap();
+
+        let snapshot = repo.find_snapshot(None).unwrap();
+        let time = snapshot.time();
+        let date = FixedOffset::east_opt(time.offset_minutes() * 60)
+            .unwrap()
+            .timestamp_opt(time.seconds(), 0)
+            .unwrap()
+            .format("%Y-%m-%d");
+        assert_eq!(
+            format!("Snapshot of {} on {} ${{DATE:%Q}} 100%", main, date),
+            snapshot.summary().unwrap()
+        );
+
+        // checkpoint messages are kept as they are
+        repo.checkpoint("before ${DATE}").unwrap();
+        assert_eq!(
+            "before ${DATE}",
+            repo.find_snapshot(None).unwrap().summary().unwrap()
+        );
+    }
+
+    #[test]
+    fn snapshot_branch_name_validation() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        let main = repo.current_branch().unwrap();
+        std::env::set_var("GIT_SNAPSHOT_TEST_NAME", "John Doe: laptop");
+        config
+            .set_str(
+                "snapshot.snapshotbranch",
+                "snapshot/${GIT_SNAPSHOT_TEST_NAME}/${BRANCH}",
+            )
+            .unwrap();
+
+        let sanitized = format!("snapshot/John-Doe--laptop/{}", main);
+        assert_eq!(
+            TemplateCheck {
+                template: "snapshot/${GIT_SNAPSHOT_TEST_NAME}/${BRANCH}".to_owned(),
+                expanded: format!("snapshot/John Doe: laptop/{}", main),
+                branch: Some(sanitized.clone()),
+            },
+            Repo::check_template(&config, None, &main, SystemTime::now())
+        );
+        repo.snapshot().unwrap();
+        assert!(repo
+            .git_repo
+            .find_branch(&sanitized, BranchType::Local)
+            .is_ok());
+
+        config.set_bool("snapshot.sanitizebranch", false).unwrap();
+        assert_eq!(
+            None,
+            Repo::check_template(&config, None, &main, SystemTime::now()).branch
+        );
+        create_temp_file(temp_dir.path());
+        assert!(matches!(
+            repo.snapshot(),
+            Err(Error::InvalidBranchName(name)) if name.contains("John Doe")
+        ));
+        assert_eq!(
+            Some("wip".to_owned()),
+            Repo::check_template(&config, Some("wip"), &main, SystemTime::now()).branch
+        );
+    }
+
+    #[test]
+    fn snapshot_on_snapshot_branch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let main = repo.current_branch().unwrap();
+        repo.git_repo
+            .set_head(&format!("refs/heads/snapshot/{}", main))
+            .unwrap();
+
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let nested = format!("snapshot/snapshot/{}", main);
+        assert!(repo
+            .git_repo
+            .find_branch(&nested, BranchType::Local)
+            .is_err());
+
+        config
+            .set_bool("snapshot.allowsnapshotbranches", true)
+            .unwrap();
+        repo.snapshot().unwrap();
+        assert!(repo
+            .git_repo
+            .find_branch(&nested, BranchType::Local)
+            .is_ok());
+    }
+
+    #[test]
+    fn snapshot_include_untracked() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+        let write = |name: &str| std::fs::write(temp_dir.path().join(name), name).unwrap();
+        write("tracked");
+        std::fs::write(temp_dir.path().join(".gitignore"), "ignored\n").unwrap();
+        // git add
+        let mut index = repo.git_repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        write("untracked");
+        write("ignored");
+        let snapshot_files = || {
+            repo.snapshot().unwrap();
+            let tree = repo.find_snapshot(None).unwrap().tree().unwrap();
+            tree.iter()
+                .map(|e| e.name().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![".gitignore", "tracked", "untracked"], snapshot_files());
+        config.set_str("snapshot.includeuntracked", "all").unwrap();
+        write("tracked2");
+        assert_eq!(
+            vec![".gitignore", "ignored", "tracked", "tracked2", "untracked"],
+            snapshot_files()
+        );
+        config.set_str("snapshot.includeuntracked", "none").unwrap();
+        write("tracked");
+        assert_eq!(vec![".gitignore", "tracked"], snapshot_files());
+    }
+
+    #[test]
+    fn snapshot_squash_sessions() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("snapshot.squashsessions", true).unwrap();
+        config.set_bool("snapshot.statstrailer", true).unwrap();
+        config.set_str("snapshot.sessiongap", "1m").unwrap();
+        let (repo, clock) = fixed_repo(repo);
+        let file = temp_dir.path().join("a");
+        for content in ["1", "2"] {
+            std::fs::write(&file, content).unwrap();
+            clock.advance(Duration::from_secs(10));
+            repo.snapshot_group(None, "group").unwrap();
+        }
+        let last = repo.find_snapshot(None).unwrap();
+        assert_eq!(1, repo.sessions(None).unwrap().len());
+
+        // still ongoing
+        repo.push_due().unwrap();
+        assert_eq!(last.id(), repo.find_snapshot(None).unwrap().id());
+
+        // squashed once the gap passed, without waiting for the next snapshot
+        clock.advance(Duration::from_secs(61));
+        repo.push_due().unwrap();
+        let squashed = repo.find_snapshot(None).unwrap();
+        assert_eq!(0, squashed.parent_count());
+        assert_eq!(last.tree_id(), squashed.tree_id());
+        let message = squashed.message().unwrap();
+        assert_eq!(last.summary(), squashed.summary());
+        assert!(message.contains(&format!("{}: 2", SESSION_TRAILER)));
+        assert!(message.contains(&format!("{}: group", GROUP_TRAILER)));
+        // the stats of the whole session replace the last snapshot's
+        assert_eq!(1, message.matches(STATS_TRAILER).count());
+        assert_ne!(
+            crate::history::trailer(&last, STATS_TRAILER),
+            crate::history::trailer(&squashed, STATS_TRAILER)
+        );
+
+        // the next snapshot starts a session of its own
+        std::fs::write(&file, "3").unwrap();
+        clock.advance(Duration::from_secs(10));
+        repo.snapshot().unwrap();
+        let sessions = repo.sessions(None).unwrap();
+        assert_eq!(2, sessions.len());
+        assert_eq!(1, sessions[1].snapshots.len());
+        assert_eq!(
+            squashed.id(),
+            repo.find_snapshot(None).unwrap().parent_id(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn snapshot_checkpoint() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("snapshot.squashsessions", true).unwrap();
+        config.set_str("snapshot.sessiongap", "1s").unwrap();
+        let (repo, clock) = fixed_repo(repo);
+        let file = temp_dir.path().join("a");
+        std::fs::write(&file, "1").unwrap();
+        repo.snapshot().unwrap();
+
+        // taken without changes
+        repo.checkpoint("before rewrite").unwrap();
+        let checkpoint = repo.find_snapshot(None).unwrap();
+        assert_eq!(Some("before rewrite"), checkpoint.summary());
+        assert!(is_checkpoint(&checkpoint));
+        assert!(repo.list(None).unwrap()[0].0.checkpoint);
+
+        for content in ["2", "3"] {
+            std::fs::write(&file, content).unwrap();
+            repo.snapshot().unwrap();
+        }
+        clock.advance(Duration::from_secs(2));
+        std::fs::write(&file, "4").unwrap();
+        repo.snapshot().unwrap();
+
+        // the session after the checkpoint is squashed onto it
+        let squashed = repo.find_snapshot(None).unwrap().parent(0).unwrap();
+        assert!(squashed
+            .message()
+            .unwrap()
+            .contains(&format!("{}: 2", SESSION_TRAILER)));
+        assert_eq!(checkpoint.id(), squashed.parent_id(0).unwrap());
+    }
+
+    fn snapshot_tree_has(repo: &Repo, path: &str) -> bool {
+        let config = repo.git_repo.config().unwrap();
+        let snapshot_branch =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+        repo.git_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .unwrap()
+            .peel_to_tree()
+            .unwrap()
+            .get_path(Path::new(path))
+            .is_ok()
+    }
+
+    #[test]
+    fn snapshot_paths() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+
+        std::fs::write(temp_dir.path().join("a"), "a").unwrap();
+        repo.snapshot().unwrap();
+
+        std::fs::write(temp_dir.path().join("b"), "b").unwrap();
+        std::fs::write(temp_dir.path().join("c"), "c").unwrap();
+        repo.snapshot_paths(&["b".into()]).unwrap();
+
+        assert!(snapshot_tree_has(&repo, "a"));
+        assert!(snapshot_tree_has(&repo, "b"));
+        assert!(!snapshot_tree_has(&repo, "c"));
+
+        std::fs::remove_file(temp_dir.path().join("b")).unwrap();
+        repo.snapshot_paths(&["b".into()]).unwrap();
+
+        assert!(!snapshot_tree_has(&repo, "b"));
+    }
+
+    #[test]
+    fn snapshot_paths_without_cached_index() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::new(repo);
+
+        std::fs::write(temp_dir.path().join("a"), "a").unwrap();
+        std::fs::write(temp_dir.path().join("b"), "b").unwrap();
+        repo.snapshot_paths(&["a".into()]).unwrap();
+
+        assert!(snapshot_tree_has(&repo, "b"));
+    }
+
+    #[test]
+    fn snapshot_metadata() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, _config) = test_repo_with_files(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path())
+            .unwrap()
+            .with_trigger(Trigger::Watcher);
+        repo.snapshot().unwrap();
+
+        create_temp_file(temp_dir.path());
+        repo.snapshot_paths(&[PathBuf::from("")]).unwrap();
+
+        let snapshots = repo.list(None).unwrap();
+        assert_eq!(2, snapshots.len());
+        let latest = snapshots[0].1.as_ref().unwrap();
+        assert_eq!(Trigger::Watcher, latest.trigger);
+        assert_eq!(vec![PathBuf::from("")], latest.changed_paths);
+        assert!(snapshots[1].1.as_ref().unwrap().changed_paths.is_empty());
+    }
+
+    #[test]
+    fn snapshot_stats() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        let a = temp_dir.path().join("a");
+        std::fs::write(&a, "1\n2\n").unwrap();
+        // not diffed for stats by default
+        let snapshot = repo.snapshot().unwrap().unwrap();
+        assert_eq!(None, snapshot.stats);
+        let metadata = SnapshotMetadata::read(repo.git_repo(), snapshot.commit).unwrap();
+        assert_eq!(None, metadata.unwrap().stats);
+
+        config.set_bool("snapshot.statstrailer", true).unwrap();
+        std::fs::write(&a, "1\n3\n4\n").unwrap();
+        std::fs::write(temp_dir.path().join("b"), "b").unwrap();
+        let stats = DiffStats {
+            files_changed: 2,
+            insertions: 3,
+            deletions: 1,
+            bytes_written: 7,
+        };
+        assert_eq!(Some(stats), repo.snapshot().unwrap().unwrap().stats);
+        // nothing left to commit
+        assert_eq!(None, repo.snapshot().unwrap());
+
+        let snapshot = repo.find_snapshot(None).unwrap();
+        let metadata = SnapshotMetadata::read(repo.git_repo(), snapshot.id()).unwrap();
+        assert_eq!(Some(stats), metadata.unwrap().stats);
+        assert!(snapshot.message().unwrap().contains(
+            "Snapshot-Stats: 2 files changed, 3 insertions(+), 1 deletions(-), 7 bytes written"
+        ));
+    }
+
+    #[test]
+    fn restore() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        std::fs::write(&a, "a1").unwrap();
+        std::fs::write(&b, "b1").unwrap();
+        repo.snapshot().unwrap();
+        let spec = SnapshotSpec::Rev(repo.find_snapshot(None).unwrap().id().to_string());
+        std::fs::write(&a, "a2").unwrap();
+        std::fs::write(&b, "b2").unwrap();
+
+        repo.restore(Some(&spec), &[PathBuf::from("a")], None)
+            .unwrap();
+        assert_eq!("a1", std::fs::read_to_string(&a).unwrap());
+        assert_eq!("b2", std::fs::read_to_string(&b).unwrap());
+
+        let c = temp_dir.path().join("c");
+        std::fs::write(&c, "c").unwrap();
+        repo.restore(Some(&spec), &[], None).unwrap();
+        assert_eq!("b1", std::fs::read_to_string(&b).unwrap());
+        assert!(!c.exists());
+    }
+
+    #[test]
+    fn undo_restore() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        assert!(matches!(repo.undo_restore(), Err(Error::NothingToUndo)));
+
+        let a = temp_dir.path().join("a");
+        std::fs::write(&a, "snapshotted").unwrap();
+        repo.snapshot().unwrap();
+        // neither change is in a snapshot
+        std::fs::write(&a, "unsnapshotted").unwrap();
+        let b = temp_dir.path().join("b");
+        std::fs::write(&b, "new").unwrap();
+
+        repo.restore(None, &[], None).unwrap();
+        assert_eq!("snapshotted", std::fs::read_to_string(&a).unwrap());
+        assert!(!b.exists());
+
+        repo.undo_restore().unwrap();
+        assert_eq!("unsnapshotted", std::fs::read_to_string(&a).unwrap());
+        assert_eq!("new", std::fs::read_to_string(&b).unwrap());
+
+        // undoing again goes back to the restored snapshot
+        repo.undo_restore().unwrap();
+        assert_eq!("snapshotted", std::fs::read_to_string(&a).unwrap());
+    }
+
+    #[test]
+    fn undo_restore_untracked_none() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, mut config) = test_repo(temp_dir.path());
+        config.set_str("snapshot.includeuntracked", "none").unwrap();
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        std::fs::write(temp_dir.path().join("a"), "a").unwrap();
+        let mut index = git_repo.index().unwrap();
+        index.add_path(Path::new("a")).unwrap();
+        index.write().unwrap();
+        repo.snapshot().unwrap();
+
+        // not captured by snapshots, but by the restore
+        let b = temp_dir.path().join("b");
+        std::fs::write(&b, "untracked").unwrap();
+        repo.restore(None, &[], None).unwrap();
+        assert!(!b.exists());
+
+        repo.undo_restore().unwrap();
+        assert_eq!("untracked", std::fs::read_to_string(&b).unwrap());
+    }
+
+    #[test]
+    fn clean() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, mut config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join(".gitignore"), "target/\n.env\n").unwrap();
+        let mut index = git_repo.index().unwrap();
+        index.add_path(Path::new(".gitignore")).unwrap();
+        index.write().unwrap();
+        let tree = git_repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git_repo.signature().unwrap();
+        git_repo
+            .commit(Some("HEAD"), &signature, &signature, "ignore", &tree, &[])
+            .unwrap();
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        let a = temp_dir.path().join("a");
+        std::fs::write(&a, "a").unwrap();
+        std::fs::create_dir(temp_dir.path().join("target")).unwrap();
+        std::fs::write(temp_dir.path().join("target/out"), "out").unwrap();
+        assert!(matches!(repo.clean(false), Err(Error::SnapshotNotFound)));
+
+        repo.snapshot().unwrap();
+        let b = temp_dir.path().join("b");
+        std::fs::write(&b, "b").unwrap();
+        assert!(matches!(repo.clean(false), Err(Error::NotCaptured(1))));
+        assert!(b.exists());
+
+        repo.snapshot().unwrap();
+        let mut cleaned = repo.clean(true).unwrap();
+        cleaned.sort();
+        assert_eq!(vec![PathBuf::from("a"), PathBuf::from("b")], cleaned);
+        assert!(a.exists());
+        // ignored files aren't in the snapshot, so they're kept
+        let env = temp_dir.path().join(".env");
+        std::fs::write(&env, "secret").unwrap();
+        repo.checkpoint("x").unwrap();
+        assert_eq!(
+            vec![PathBuf::from("a"), PathBuf::from("b")],
+            repo.clean(false).unwrap()
+        );
+        assert!(!a.exists() && !b.exists());
+        assert!(env.exists() && temp_dir.path().join("target/out").exists());
+        assert!(temp_dir.path().join(".gitignore").exists());
+
+        // libgit2 doesn't descend into ignored directories when adding them
+        std::fs::remove_dir_all(temp_dir.path().join("target")).unwrap();
+        config.set_str("snapshot.includeuntracked", "all").unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(vec![PathBuf::from(".env")], repo.clean(false).unwrap());
+        assert!(!env.exists());
+    }
+
+    #[test]
+    fn restore_merge() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        std::fs::write(&a, "1\n2\n3\n").unwrap();
+        std::fs::write(&b, "b\n").unwrap();
+        commit_all(&git_repo);
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+
+        // the snapshot changes the first line of a and all of b
+        std::fs::write(&a, "one\n2\n3\n").unwrap();
+        std::fs::write(&b, "snapshot\n").unwrap();
+        repo.snapshot().unwrap();
+
+        // the working tree changes the last line of a and all of b
+        std::fs::write(&a, "1\n2\nthree\n").unwrap();
+        std::fs::write(&b, "worktree\n").unwrap();
+        repo.restore(None, &[], Some(MergeStrategy::Markers))
+            .unwrap();
+        assert_eq!("one\n2\nthree\n", std::fs::read_to_string(&a).unwrap());
+        let b_merged = std::fs::read_to_string(&b).unwrap();
+        assert!(b_merged.contains("<<<<<<<"));
+        assert!(b_merged.contains("worktree") && b_merged.contains("snapshot"));
+
+        repo.undo_restore().unwrap();
+        repo.restore(None, &[], Some(MergeStrategy::Ours)).unwrap();
+        assert_eq!("worktree\n", std::fs::read_to_string(&b).unwrap());
+
+        repo.undo_restore().unwrap();
+        repo.restore(None, &[], Some(MergeStrategy::Theirs))
+            .unwrap();
+        assert_eq!("snapshot\n", std::fs::read_to_string(&b).unwrap());
+        assert_eq!("one\n2\nthree\n", std::fs::read_to_string(&a).unwrap());
+    }
+
+    #[test]
+    fn export() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        std::fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        std::fs::write(temp_dir.path().join("dir/a"), "a").unwrap();
+        repo.snapshot().unwrap();
+
+        let dest = tempdir().unwrap();
+        repo.export(None, dest.path()).unwrap();
+        assert_eq!(
+            "a",
+            std::fs::read_to_string(dest.path().join("dir/a")).unwrap()
+        );
+    }
+
+    #[test]
+    fn snapshot_threads() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo).with_settings(SettingLayers {
+            flags: SettingOverrides {
+                threads: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo))
+    }
+
+    #[test]
+    fn snapshot_shared_objects() {
+        let temp_dir = tempdir().unwrap();
+        let shared_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config
+            .set_str(
+                "snapshot.sharedobjects",
+                shared_dir.path().join("shared").to_str().unwrap(),
+            )
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let commit = repo
+            .git_repo
+            .resolve_reference_from_short_name(&Repo::snapshot_branch(
+                &config,
+                &repo.current_branch().unwrap(),
+                SystemTime::now(),
+            ))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id();
 
-    use crate::util::tests::*;
+        let shared = Repository::open_bare(shared_dir.path().join("shared")).unwrap();
+        assert!(shared.find_commit(commit).is_ok());
 
-    const TEST_REMOTE_NAME: &'static str = "test";
+        // readable through the alternates file with a fresh handle
+        let reopened = Repository::open(temp_dir.path()).unwrap();
+        assert!(reopened.find_commit(commit).is_ok());
 
-    fn test_repo_with_files(path: &Path) -> (Repository, Config) {
-        let (repo, config) = test_repo(path);
-        NamedTempFile::new_in(path).unwrap().keep().unwrap();
-        (repo, config)
+        // kept by a ref of the store through its gc
+        let status = std::process::Command::new("git")
+            .args(["gc", "--prune=now", "--quiet"])
+            .current_dir(shared.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let reopened = Repository::open(temp_dir.path()).unwrap();
+        assert!(reopened.find_commit(commit).is_ok());
+        assert!(reopened.find_note(Some(NOTES_REF), commit).is_ok());
     }
 
-    fn test_repo_with_remote(path: &Path, remote_path: &Path) -> (Repository, Repository, Config) {
-        let (repo, config) = test_repo_with_files(path);
-        let remote_repo = Repository::init_bare(remote_path).unwrap();
-        repo.remote(
-            TEST_REMOTE_NAME,
-            &format!("file://{}", remote_repo.path().to_str().unwrap()),
-        )
-        .unwrap();
-        repo.config()
+    #[test]
+    fn shared_store_created_once() {
+        let shared_dir = tempdir().unwrap();
+        let path = shared_dir.path().join("objects");
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || open_shared_store(&path).map(|store| store.is_bare()))
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().unwrap());
+        }
+    }
+
+    #[test]
+    fn snapshot_branch_config_disabled() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, SystemTime::now());
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_bool(&format!("branch.{}.snapshotenabled", current_branch), false)
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        let ref_result = repo
+            .git_repo
+            .resolve_reference_from_short_name(&snapshot_branch);
+
+        assert_eq!(ErrorCode::NotFound, ref_result.err().unwrap().code());
+    }
+
+    #[test]
+    fn snapshot_branch_config_snapshotbranch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = "snapshottest";
+
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_str(
+                &format!("branch.{}.snapshotbranch", current_branch),
+                snapshot_branch,
+            )
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_snapshot_config_snapshotbranch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+
+        let snapshot_branch = "snapshottest";
+
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_str("snapshot.snapshotbranch", snapshot_branch)
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_snapshot_config_env_expansion() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        std::env::set_var("TEST_NAME", "test");
+
+        let snapshot_branch = "snapshottest/${TEST_NAME}";
+
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_str("snapshot.snapshotbranch", snapshot_branch)
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_remote_push() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+
+        let (repo, remote_repo, config) = test_repo_with_remote(temp_dir.path(), remote_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, SystemTime::now());
+
+        assert_eq!(
+            None,
+            remote_repo
+                .resolve_reference_from_short_name(&snapshot_branch)
+                .err()
+        );
+    }
+
+    #[test]
+    fn push_snapshots() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        let enabled = format!("remote.{}.snapshotenabled", TEST_REMOTE_NAME);
+        config.set_bool(&enabled, false).unwrap();
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        repo.git_repo.set_head("refs/heads/dev").unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+
+        config.set_bool(&enabled, true).unwrap();
+        repo.push_snapshots().unwrap();
+        for branch in ["snapshot/master", "snapshot/dev"] {
+            assert!(remote_repo.find_branch(branch, BranchType::Local).is_ok());
+        }
+        assert_eq!(2, State::load(repo.git_repo.path()).unwrap().pushed.len());
+
+        // unchanged since, so not pushed again
+        remote_repo
+            .find_reference("refs/heads/snapshot/master")
             .unwrap()
-            .set_bool(&format!("remote.{}.snapshotenabled", "test"), true)
+            .delete()
+            .unwrap();
+        repo.push_snapshots().unwrap();
+        assert!(remote_repo
+            .find_branch("snapshot/master", BranchType::Local)
+            .is_err());
+    }
+
+    #[test]
+    fn push_git_cli() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.push.backend", "git-cli").unwrap();
+        let (repo, clock) = fixed_repo(repo);
+        repo.snapshot().unwrap();
+        assert_eq!(
+            repo.find_snapshot(None).unwrap().id(),
+            remote_repo
+                .refname_to_id("refs/heads/snapshot/master")
+                .unwrap()
+        );
+
+        // rejected by the remote like with libgit2
+        let mut remote_config = remote_repo.config().unwrap();
+        remote_config
+            .set_bool("receive.denynonfastforwards", true)
+            .unwrap();
+        config.set_bool("snapshot.squashsessions", true).unwrap();
+        config.set_str("snapshot.sessiongap", "1s").unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        clock.advance(Duration::from_secs(2));
+        create_temp_file(temp_dir.path());
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::error::ErrorCode::NonFastForward, err.code());
+    }
+
+    #[test]
+    fn push_timeout() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        // accepting connections without ever answering, like a half-open connection
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo.git", listener.local_addr().unwrap());
+        repo.remote("wedged", &url).unwrap();
+        config
+            .set_bool("remote.wedged.snapshotenabled", true)
+            .unwrap();
+        config
+            .set_str("remote.wedged.snapshotconnecttimeout", "1s")
+            .unwrap();
+        config.set_i64("snapshot.retryattempts", 1).unwrap();
+        let repo = Repo::new(repo);
+
+        let started = std::time::Instant::now();
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::error::ErrorCode::Network, err.code());
+        assert!(err.to_string().contains("timed out connecting to wedged"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        drop(listener);
+    }
+
+    #[test]
+    fn push_max_blob_size() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.push.maxblobsize", "1k").unwrap();
+        std::fs::write(temp_dir.path().join("large.bin"), [1; 2048]).unwrap();
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let content = |repo: &Repository, commit: Oid| {
+            let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+            let entry = tree.get_name("large.bin").unwrap();
+            repo.find_blob(entry.id()).unwrap().content().len()
+        };
+        let local = repo.find_snapshot(None).unwrap().id();
+        let pushed = remote_repo
+            .refname_to_id("refs/heads/snapshot/master")
+            .unwrap();
+        assert_ne!(local, pushed);
+        assert_eq!(2048, content(&repo.git_repo, local));
+        assert!(content(&remote_repo, pushed) < 1024);
+        let checks = repo.verify_remotes(None).unwrap();
+        assert_eq!(Parity::InSync, checks[0].parity);
+
+        // later snapshots build on the rewritten ones
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let next = remote_repo
+            .refname_to_id("refs/heads/snapshot/master")
+            .unwrap();
+        assert_eq!(
+            pushed,
+            remote_repo.find_commit(next).unwrap().parent_id(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn bundle_files() {
+        let temp_dir = tempdir().unwrap();
+        let bundle_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+        let clock = Arc::new(FixedClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_650_000_000),
+        ));
+        let repo = Repo::new(repo).with_clock(clock.clone());
+        repo.snapshot().unwrap();
+        let first = repo.find_snapshot(None).unwrap().id();
+        let full = bundle_dir.path().join("full.bundle");
+        let heads = repo.bundle(&full, None).unwrap().unwrap();
+        assert_eq!(
+            vec![("refs/heads/snapshot/master".to_owned(), first)],
+            heads
+        );
+        // nothing new since
+        let empty = bundle_dir.path().join("empty.bundle");
+        assert_eq!(None, repo.bundle(&empty, None).unwrap());
+        assert!(!empty.exists());
+
+        clock.advance(Duration::from_secs(3600));
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let second = repo.find_snapshot(None).unwrap().id();
+        let incremental = bundle_dir.path().join("incremental.bundle");
+        repo.bundle(&incremental, None).unwrap().unwrap();
+        // bundled again from a time, whatever was bundled before
+        let since = bundle_dir.path().join("since.bundle");
+        let time = UNIX_EPOCH + Duration::from_secs(1_650_000_000 + 60);
+        repo.bundle(&since, Some(time)).unwrap().unwrap();
+
+        let other_dir = tempdir().unwrap();
+        let other = Repo::new(Repository::init(other_dir.path()).unwrap());
+        assert!(other.unbundle(&incremental).is_err());
+        assert!(other.unbundle(&since).is_err());
+        other.unbundle(&full).unwrap();
+        other.unbundle(&incremental).unwrap();
+        assert_eq!(
+            second,
+            other
+                .git_repo
+                .refname_to_id("refs/heads/snapshot/master")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn push_interval() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.push.interval", "15m").unwrap();
+        let clock = Arc::new(FixedClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_650_000_000),
+        ));
+        let repo = Repo::new(repo).with_clock(clock.clone());
+        let remote_tip = || {
+            remote_repo
+                .refname_to_id("refs/heads/snapshot/master")
+                .unwrap()
+        };
+        // the first push isn't held back
+        repo.snapshot().unwrap();
+        let first = repo.find_snapshot(None).unwrap().id();
+        assert_eq!(first, remote_tip());
+
+        clock.advance(Duration::from_secs(60));
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        repo.push_due().unwrap();
+        assert_eq!(first, remote_tip());
+
+        clock.advance(Duration::from_secs(15 * 60));
+        repo.push_due().unwrap();
+        assert_eq!(repo.find_snapshot(None).unwrap().id(), remote_tip());
+    }
+
+    #[test]
+    fn snapshot_remote_approval() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_bool("snapshot.confirmnewremotes", true).unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, SystemTime::now());
+        let pushed = || {
+            remote_repo
+                .resolve_reference_from_short_name(&snapshot_branch)
+                .is_ok()
+        };
+        assert!(!pushed());
+        let pending = repo.pending_pushes().unwrap();
+        assert_eq!(1, pending.len());
+        assert_eq!(TEST_REMOTE_NAME, pending[0].remote);
+
+        repo.approve_remote(TEST_REMOTE_NAME).unwrap();
+        assert!(pushed());
+        assert!(repo.pending_pushes().unwrap().is_empty());
+
+        // pushed right away from now on
+        std::fs::write(temp_dir.path().join("new"), "new").unwrap();
+        repo.snapshot().unwrap();
+        let remote_tip = || {
+            remote_repo
+                .resolve_reference_from_short_name(&snapshot_branch)
+                .unwrap()
+                .target()
+                .unwrap()
+        };
+        assert_eq!(repo.find_snapshot(None).unwrap().id(), remote_tip());
+
+        // a pushurl sends snapshots elsewhere, which needs approving again
+        let other_dir = tempdir().unwrap();
+        Repository::init_bare(other_dir.path()).unwrap();
+        config
+            .set_str(
+                &format!("remote.{}.pushurl", TEST_REMOTE_NAME),
+                &format!("file://{}", other_dir.path().display()),
+            )
+            .unwrap();
+        std::fs::write(temp_dir.path().join("new"), "newer").unwrap();
+        repo.snapshot().unwrap();
+        assert_ne!(repo.find_snapshot(None).unwrap().id(), remote_tip());
+        assert_eq!(1, repo.pending_pushes().unwrap().len());
+    }
+
+    #[test]
+    fn snapshot_remote_not_allowed() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config
+            .set_str("snapshot.push.allowurls", "git@github.example.com:*")
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::error::ErrorCode::RemoteNotAllowed, err.code());
+        // the snapshot is still taken locally
+        assert!(check_snapshot_exists(&repo));
+        let snapshot_branch =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+        assert!(remote_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .is_err());
+
+        config
+            .set_multivar("snapshot.push.allowurls", "^$", "regex:^file://")
+            .unwrap();
+        std::fs::write(temp_dir.path().join("new"), "new").unwrap();
+        repo.snapshot().unwrap();
+        assert!(remote_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .is_ok());
+
+        // pushes go to the pushurl, whatever the url is
+        config
+            .remove_multivar("snapshot.push.allowurls", "^regex:")
+            .unwrap();
+        let url_key = format!("remote.{}.url", TEST_REMOTE_NAME);
+        let url = config.get_string(&url_key).unwrap();
+        config
+            .set_str(&format!("remote.{}.pushurl", TEST_REMOTE_NAME), &url)
             .unwrap();
-        (repo, remote_repo, config)
+        config
+            .set_str(&url_key, "git@github.example.com:user/project")
+            .unwrap();
+        std::fs::write(temp_dir.path().join("new"), "newer").unwrap();
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::error::ErrorCode::RemoteNotAllowed, err.code());
     }
 
-    fn commit_all(repo: &Repository) {
-        let mut index = Index::new().unwrap();
-        repo.set_index(&mut index).unwrap();
-        index
-            .add_all(&["*"], IndexAddOption::DEFAULT, None)
+    #[test]
+    fn snapshot_author() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config
+            .set_str("snapshot.authorname", "snapshot-bot")
             .unwrap();
-        let tree = index.write_tree().unwrap();
-        let tree = repo.find_tree(tree).unwrap();
-
-        let signature = Signature::now("test", "test").unwrap();
-
-        repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+        config
+            .set_str("snapshot.authoremail", "${GIT_SNAPSHOT_TEST_EMAIL}")
             .unwrap();
-    }
+        std::env::set_var("GIT_SNAPSHOT_TEST_EMAIL", "bot@example.com");
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let author = repo.find_snapshot(None).unwrap().author().to_owned();
+        assert_eq!(Some("snapshot-bot"), author.name());
+        assert_eq!(Some("bot@example.com"), author.email());
 
-    pub fn check_snapshot_exists(repo: &Repo) -> bool {
-        let config = repo.git_repo.config().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &repo.current_branch().unwrap());
-        repo.git_repo
-            .resolve_reference_from_short_name(&snapshot_branch)
-            .is_ok()
+        // no identity at all
+        config.remove("snapshot.authorname").unwrap();
+        config.remove("snapshot.authoremail").unwrap();
+        config.set_str("user.name", "").unwrap();
+        config.set_str("user.email", "").unwrap();
+        std::fs::write(temp_dir.path().join("new"), "new").unwrap();
+        repo.snapshot().unwrap();
+        let author = repo.find_snapshot(None).unwrap().author().to_owned();
+        assert_eq!(Some(DEFAULT_AUTHOR_NAME), author.name());
+
+        config.set_str("snapshot.authorname", "<bot>").unwrap();
+        std::fs::write(temp_dir.path().join("new"), "newer").unwrap();
+        assert_eq!(
+            crate::error::ErrorCode::InvalidSignature,
+            repo.snapshot().unwrap_err().code()
+        );
     }
 
     #[test]
-    fn snapshot() {
+    fn snapshot_injected_clock() {
         let temp_dir = tempdir().unwrap();
         let (repo, _config) = test_repo_with_files(temp_dir.path());
-
-        commit_all(&repo);
-
-        NamedTempFile::new_in(temp_dir.path())
-            .unwrap()
-            .keep()
-            .unwrap();
-
-        let repo = Repo::new(repo);
+        let time = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let repo = Repo::new(repo)
+            .with_clock(Arc::new(FixedClock::new(time)))
+            .with_signature(Arc::new(FixedSignature {
+                name: "Build".to_owned(),
+                email: "build@example.com".to_owned(),
+            }));
         repo.snapshot().unwrap();
+
+        let snapshot = repo.find_snapshot(None).unwrap();
+        assert_eq!(1_650_000_000, snapshot.time().seconds());
+        assert_eq!(Some("Build"), snapshot.author().name());
+        assert_eq!(Some("build@example.com"), snapshot.committer().email());
+        let state = State::load(repo.git_repo.path()).unwrap();
+        assert_eq!(Some(time), state.last_snapshot);
     }
 
     #[test]
-    fn test_snapshot_empty_branch() {
+    fn snapshot_template_trailers() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
-
+        let (repo, mut config) = test_repo(temp_dir.path());
+        std::fs::write(
+            temp_dir.path().join(".gitmessage"),
+            "# why\nIssue: PROJ-1\n",
+        )
+        .unwrap();
+        config.set_str("commit.template", ".gitmessage").unwrap();
+        config.set_bool("snapshot.committemplate", true).unwrap();
+        config.set_bool("snapshot.signoff", true).unwrap();
+        config
+            .set_multivar("snapshot.trailers", "^$", "Snapshot-Branch: ${BRANCH}")
+            .unwrap();
         let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
-        assert!(check_snapshot_exists(&repo))
+        let snapshot = repo.find_snapshot(None).unwrap();
+        assert_eq!(
+            "Snapshot\n\nIssue: PROJ-1\nSnapshot-Branch: master\nSigned-off-by: Test <test@test.test>",
+            snapshot.message().unwrap()
+        );
     }
 
     #[test]
-    fn snapshot_no_changes() {
+    fn snapshot_clock_skew() {
         let temp_dir = tempdir().unwrap();
-        let (repo, config) = test_repo_with_files(temp_dir.path());
-
-        commit_all(&repo);
-
-        NamedTempFile::new_in(temp_dir.path())
-            .unwrap()
-            .keep()
-            .unwrap();
-
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
         let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
-        let snapshot_ref = repo
-            .git_repo
-            .resolve_reference_from_short_name(&snapshot_branch)
+        // a snapshot taken before the clock was set back an hour
+        let snapshot_ref = repo.branch_refs(None).unwrap().1;
+        let previous = repo.find_snapshot(None).unwrap();
+        let ahead = Time::new(previous.time().seconds() + 3600, 0);
+        let signature = Signature::new("Test", "test@test.test", &ahead).unwrap();
+        let ahead_id = repo
+            .git_repo()
+            .commit(
+                Some(&snapshot_ref),
+                &signature,
+                &signature,
+                "Snapshot",
+                &previous.tree().unwrap(),
+                &[&previous],
+            )
             .unwrap();
 
-        let first_commit = snapshot_ref.peel_to_commit().unwrap();
+        config.set_str("snapshot.clockskew", "error").unwrap();
+        std::fs::write(temp_dir.path().join("new"), "new").unwrap();
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::error::ErrorCode::ClockSkew, err.code());
+        assert_eq!(ahead_id, repo.find_snapshot(None).unwrap().id());
 
+        config.set_str("snapshot.clockskew", "clamp").unwrap();
+        config.set_bool("snapshot.utc", true).unwrap();
         repo.snapshot().unwrap();
+        let snapshot = repo.find_snapshot(None).unwrap();
+        assert_eq!(ahead.seconds(), snapshot.time().seconds());
+        assert_eq!(0, snapshot.time().offset_minutes());
+        let metadata = SnapshotMetadata::read(repo.git_repo(), snapshot.id())
+            .unwrap()
+            .unwrap();
+        assert!(metadata.triggered.unwrap() < commit_time(&snapshot));
+    }
 
-        let second_commit = snapshot_ref.peel_to_commit().unwrap();
+    #[test]
+    fn audit() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
 
-        assert_eq!(first_commit.id(), second_commit.id());
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        // off by default
+        assert_eq!(0, repo.verify_audit().unwrap().entries);
+
+        config.set_bool("snapshot.audit", true).unwrap();
+        config.set_str("snapshot.auditkey", "key").unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        repo.restore(None, &[], None).unwrap();
+        repo.undo_restore().unwrap();
+        assert_eq!(
+            AuditSummary {
+                entries: 3,
+                signed: 3
+            },
+            repo.verify_audit().unwrap()
+        );
     }
 
     #[test]
-    fn snapshot_branch_config_disabled() {
+    fn snapshot_verify() {
         let temp_dir = tempdir().unwrap();
-        let (repo, config) = test_repo_with_files(temp_dir.path());
-
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("snapshot.verify", true).unwrap();
         let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
 
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
-        repo.git_repo()
-            .config()
+        let verification = State::load(repo.git_repo().path())
             .unwrap()
-            .set_bool(&format!("branch.{}.snapshotenabled", current_branch), false)
+            .last_verification
             .unwrap();
-
-        repo.snapshot().unwrap();
-
-        let ref_result = repo
-            .git_repo
-            .resolve_reference_from_short_name(&snapshot_branch);
-
-        assert_eq!(ErrorCode::NotFound, ref_result.err().unwrap().code());
+        assert!(verification.is_ok());
+        assert_eq!(2, verification.checked);
+        assert_eq!(
+            repo.find_snapshot(None).unwrap().id().to_string(),
+            verification.commit
+        );
     }
 
     #[test]
-    fn snapshot_branch_config_snapshotbranch() {
+    fn snapshot_retried_while_locked() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
-
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_i32("snapshot.retryattempts", 10).unwrap();
+        config.set_str("snapshot.retrydelay", "50ms").unwrap();
         let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
 
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = "snapshottest";
-
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_str(
-                &format!("branch.{}.snapshotbranch", current_branch),
-                snapshot_branch,
-            )
-            .unwrap();
+        // another git process updating the snapshot branch
+        let snapshot_branch =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+        let lock = repo
+            .git_repo()
+            .path()
+            .join(format!("{}{}.lock", BRANCH_REF_PREFIX, snapshot_branch));
+        create_dir_all(lock.parent().unwrap()).unwrap();
+        std::fs::write(&lock, "").unwrap();
+        let unlock = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            std::fs::remove_file(lock).unwrap();
+        });
 
         repo.snapshot().unwrap();
-
+        unlock.join().unwrap();
         assert!(check_snapshot_exists(&repo));
     }
 
     #[test]
-    fn snapshot_snapshot_config_snapshotbranch() {
+    fn snapshot_ref_namespace() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
-
-        let repo = Repo::new(repo);
-
-        let snapshot_branch = "snapshottest";
-
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_str("snapshot.snapshotbranch", snapshot_branch)
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config
+            .set_str("snapshot.refnamespace", "snapshots")
             .unwrap();
-
+        let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
-        assert!(check_snapshot_exists(&repo));
+        let snapshot = repo.find_snapshot(None).unwrap().id();
+        assert_eq!(
+            snapshot,
+            repo.git_repo
+                .refname_to_id("refs/snapshots/master")
+                .unwrap()
+        );
+        assert_eq!(
+            snapshot,
+            remote_repo.refname_to_id("refs/snapshots/master").unwrap()
+        );
+        // no snapshot branches among the branches
+        assert_eq!(
+            0,
+            repo.git_repo
+                .branches(Some(BranchType::Local))
+                .unwrap()
+                .count()
+        );
+        assert!(!is_snapshot_branch(&config, "snapshot/master"));
+        let parities: Vec<Parity> = repo
+            .verify_remotes(None)
+            .unwrap()
+            .into_iter()
+            .map(|check| check.parity)
+            .collect();
+        assert_eq!(vec![Parity::InSync], parities);
     }
 
     #[test]
-    fn snapshot_snapshot_config_env_expansion() {
+    fn verify_objects_and_remotes() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
-
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
         let repo = Repo::new(repo);
-        std::env::set_var("TEST_NAME", "test");
+        repo.snapshot().unwrap();
+        assert!(repo.verify_objects(None).unwrap().is_ok());
+        let parities = || -> Vec<Parity> {
+            repo.verify_remotes(None)
+                .unwrap()
+                .into_iter()
+                .map(|check| check.parity)
+                .collect()
+        };
+        assert_eq!(vec![Parity::InSync], parities());
 
-        let snapshot_branch = "snapshottest/${TEST_NAME}";
+        // a snapshot taken while pushing was off
+        let enabled = format!("remote.{}.snapshotenabled", TEST_REMOTE_NAME);
+        config.set_bool(&enabled, false).unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        config.set_bool(&enabled, true).unwrap();
+        assert_eq!(vec![Parity::Ahead(1)], parities());
 
-        repo.git_repo()
-            .config()
+        let snapshot_branch =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+        remote_repo
+            .find_reference(&[BRANCH_REF_PREFIX, &snapshot_branch].concat())
             .unwrap()
-            .set_str("snapshot.snapshotbranch", snapshot_branch)
+            .delete()
             .unwrap();
-
-        repo.snapshot().unwrap();
-
-        assert!(check_snapshot_exists(&repo));
+        assert_eq!(vec![Parity::Missing], parities());
     }
 
     #[test]
-    fn snapshot_remote_push() {
+    fn snapshot_push_diverged() {
         let temp_dir = tempdir().unwrap();
         let remote_dir = tempdir().unwrap();
-
-        let (repo, remote_repo, config) = test_repo_with_remote(temp_dir.path(), remote_dir.path());
-
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        // only snapshots squashed since they were pushed are forced over the remote's
+        config.set_bool("snapshot.squashsessions", true).unwrap();
         let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
+        // someone else pushed to the snapshot branch
+        let snapshot_branch =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+        let signature = Signature::now("test", "test").unwrap();
+        let tree = remote_repo
+            .find_tree(remote_repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let other = remote_repo
+            .commit(None, &signature, &signature, "other", &tree, &[])
+            .unwrap();
+        remote_repo
+            .reference(
+                &[BRANCH_REF_PREFIX, &snapshot_branch].concat(),
+                other,
+                true,
+                "diverge",
+            )
+            .unwrap();
 
-        assert_eq!(
-            None,
-            remote_repo
-                .resolve_reference_from_short_name(&snapshot_branch)
-                .err()
-        );
+        create_temp_file(temp_dir.path());
+        let err = repo.snapshot().unwrap_err();
+        assert_eq!(crate::ErrorCode::NonFastForward, err.code());
+        assert!(err.hint().is_some());
     }
 
     #[test]
@@ -488,7 +5206,7 @@ pub mod tests {
         repo.snapshot().unwrap();
 
         let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, SystemTime::now());
 
         assert_eq!(
             ErrorCode::NotFound,
@@ -528,6 +5246,86 @@ pub mod tests {
         );
     }
 
+    fn unborn_repo(path: &Path, initial_head: &str) -> Repository {
+        let repo = Repository::init_opts(
+            path,
+            git2::RepositoryInitOptions::new().initial_head(initial_head),
+        )
+        .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@test.test").unwrap();
+        repo
+    }
+
+    #[test]
+    fn snapshot_unborn_branch() {
+        for default_branch in ["trunk", "feature/first"] {
+            let temp_dir = tempdir().unwrap();
+            let repo = Repo::new(unborn_repo(temp_dir.path(), default_branch));
+            assert_eq!(default_branch, repo.current_branch().unwrap());
+            create_temp_file(temp_dir.path());
+            repo.snapshot().unwrap();
+
+            let snapshot_ref = format!("refs/heads/snapshot/{}", default_branch);
+            let root = repo
+                .git_repo
+                .find_reference(&snapshot_ref)
+                .unwrap()
+                .peel_to_commit()
+                .unwrap();
+            assert_eq!(0, root.parent_count());
+            assert_eq!(None, base_id(&root));
+
+            // snapshots after the first commit build on the root snapshot
+            commit_all(&repo.git_repo);
+            create_temp_file(temp_dir.path());
+            repo.snapshot().unwrap();
+            let snapshot = repo.find_snapshot(None).unwrap();
+            assert_eq!(root.id(), snapshot.parent_id(0).unwrap());
+            assert_eq!(repo.git_repo.refname_to_id("HEAD").ok(), base_id(&snapshot));
+        }
+    }
+
+    #[test]
+    fn snapshot_unborn_branch_template_and_push() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let repo = unborn_repo(temp_dir.path(), "main");
+        let remote_repo = Repository::init_bare(remote_dir.path()).unwrap();
+        repo.remote(
+            TEST_REMOTE_NAME,
+            &format!("file://{}", remote_repo.path().to_str().unwrap()),
+        )
+        .unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_bool(
+                &format!("remote.{}.snapshotenabled", TEST_REMOTE_NAME),
+                true,
+            )
+            .unwrap();
+        config
+            .set_str("snapshot.snapshotbranch", "wip/${BRANCH}")
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        assert!(repo.git_repo.find_reference("refs/heads/wip/main").is_ok());
+        assert!(remote_repo.find_reference("refs/heads/wip/main").is_ok());
+    }
+
+    #[test]
+    fn unborn_head_outside_branches() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        repo.reference_symbolic("HEAD", "refs/tags/unborn", true, "")
+            .unwrap();
+        let repo = Repo::new(repo);
+        assert!(matches!(repo.current_branch(), Err(Error::InvalidHead)));
+    }
+
     #[test]
     fn snapshot_invalid_head() {
         let temp_dir = tempdir().unwrap();
@@ -557,4 +5355,194 @@ pub mod tests {
 
         assert!(Repo::from_path(temp_dir.path()).is_ok());
     }
+
+    // Working trees as file paths and contents, the paths short so edits often hit the same file
+    type Files = BTreeMap<PathBuf, Vec<u8>>;
+
+    fn path_strategy() -> impl Strategy<Value = PathBuf> {
+        "[ab]{1,2}(/[ab]{1,2}){0,2}".prop_map(PathBuf::from)
+    }
+
+    fn files_strategy() -> impl Strategy<Value = Files> {
+        prop::collection::vec(
+            (path_strategy(), prop::collection::vec(any::<u8>(), 0..32)),
+            1..8,
+        )
+        .prop_map(|entries| {
+            let mut files = Files::new();
+            for (path, content) in entries {
+                insert_file(&mut files, path, content);
+            }
+            files
+        })
+    }
+
+    // Configs that change how snapshots are committed but not what they hold
+    fn config_strategy() -> impl Strategy<Value = [bool; 3]> {
+        any::<[bool; 3]>()
+    }
+
+    fn apply_config(config: &mut Config, [utc, squash, ignore_modes]: [bool; 3]) {
+        config.set_bool("snapshot.utc", utc).unwrap();
+        config.set_bool("snapshot.squashsessions", squash).unwrap();
+        config.set_str("snapshot.sessiongap", "5s").unwrap();
+        config
+            .set_bool("snapshot.ignoremodechanges", ignore_modes)
+            .unwrap();
+    }
+
+    // A file replaces the directory at its path, or the files on the way to it
+    fn insert_file(files: &mut Files, path: PathBuf, content: Vec<u8>) {
+        files.retain(|existing, _| !existing.starts_with(&path) && !path.starts_with(existing));
+        files.insert(path, content);
+    }
+
+    fn write_files(root: &Path, files: &Files) {
+        for entry in std::fs::read_dir(root).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name() == Some(".git".as_ref()) {
+                continue;
+            }
+            match path.is_dir() {
+                true => std::fs::remove_dir_all(path).unwrap(),
+                false => std::fs::remove_file(path).unwrap(),
+            }
+        }
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    fn read_files(root: &Path) -> Files {
+        fn read(root: &Path, dir: &Path, files: &mut Files) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.file_name() == Some(".git".as_ref()) {
+                    continue;
+                }
+                match path.is_dir() {
+                    true => read(root, &path, files),
+                    false => {
+                        let relative = path.strip_prefix(root).unwrap().to_owned();
+                        files.insert(relative, std::fs::read(&path).unwrap());
+                    }
+                }
+            }
+        }
+        let mut files = Files::new();
+        read(root, root, &mut files);
+        files
+    }
+
+    // A repo whose snapshots are taken at the time the returned clock is set to
+    fn fixed_repo(repo: Repository) -> (Repo, Arc<FixedClock>) {
+        let clock = Arc::new(FixedClock::new(UNIX_EPOCH + START));
+        (Repo::new(repo).with_clock(clock.clone()), clock)
+    }
+
+    fn snapshot_at(repo: &Repo, clock: &FixedClock, now: SystemTime) -> Option<Oid> {
+        clock.set(now);
+        repo.snapshot().unwrap();
+        repo.find_snapshot(None).ok().map(|snapshot| snapshot.id())
+    }
+
+    const START: Duration = Duration::from_secs(1_650_000_000);
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn restore_round_trip(
+            files in files_strategy(),
+            edited in files_strategy(),
+            config in config_strategy(),
+        ) {
+            let temp_dir = tempdir().unwrap();
+            let (repo, mut git_config) = test_repo(temp_dir.path());
+            apply_config(&mut git_config, config);
+            let (repo, clock) = fixed_repo(repo);
+            write_files(temp_dir.path(), &files);
+            snapshot_at(&repo, &clock, UNIX_EPOCH + START).unwrap();
+
+            write_files(temp_dir.path(), &edited);
+            repo.restore(None, &[], None).unwrap();
+            prop_assert_eq!(files, read_files(temp_dir.path()));
+        }
+
+        #[test]
+        fn unchanged_snapshot_idempotent(
+            files in files_strategy(),
+            config in config_strategy(),
+            later in 0..60u64,
+        ) {
+            let temp_dir = tempdir().unwrap();
+            let (repo, mut git_config) = test_repo(temp_dir.path());
+            apply_config(&mut git_config, config);
+            let (repo, clock) = fixed_repo(repo);
+            write_files(temp_dir.path(), &files);
+            let first = snapshot_at(&repo, &clock, UNIX_EPOCH + START);
+
+            // rewritten with the same contents
+            write_files(temp_dir.path(), &files);
+            let second = snapshot_at(&repo, &clock, UNIX_EPOCH + START + Duration::from_secs(later));
+            prop_assert_eq!(first, second);
+        }
+
+        #[test]
+        fn snapshot_deterministic(files in files_strategy(), config in config_strategy()) {
+            let ids: Vec<_> = (0..2)
+                .map(|_| {
+                    let temp_dir = tempdir().unwrap();
+                    let (repo, mut git_config) = test_repo(temp_dir.path());
+                    apply_config(&mut git_config, config);
+                    let (repo, clock) = fixed_repo(repo);
+                    write_files(temp_dir.path(), &files);
+                    snapshot_at(&repo, &clock, UNIX_EPOCH + START)
+                })
+                .collect();
+            prop_assert_eq!(&ids[0], &ids[1]);
+        }
+
+        #[test]
+        fn squash_keeps_latest_snapshot(
+            files in files_strategy(),
+            edits in prop::collection::vec(
+                (path_strategy(), prop::option::of(prop::collection::vec(any::<u8>(), 0..32)), 0..10u64),
+                1..12,
+            ),
+        ) {
+            let temp_dir = tempdir().unwrap();
+            let (repo, mut git_config) = test_repo(temp_dir.path());
+            apply_config(&mut git_config, [false, true, false]);
+            let (repo, clock) = fixed_repo(repo);
+            let mut files = files;
+            let mut now = UNIX_EPOCH + START;
+            write_files(temp_dir.path(), &files);
+            snapshot_at(&repo, &clock, now).unwrap();
+
+            for (path, content, elapsed) in edits {
+                let previous = repo.find_snapshot(None).unwrap();
+                match content {
+                    Some(content) => insert_file(&mut files, path, content),
+                    None => {
+                        files.remove(&path);
+                    }
+                }
+                // some edits are after the session gap
+                now += Duration::from_secs(elapsed);
+                write_files(temp_dir.path(), &files);
+                snapshot_at(&repo, &clock, now).unwrap();
+
+                // the previous snapshot's tree is still the parent of the latest one, squashed or not
+                let latest = repo.find_snapshot(None).unwrap();
+                if latest.id() != previous.id() {
+                    prop_assert_eq!(previous.tree_id(), latest.parent(0).unwrap().tree_id());
+                }
+                repo.restore(None, &[], None).unwrap();
+                prop_assert_eq!(&files, &read_files(temp_dir.path()));
+            }
+        }
+    }
 }
@@ -1,16 +1,43 @@
-use log::{error, info};
+use git2::{Index, Repository};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::from_reader;
+use serde_json::{from_reader, to_writer};
 use std::{
-    fs::{canonicalize, OpenOptions},
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{canonicalize, create_dir_all, read_dir, write, OpenOptions},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::sync::Notify;
+use tracing::info_span;
 
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcServer;
 use crate::{
-    watcher::{WatchMode, Watcher},
-    Error, Repo,
+    api::{ApiConfig, ApiServer, ApiState},
+    cancel::InFlight,
+    events::{self, EventSender, WatchEvent},
+    failures::Failure,
+    filter::{BranchFilter, EventFilter, DEFAULT_IGNORE_PATTERNS},
+    history::group_id,
+    metadata::{DiffStats, Trigger},
+    notify::{ChannelConfig, Notifications},
+    overlay,
+    pause::Pause,
+    performance::PerformanceConfig,
+    recorder::{self, Record},
+    repo::ConfigStamp,
+    report::{Report, ReportConfig},
+    settings::{SettingLayers, SettingOverrides},
+    setup::RepoDefaults,
+    stream::SnapshotStream,
+    util::{catch_panic, path_starts_with, repo_id, run_blocking},
+    watcher::{EventKind, Handler, WatchMode, WatchStrategy, Watcher, DEFAULT_EVENT_KINDS},
+    Check, Error, Repo,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -21,17 +48,416 @@ pub struct WatchConfig {
     pub mode: WatchMode,
     #[serde(with = "humantime_serde")]
     pub debounce_period: Duration,
+    /// Threads and timings of every repo, unless set on the command line or the environment
+    #[serde(flatten)]
+    pub settings: SettingOverrides,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub strategy: WatchStrategy,
+    #[serde(default = "default_event_kinds")]
+    pub event_kinds: Vec<EventKind>,
+    /// File name globs of paths whose events are dropped, replaces the editor temp file defaults
+    #[serde(default = "default_ignore_patterns")]
+    pub ignore_patterns: Vec<String>,
+    /// Repos snapshotted together whenever one of them is
+    #[serde(default)]
+    pub groups: Vec<GroupConfig>,
+    /// Git config applied to repos as they're added
+    #[serde(default)]
+    pub defaults: RepoDefaults,
+    /// How often the watched repos are checked for deleted branches whose snapshot branches are
+    /// retired, see `snapshot.deletedbranches`
+    #[serde(with = "humantime_serde", default = "default_branch_scan_interval")]
+    pub branch_scan_interval: Duration,
+    /// How often the watched repos are checked for pushes held back by a push interval, see
+    /// `snapshot.push.interval`
+    #[serde(with = "humantime_serde", default = "default_push_scan_interval")]
+    pub push_scan_interval: Duration,
+    /// How often the watchdog checks the watcher still delivers events, rebuilding it when not
+    #[serde(with = "humantime_serde", default = "default_watchdog_interval")]
+    pub watchdog_interval: Duration,
+    /// How long a snapshot may run before the watcher counts as stuck
+    #[serde(with = "humantime_serde", default = "default_stall_timeout")]
+    pub stall_timeout: Duration,
+    /// How long a snapshot or push of the watcher may run before it's given up on, no limit when
+    /// zero
+    #[serde(with = "humantime_serde", default)]
+    pub handler_timeout: Duration,
+    /// Branch globs snapshots are limited to, all branches when empty
+    #[serde(default)]
+    pub branch_allow: Vec<String>,
+    /// Branch globs never snapshotted
+    #[serde(default)]
+    pub branch_deny: Vec<String>,
+    /// Channels notified with digests of failed snapshots
+    #[serde(default)]
+    pub notifications: Vec<ChannelConfig>,
+    /// Digests of snapshot activity across the watched repos
+    #[serde(default)]
+    pub reports: Vec<ReportConfig>,
+    /// HTTP API to manage the watcher, read when it starts
+    #[serde(default)]
+    pub api: Option<ApiConfig>,
+    /// gRPC control service, read when the watcher starts
+    #[cfg(feature = "grpc")]
+    #[serde(default)]
+    pub grpc: Option<ApiConfig>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn default_branch_scan_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_push_scan_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_watchdog_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_stall_timeout() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+fn default_event_kinds() -> Vec<EventKind> {
+    DEFAULT_EVENT_KINDS.to_vec()
+}
+
+fn default_ignore_patterns() -> Vec<String> {
+    DEFAULT_IGNORE_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename = "camelCase")]
 pub struct RepoConfig {
     pub path: PathBuf,
+    #[serde(default)]
+    pub trigger: TriggerMode,
+    /// Minimum time between two snapshots, changes in between are snapshotted together
+    #[serde(with = "humantime_serde", default)]
+    pub min_snapshot_interval: Option<Duration>,
+    /// Subdirectories snapshotted on branches of their own instead of the whole repo
+    #[serde(default)]
+    pub streams: Vec<SnapshotStream>,
+    /// A directory without a repo, snapshotted into one kept in the data directory, see
+    /// `overlay::create`
+    #[serde(default)]
+    pub overlay: bool,
+    /// Threads and timings of the repo, over the ones of every repo
+    #[serde(flatten)]
+    pub settings: SettingOverrides,
+}
+
+/// Repos whose snapshots are taken together, sharing a group id so their state can be restored
+/// as a whole
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GroupConfig {
+    pub name: String,
+    pub repos: Vec<PathBuf>,
+}
+
+/// A group of watched repos, snapshotted one trigger at a time
+struct RepoGroup {
+    name: String,
+    repos: Vec<PathBuf>,
+    lock: Mutex<()>,
+}
+
+/// When the watcher snapshots the changes in a repo
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TriggerMode {
+    /// Once the debounce period has passed after a change
+    #[default]
+    OnSave,
+    /// Every interval, when there were changes since the last snapshot
+    OnInterval {
+        #[serde(with = "humantime_serde")]
+        interval: Duration,
+    },
+    /// Once there were no changes for the idle time
+    OnIdle {
+        #[serde(with = "humantime_serde")]
+        idle: Duration,
+    },
+    /// Never by the watcher, only through the command line or the API
+    ManualOnly,
+}
+
+impl TriggerMode {
+    /// Handler of the repo's debounced changes, None when they aren't watched
+    fn handler(self, snapshot: Arc<SnapshotFn>) -> Option<Box<dyn Handler + Send + Sync>> {
+        match self {
+            Self::OnSave => Some(Box::new(move |path, changed_paths| {
+                snapshot(path, changed_paths)
+            })),
+            Self::OnInterval { interval } => {
+                let pending = Pending::default();
+                pending.schedule_every(interval, snapshot);
+                Some(Box::new(move |path, changed_paths| {
+                    pending.add(path, changed_paths);
+                }))
+            }
+            Self::OnIdle { idle } => {
+                let pending = Pending::default();
+                Some(Box::new(move |path, changed_paths| {
+                    let generation = pending.add(path, changed_paths);
+                    let pending = pending.clone();
+                    let snapshot = snapshot.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(idle).await;
+                        // later changes restarted the idle time
+                        if let Some((path, changed_paths)) = pending.take_if(generation) {
+                            snapshot(path, changed_paths);
+                        }
+                    });
+                }))
+            }
+            Self::ManualOnly => None,
+        }
+    }
 }
 
 type SyncWatcher = Arc<Mutex<Watcher>>;
 
-pub struct RepoWatcher(SyncWatcher);
+// restarts in a row after which they're logged as errors rather than warnings
+const ESCALATE_RESTARTS: u32 = 3;
+
+// how often a paused watcher checks whether it can resume
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Snapshots a watched repo given its root and the paths that changed beneath it
+type SnapshotFn = dyn Fn(PathBuf, Vec<PathBuf>) + Send + Sync;
+
+/// Paths a snapshot of changes was taken of, relative to the working tree, with its stats when
+/// there was a change to commit
+pub(crate) type SnapshottedPaths = (Vec<PathBuf>, Option<DiffStats>);
+
+/// Changes of a repo waiting for their trigger
+#[derive(Clone, Default)]
+struct Pending(Arc<Mutex<PendingState>>);
+
+#[derive(Default)]
+struct PendingState {
+    root: Option<PathBuf>,
+    changed_paths: BTreeSet<PathBuf>,
+    /// Bumped with every change
+    generation: u64,
+}
+
+impl Pending {
+    fn add(&self, root: PathBuf, changed_paths: Vec<PathBuf>) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        state.root = Some(root);
+        state.changed_paths.extend(changed_paths);
+        state.generation += 1;
+        state.generation
+    }
+
+    /// The changes when there were any and none came after `generation`
+    fn take_if(&self, generation: u64) -> Option<(PathBuf, Vec<PathBuf>)> {
+        let mut state = self.0.lock().unwrap();
+        if state.generation != generation || state.changed_paths.is_empty() {
+            return None;
+        }
+        let changed_paths = std::mem::take(&mut state.changed_paths);
+        Some((state.root.clone()?, changed_paths.into_iter().collect()))
+    }
+
+    // Until the handler holding the changes is dropped
+    fn schedule_every(&self, interval: Duration, snapshot: Arc<SnapshotFn>) {
+        let weak = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let pending = match weak.upgrade() {
+                    Some(state) => Pending(state),
+                    None => break,
+                };
+                let generation = pending.0.lock().unwrap().generation;
+                if let Some((path, changed_paths)) = pending.take_if(generation) {
+                    snapshot(path, changed_paths);
+                }
+            }
+        });
+    }
+}
+
+/// Holds back snapshots of a repo sooner than the interval after its last one, coalescing their
+/// changed paths into a single snapshot once the interval has passed
+#[derive(Clone)]
+struct Throttle {
+    interval: Duration,
+    state: Arc<Mutex<ThrottleState>>,
+    snapshot: Arc<SnapshotFn>,
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    last: Option<Instant>,
+    /// Changed paths held back, a snapshot of them is scheduled while set
+    pending: Option<BTreeSet<PathBuf>>,
+}
+
+impl Throttle {
+    fn wrap(interval: Duration, snapshot: Arc<SnapshotFn>) -> Arc<SnapshotFn> {
+        let throttle = Self {
+            interval,
+            state: Arc::default(),
+            snapshot,
+        };
+        Arc::new(move |path, changed_paths| throttle.run(path, changed_paths))
+    }
+
+    fn run(&self, path: PathBuf, changed_paths: Vec<PathBuf>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = &mut state.pending {
+            pending.extend(changed_paths);
+            return;
+        }
+        let now = Instant::now();
+        match state.last.map(|last| last + self.interval) {
+            Some(next) if next > now => {
+                recorder::record(|| Record::Held {
+                    root: path.clone(),
+                    reason: "min snapshot interval".to_owned(),
+                });
+                state.pending = Some(changed_paths.into_iter().collect());
+                let throttle = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(next.into()).await;
+                    let changed_paths = {
+                        let mut state = throttle.state.lock().unwrap();
+                        state.last = Some(Instant::now());
+                        state.pending.take().unwrap_or_default()
+                    };
+                    (throttle.snapshot)(path, changed_paths.into_iter().collect());
+                });
+            }
+            _ => {
+                state.last = Some(now);
+                drop(state);
+                (self.snapshot)(path, changed_paths);
+            }
+        }
+    }
+}
+
+/// Holds back snapshots while the watcher is paused, snapshotting the changed paths in between once
+/// the pause is over
+#[derive(Clone)]
+struct PauseGate {
+    pause_path: PathBuf,
+    /// Changed paths held back, a snapshot of them is scheduled while set
+    pending: Arc<Mutex<Option<BTreeSet<PathBuf>>>>,
+    snapshot: Arc<SnapshotFn>,
+}
+
+impl PauseGate {
+    fn wrap(pause_path: PathBuf, snapshot: Arc<SnapshotFn>) -> Arc<SnapshotFn> {
+        let gate = Self {
+            pause_path,
+            pending: Arc::default(),
+            snapshot,
+        };
+        Arc::new(move |path, changed_paths| gate.run(path, changed_paths))
+    }
+
+    fn pause(&self) -> Option<Pause> {
+        Pause::load(&self.pause_path).unwrap_or_else(|err| {
+            error!(
+                "failed to read pause {}: {}",
+                self.pause_path.display(),
+                err
+            );
+            None
+        })
+    }
+
+    fn run(&self, path: PathBuf, changed_paths: Vec<PathBuf>) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(pending) = &mut *pending {
+            pending.extend(changed_paths);
+            return;
+        }
+        if self.pause().is_none() {
+            drop(pending);
+            (self.snapshot)(path, changed_paths);
+            return;
+        }
+        recorder::record(|| Record::Held {
+            root: path.clone(),
+            reason: "paused".to_owned(),
+        });
+        *pending = Some(changed_paths.into_iter().collect());
+        let gate = self.clone();
+        tokio::spawn(async move {
+            // polled so that a resume or a longer pause is picked up
+            while let Some(pause) = gate.pause() {
+                let remaining = pause.remaining(SystemTime::now());
+                tokio::time::sleep(remaining.min(PAUSE_POLL_INTERVAL)).await;
+            }
+            let changed_paths = gate.pending.lock().unwrap().take().unwrap_or_default();
+            (gate.snapshot)(path, changed_paths.into_iter().collect());
+        });
+    }
+}
+
+/// Repo handle reused between events, reopened once the repo's git config changes
+struct CachedRepo {
+    repo: Repo,
+    config_stamp: ConfigStamp,
+}
+
+impl CachedRepo {
+    fn new(repo: Repo) -> Self {
+        let config_stamp = repo.config_stamp();
+        Self { repo, config_stamp }
+    }
+
+    fn is_stale(&self) -> bool {
+        self.repo.config_stamp() != self.config_stamp
+    }
+
+    // Taken out of the cache for a snapshot, which puts it back unless it was given up on
+    fn take(
+        cache: &mut Option<Self>,
+        open: impl FnOnce() -> Result<Repo, Error>,
+    ) -> Result<Self, Error> {
+        match cache.take() {
+            Some(cached) if !cached.is_stale() => Ok(cached),
+            _ => Ok(Self::new(open()?)),
+        }
+    }
+}
+
+/// State outliving the watchers rebuilt on config changes
+#[derive(Clone)]
+struct WatchContext {
+    events: EventSender,
+    repos: Arc<Mutex<Vec<PathBuf>>>,
+    /// Set when started from a config file
+    pause_path: Option<PathBuf>,
+    /// Times the watchdog rebuilt the watcher
+    restarts: Arc<AtomicU64>,
+    /// Asks the watchdog to rebuild the watcher, e.g. when worktrees were added or removed
+    rebuild: Arc<Notify>,
+    /// Repos with a snapshot or push running, kept across rebuilds as ones given up on go on
+    in_flight: InFlight,
+}
+
+pub struct RepoWatcher {
+    watcher: SyncWatcher,
+    context: WatchContext,
+    api: Option<ApiServer>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcServer>,
+}
 
 impl Default for WatchConfig {
     fn default() -> Self {
@@ -39,90 +465,1092 @@ impl Default for WatchConfig {
             repos: Vec::default(),
             mode: WatchMode::default(),
             debounce_period: Duration::from_secs(30),
+            settings: SettingOverrides::default(),
+            performance: PerformanceConfig::default(),
+            strategy: WatchStrategy::default(),
+            event_kinds: default_event_kinds(),
+            ignore_patterns: default_ignore_patterns(),
+            groups: Vec::new(),
+            defaults: RepoDefaults::default(),
+            branch_scan_interval: default_branch_scan_interval(),
+            push_scan_interval: default_push_scan_interval(),
+            watchdog_interval: default_watchdog_interval(),
+            stall_timeout: default_stall_timeout(),
+            handler_timeout: Duration::ZERO,
+            branch_allow: Vec::new(),
+            branch_deny: Vec::new(),
+            notifications: Vec::new(),
+            reports: Vec::new(),
+            api: None,
+            #[cfg(feature = "grpc")]
+            grpc: None,
         }
     }
 }
 
-impl Default for WatchMode {
-    fn default() -> Self {
-        Self::Event
-    }
-}
-
 impl RepoWatcher {
     pub fn new(config: WatchConfig) -> Result<Self, Error> {
-        Ok(Self(Arc::new(Mutex::new(Self::watcher(config)?))))
+        Self::start(config, None)
     }
 
     pub fn with_config(config_path: impl AsRef<Path>) -> Result<Self, Error> {
         let config_path = config_path.as_ref();
-        let config = Self::open_config(config_path)?;
+        let config = load_config(config_path)?;
+
+        let watcher = Self::start(config, Some(config_path))?;
+        Self::watch_config(
+            watcher.watcher.clone(),
+            config_path,
+            watcher.context.clone(),
+        )?;
+
+        Ok(watcher)
+    }
+
+    /// A watcher for each of `config_paths`, each with its own reloads, pause and API. A repo
+    /// watched by more than one of them is snapshotted by each, which is logged.
+    pub fn with_configs(config_paths: &[PathBuf]) -> Result<Vec<Self>, Error> {
+        let watchers = config_paths
+            .iter()
+            .map(Self::with_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut watched: HashMap<PathBuf, &Path> = HashMap::new();
+        for (watcher, config_path) in watchers.iter().zip(config_paths) {
+            for repo in watcher.context.repos.lock().unwrap().iter() {
+                if let Some(first) = watched.insert(repo.clone(), config_path) {
+                    warn!(
+                        "{} is watched by both {} and {}",
+                        repo.display(),
+                        first.display(),
+                        config_path.display()
+                    );
+                }
+            }
+        }
+        Ok(watchers)
+    }
 
-        let debounce_period = config.debounce_period.clone();
+    fn start(config: WatchConfig, config_path: Option<&Path>) -> Result<Self, Error> {
+        let context = WatchContext {
+            events: events::channel(),
+            repos: Arc::default(),
+            pause_path: config_path.map(Pause::path),
+            restarts: Arc::default(),
+            rebuild: Arc::default(),
+            in_flight: InFlight::default(),
+        };
+        let started = SystemTime::now();
+        let state = |config: &ApiConfig| -> Result<ApiState, Error> {
+            Ok(ApiState {
+                token: config.token.reveal()?,
+                config_path: config_path.map(Path::to_owned),
+                repos: context.repos.clone(),
+                events: context.events.clone(),
+                started,
+                watcher_restarts: context.restarts.clone(),
+            })
+        };
+        let api = match &config.api {
+            Some(api) => Some(ApiServer::start(api.listen, state(api)?)?),
+            None => None,
+        };
+        #[cfg(feature = "grpc")]
+        let grpc = match &config.grpc {
+            Some(grpc) => Some(GrpcServer::start(grpc.listen, state(grpc)?)?),
+            None => None,
+        };
+        let watcher = Arc::new(Mutex::new(Self::watcher(&config, &context)?));
+        Self::start_watchdog(&watcher, config, config_path, &context);
+        Ok(Self {
+            watcher,
+            context,
+            api,
+            #[cfg(feature = "grpc")]
+            grpc,
+        })
+    }
+
+    /// Snapshots and config reloads as they happen
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<WatchEvent> {
+        self.context.events.subscribe()
+    }
 
-        let watcher = Self::new(config)?;
-        Self::watch_config(watcher.0.clone(), config_path, debounce_period)?;
+    /// Address the API is listening on when enabled
+    pub fn api_addr(&self) -> Option<std::net::SocketAddr> {
+        self.api.as_ref().map(ApiServer::local_addr)
+    }
+
+    /// Address the gRPC service is listening on when enabled
+    #[cfg(feature = "grpc")]
+    pub fn grpc_addr(&self) -> Option<std::net::SocketAddr> {
+        self.grpc.as_ref().map(GrpcServer::local_addr)
+    }
+
+    /// Times the watcher was rebuilt after it stopped delivering events
+    pub fn restarts(&self) -> u64 {
+        self.context.restarts.load(Ordering::Relaxed)
+    }
 
+    fn watcher(config: &WatchConfig, context: &WatchContext) -> Result<Watcher, Error> {
+        config.performance.apply()?;
+        let debounce_period = config.debounce_period;
+        let filter = EventFilter::new(&config.event_kinds, &config.ignore_patterns)?;
+        let mut watcher = Watcher::new(&config.mode, debounce_period, filter)?;
+        let notifications = Self::notifications(&config.notifications)?;
+        let branch_filter = BranchFilter::new(&config.branch_allow, &config.branch_deny)?;
+        let groups = config
+            .groups
+            .iter()
+            .map(|group| {
+                Ok(Arc::new(RepoGroup {
+                    name: group.name.clone(),
+                    repos: group
+                        .repos
+                        .iter()
+                        .map(canonicalize)
+                        .collect::<Result<_, _>>()?,
+                    lock: Mutex::new(()),
+                }))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let paths: Vec<PathBuf> = config.repos.iter().map(|r| r.path.clone()).collect();
+        for report in &config.reports {
+            Self::schedule_report(report.clone(), paths.clone(), &notifications);
+        }
+        Self::schedule_branch_scan(config.branch_scan_interval, paths.clone(), &notifications);
+        Self::schedule_push_scan(
+            config.push_scan_interval,
+            config.handler_timeout,
+            paths.clone(),
+            &notifications,
+            context,
+        );
+        let mut repos = Vec::new();
+        let mut watched: HashMap<_, PathBuf> = HashMap::new();
+        let mut watched_roots = Vec::new();
+        for RepoConfig {
+            path,
+            trigger,
+            min_snapshot_interval,
+            streams,
+            overlay,
+            settings,
+        } in &config.repos
+        {
+            let path = canonicalize(path)?;
+            // created again where the data directory doesn't have it, e.g. on a new machine
+            if *overlay {
+                overlay::create(&path)?;
+            }
+            // a repo reached through several paths, e.g. a bind mount, would be snapshotted and
+            // pushed once for each
+            if let Some(id) = repo_id(&path) {
+                if let Some(first) = watched.get(&id) {
+                    warn!(
+                        "{} is the same repo as {}, watching it once",
+                        path.display(),
+                        first.display()
+                    );
+                    continue;
+                }
+                watched.insert(id, path.clone());
+            }
+            repos.push(path.clone());
+            let group = groups.iter().find(|g| g.repos.contains(&path)).cloned();
+            // a monorepo's streams are watched and snapshotted each on their own
+            let mut roots = match streams.is_empty() {
+                true => vec![(path.clone(), None, group)],
+                false => streams
+                    .iter()
+                    .map(|stream| {
+                        Ok((
+                            canonicalize(path.join(&stream.path))?,
+                            Some(stream.clone()),
+                            group.clone(),
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?,
+            };
+            // linked worktrees are snapshotted like the repo, on their own branches
+            let worktrees = match overlay {
+                true => Vec::new(),
+                false => linked_worktrees(&path),
+            };
+            for worktree in &worktrees {
+                if let Some(id) = repo_id(worktree) {
+                    if watched.contains_key(&id) {
+                        continue;
+                    }
+                    watched.insert(id, worktree.clone());
+                }
+                repos.push(worktree.clone());
+                match streams.is_empty() {
+                    true => roots.push((worktree.clone(), None, None)),
+                    // streams the worktree's checkout doesn't have are left out
+                    false => roots.extend(streams.iter().filter_map(|stream| {
+                        let root = canonicalize(worktree.join(&stream.path)).ok()?;
+                        Some((root, Some(stream.clone()), None))
+                    })),
+                }
+            }
+            if *trigger != TriggerMode::ManualOnly && !overlay {
+                if let Err(err) = Self::watch_worktrees(&mut watcher, &path, worktrees, context) {
+                    warn!(
+                        "can't watch {} for added worktrees: {:?}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+            for (root, stream, group) in roots {
+                let settings = SettingLayers {
+                    repo: *settings,
+                    watch: config.settings,
+                    ..Default::default()
+                };
+                let mut snapshot = Self::snapshot_handler(
+                    settings,
+                    branch_filter.clone(),
+                    stream,
+                    group,
+                    notifications.clone(),
+                    config.handler_timeout,
+                    context,
+                );
+                if let Some(pause_path) = &context.pause_path {
+                    snapshot = PauseGate::wrap(pause_path.clone(), snapshot);
+                }
+                if let Some(interval) = min_snapshot_interval {
+                    snapshot = Throttle::wrap(*interval, snapshot);
+                }
+                let handler = match trigger.handler(snapshot) {
+                    Some(handler) => handler,
+                    None => continue,
+                };
+                watched_roots.push(root.clone());
+                match config.strategy {
+                    WatchStrategy::Recursive => watcher.watch_path(root, handler)?,
+                    WatchStrategy::TrackedDirs => {
+                        let dirs = tracked_dirs(&root)?;
+                        let tracked_root = root.clone();
+                        // the tracked directories change along with the repo index
+                        let provider = move |changed_paths: &[PathBuf]| {
+                            if changed_paths.iter().any(|p| p.ends_with(".git/index")) {
+                                tracked_dirs(&tracked_root).ok()
+                            } else {
+                                None
+                            }
+                        };
+                        watcher.watch_dirs(root, dirs, Box::new(provider), handler)?
+                    }
+                }
+            }
+        }
+        *context.repos.lock().unwrap() = repos;
+        recorder::record(|| Record::Watching {
+            roots: watched_roots,
+            debounce_period,
+            event_kinds: config.event_kinds.clone(),
+            ignore_patterns: config.ignore_patterns.clone(),
+        });
         Ok(watcher)
     }
 
-    fn open_config(config_path: &Path) -> Result<WatchConfig, Error> {
-        let f = OpenOptions::new().read(true).open(config_path)?;
-        Ok(from_reader(f)?)
+    // Rebuild the watcher once the linked worktrees of the repo at `path` are no longer `worktrees`,
+    // after one was added, removed or had snapshots turned on or off
+    fn watch_worktrees(
+        watcher: &mut Watcher,
+        path: &Path,
+        worktrees: Vec<PathBuf>,
+        context: &WatchContext,
+    ) -> Result<(), Error> {
+        let git_repo = Repository::discover(path)?;
+        let dir = match git_repo.is_worktree() {
+            true => git_repo.path().parent().map(Path::to_owned),
+            false => Some(git_repo.path().join("worktrees")),
+        };
+        let Some(dir) = dir else {
+            return Ok(());
+        };
+        // git creates it along with the first worktree, it has to exist to be watched until then
+        create_dir_all(&dir)?;
+        let path = path.to_owned();
+        let rebuild = context.rebuild.clone();
+        watcher.watch_path(
+            dir,
+            Box::new(move |_: PathBuf, _| {
+                // most changes are git's bookkeeping within a worktree's git dir
+                if linked_worktrees(&path) != worktrees {
+                    info!(
+                        "worktrees of {} changed, rebuilding the watcher",
+                        path.display()
+                    );
+                    rebuild.notify_one();
+                }
+            }),
+        )
+    }
+
+    // Snapshots a repo on the watcher's behalf, along with the rest of its group, reporting the
+    // outcome to subscribers and channels
+    fn snapshot_handler(
+        settings: SettingLayers,
+        branch_filter: BranchFilter,
+        stream: Option<SnapshotStream>,
+        group: Option<Arc<RepoGroup>>,
+        notifications: Arc<Notifications>,
+        timeout: Duration,
+        context: &WatchContext,
+    ) -> Arc<SnapshotFn> {
+        let cache = Mutex::new(None);
+        let events = context.events.clone();
+        let in_flight = context.in_flight.clone();
+        let handle = move |path: PathBuf, changed_paths: Vec<PathBuf>| {
+            // named like the repo's log lines
+            let name = path
+                .file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy();
+            let _span = info_span!(
+                "watch_event",
+                repo = %name,
+                path = %path.display(),
+                changed = changed_paths.len()
+            )
+            .entered();
+            // a panic is caught below, but recover a poisoned lock rather than wedge the repo
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            let open = |path: &Path| -> Result<Repo, Error> {
+                Ok(Repo::from_path(path)?
+                    .with_settings(settings)
+                    .with_trigger(Trigger::Watcher)
+                    .with_branch_filter(branch_filter.clone()))
+            };
+            // the group's snapshots are taken one trigger at a time
+            let taking = group.as_ref().map(|group| {
+                let lock = group.lock.lock().unwrap_or_else(PoisonError::into_inner);
+                (lock, group_id(&group.name, SystemTime::now()))
+            });
+            let group_id = taking.as_ref().map(|(_, id)| id.as_str());
+
+            let open_own = || Ok(open(&path)?.with_stream(stream.clone()));
+            let result = catch_panic(|| {
+                // one given up on may still be at it, racing a new snapshot on the index and refs
+                let running = in_flight.start(&path)?;
+                let CachedRepo { repo, config_stamp } = CachedRepo::take(&mut cache, open_own)?;
+                let group_id = group_id.map(str::to_owned);
+                // a repo hanging on the filesystem is given up on, along with its cached handle
+                let (cached, result) = running.run_within(timeout, move |cancel| {
+                    let cached = CachedRepo {
+                        repo: repo.with_cancel(cancel),
+                        config_stamp,
+                    };
+                    let result =
+                        snapshot_changes(&cached.repo, &changed_paths, group_id.as_deref());
+                    (cached, result)
+                })?;
+                *cache = Some(cached);
+                result
+            });
+            // the cached repo may be left half way through a snapshot
+            if let Err(Error::Panicked(_)) = result {
+                *cache = None;
+            }
+            let snapshotted = matches!(result, Ok(Some(_)));
+            Self::report_snapshot(&path, result, &events, &notifications);
+
+            if let (Some(group), Some(group_id), true) = (&group, group_id, snapshotted) {
+                for member in group.repos.iter().filter(|member| **member != path) {
+                    let result = catch_panic(|| {
+                        let running = in_flight.start(member)?;
+                        let repo = open(member)?;
+                        let group_id = group_id.to_owned();
+                        let snapshot = running.run_within(timeout, move |cancel| {
+                            repo.with_cancel(cancel).snapshot_group(None, &group_id)
+                        })??;
+                        // members without changes of their own aren't reported as snapshotted
+                        Ok(snapshot.map(|s| (Vec::new(), s.stats)))
+                    });
+                    Self::report_snapshot(member, result, &events, &notifications);
+                }
+            }
+        };
+        // called from watcher tasks, git and waiting out retries or a timeout block
+        Arc::new(move |path, changed_paths| run_blocking(|| handle(path, changed_paths)))
+    }
+
+    // No subscribers isn't an error
+    fn report_snapshot(
+        path: &Path,
+        result: Result<Option<SnapshottedPaths>, Error>,
+        events: &EventSender,
+        notifications: &Notifications,
+    ) {
+        recorder::record(|| Record::outcome(path, &result));
+        match result {
+            Ok(Some((changed_paths, stats))) => {
+                let _ = events.send(WatchEvent::Snapshot {
+                    repo: path.to_owned(),
+                    changed_paths,
+                    stats,
+                    time: SystemTime::now(),
+                });
+            }
+            Ok(None) => {}
+            Err(err @ (Error::LowDiskSpace { .. } | Error::StillRunning(_))) => {
+                warn!("snapshot skipped in {}: {}", path.display(), err);
+                let _ = events.send(WatchEvent::Skipped {
+                    repo: path.to_owned(),
+                    reason: err.to_string(),
+                    time: SystemTime::now(),
+                });
+            }
+            Err(err) => {
+                error!("snapshot error in {}: {:?}", path.display(), err);
+                let failure = Failure {
+                    repo: path.to_owned(),
+                    error: err.to_string(),
+                    code: err.code(),
+                    hint: err.hint().map(str::to_owned),
+                    time: SystemTime::now(),
+                };
+                let event = match err {
+                    // the phase shows where a repo hangs, e.g. on an unresponsive filesystem
+                    Error::TimedOut { phase, after } => WatchEvent::TimedOut {
+                        repo: failure.repo.clone(),
+                        phase: phase.to_owned(),
+                        after,
+                        time: failure.time,
+                    },
+                    _ => WatchEvent::Failed {
+                        repo: failure.repo.clone(),
+                        error: failure.error.clone(),
+                        code: failure.code,
+                        hint: failure.hint.clone(),
+                        time: failure.time,
+                    },
+                };
+                let _ = events.send(event);
+                notifications.failed(failure);
+            }
+        }
     }
 
-    fn watcher(config: WatchConfig) -> Result<Watcher, Error> {
-        let debounce_period = config.debounce_period.clone();
-        let mut watcher = Watcher::new(&config.mode, debounce_period.clone())?;
-        for RepoConfig { path } in &config.repos {
-            let handler = move |path: PathBuf| {
-                let rel = path.strip_prefix(&path).unwrap();
-                if rel.starts_with(".git") {
-                    return;
+    // Channels shared by the repo handlers, flushing held back failures until they are all dropped
+    fn notifications(configs: &[ChannelConfig]) -> Result<Arc<Notifications>, Error> {
+        let notifications = Arc::new(Notifications::new(configs)?);
+        if let Some(interval) = notifications.flush_interval() {
+            let weak = Arc::downgrade(&notifications);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    match weak.upgrade() {
+                        Some(notifications) => notifications.flush(),
+                        None => break,
+                    }
                 }
+            });
+        }
+        Ok(notifications)
+    }
 
-                if let Ok(repo) = Repo::from_path(&path) {
-                    if !repo.is_ignored(rel).unwrap_or(false) {
-                        if let Err(err) = repo.snapshot() {
-                            error!(target: repo.name(), "snapshot error: {:?}", err);
-                        }
+    // Generated every period for as long as the notifications are in use by the repo handlers
+    fn schedule_report(
+        config: ReportConfig,
+        paths: Vec<PathBuf>,
+        notifications: &Arc<Notifications>,
+    ) {
+        let weak = Arc::downgrade(notifications);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config.period.duration()).await;
+                let notifications = match weak.upgrade() {
+                    Some(notifications) => notifications,
+                    None => break,
+                };
+                let report = Report::new(config.period, &paths, SystemTime::now());
+                if let Err(err) = deliver_report(&config, &report, &notifications) {
+                    error!("error delivering {} report: {:?}", config.period, err);
+                }
+            }
+        });
+    }
+
+    // Retires the snapshot branches of deleted branches for as long as the notifications are in
+    // use by the repo handlers
+    fn schedule_branch_scan(
+        interval: Duration,
+        paths: Vec<PathBuf>,
+        notifications: &Arc<Notifications>,
+    ) {
+        let weak = Arc::downgrade(notifications);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if weak.strong_count() == 0 {
+                    break;
+                }
+                for path in &paths {
+                    let result = Repo::from_path(path)
+                        .and_then(|repo| repo.retire_deleted_branches(SystemTime::now()));
+                    if let Err(err) = result {
+                        error!(
+                            "error checking {} for deleted branches: {:?}",
+                            path.display(),
+                            err
+                        );
                     }
                 }
-            };
-            watcher.watch_path(canonicalize(path)?, Box::new(handler))?;
+            }
+        });
+    }
+
+    // Squashes the sessions that closed and pushes the snapshots held back by push intervals once
+    // they're due, and uploads bundles to `snapshot.s3.bucket` with the s3 feature, for as long as
+    // the notifications are in use by the repo handlers
+    fn schedule_push_scan(
+        interval: Duration,
+        timeout: Duration,
+        paths: Vec<PathBuf>,
+        notifications: &Arc<Notifications>,
+        context: &WatchContext,
+    ) {
+        let weak = Arc::downgrade(notifications);
+        let events = context.events.clone();
+        let in_flight = context.in_flight.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if weak.strong_count() == 0 {
+                    break;
+                }
+                for path in &paths {
+                    let pushed = run_blocking(|| {
+                        let running = in_flight.start(path)?;
+                        let repo = Repo::from_path(path)?;
+                        running.run_within(timeout, move |cancel| {
+                            repo.with_cancel(cancel).push_due()
+                        })?
+                    });
+                    if let Err(err) = pushed {
+                        Self::scan_failed(&events, path, "pushing held back snapshots", err);
+                    }
+                    // as slow to upload as a push, so given up on alike
+                    #[cfg(feature = "s3")]
+                    if let Err(err) = run_blocking(|| {
+                        let running = in_flight.start(path)?;
+                        let repo = Repo::from_path(path)?;
+                        running.run_within(timeout, move |cancel| {
+                            crate::s3::backup_due(&repo.with_cancel(cancel))
+                        })?
+                    }) {
+                        Self::scan_failed(&events, path, "uploading snapshot bundle", err);
+                    }
+                }
+            }
+        });
+    }
+
+    fn scan_failed(events: &EventSender, path: &Path, doing: &str, err: Error) {
+        match err {
+            // tried again on the next scan
+            Error::StillRunning(_) => {
+                debug!("not {} of {}: {}", doing, path.display(), err)
+            }
+            err => {
+                error!("error {} of {}: {:?}", doing, path.display(), err);
+                if let Error::TimedOut { phase, after } = err {
+                    let _ = events.send(WatchEvent::TimedOut {
+                        repo: path.to_owned(),
+                        phase: phase.to_owned(),
+                        after,
+                        time: SystemTime::now(),
+                    });
+                }
+            }
         }
-        Ok(watcher)
     }
 
+    // Rebuild the watcher whenever it stops delivering events, from the config file when started
+    // from one so it isn't reverted to the config it started with
+    fn start_watchdog(
+        watcher: &SyncWatcher,
+        config: WatchConfig,
+        config_path: Option<&Path>,
+        context: &WatchContext,
+    ) {
+        let watcher = Arc::downgrade(watcher);
+        let config_path = config_path.map(Path::to_owned);
+        let context = context.clone();
+        tokio::spawn(async move {
+            let mut in_a_row = 0;
+            loop {
+                let requested = tokio::select! {
+                    _ = tokio::time::sleep(config.watchdog_interval) => false,
+                    _ = context.rebuild.notified() => true,
+                };
+                let watcher = match watcher.upgrade() {
+                    Some(watcher) => watcher,
+                    None => break,
+                };
+                if !requested {
+                    let fault = watcher.lock().unwrap().check(config.stall_timeout);
+                    let fault = match fault {
+                        Some(fault) => fault,
+                        None => {
+                            in_a_row = 0;
+                            continue;
+                        }
+                    };
+                    in_a_row += 1;
+                    if in_a_row < ESCALATE_RESTARTS {
+                        warn!("{}, restarting the watcher", fault);
+                    } else {
+                        error!(
+                            "{}, restarting the watcher {} times in a row, check the filesystem \
+                             and the watch limits",
+                            fault, in_a_row
+                        );
+                    }
+                }
+                let rebuilt = match &config_path {
+                    Some(config_path) => {
+                        load_config(config_path).and_then(|config| Self::watcher(&config, &context))
+                    }
+                    None => Self::watcher(&config, &context),
+                };
+                match rebuilt {
+                    Ok(rebuilt) => {
+                        *watcher.lock().unwrap() = rebuilt;
+                        if !requested {
+                            context.restarts.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if let Some(config_path) = &config_path {
+                            if let Err(err) =
+                                Self::watch_config(watcher.clone(), config_path, context.clone())
+                            {
+                                error!("{:?}", err);
+                            }
+                        }
+                    }
+                    Err(err) => error!("couldn't restart the watcher: {:?}", err),
+                }
+            }
+        });
+    }
+
+    // Reload the config whenever the file or one of its drop-ins changes
     fn watch_config(
         watcher: SyncWatcher,
         config_path: &Path,
-        period: Duration,
+        context: WatchContext,
     ) -> Result<(), Error> {
-        watcher.clone().lock().unwrap().watch_path(
-            config_path,
-            Box::new(move |path: PathBuf| {
+        let reload = |watcher: SyncWatcher, config_path: PathBuf, context: WatchContext| {
+            move |_: PathBuf, _| {
                 info!("Watcher detected config change, reloading config...");
-                if let Ok(config) = Self::open_config(&path) {
-                    if let Ok(w) = Self::watcher(config) {
+                if let Ok(config) = load_config(&config_path) {
+                    if let Ok(w) = Self::watcher(&config, &context) {
                         let mut w_lock = watcher.lock().unwrap();
                         *w_lock = w;
                         drop(w_lock);
-                        if let Err(err) = Self::watch_config(watcher.clone(), &path, period) {
+                        let _ = context.events.send(WatchEvent::ConfigReloaded {
+                            time: SystemTime::now(),
+                        });
+                        if let Err(err) =
+                            Self::watch_config(watcher.clone(), &config_path, context.clone())
+                        {
                             error!("{:?}", err);
                         }
                     }
                 }
-            }),
-        )
+            }
+        };
+        let mut w = watcher.lock().unwrap();
+        w.watch_path(
+            config_path,
+            Box::new(reload(
+                watcher.clone(),
+                config_path.to_owned(),
+                context.clone(),
+            )),
+        )?;
+        let drop_ins = drop_in_dir(config_path);
+        if drop_ins.is_dir() {
+            w.watch_path(
+                &drop_ins,
+                Box::new(reload(watcher.clone(), config_path.to_owned(), context)),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of snapshotting every watched repo once
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub snapshotted: Vec<PathBuf>,
+    pub unchanged: Vec<PathBuf>,
+    /// Repos left alone, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+impl RunSummary {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// The rules of the watcher of `config` deciding whether a change of the repo at `path` gets
+/// snapshotted now, followed by the repo's own, with the ones that would stop a snapshot
+pub fn explain(
+    config: &WatchConfig,
+    pause_path: Option<&Path>,
+    path: &Path,
+) -> Result<Vec<Check>, Error> {
+    let branch_filter = BranchFilter::new(&config.branch_allow, &config.branch_deny)?;
+    let repo = Repo::from_path(path)?.with_branch_filter(branch_filter);
+    let root = repo
+        .git_repo()
+        .workdir()
+        .and_then(|workdir| canonicalize(workdir).ok());
+    let repo_config = config
+        .repos
+        .iter()
+        .find(|r| root.is_some() && canonicalize(&r.path).ok() == root);
+    let mut checks = vec![Check::new(
+        "watched",
+        repo_config
+            .is_none()
+            .then(|| "not in the watcher config, see git snapshot watch".to_owned()),
+    )];
+    let pause = pause_path.map(Pause::load).transpose()?.flatten();
+    checks.push(Check::new(
+        "watcher not paused",
+        pause.map(|pause| {
+            format!(
+                "paused until {}",
+                humantime::format_rfc3339_seconds(pause.until)
+            )
+        }),
+    ));
+    checks.push(Check::new(
+        "triggered by changes",
+        repo_config
+            .filter(|r| r.trigger == TriggerMode::ManualOnly)
+            .map(|_| "the trigger is manualOnly".to_owned()),
+    ));
+    checks.extend(repo.explain()?);
+    Ok(checks)
+}
+
+/// Snapshot and push the changes of every repo in `config` once, the watcher's work without the
+/// watching. Repos only snapshotted manually are skipped, and nothing is done while the watcher
+/// is paused through `pause_path`.
+pub fn run_once(config: &WatchConfig, pause_path: Option<&Path>) -> Result<RunSummary, Error> {
+    let mut summary = RunSummary::default();
+    if let Some(pause) = pause_path.map(Pause::load).transpose()?.flatten() {
+        let reason = format!(
+            "watcher paused until {}",
+            humantime::format_rfc3339_seconds(pause.until)
+        );
+        for repo in &config.repos {
+            summary.skipped.push((repo.path.clone(), reason.clone()));
+        }
+        return Ok(summary);
+    }
+
+    let branch_filter = BranchFilter::new(&config.branch_allow, &config.branch_deny)?;
+    // a repo group's snapshots share one id for the run
+    let group_ids: Vec<(Vec<PathBuf>, String)> = config
+        .groups
+        .iter()
+        .map(|group| {
+            let repos = group.repos.iter().filter_map(|p| canonicalize(p).ok());
+            (repos.collect(), group_id(&group.name, SystemTime::now()))
+        })
+        .collect();
+    let mut seen: HashMap<_, PathBuf> = HashMap::new();
+    for repo_config in &config.repos {
+        let path = &repo_config.path;
+        if let Some(id) = repo_id(path) {
+            if let Some(first) = seen.get(&id) {
+                let reason = format!("same repo as {}", first.display());
+                summary.skipped.push((path.clone(), reason));
+                continue;
+            }
+            seen.insert(id, path.clone());
+        }
+        if repo_config.trigger == TriggerMode::ManualOnly {
+            summary
+                .skipped
+                .push((path.clone(), "only snapshotted manually".to_owned()));
+            continue;
+        }
+        if repo_config.overlay {
+            if let Err(err) = overlay::create(path) {
+                summary.failed.push((path.clone(), err));
+                continue;
+            }
+        }
+        if let Some(reason) = Repo::from_path(path)
+            .ok()
+            .and_then(|repo| repo.disabled_reason())
+        {
+            summary.skipped.push((path.clone(), reason));
+            continue;
+        }
+        let group_id = canonicalize(path).ok().and_then(|path| {
+            group_ids
+                .iter()
+                .find(|(repos, _)| repos.contains(&path))
+                .map(|(_, id)| id.as_str())
+        });
+        let streams = match repo_config.streams.is_empty() {
+            true => vec![None],
+            false => repo_config.streams.iter().cloned().map(Some).collect(),
+        };
+        let mut result = Ok(false);
+        for stream in streams {
+            let snapshotted = Repo::from_path(path).and_then(|repo| {
+                let repo = repo
+                    .with_settings(SettingLayers {
+                        repo: repo_config.settings,
+                        watch: config.settings,
+                        ..Default::default()
+                    })
+                    .with_trigger(Trigger::Scheduled)
+                    .with_branch_filter(branch_filter.clone())
+                    .with_stream(stream);
+                let tip = || repo.find_snapshot(None).map(|c| c.id()).ok();
+                let before = tip();
+                match group_id {
+                    Some(group_id) => repo.snapshot_group(None, group_id)?,
+                    None => repo.snapshot()?,
+                };
+                Ok(tip() != before)
+            });
+            result = match (result, snapshotted) {
+                (Err(err), _) | (_, Err(err)) => Err(err),
+                (Ok(a), Ok(b)) => Ok(a || b),
+            };
+        }
+        match result {
+            Ok(true) => summary.snapshotted.push(path.clone()),
+            Ok(false) => summary.unchanged.push(path.clone()),
+            Err(err @ Error::LowDiskSpace { .. }) => {
+                summary.skipped.push((path.clone(), err.to_string()))
+            }
+            Err(err) => summary.failed.push((path.clone(), err)),
+        }
+    }
+    Ok(summary)
+}
+
+/// Snapshot the changed paths of `repo` outside `.git` and not ignored, with the rest of its group
+/// when given a group id, or nothing without any such paths.
+pub(crate) fn snapshot_changes(
+    repo: &Repo,
+    changed_paths: &[PathBuf],
+    group_id: Option<&str>,
+) -> Result<Option<SnapshottedPaths>, Error> {
+    let changed_paths: Vec<PathBuf> = changed_paths
+        .iter()
+        .filter_map(|p| repo.relative_path(p))
+        .filter(|rel| !rel.starts_with(".git"))
+        .filter(|rel| !repo.is_ignored(rel).unwrap_or(false))
+        .collect();
+    if changed_paths.is_empty() {
+        return Ok(None);
+    }
+    let snapshot = match group_id {
+        Some(group_id) => repo.snapshot_group(Some(&changed_paths), group_id)?,
+        None => repo.snapshot_paths(&changed_paths)?,
+    };
+    Ok(Some((changed_paths, snapshot.and_then(|s| s.stats))))
+}
+
+/// The config files of `paths`, each `.json` file of a directory in file name order. Pause files
+/// kept next to the configs are left out.
+pub fn config_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut configs = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            configs.push(path.clone());
+            continue;
+        }
+        let mut dir_configs: Vec<PathBuf> = read_dir(path)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+            .filter(|path| !path.to_string_lossy().ends_with(".paused.json"))
+            .collect();
+        dir_configs.sort();
+        configs.extend(dir_configs);
+    }
+    Ok(configs)
+}
+
+/// Directory next to the config file holding a repo config per file, `config.d` for
+/// `config.json`, so repos can be added and removed without rewriting the config
+pub fn drop_in_dir(config_path: &Path) -> PathBuf {
+    config_path.with_extension("d")
+}
+
+/// Repo configs of the `.json` files in `dir` in file name order, files that can't be read are
+/// skipped
+pub fn drop_in_repos(dir: &Path) -> Vec<RepoConfig> {
+    let mut paths: Vec<PathBuf> = match read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let repo = OpenOptions::new()
+                .read(true)
+                .open(&path)
+                .map_err(Error::from)
+                .and_then(|f| Ok(from_reader(f)?));
+            match repo {
+                Ok(repo) => Some(repo),
+                Err(err) => {
+                    warn!("skipping drop-in config {}: {}", path.display(), err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The config file along with the repos of its drop-ins, those already in the file are skipped
+pub(crate) fn load_config(config_path: &Path) -> Result<WatchConfig, Error> {
+    let mut config = open_config(config_path)?;
+    config.add_drop_ins(config_path);
+    Ok(config)
+}
+
+pub(crate) fn open_config(config_path: &Path) -> Result<WatchConfig, Error> {
+    let f = OpenOptions::new().read(true).open(config_path)?;
+    Ok(from_reader(f)?)
+}
+
+pub(crate) fn save_config(config_path: &Path, config: &WatchConfig) -> Result<(), Error> {
+    if let Some(parent) = config_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let f = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(config_path)?;
+    Ok(to_writer(f, config)?)
+}
+
+fn deliver_report(
+    config: &ReportConfig,
+    report: &Report,
+    notifications: &Notifications,
+) -> Result<(), Error> {
+    if let Some(output) = &config.output {
+        write(output, report.render(config.format)?)?;
+    }
+    if config.notify {
+        notifications.send(&report.into());
+    }
+    Ok(())
+}
+
+/// Linked worktrees of the repo at `path`, canonical and sorted, leaving out pruned ones and those
+/// with snapshots off, e.g. by `git config --worktree snapshot.enabled false`
+fn linked_worktrees(path: &Path) -> Vec<PathBuf> {
+    let Ok(repo) = Repository::discover(path) else {
+        return Vec::new();
+    };
+    let Ok(names) = repo.worktrees() else {
+        return Vec::new();
+    };
+    let mut worktrees: Vec<PathBuf> = names
+        .iter()
+        .flatten()
+        .filter_map(|name| {
+            let worktree = repo.find_worktree(name).ok()?;
+            worktree.validate().ok()?;
+            let worktree_repo = Repo::new(Repository::open_from_worktree(&worktree).ok()?);
+            if worktree_repo.disabled_reason().is_some() {
+                return None;
+            }
+            canonicalize(worktree.path()).ok()
+        })
+        .collect();
+    worktrees.sort();
+    worktrees
+}
+
+/// Directories under `root` containing files tracked in the repo index, along with the git dir
+fn tracked_dirs(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let repo = Repo::from_path(root)?;
+    let repo = repo.git_repo();
+    let git_dir = canonicalize(repo.path())?;
+    let workdir = match repo.workdir() {
+        Some(workdir) => canonicalize(workdir)?,
+        None => return Ok(vec![git_dir]),
+    };
+
+    let mut dirs = HashSet::new();
+    dirs.insert(workdir.clone());
+    let index_path = git_dir.join("index");
+    if index_path.exists() {
+        for entry in Index::open(&index_path)?.iter() {
+            if let Ok(path) = std::str::from_utf8(&entry.path) {
+                let mut dir = workdir.join(path);
+                while dir.pop() && dir != workdir && dirs.insert(dir.clone()) {}
+            }
+        }
     }
+
+    let mut dirs: Vec<PathBuf> = dirs
+        .into_iter()
+        .filter(|dir| path_starts_with(dir, root))
+        .collect();
+    dirs.push(git_dir);
+    Ok(dirs)
 }
 
 impl WatchConfig {
+    /// Add the repos of the drop-ins next to `config_path` that aren't in the config already
+    pub fn add_drop_ins(&mut self, config_path: &Path) {
+        for repo in drop_in_repos(&drop_in_dir(config_path)) {
+            if !self.repos.iter().any(|r| r.path == repo.path) {
+                self.repos.push(repo);
+            }
+        }
+    }
+
     pub fn add_repo(&mut self, p: impl AsRef<Path>) -> Result<(), Error> {
         let p = canonicalize(p)?;
         if self.repos.iter().find(|&v| v.path == p).is_none() {
-            self.repos.push(RepoConfig { path: p });
+            self.repos.push(RepoConfig {
+                path: p,
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Add `p`, a directory without a repo, snapshotted into its overlay repo
+    pub fn add_overlay(&mut self, p: impl AsRef<Path>) -> Result<(), Error> {
+        let p = canonicalize(p)?;
+        match self.repos.iter_mut().find(|v| v.path == p) {
+            Some(repo) => repo.overlay = true,
+            None => self.repos.push(RepoConfig {
+                path: p,
+                overlay: true,
+                ..Default::default()
+            }),
         }
         Ok(())
     }
@@ -139,15 +1567,15 @@ impl WatchConfig {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{fs::metadata, time::Duration};
 
     use super::*;
+    use filetime::{set_file_mtime, FileTime};
     use tempfile::{tempdir, NamedTempFile, TempDir};
-    use tokio::time::sleep;
+    use tokio::{sync::broadcast::Receiver, time::sleep};
 
     use crate::{
-        tests::check_snapshot_exists,
-        util::tests::{create_temp_file, test_repo},
+        test_util::{check_snapshot_exists, create_temp_file, test_repo},
         watcher::WatchMode,
         Repo,
     };
@@ -161,9 +1589,11 @@ mod tests {
         let repo_watcher = RepoWatcher::new(WatchConfig {
             repos: vec![RepoConfig {
                 path: repo_path.path().to_owned(),
+                ..Default::default()
             }],
             mode: WatchMode::Event,
             debounce_period: Duration::from_millis(50),
+            ..WatchConfig::default()
         })
         .unwrap();
 
@@ -181,6 +1611,422 @@ mod tests {
         assert!(check_snapshot_exists(&repo));
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn min_snapshot_interval() {
+        let repo_path = tempdir().unwrap();
+        test_repo(repo_path.path());
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                min_snapshot_interval: Some(Duration::from_millis(500)),
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+        let mut snapshotted_paths = || {
+            let mut count = 0;
+            while let Ok(event) = events.try_recv() {
+                if let WatchEvent::Snapshot { changed_paths, .. } = event {
+                    count += changed_paths.len();
+                }
+            }
+            count
+        };
+
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, snapshotted_paths());
+
+        // held back until the interval has passed, then snapshotted together
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(50)).await;
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, snapshotted_paths());
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(2, snapshotted_paths());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watchdog_restart() {
+        let repo_path = tempdir().unwrap();
+        test_repo(repo_path.path());
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            watchdog_interval: Duration::from_millis(100),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+
+        repo_watcher.watcher.lock().unwrap().abort_dispatcher();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(1, repo_watcher.restarts());
+
+        // the rebuilt watcher snapshots again
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(200)).await;
+        assert!(matches!(events.try_recv(), Ok(WatchEvent::Snapshot { .. })));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn linked_worktrees_followed() {
+        let repo_path = tempdir().unwrap();
+        let (git_repo, _) = test_repo(repo_path.path());
+        let signature = git_repo.signature().unwrap();
+        let tree = git_repo
+            .find_tree(git_repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        git_repo
+            .commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[])
+            .unwrap();
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+        let watched = || repo_watcher.context.repos.lock().unwrap().clone();
+        assert_eq!(1, watched().len());
+
+        // picked up once added
+        let worktree_dir = tempdir().unwrap();
+        let worktree_path = worktree_dir.path().join("feature");
+        git_repo.worktree("feature", &worktree_path, None).unwrap();
+        let worktree_path = canonicalize(&worktree_path).unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert!(watched().contains(&worktree_path));
+        create_temp_file(&worktree_path);
+        sleep(Duration::from_millis(300)).await;
+        let snapshotted = std::iter::from_fn(|| events.try_recv().ok()).any(
+            |event| matches!(event, WatchEvent::Snapshot { repo, .. } if repo == worktree_path),
+        );
+        assert!(snapshotted);
+        let worktree_repo = Repo::from_path(&worktree_path).unwrap();
+        assert_eq!("feature", worktree_repo.name());
+        assert!(check_snapshot_exists(&worktree_repo));
+
+        // and dropped once its snapshots are turned off
+        let git_dir = worktree_repo.git_repo().path().to_owned();
+        git2::Config::open(&git_dir.join("config.worktree"))
+            .unwrap()
+            .set_bool("snapshot.enabled", false)
+            .unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(1, watched().len());
+        assert_eq!(0, repo_watcher.restarts());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn overlay_watched() {
+        let data_dir = tempdir().unwrap();
+        std::env::set_var(overlay::DATA_DIR_ENV, data_dir.path());
+        let target = tempdir().unwrap();
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: target.path().to_owned(),
+                overlay: true,
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+
+        create_temp_file(target.path());
+        sleep(Duration::from_millis(300)).await;
+        assert!(matches!(events.try_recv(), Ok(WatchEvent::Snapshot { .. })));
+        let repo = Repo::from_path(target.path()).unwrap();
+        assert!(repo.git_repo().path().starts_with(data_dir.path()));
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn explain_watcher_rules() {
+        let repo_path = tempdir().unwrap();
+        test_repo(repo_path.path());
+        let pause_dir = tempdir().unwrap();
+        let pause_path = pause_dir.path().join("pause.json");
+        let stops = |config: &WatchConfig| -> Vec<String> {
+            explain(config, Some(&pause_path), repo_path.path())
+                .unwrap()
+                .into_iter()
+                .filter(|check| check.stop.is_some())
+                .map(|check| check.rule)
+                .collect()
+        };
+        assert_eq!(vec!["watched".to_owned()], stops(&WatchConfig::default()));
+
+        let mut config = WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                trigger: TriggerMode::ManualOnly,
+                ..Default::default()
+            }],
+            branch_deny: vec!["*".to_owned()],
+            ..WatchConfig::default()
+        };
+        Pause {
+            until: SystemTime::now() + Duration::from_secs(60),
+        }
+        .save(&pause_path)
+        .unwrap();
+        assert_eq!(
+            vec![
+                "watcher not paused".to_owned(),
+                "triggered by changes".to_owned(),
+                "watcher branch filter".to_owned()
+            ],
+            stops(&config)
+        );
+
+        std::fs::remove_file(&pause_path).unwrap();
+        config.repos[0].trigger = TriggerMode::OnSave;
+        config.branch_deny.clear();
+        assert!(stops(&config).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handler_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo_path = tempdir().unwrap();
+        let (git_repo, mut config) = test_repo(repo_path.path());
+        // a hook hanging like a filesystem that stopped responding
+        let hook = git_repo.path().join("hooks/prepare-commit-msg");
+        std::fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        std::fs::write(&hook, "#!/bin/sh\nsleep 1\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        config.set_bool("snapshot.preparecommitmsg", true).unwrap();
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            handler_timeout: Duration::from_millis(200),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(500)).await;
+        match events.try_recv() {
+            Ok(WatchEvent::TimedOut { phase, after, .. }) => {
+                assert_eq!("commit message", phase);
+                assert_eq!(Duration::from_millis(200), after);
+            }
+            event => panic!("not timed out: {:?}", event),
+        }
+        // not snapshotted again while the one given up on hangs
+        std::fs::write(repo_path.path().join("b"), "b").unwrap();
+        sleep(Duration::from_millis(200)).await;
+        match events.try_recv() {
+            Ok(WatchEvent::Skipped { reason, .. }) => assert!(reason.contains("commit message")),
+            event => panic!("not skipped: {:?}", event),
+        }
+        // given up on before committing once the hook returns
+        sleep(Duration::from_millis(1000)).await;
+        let repo = Repo::new(git_repo);
+        assert!(!check_snapshot_exists(&repo));
+
+        std::fs::remove_file(&hook).unwrap();
+        std::fs::write(repo_path.path().join("b"), "c").unwrap();
+        sleep(Duration::from_millis(500)).await;
+        // skipped ones may be left from the trigger before
+        let mut snapshotted = false;
+        while let Ok(event) = events.try_recv() {
+            snapshotted |= matches!(event, WatchEvent::Snapshot { .. });
+        }
+        assert!(snapshotted);
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    fn watch_with_trigger(trigger: TriggerMode) -> (TempDir, Receiver<WatchEvent>, RepoWatcher) {
+        let repo_path = tempdir().unwrap();
+        test_repo(repo_path.path());
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                trigger,
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let events = repo_watcher.subscribe();
+        (repo_path, events, repo_watcher)
+    }
+
+    fn snapshotted_paths(events: &mut Receiver<WatchEvent>) -> Vec<usize> {
+        let mut snapshots = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let WatchEvent::Snapshot { changed_paths, .. } = event {
+                snapshots.push(changed_paths.len());
+            }
+        }
+        snapshots
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trigger_on_idle() {
+        let (repo_path, mut events, _repo_watcher) = watch_with_trigger(TriggerMode::OnIdle {
+            idle: Duration::from_millis(300),
+        });
+        for _ in 0..3 {
+            create_temp_file(repo_path.path());
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(snapshotted_paths(&mut events).is_empty());
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(vec![3], snapshotted_paths(&mut events));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trigger_on_interval() {
+        let (repo_path, mut events, _repo_watcher) = watch_with_trigger(TriggerMode::OnInterval {
+            interval: Duration::from_millis(300),
+        });
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(100)).await;
+        assert!(snapshotted_paths(&mut events).is_empty());
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(vec![1], snapshotted_paths(&mut events));
+        // nothing changed during the next interval
+        sleep(Duration::from_millis(300)).await;
+        assert!(snapshotted_paths(&mut events).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trigger_manual_only() {
+        let (repo_path, mut events, repo_watcher) = watch_with_trigger(TriggerMode::ManualOnly);
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(100)).await;
+        assert!(snapshotted_paths(&mut events).is_empty());
+        // still known to the API
+        assert_eq!(1, repo_watcher.context.repos.lock().unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn group_snapshotted_together() {
+        let (front, back) = (tempdir().unwrap(), tempdir().unwrap());
+        test_repo(front.path());
+        test_repo(back.path());
+        create_temp_file(back.path());
+        // only the front end is watched, changes to it snapshot the back end as well
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: front.path().to_owned(),
+                ..Default::default()
+            }],
+            groups: vec![GroupConfig {
+                name: "app".to_owned(),
+                repos: vec![front.path().to_owned(), back.path().to_owned()],
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        let mut events = repo_watcher.subscribe();
+        create_temp_file(front.path());
+        sleep(Duration::from_millis(100)).await;
+        let snapshotted = |events: &mut Receiver<WatchEvent>| {
+            let mut repos = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                if let WatchEvent::Snapshot { repo, .. } = event {
+                    repos.push(repo);
+                }
+            }
+            repos
+        };
+        assert_eq!(2, snapshotted(&mut events).len());
+
+        let group_ids: Vec<_> = [front.path(), back.path()]
+            .iter()
+            .map(|path| {
+                let repo = Repo::from_path(path).unwrap();
+                let snapshot = repo.find_snapshot(None).unwrap();
+                crate::history::trailer(&snapshot, crate::history::GROUP_TRAILER).unwrap()
+            })
+            .collect();
+        assert_eq!(group_ids[0], group_ids[1]);
+        assert!(group_ids[0].starts_with("app@"));
+
+        // the back end had nothing left to commit
+        create_temp_file(front.path());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(vec![front.path().to_owned()], snapshotted(&mut events));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_snapshotted_separately() {
+        let repo_path = tempdir().unwrap();
+        let (repo, _) = test_repo(repo_path.path());
+        let stream = |name: &str| SnapshotStream {
+            name: name.to_owned(),
+            path: PathBuf::from("services").join(name),
+            branch: None,
+        };
+        for name in ["a", "b"] {
+            std::fs::create_dir_all(repo_path.path().join("services").join(name)).unwrap();
+        }
+        let _repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                streams: vec![stream("a"), stream("b")],
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        })
+        .unwrap();
+        create_temp_file(&repo_path.path().join("services/a"));
+        sleep(Duration::from_millis(100)).await;
+
+        let branch = Repo::new(repo).current_branch().unwrap();
+        let repo = Repository::open(repo_path.path()).unwrap();
+        let snapshotted =
+            |name: &str| repo.find_reference(&format!("refs/heads/snapshot/{}/{}", name, branch));
+        assert!(snapshotted("a").is_ok());
+        assert!(snapshotted("b").is_err());
+    }
+
+    #[test]
+    fn trigger_config() {
+        let config: RepoConfig =
+            serde_json::from_str(r#"{"path": "/", "trigger": {"type": "onIdle", "idle": "2m"}}"#)
+                .unwrap();
+        assert_eq!(
+            TriggerMode::OnIdle {
+                idle: Duration::from_secs(120)
+            },
+            config.trigger
+        );
+        let config: RepoConfig = serde_json::from_str(r#"{"path": "/"}"#).unwrap();
+        assert_eq!(TriggerMode::OnSave, config.trigger);
+        assert_eq!(SettingOverrides::default(), config.settings);
+
+        let config: RepoConfig =
+            serde_json::from_str(r#"{"path": "/", "threads": 4, "timings": true}"#).unwrap();
+        assert_eq!(Some(4), config.settings.threads);
+        assert_eq!(Some(true), config.settings.timings);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn config_file() {
         let repo_path = tempdir().unwrap();
@@ -190,9 +2036,11 @@ mod tests {
         let config = WatchConfig {
             repos: vec![RepoConfig {
                 path: repo_path.path().to_owned(),
+                ..Default::default()
             }],
             mode: WatchMode::Event,
             debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
         };
         to_writer(config_path.as_file(), &config).unwrap();
 
@@ -206,6 +2054,211 @@ mod tests {
         assert!(check_snapshot_exists(&repo));
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused() {
+        let repo_path = tempdir().unwrap();
+        let (repo, _) = test_repo(repo_path.path());
+        let repo = Repo::new(repo);
+        let config_dir = tempdir().unwrap();
+        let config_path = config_dir.path().join("config.json");
+        let config = WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+                ..Default::default()
+            }],
+            mode: WatchMode::Event,
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        };
+        save_config(&config_path, &config).unwrap();
+        Pause {
+            until: SystemTime::now() + Duration::from_millis(500),
+        }
+        .save(&Pause::path(&config_path))
+        .unwrap();
+
+        let _repo_watcher = RepoWatcher::with_config(&config_path).unwrap();
+
+        create_temp_file(repo_path.path());
+        sleep(Duration::from_millis(200)).await;
+        assert!(!check_snapshot_exists(&repo));
+
+        // the changes made while paused are snapshotted once it's over
+        sleep(Duration::from_millis(700)).await;
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn several_configs() {
+        let personal = tempdir().unwrap();
+        let personal_repo = Repo::new(test_repo(personal.path()).0);
+        let work = tempdir().unwrap();
+        let work_repo = Repo::new(test_repo(work.path()).0);
+        let config_dir = tempdir().unwrap();
+        for (name, repo_path) in [("personal", &personal), ("work", &work)] {
+            let config = WatchConfig {
+                repos: vec![RepoConfig {
+                    path: repo_path.path().to_owned(),
+                    ..Default::default()
+                }],
+                debounce_period: Duration::from_millis(10),
+                ..WatchConfig::default()
+            };
+            save_config(&config_dir.path().join(format!("{}.json", name)), &config).unwrap();
+        }
+        let work_config = config_dir.path().join("work.json");
+        Pause {
+            until: SystemTime::now() + Duration::from_secs(60),
+        }
+        .save(&Pause::path(&work_config))
+        .unwrap();
+        let config_paths = config_paths(&[config_dir.path().to_owned()]).unwrap();
+        assert_eq!(
+            vec![config_dir.path().join("personal.json"), work_config],
+            config_paths
+        );
+
+        let watchers = RepoWatcher::with_configs(&config_paths).unwrap();
+        assert_eq!(2, watchers.len());
+        create_temp_file(personal.path());
+        create_temp_file(work.path());
+        sleep(Duration::from_millis(200)).await;
+        assert!(check_snapshot_exists(&personal_repo));
+        // only the work config is paused
+        assert!(!check_snapshot_exists(&work_repo));
+    }
+
+    #[test]
+    fn run_once_repos() {
+        let changed = tempdir().unwrap();
+        test_repo(changed.path());
+        create_temp_file(changed.path());
+        let unchanged = tempdir().unwrap();
+        let (repo, _) = test_repo(unchanged.path());
+        let unchanged_repo = Repo::new(repo);
+        create_temp_file(unchanged.path());
+        unchanged_repo.snapshot().unwrap();
+        let manual = tempdir().unwrap();
+        test_repo(manual.path());
+        create_temp_file(manual.path());
+        let broken = tempdir().unwrap();
+
+        let repo = |path: &Path, trigger| RepoConfig {
+            path: path.to_owned(),
+            trigger,
+            ..Default::default()
+        };
+        let config = WatchConfig {
+            repos: vec![
+                repo(changed.path(), TriggerMode::OnSave),
+                repo(unchanged.path(), TriggerMode::OnSave),
+                repo(manual.path(), TriggerMode::ManualOnly),
+                repo(broken.path(), TriggerMode::OnSave),
+            ],
+            ..WatchConfig::default()
+        };
+        let summary = run_once(&config, None).unwrap();
+        assert_eq!(vec![changed.path().to_owned()], summary.snapshotted);
+        assert_eq!(vec![unchanged.path().to_owned()], summary.unchanged);
+        assert_eq!(manual.path(), summary.skipped[0].0);
+        assert_eq!(broken.path(), summary.failed[0].0);
+        assert!(!summary.is_ok());
+
+        // nothing happens while paused
+        let pause_path = changed.path().join("paused.json");
+        Pause {
+            until: SystemTime::now() + Duration::from_secs(60),
+        }
+        .save(&pause_path)
+        .unwrap();
+        create_temp_file(changed.path());
+        let summary = run_once(&config, Some(&pause_path)).unwrap();
+        assert!(summary.snapshotted.is_empty());
+        assert_eq!(4, summary.skipped.len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_once_same_repo() {
+        let temp_dir = tempdir().unwrap();
+        let repo_path = temp_dir.path().join("repo");
+        create_dir_all(&repo_path).unwrap();
+        test_repo(&repo_path);
+        create_temp_file(&repo_path);
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&repo_path, &link).unwrap();
+        let other = tempdir().unwrap();
+        test_repo(other.path());
+        assert_eq!(repo_id(&repo_path), repo_id(&link));
+        assert_ne!(repo_id(&repo_path), repo_id(other.path()));
+
+        let config = WatchConfig {
+            repos: vec![
+                RepoConfig {
+                    path: repo_path.clone(),
+                    ..Default::default()
+                },
+                RepoConfig {
+                    path: link.clone(),
+                    ..Default::default()
+                },
+            ],
+            ..WatchConfig::default()
+        };
+        let summary = run_once(&config, None).unwrap();
+        assert_eq!(vec![repo_path], summary.snapshotted);
+        assert_eq!(link, summary.skipped[0].0);
+        assert_eq!(1, Repo::from_path(&link).unwrap().list(None).unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drop_in_configs() {
+        let repo_path1 = tempdir().unwrap();
+        let (repo, _) = test_repo(repo_path1.path());
+        let repo1 = Repo::new(repo);
+        let repo_path2 = tempdir().unwrap();
+        let (repo, _) = test_repo(repo_path2.path());
+        let repo2 = Repo::new(repo);
+
+        let config_dir = tempdir().unwrap();
+        let config_path = config_dir.path().join("config.json");
+        let config = WatchConfig {
+            mode: WatchMode::Event,
+            debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
+        };
+        save_config(&config_path, &config).unwrap();
+        let drop_ins = drop_in_dir(&config_path);
+        assert_eq!(config_dir.path().join("config.d"), drop_ins);
+        create_dir_all(&drop_ins).unwrap();
+        let drop_in = |path: &Path| RepoConfig {
+            path: path.to_owned(),
+            ..Default::default()
+        };
+        write(
+            drop_ins.join("repo1.json"),
+            serde_json::to_vec(&drop_in(repo_path1.path())).unwrap(),
+        )
+        .unwrap();
+        write(drop_ins.join("broken.json"), "{").unwrap();
+        assert_eq!(1, load_config(&config_path).unwrap().repos.len());
+
+        let _repo_watcher = RepoWatcher::with_config(&config_path).unwrap();
+        create_temp_file(repo_path1.path());
+        sleep(Duration::from_millis(50)).await;
+        assert!(check_snapshot_exists(&repo1));
+
+        write(
+            drop_ins.join("repo2.json"),
+            serde_json::to_vec(&drop_in(repo_path2.path())).unwrap(),
+        )
+        .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        create_temp_file(repo_path2.path());
+        sleep(Duration::from_millis(50)).await;
+        assert!(check_snapshot_exists(&repo2));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn config_file_change() {
         let repo_path1 = tempdir().unwrap();
@@ -221,9 +2274,11 @@ mod tests {
         let config = WatchConfig {
             repos: vec![RepoConfig {
                 path: repo_path1.path().to_owned(),
+                ..Default::default()
             }],
             mode: WatchMode::Event,
             debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
         };
         to_writer(config_path.as_file(), &config).unwrap();
 
@@ -232,9 +2287,11 @@ mod tests {
         let config = WatchConfig {
             repos: vec![RepoConfig {
                 path: repo_path2.path().to_owned(),
+                ..Default::default()
             }],
             mode: WatchMode::Event,
             debounce_period: Duration::from_millis(10),
+            ..WatchConfig::default()
         };
         to_writer(
             OpenOptions::new()
@@ -263,6 +2320,92 @@ mod tests {
         assert!(check_snapshot_exists(&repo2));
     }
 
+    #[test]
+    fn cached_repo_config_change() {
+        let repo_path = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(repo_path.path());
+
+        let mut cache = None;
+        cache = Some(CachedRepo::take(&mut cache, || Repo::from_path(repo_path.path())).unwrap());
+        assert!(!cache.as_ref().unwrap().is_stale());
+
+        // a change within the mtime's granularity is still told by the size
+        let config_path = repo_path.path().join(".git/config");
+        let modified = FileTime::from_last_modification_time(&metadata(&config_path).unwrap());
+        config.set_str("snapshot.snapshotbranch", "test").unwrap();
+        set_file_mtime(&config_path, modified).unwrap();
+        assert!(cache.as_ref().unwrap().is_stale());
+
+        cache = Some(CachedRepo::take(&mut cache, || Repo::from_path(repo_path.path())).unwrap());
+        assert!(!cache.as_ref().unwrap().is_stale());
+
+        // as is one keeping the size
+        config.set_str("snapshot.snapshotbranch", "tset").unwrap();
+        set_file_mtime(&config_path, FileTime::from_unix_time(1, 0)).unwrap();
+        assert!(cache.as_ref().unwrap().is_stale());
+
+        cache = Some(CachedRepo::take(&mut cache, || Repo::from_path(repo_path.path())).unwrap());
+        assert!(!cache.as_ref().unwrap().is_stale());
+    }
+
+    #[test]
+    fn tracked_dirs_from_index() {
+        let repo_path = tempdir().unwrap();
+        let root = canonicalize(repo_path.path()).unwrap();
+        let (repo, _) = test_repo(&root);
+        std::fs::create_dir_all(root.join("src/nested")).unwrap();
+        std::fs::create_dir(root.join("node_modules")).unwrap();
+        std::fs::write(root.join("src/nested/a"), "a").unwrap();
+        std::fs::write(root.join("node_modules/b"), "b").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("src/nested/a")).unwrap();
+        index.write().unwrap();
+
+        let dirs: HashSet<PathBuf> = tracked_dirs(&root).unwrap().into_iter().collect();
+        let expected: HashSet<PathBuf> = [
+            root.clone(),
+            root.join("src"),
+            root.join("src/nested"),
+            root.join(".git"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, dirs);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tracked_dirs_strategy() {
+        let repo_path = tempdir().unwrap();
+        let root = canonicalize(repo_path.path()).unwrap();
+        let (repo, _) = test_repo(&root);
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::create_dir(root.join("node_modules")).unwrap();
+        std::fs::write(root.join("src/a"), "a").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("src/a")).unwrap();
+        index.write().unwrap();
+        let repo = Repo::new(repo);
+
+        let _repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: root.clone(),
+                ..Default::default()
+            }],
+            debounce_period: Duration::from_millis(10),
+            strategy: WatchStrategy::TrackedDirs,
+            ..WatchConfig::default()
+        })
+        .unwrap();
+
+        create_temp_file(&root.join("node_modules"));
+        sleep(Duration::from_millis(50)).await;
+        assert!(!check_snapshot_exists(&repo));
+
+        create_temp_file(&root.join("src"));
+        sleep(Duration::from_millis(50)).await;
+        assert!(check_snapshot_exists(&repo));
+    }
+
     #[test]
     fn watch_config_add_repo() {
         let mut config = WatchConfig::default();
@@ -285,7 +2428,10 @@ mod tests {
     fn watch_config_remove_repo() {
         let mut config = WatchConfig::default();
         let p = "/";
-        config.repos.push(RepoConfig { path: p.into() });
+        config.repos.push(RepoConfig {
+            path: p.into(),
+            ..Default::default()
+        });
 
         config.remove_repo(p).unwrap();
         assert_eq!(0, config.repos.len());
@@ -0,0 +1,327 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use git2::{Delta, Oid, Repository};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::Error,
+    history::{commit_time, walk},
+    metadata::SnapshotMetadata,
+    notify::Notification,
+    state::PushFailure,
+    Repo,
+};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How much time a report covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    pub fn duration(self) -> Duration {
+        match self {
+            Self::Daily => DAY,
+            Self::Weekly => 7 * DAY,
+        }
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Weekly => write!(f, "weekly"),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err(format!("invalid period: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(Self::Markdown),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("invalid report format: {}", s)),
+        }
+    }
+}
+
+/// A report generated by the watcher every period
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReportConfig {
+    pub period: Period,
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// File the report is written to, replaced every period
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    /// Send the report to the notification channels
+    #[serde(default)]
+    pub notify: bool,
+}
+
+/// Snapshot activity of one repo over a report's period
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepoReport {
+    pub path: PathBuf,
+    pub snapshots: usize,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+    pub push_failures: usize,
+    /// Size of the file contents the snapshots added or modified, before compression
+    pub stored_bytes: u64,
+}
+
+impl RepoReport {
+    /// Summarize the snapshots on `snapshot_refs` taken in `[since, until)`
+    pub(crate) fn new(
+        repo: &Repository,
+        snapshot_refs: &[String],
+        push_failures: &[PushFailure],
+        since: SystemTime,
+        until: SystemTime,
+    ) -> Result<Self, Error> {
+        let mut report = Self {
+            path: repo.workdir().unwrap_or_else(|| repo.path()).to_owned(),
+            push_failures: push_failures
+                .iter()
+                .filter(|f| f.time >= since && f.time < until)
+                .count(),
+            ..Default::default()
+        };
+        let odb = repo.odb()?;
+        let mut seen_commits = HashSet::new();
+        let mut seen_blobs: HashSet<Oid> = HashSet::new();
+        for snapshot_ref in snapshot_refs {
+            for commit in walk(repo, snapshot_ref, Some(since))? {
+                if !seen_commits.insert(commit.id()) || commit_time(&commit) >= until {
+                    continue;
+                }
+                report.snapshots += 1;
+
+                // noted by the snapshot, older ones are diffed
+                if let Some(stats) =
+                    SnapshotMetadata::read(repo, commit.id())?.and_then(|metadata| metadata.stats)
+                {
+                    report.lines_added += stats.insertions;
+                    report.lines_removed += stats.deletions;
+                    report.stored_bytes += stats.bytes_written;
+                    continue;
+                }
+                let parent = commit.parent(0).ok().map(|p| p.tree()).transpose()?;
+                let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+                let stats = diff.stats()?;
+                report.lines_added += stats.insertions();
+                report.lines_removed += stats.deletions();
+                for delta in diff.deltas() {
+                    let blob = delta.new_file().id();
+                    if matches!(delta.status(), Delta::Added | Delta::Modified)
+                        && seen_blobs.insert(blob)
+                    {
+                        report.stored_bytes += odb.read_header(blob)?.0 as u64;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Snapshot activity of the watched repos over a period
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub period: Period,
+    #[serde(with = "humantime_serde")]
+    pub since: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub until: SystemTime,
+    pub repos: Vec<RepoReport>,
+}
+
+impl Report {
+    /// Report on the repos at `paths` for the period ending at `until`, repos that can't be read
+    /// are left out
+    pub fn new(period: Period, paths: &[PathBuf], until: SystemTime) -> Self {
+        let since = until - period.duration();
+        let repos = paths
+            .iter()
+            .filter_map(|path| {
+                match Repo::from_path(path).and_then(|repo| repo.report(since, until)) {
+                    Ok(report) => Some(report),
+                    Err(err) => {
+                        error!("error reporting on {}: {:?}", path.display(), err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self {
+            period,
+            since,
+            until,
+            repos,
+        }
+    }
+
+    pub fn title(&self) -> String {
+        format!("git-snapshot {} report", self.period)
+    }
+
+    pub fn render(&self, format: ReportFormat) -> Result<String, Error> {
+        Ok(match format {
+            ReportFormat::Markdown => self.to_string(),
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+}
+
+/// Markdown table with a row per repo
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# {}", self.title())?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{} to {}",
+            humantime::format_rfc3339_seconds(self.since),
+            humantime::format_rfc3339_seconds(self.until)
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "| repo | snapshots | lines added | lines removed | push failures | stored |"
+        )?;
+        writeln!(f, "|---|---:|---:|---:|---:|---:|")?;
+        for repo in &self.repos {
+            writeln!(
+                f,
+                "| {} | {} | {} | {} | {} | {} |",
+                repo.path.display(),
+                repo.snapshots,
+                repo.lines_added,
+                repo.lines_removed,
+                repo.push_failures,
+                format_bytes(repo.stored_bytes)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&Report> for Notification {
+    fn from(report: &Report) -> Self {
+        Self {
+            title: report.title(),
+            text: report.to_string(),
+            data: json!({ "report": report }),
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = u;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{test_util::test_repo, Repo};
+
+    #[test]
+    fn repo_report() {
+        let temp_dir = tempdir().unwrap();
+        let (_git_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+        let since = SystemTime::now() - DAY;
+
+        write(temp_dir.path().join("a"), "1\n2\n").unwrap();
+        repo.snapshot().unwrap();
+        write(temp_dir.path().join("a"), "1\n3\n").unwrap();
+        repo.snapshot().unwrap();
+
+        let report = repo.report(since, SystemTime::now() + DAY).unwrap();
+        assert_eq!(2, report.snapshots);
+        assert_eq!(3, report.lines_added);
+        assert_eq!(1, report.lines_removed);
+        assert_eq!(0, report.push_failures);
+        assert_eq!(8, report.stored_bytes);
+
+        // nothing in a period before the snapshots
+        let report = repo.report(since - DAY, since).unwrap();
+        assert_eq!(0, report.snapshots);
+    }
+
+    #[test]
+    fn render() {
+        let report = Report {
+            period: Period::Weekly,
+            since: SystemTime::UNIX_EPOCH,
+            until: SystemTime::UNIX_EPOCH + 7 * DAY,
+            repos: vec![RepoReport {
+                path: PathBuf::from("/a"),
+                snapshots: 3,
+                stored_bytes: 2048,
+                ..Default::default()
+            }],
+        };
+        let markdown = report.render(ReportFormat::Markdown).unwrap();
+        assert!(markdown.starts_with("# git-snapshot weekly report\n"));
+        assert!(markdown.ends_with("| /a | 3 | 0 | 0 | 0 | 2.0 KiB |\n"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&report.render(ReportFormat::Json).unwrap()).unwrap();
+        assert_eq!("weekly", json["period"]);
+        assert_eq!(3, json["repos"][0]["snapshots"]);
+    }
+}
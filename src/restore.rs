@@ -0,0 +1,185 @@
+use std::{
+    fs::{copy, create_dir_all, hard_link, set_permissions, write},
+    path::{Path, PathBuf},
+};
+
+use git2::{
+    build::CheckoutBuilder, FileFavor, FileMode, MergeOptions, ObjectType, Oid, Repository, Tree,
+    TreeWalkMode, TreeWalkResult,
+};
+use log::debug;
+
+use crate::error::Error;
+
+/// How regions changed both in the working tree and the restored snapshot are resolved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Leave standard conflict markers in the file
+    #[default]
+    Markers,
+    /// Keep the working tree's side
+    Ours,
+    /// Take the snapshot's side
+    Theirs,
+}
+
+impl From<MergeStrategy> for FileFavor {
+    fn from(strategy: MergeStrategy) -> Self {
+        match strategy {
+            MergeStrategy::Markers => FileFavor::Normal,
+            MergeStrategy::Ours => FileFavor::Ours,
+            MergeStrategy::Theirs => FileFavor::Theirs,
+        }
+    }
+}
+
+/// Make the working tree, or only `paths` in it, match `tree`, removing files that aren't in it.
+/// HEAD, the index and ignored files are left alone.
+pub fn restore(repo: &Repository, tree: Oid, paths: &[PathBuf]) -> Result<(), Error> {
+    // a fresh handle so untracked files are judged against the repo index, not the snapshot index
+    let repo = Repository::open(repo.path())?;
+    let tree = repo.find_tree(tree)?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force().remove_untracked(true).update_index(false);
+    for path in paths {
+        checkout.path(path.as_path());
+    }
+    repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+    Ok(())
+}
+
+/// 3-way merge `theirs` into the working tree captured as `ours`, limited to `paths` when given.
+/// Without a `base` every file changed on both sides conflicts.
+pub fn merge(
+    repo: &Repository,
+    base: Option<Oid>,
+    ours: Oid,
+    theirs: Oid,
+    paths: &[PathBuf],
+    strategy: MergeStrategy,
+) -> Result<(), Error> {
+    let repo = Repository::open(repo.path())?;
+    let base = match base {
+        Some(base) => base,
+        None => repo.treebuilder(None)?.write()?,
+    };
+    let mut merged = repo.merge_trees(
+        &repo.find_tree(base)?,
+        &repo.find_tree(ours)?,
+        &repo.find_tree(theirs)?,
+        Some(MergeOptions::new().file_favor(strategy.into())),
+    )?;
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .force()
+        .remove_untracked(true)
+        .update_index(false)
+        .allow_conflicts(true)
+        .conflict_style_merge(true);
+    for path in paths {
+        checkout.path(path.as_path());
+    }
+    repo.checkout_index(Some(&mut merged), Some(&mut checkout))?;
+    Ok(())
+}
+
+/// Write the files of `tree` into `dest`
+pub fn export(repo: &Repository, tree: &Tree, dest: &Path) -> Result<(), Error> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force().update_index(false).target_dir(dest);
+    repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+    Ok(())
+}
+
+/// Write the files of `tree` into `dest` as hard links to a single read-only copy of each blob in
+/// `objects`, shared by every export into it, copying them where hard links aren't supported
+pub fn export_linked(
+    repo: &Repository,
+    tree: &Tree,
+    dest: &Path,
+    objects: &Path,
+) -> Result<(), Error> {
+    let mut entries = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if let (Some(name), Some(ObjectType::Blob)) = (entry.name(), entry.kind()) {
+            entries.push((PathBuf::from(root).join(name), entry.id(), entry.filemode()));
+        }
+        TreeWalkResult::Ok
+    })?;
+    create_dir_all(dest)?;
+    for (path, oid, mode) in entries {
+        let target = dest.join(&path);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        if mode == i32::from(FileMode::Link) {
+            let blob = repo.find_blob(oid)?;
+            write_link(&target, blob.content())?;
+            continue;
+        }
+        let executable = mode == i32::from(FileMode::BlobExecutable);
+        let object = stored_object(repo, objects, oid, executable)?;
+        if let Err(err) = hard_link(&object, &target) {
+            debug!("copying {}: {}", path.display(), err);
+            copy(&object, &target)?;
+        }
+    }
+    Ok(())
+}
+
+// `objects/<aa>/<rest of the oid>`, written once per blob and mode since links share permissions
+fn stored_object(
+    repo: &Repository,
+    objects: &Path,
+    oid: Oid,
+    executable: bool,
+) -> Result<PathBuf, Error> {
+    let hex = oid.to_string();
+    let name = match executable {
+        true => format!("{}.x", &hex[2..]),
+        false => hex[2..].to_owned(),
+    };
+    let path = objects.join(&hex[..2]).join(&name);
+    if path.exists() {
+        return Ok(path);
+    }
+    let dir = path.parent().unwrap_or(objects);
+    create_dir_all(dir)?;
+    // complete or not there at all, each export writes its own so running into another one
+    // only writes it twice
+    let partial = tempfile::Builder::new()
+        .prefix(&format!("{}.", name))
+        .suffix(".partial")
+        .tempfile_in(dir)?;
+    write(partial.path(), repo.find_blob(oid)?.content())?;
+    let mut permissions = partial.as_file().metadata()?.permissions();
+    permissions.set_readonly(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(if executable { 0o555 } else { 0o444 });
+    }
+    set_permissions(partial.path(), permissions)?;
+    if let Err(err) = partial.persist(&path) {
+        // replacing one that's there already can fail on Windows
+        if !path.exists() {
+            return Err(err.error.into());
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(unix)]
+fn write_link(path: &Path, target: &[u8]) -> Result<(), Error> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), path)?;
+    Ok(())
+}
+
+// the link's target as the file's content, like git without core.symlinks
+#[cfg(not(unix))]
+fn write_link(path: &Path, target: &[u8]) -> Result<(), Error> {
+    write(path, target)?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use git2::Config;
+use log::warn;
+
+use crate::{
+    error::Error,
+    units::HumanDuration,
+    util::{run_blocking, ConfigValue},
+};
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_JITTER: Duration = Duration::from_millis(500);
+
+/// How often and how far apart operations failing for a passing reason are tried, set with
+/// `snapshot.retryattempts`, `snapshot.retrydelay` and `snapshot.retryjitter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Including the first, 1 never retries
+    pub attempts: u32,
+    /// Before the first retry, doubling with every one after it
+    pub delay: Duration,
+    /// Upper bound of the random time added to each delay, so clients failing together don't
+    /// retry together
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_ATTEMPTS,
+            delay: DEFAULT_DELAY,
+            jitter: DEFAULT_JITTER,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        let duration = |key: &str, default: Duration| {
+            HumanDuration::from_config(config, &[key], default.into()).0
+        };
+        Self {
+            attempts: u32::try_from(i64::from_config(
+                config,
+                &["snapshot.retryattempts"],
+                DEFAULT_ATTEMPTS.into(),
+            ))
+            .unwrap_or(DEFAULT_ATTEMPTS)
+            .max(1),
+            delay: duration("snapshot.retrydelay", DEFAULT_DELAY),
+            jitter: duration("snapshot.retryjitter", DEFAULT_JITTER),
+        }
+    }
+
+    /// Wait before the given retry, counting from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        backoff + random_below(self.jitter)
+    }
+
+    /// Run `op` until it succeeds, fails with an error `retryable` rejects or runs out of attempts
+    pub fn run<T>(
+        &self,
+        retryable: impl Fn(&Error) -> bool,
+        mut op: impl FnMut() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(err) if attempt < self.attempts && retryable(&err) => {
+                    let delay = self.delay(attempt);
+                    warn!("retrying in {:?} after: {}", delay, err);
+                    // snapshots are retried from watcher tasks
+                    run_blocking(|| std::thread::sleep(delay));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn random_below(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    // every RandomState is seeded anew
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max.as_nanos().min(u64::MAX as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    fn locked() -> Error {
+        git2::Error::new(git2::ErrorCode::Locked, git2::ErrorClass::Index, "locked").into()
+    }
+
+    #[test]
+    fn config() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        assert_eq!(RetryPolicy::default(), RetryPolicy::from_config(&config));
+
+        config.set_i32("snapshot.retryattempts", 5).unwrap();
+        config.set_str("snapshot.retrydelay", "2s").unwrap();
+        config.set_str("snapshot.retryjitter", "0s").unwrap();
+        let policy = RetryPolicy::from_config(&config);
+        assert_eq!(5, policy.attempts);
+        assert_eq!(Duration::from_secs(2), policy.delay(1));
+        assert_eq!(Duration::from_secs(8), policy.delay(3));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+        };
+        for _ in 0..20 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay < Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn run() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+        };
+        let retryable = |err: &Error| err.code().is_transient();
+
+        let mut calls = 0;
+        let result = policy.run(retryable, || {
+            calls += 1;
+            match calls {
+                1 => Err(locked()),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(2, result.unwrap());
+
+        // gives up after the last attempt
+        calls = 0;
+        assert!(policy
+            .run(retryable, || -> Result<(), Error> {
+                calls += 1;
+                Err(locked())
+            })
+            .is_err());
+        assert_eq!(3, calls);
+
+        // permanent errors aren't retried
+        calls = 0;
+        assert!(policy
+            .run(retryable, || -> Result<(), Error> {
+                calls += 1;
+                Err(Error::SnapshotNotFound)
+            })
+            .is_err());
+        assert_eq!(1, calls);
+    }
+}
@@ -0,0 +1,904 @@
+use std::{
+    fs::{metadata, remove_file, File},
+    io::{copy, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use git2::{Config, Oid};
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    error::Error,
+    repo::Repo,
+    secret::Secret,
+    state::State,
+    units::{ByteSize, HumanDuration},
+    util::ConfigValue,
+};
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_PREFIX: &str = "git-snapshot";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BUNDLE_EXTENSION: &str = ".bundle";
+const DEFAULT_PART_SIZE: u64 = 64 << 20;
+// the smallest part S3 takes but for the last, and the most parts of an upload
+const MIN_PART_SIZE: u64 = 5 << 20;
+const MAX_PARTS: u64 = 10_000;
+// written in the git dir while it's uploaded
+const UPLOAD_FILE: &str = "snapshot-upload.bundle";
+
+/// An S3 compatible bucket snapshot bundles are mirrored to, `snapshot.s3.*`
+#[derive(Debug, Clone)]
+pub struct S3Target {
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    /// Keys of the repo's bundles start with it
+    prefix: String,
+    path_style: bool,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    /// Larger bundles are uploaded in parts of this size
+    part_size: u64,
+    /// How often bundles are written by the watcher
+    pub interval: Duration,
+}
+
+// The body of a request, hashed for its signature and streamed from a file rather than held in
+// memory for uploads
+enum Payload<'a> {
+    Bytes(&'a [u8]),
+    /// `len` bytes of the file from `offset`
+    File {
+        path: &'a Path,
+        offset: u64,
+        len: u64,
+    },
+}
+
+impl Payload<'_> {
+    fn reader(&self) -> Result<Box<dyn Read + '_>, Error> {
+        Ok(match self {
+            Self::Bytes(bytes) => Box::new(*bytes),
+            Self::File { path, offset, len } => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                Box::new(file.take(*len))
+            }
+        })
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File { len, .. } => *len,
+        }
+    }
+
+    fn sha256(&self) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        copy(&mut self.reader()?, &mut hasher)?;
+        Ok(hex(&hasher.finalize()))
+    }
+}
+
+impl S3Target {
+    /// The repo's bucket, `None` without `snapshot.s3.bucket`
+    pub fn from_config(config: &Config, repo_name: &str) -> Result<Option<Self>, Error> {
+        let bucket = String::from_config(config, &["snapshot.s3.bucket"], String::new());
+        if bucket.is_empty() {
+            return Ok(None);
+        }
+        let prefix =
+            String::from_config(config, &["snapshot.s3.prefix"], DEFAULT_PREFIX.to_owned());
+        let name = String::from_config(config, &["snapshot.s3.name"], repo_name.to_owned());
+        let prefix = [prefix.trim_matches('/'), &name]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/");
+        Self::new(config, bucket, prefix).map(Some)
+    }
+
+    /// The bucket and key prefix of an `s3://<bucket>/<prefix>` url, with the endpoint and
+    /// credentials of the config
+    pub fn from_url(config: &Config, url: &str) -> Result<Self, Error> {
+        let location = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| Error::Storage(format!("not an s3:// url: {}", url)))?;
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        Self::new(
+            config,
+            bucket.to_owned(),
+            prefix.trim_matches('/').to_owned(),
+        )
+    }
+
+    fn new(config: &Config, bucket: String, prefix: String) -> Result<Self, Error> {
+        let value = |key: &str, env: &str| {
+            Some(String::from_config(config, &[key], String::new()))
+                .filter(|value| !value.is_empty())
+                .or_else(|| std::env::var(env).ok())
+        };
+        let region =
+            String::from_config(config, &["snapshot.s3.region"], DEFAULT_REGION.to_owned());
+        let endpoint = String::from_config(config, &["snapshot.s3.endpoint"], String::new());
+        // virtual-hosted buckets on AWS, path-style ones elsewhere like most compatible stores
+        let path_style =
+            bool::from_config(config, &["snapshot.s3.pathstyle"], !endpoint.is_empty());
+        let endpoint = match endpoint.is_empty() {
+            true => format!("https://s3.{}.amazonaws.com", region),
+            false => endpoint,
+        };
+        let endpoint = Url::parse(&endpoint)
+            .map_err(|err| Error::Storage(format!("invalid snapshot.s3.endpoint: {}", err)))?;
+        let secret_key = match value("snapshot.s3.secretaccesskey", "AWS_SECRET_ACCESS_KEY") {
+            Some(secret) => secret.parse::<Secret>()?.reveal()?,
+            None => String::new(),
+        };
+        Ok(Self {
+            endpoint,
+            bucket,
+            region,
+            prefix,
+            path_style,
+            access_key: value("snapshot.s3.accesskeyid", "AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            part_size: ByteSize::from_config(
+                config,
+                &["snapshot.s3.partsize"],
+                ByteSize(DEFAULT_PART_SIZE),
+            )
+            .0
+            .max(MIN_PART_SIZE),
+            interval: HumanDuration::from_config(
+                config,
+                &["snapshot.s3.interval"],
+                DEFAULT_INTERVAL.into(),
+            )
+            .0,
+        })
+    }
+
+    /// The target bundles are recorded for in the repo's state
+    pub fn url(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    /// Upload the file at `path` to `key`, in a multipart upload once it's larger than a part
+    pub fn upload(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let size = metadata(path)?.len();
+        if size <= self.part_size {
+            let payload = Payload::File {
+                path,
+                offset: 0,
+                len: size,
+            };
+            self.request("PUT", key, &[], &payload)?;
+            return Ok(());
+        }
+        let initiated = self
+            .request("POST", key, &[("uploads", "")], &Payload::Bytes(&[]))?
+            .into_string()?;
+        let upload_id = xml_values(&initiated, "UploadId")
+            .pop()
+            .ok_or_else(|| Error::Storage(format!("no upload id for {}", key)))?;
+        let result = self.upload_parts(key, path, size, &upload_id);
+        if result.is_err() {
+            // parts of an upload neither completed nor aborted are stored, and billed, until then
+            let query = [("uploadId", upload_id.as_str())];
+            if let Err(err) = self.request("DELETE", key, &query, &Payload::Bytes(&[])) {
+                debug!("error aborting upload of {}: {}", key, err);
+            }
+        }
+        result
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        path: &Path,
+        size: u64,
+        upload_id: &str,
+    ) -> Result<(), Error> {
+        let part_size = self.part_size.max(size.div_ceil(MAX_PARTS));
+        let mut parts = String::new();
+        for (index, offset) in (0..size).step_by(part_size as usize).enumerate() {
+            let number = (index + 1).to_string();
+            let payload = Payload::File {
+                path,
+                offset,
+                len: part_size.min(size - offset),
+            };
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+            let response = self.request("PUT", key, &query, &payload)?;
+            let etag = response
+                .header("ETag")
+                .ok_or_else(|| Error::Storage(format!("no ETag for part {} of {}", number, key)))?;
+            parts.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number, etag
+            ));
+        }
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let query = [("uploadId", upload_id)];
+        let completed = self
+            .request("POST", key, &query, &Payload::Bytes(body.as_bytes()))?
+            .into_string()?;
+        // completing can still fail after the response started, with an error in its body
+        match xml_values(&completed, "Message").pop() {
+            Some(message) if completed.contains("<Error>") => Err(Error::Storage(message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the object at `key` to `path`
+    pub fn download(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let mut reader = self
+            .request("GET", key, &[], &Payload::Bytes(&[]))?
+            .into_reader();
+        copy(&mut reader, &mut File::create(path)?)?;
+        Ok(())
+    }
+
+    /// Keys starting with `prefix`, in the order the bucket lists them
+    pub fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_owned()), ("prefix", prefix.to_owned())];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let query: Vec<(&str, &str)> = query.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            let listing = self
+                .request("GET", "", &query, &Payload::Bytes(&[]))?
+                .into_string()?;
+            keys.extend(xml_values(&listing, "Key"));
+            match xml_values(&listing, "IsTruncated")
+                .first()
+                .map(String::as_str)
+            {
+                Some("true") => token = xml_values(&listing, "NextContinuationToken").pop(),
+                _ => break,
+            }
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        payload: &Payload,
+    ) -> Result<ureq::Response, Error> {
+        let mut url = self.endpoint.clone();
+        let path = match self.path_style {
+            true => format!("/{}/{}", self.bucket, key),
+            false => {
+                let host = format!("{}.{}", self.bucket, url.host_str().unwrap_or_default());
+                url.set_host(Some(&host))
+                    .map_err(|err| Error::Storage(err.to_string()))?;
+                format!("/{}", key)
+            }
+        };
+        let path = uri_encode(&path, false);
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let time = Utc::now();
+        let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = payload.sha256()?;
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+        let canonical = canonical_request(method, &path, query, &headers, &payload_hash);
+        let authorization = authorization(
+            &self.access_key,
+            &self.secret_key,
+            &self.region,
+            "s3",
+            time,
+            &canonical,
+            &headers,
+        );
+        let query_string = canonical_query(query);
+        let url = format!(
+            "{}://{}{}{}{}",
+            url.scheme(),
+            host,
+            path,
+            if query_string.is_empty() { "" } else { "?" },
+            query_string
+        );
+        debug!("{} {}", method, url);
+        let mut request = ureq::request(method, &url).set("Authorization", &authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        request
+            .set("Content-Length", &payload.len().to_string())
+            .send(payload.reader()?)
+            .map_err(|err| Error::Storage(err.to_string()))
+    }
+}
+
+/// Upload a bundle of the repo's snapshots taken since the last one to its bucket, with
+/// `snapshot.s3.bucket` set. The key written, `None` without anything new.
+pub fn backup(repo: &Repo) -> Result<Option<String>, Error> {
+    let config = repo.git_repo().config()?;
+    let Some(target) = S3Target::from_config(&config, repo.name())? else {
+        return Ok(None);
+    };
+    let target_url = target.url();
+    let full = State::load(repo.git_repo().path())?
+        .last_bundled(&target_url)
+        .is_empty();
+    let path = repo.git_repo().path().join(UPLOAD_FILE);
+    repo.enter_phase("bundle")?;
+    let Some(heads) = repo.create_bundle(&path, &target_url, None)? else {
+        return Ok(None);
+    };
+    let key = bundle_key(&target.prefix, full, Utc::now(), heads[0].1);
+    let uploaded = repo
+        .enter_phase("upload")
+        .and_then(|_| target.upload(&key, &path))
+        .map(|_| key);
+    let _ = remove_file(&path);
+    let key = uploaded?;
+    // a bundle given up on during its upload isn't recorded, so the next one carries it again
+    repo.enter_phase("record")?;
+    repo.record_bundle(&target_url, &heads)?;
+    info!(target: repo.name(), "uploaded snapshot bundle {}", key);
+    Ok(Some(key))
+}
+
+/// `backup` once `snapshot.s3.interval` passed since the last bundle
+pub fn backup_due(repo: &Repo) -> Result<Option<String>, Error> {
+    let config = repo.git_repo().config()?;
+    let Some(target) = S3Target::from_config(&config, repo.name())? else {
+        return Ok(None);
+    };
+    match repo.bundle_due(&target.url(), target.interval)? {
+        true => backup(repo),
+        false => Ok(None),
+    }
+}
+
+/// Fetch the snapshot branches of the bundles in `target`: the latest full one and the
+/// incremental ones written after it
+pub fn restore_bundles(repo: &Repo, target: &S3Target) -> Result<Vec<(String, Oid)>, Error> {
+    let mut keys: Vec<String> = target
+        .list(&target.prefix)?
+        .into_iter()
+        .filter(|key| key.ends_with(BUNDLE_EXTENSION))
+        .collect();
+    // named by time whether full or incremental
+    keys.sort_by(|a, b| file_name(a).cmp(file_name(b)));
+    let start = keys
+        .iter()
+        .rposition(|key| is_full(&target.prefix, key))
+        .ok_or_else(|| Error::Storage(format!("no full bundle in {}", target.url())))?;
+    let path = repo.git_repo().path().join(UPLOAD_FILE);
+    let mut heads = Vec::new();
+    for key in &keys[start..] {
+        target.download(key, &path)?;
+        let fetched = repo.unbundle(&path);
+        let _ = remove_file(&path);
+        heads = fetched?;
+    }
+    Ok(heads)
+}
+
+// `<prefix>/full|incremental/<yyyy>/<mm>/<dd>/<time>-<commit>.bundle`, dated so lifecycle rules
+// can move or expire them by prefix, with milliseconds so they sort in the order they're written
+fn bundle_key(prefix: &str, full: bool, time: DateTime<Utc>, tip: Oid) -> String {
+    let kind = if full { "full" } else { "incremental" };
+    let short = tip.to_string();
+    format!(
+        "{}/{}/{}/{}-{}{}",
+        prefix,
+        kind,
+        time.format("%Y/%m/%d"),
+        time.format("%Y%m%dT%H%M%S%.3fZ"),
+        &short[..12],
+        BUNDLE_EXTENSION
+    )
+}
+
+fn is_full(prefix: &str, key: &str) -> bool {
+    key.strip_prefix(prefix)
+        .is_some_and(|rest| rest.trim_start_matches('/').starts_with("full/"))
+}
+
+fn file_name(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+// The text of every `<tag>` element, which S3 listings don't nest
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|rest| rest.split_once(&close).map(|(value, _)| value))
+        .map(|value| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+// Percent-encoding of everything but unreserved characters, and slashes in paths
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_owned(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut pairs: Vec<String> = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+        .collect();
+    pairs.sort();
+    pairs.join("&")
+}
+
+// Signature version 4 canonical request, with `headers` lowercase and sorted by name
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &[(&str, &str)],
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        canonical_query(query),
+        canonical_headers,
+        signed_headers(headers),
+        payload_hash
+    )
+}
+
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+    headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn authorization(
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    service: &str,
+    time: DateTime<Utc>,
+    canonical_request: &str,
+    headers: &[(&str, &str)],
+) -> String {
+    let date = time.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        time.format("%Y%m%dT%H%M%SZ"),
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| {
+            hmac(&key, part.as_bytes())
+        });
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key,
+        scope,
+        signed_headers(headers),
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        cancel::CancelToken,
+        test_util::{create_temp_file, test_repo_with_files},
+    };
+
+    #[test]
+    fn signature() {
+        // the example request of the AWS signature version 4 documentation
+        let headers = [
+            (
+                "content-type",
+                "application/x-www-form-urlencoded; charset=utf-8",
+            ),
+            ("host", "iam.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ];
+        let canonical = canonical_request(
+            "GET",
+            "/",
+            &[("Version", "2010-05-08"), ("Action", "ListUsers")],
+            &headers,
+            &hex(&Sha256::digest(b"")),
+        );
+        assert_eq!(
+            "f536975d06c0309214f805bb90ccff089219ecd68b2577efef23edd43b7e1a59",
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let authorization = authorization(
+            "AKIDEXAMPLE",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "iam",
+            Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap(),
+            &canonical,
+            &headers,
+        );
+        assert_eq!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7",
+            authorization
+        );
+    }
+
+    #[test]
+    fn signature_test_suite() {
+        // requests of the AWS signature version 4 test suite, signed with its credentials
+        let unreserved = "-._~0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        // test name, path, query and signature
+        type Case<'a> = (&'a str, &'a str, &'a [(&'a str, &'a str)], &'a str);
+        let cases: [Case; 9] = [
+            (
+                "get-vanilla",
+                "/",
+                &[],
+                "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            ),
+            (
+                "get-vanilla-query-order-key-case",
+                "/",
+                &[("Param2", "value2"), ("Param1", "value1")],
+                "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+            ),
+            (
+                "get-vanilla-query-order-value",
+                "/",
+                &[("Param1", "value2"), ("Param1", "Value1")],
+                "eedbc4e291e521cf13422ffca22be7d2eb8146eecf653089df300a15b2382bd1",
+            ),
+            (
+                "get-vanilla-empty-query-key",
+                "/",
+                &[("Param1", "value1")],
+                "a67d582fa61cc504c4bae71f336f98b97f1ea3c7a6bfe1b6e45aec72011b9aeb",
+            ),
+            (
+                "get-vanilla-query-unreserved",
+                "/",
+                &[(unreserved, unreserved)],
+                "9c3e54bfcdf0b19771a7f523ee5669cdf59bc7cc0884027167c21bb143a40197",
+            ),
+            (
+                "get-unreserved",
+                &format!("/{}", unreserved),
+                &[],
+                "07ef7494c76fa4850883e2b006601f940f8a34d404d0cfa977f52a65bbf5f24f",
+            ),
+            (
+                "get-utf8",
+                "/\u{1234}",
+                &[],
+                "8318018e0b0f223aa2bbf98705b62bb787dc9c0e678f255a891fd03141be5d85",
+            ),
+            (
+                "get-space",
+                "/example space/",
+                &[],
+                "652487583200325589f1fba4c7e578f72c47cb61beeca81406b39ddec1366741",
+            ),
+            (
+                "post-vanilla",
+                "/",
+                &[],
+                "5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b",
+            ),
+        ];
+        let headers = [
+            ("host", "example.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ];
+        for (name, path, query, signature) in cases {
+            let method = name.split('-').next().unwrap().to_ascii_uppercase();
+            let canonical = canonical_request(
+                &method,
+                &uri_encode(path, false),
+                query,
+                &headers,
+                &hex(&Sha256::digest(b"")),
+            );
+            let authorization = authorization(
+                "AKIDEXAMPLE",
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                "us-east-1",
+                "service",
+                Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap(),
+                &canonical,
+                &headers,
+            );
+            assert_eq!(
+                format!(
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                     SignedHeaders=host;x-amz-date, Signature={}",
+                    signature
+                ),
+                authorization,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 1).unwrap();
+        let tip = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let key = bundle_key("git-snapshot/app", false, time, tip);
+        assert_eq!(
+            "git-snapshot/app/incremental/2024/03/05/20240305T080001.000Z-0123456789ab.bundle",
+            key
+        );
+        assert!(!is_full("git-snapshot/app", &key));
+        assert!(is_full(
+            "git-snapshot/app",
+            &bundle_key("git-snapshot/app", true, time, tip)
+        ));
+        assert_eq!(
+            vec!["a&b".to_owned(), "c".to_owned()],
+            xml_values("<R><Key>a&amp;b</Key><Key>c</Key></R>", "Key")
+        );
+    }
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    // An S3 endpoint keeping objects, and the parts of multipart uploads, in memory, without
+    // checking signatures
+    fn fake_s3() -> (String, Objects) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        let store = objects.clone();
+        let mut uploaded_parts: BTreeMap<(String, usize), Vec<u8>> = BTreeMap::new();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let mut objects = store.lock().unwrap();
+                let part_number = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("partNumber="))
+                    .map(|number| number.parse::<usize>().unwrap());
+                let mut etag = String::new();
+                let (status, response) = match method {
+                    "PUT" => {
+                        match part_number {
+                            Some(number) => {
+                                etag = format!("ETag: \"{}\"\r\n", number);
+                                uploaded_parts.insert((path.to_owned(), number), body);
+                            }
+                            None => {
+                                objects.insert(path.to_owned(), body);
+                            }
+                        }
+                        ("200 OK", Vec::new())
+                    }
+                    "POST" if query.starts_with("uploads") => (
+                        "200 OK",
+                        b"<InitiateMultipartUploadResult><UploadId>1</UploadId>\
+                          </InitiateMultipartUploadResult>"
+                            .to_vec(),
+                    ),
+                    "POST" => {
+                        let listed = String::from_utf8(body).unwrap();
+                        let object = xml_values(&listed, "PartNumber")
+                            .iter()
+                            .flat_map(|number| {
+                                uploaded_parts
+                                    .remove(&(path.to_owned(), number.parse().unwrap()))
+                                    .unwrap()
+                            })
+                            .collect();
+                        objects.insert(path.to_owned(), object);
+                        ("200 OK", b"<CompleteMultipartUploadResult/>".to_vec())
+                    }
+                    "GET" if query.contains("list-type=2") => {
+                        let keys: String = objects
+                            .keys()
+                            .map(|key| format!("<Key>{}</Key>", key.splitn(3, '/').nth(2).unwrap()))
+                            .collect();
+                        let xml = format!(
+                            "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                            keys
+                        );
+                        ("200 OK", xml.into_bytes())
+                    }
+                    _ => match objects.get(path) {
+                        Some(object) => ("200 OK", object.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    },
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    etag,
+                    response.len()
+                )
+                .unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (endpoint, objects)
+    }
+
+    #[test]
+    fn backup_restore() {
+        let (endpoint, objects) = fake_s3();
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.set_str("snapshot.s3.bucket", "backups").unwrap();
+        config.set_str("snapshot.s3.endpoint", &endpoint).unwrap();
+        config.set_str("snapshot.s3.name", "app").unwrap();
+        config.set_str("snapshot.s3.accesskeyid", "key").unwrap();
+        config
+            .set_str("snapshot.s3.secretaccesskey", "secret")
+            .unwrap();
+        let repo = Repo::new(repo);
+        assert_eq!(None, backup(&repo).unwrap());
+
+        repo.snapshot().unwrap();
+        // one given up on stops before bundling
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let cancelled = Repo::from_path(temp_dir.path())
+            .unwrap()
+            .with_cancel(cancel);
+        assert!(matches!(
+            backup(&cancelled),
+            Err(Error::Cancelled("bundle"))
+        ));
+        assert!(objects.lock().unwrap().is_empty());
+
+        let full = backup(&repo).unwrap().unwrap();
+        assert!(full.starts_with("git-snapshot/app/full/"));
+        // nothing new, and not due either
+        assert_eq!(None, backup(&repo).unwrap());
+        assert_eq!(None, backup_due(&repo).unwrap());
+
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        let incremental = backup(&repo).unwrap().unwrap();
+        assert!(incremental.starts_with("git-snapshot/app/incremental/"));
+        assert_eq!(2, objects.lock().unwrap().len());
+
+        // into a new clone of the repo
+        let other_dir = tempdir().unwrap();
+        let (other, _) = test_repo_with_files(other_dir.path());
+        let other = Repo::new(other);
+        let target = S3Target::from_url(&config, "s3://backups/git-snapshot/app").unwrap();
+        let heads = restore_bundles(&other, &target).unwrap();
+        assert_eq!(
+            vec![(
+                "refs/heads/snapshot/master".to_owned(),
+                repo.find_snapshot(None).unwrap().id()
+            )],
+            heads
+        );
+    }
+
+    #[test]
+    fn multipart_upload() {
+        let (endpoint, objects) = fake_s3();
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.set_str("snapshot.s3.endpoint", &endpoint).unwrap();
+        let mut target = S3Target::from_url(&config, "s3://backups/app").unwrap();
+        // smaller than S3 takes
+        target.part_size = 4;
+        let path = temp_dir.path().join("upload");
+        std::fs::write(&path, "0123456789").unwrap();
+
+        target.upload("app/a", &path).unwrap();
+        assert_eq!(
+            Some(&b"0123456789".to_vec()),
+            objects.lock().unwrap().get("/backups/app/a")
+        );
+        let downloaded = temp_dir.path().join("download");
+        target.download("app/a", &downloaded).unwrap();
+        assert_eq!("0123456789", std::fs::read_to_string(downloaded).unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use git2::{Commit, Delta, DiffOptions, Repository};
+use regex::Regex;
+
+use crate::{
+    error::Error,
+    history::{walk, LogCommit},
+};
+
+/// A line matching a grep pattern in a file version introduced by a snapshot
+#[derive(Debug)]
+pub struct GrepMatch {
+    pub snapshot: LogCommit,
+    pub path: PathBuf,
+    pub line_number: usize,
+    pub line: String,
+}
+
+impl Display for GrepMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}:{}:{}",
+            self.snapshot.short_id(),
+            humantime::format_rfc3339_seconds(self.snapshot.time),
+            self.path.display(),
+            self.line_number,
+            self.line
+        )
+    }
+}
+
+/// Search the files added or modified by each snapshot on `snapshot_ref` newer than `since`, so
+/// every version of a file is searched once and reported against the snapshot that introduced it.
+/// With `since` the whole tree of the last snapshot before it is searched as well, reported
+/// against that snapshot, so content from before the window still in it is found
+pub fn grep(
+    repo: &Repository,
+    snapshot_ref: &str,
+    pattern: &Regex,
+    since: Option<SystemTime>,
+) -> Result<Vec<GrepMatch>, Error> {
+    let mut matches = Vec::new();
+    let commits = walk(repo, snapshot_ref, since)?;
+    for commit in &commits {
+        let parent = commit.parents().next();
+        grep_commit(repo, commit, parent.as_ref(), pattern, &mut matches)?;
+    }
+
+    if since.is_some() {
+        let start = match commits.last() {
+            Some(oldest) => oldest.parents().next(),
+            None => match repo.refname_to_id(snapshot_ref) {
+                Ok(id) => Some(repo.find_commit(id)?),
+                Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+                Err(err) => return Err(err.into()),
+            },
+        };
+        if let Some(start) = start {
+            grep_commit(repo, &start, None, pattern, &mut matches)?;
+        }
+    }
+    Ok(matches)
+}
+
+// Lines matching `pattern` in the files `commit` added or modified since `parent`, all of its
+// files without one
+fn grep_commit(
+    repo: &Repository,
+    commit: &Commit,
+    parent: Option<&Commit>,
+    pattern: &Regex,
+    matches: &mut Vec<GrepMatch>,
+) -> Result<(), Error> {
+    let tree = commit.tree()?;
+    let parent_tree = match parent {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&tree),
+        Some(DiffOptions::new().skip_binary_check(true)),
+    )?;
+
+    let snapshot = LogCommit::new(commit);
+    for delta in diff.deltas() {
+        if !matches!(delta.status(), Delta::Added | Delta::Modified) {
+            continue;
+        }
+        let file = delta.new_file();
+        let (path, blob) = match (file.path(), repo.find_blob(file.id())) {
+            (Some(path), Ok(blob)) if !blob.is_binary() => (path, blob),
+            _ => continue,
+        };
+        let content = String::from_utf8_lossy(blob.content());
+        for (i, line) in content.lines().enumerate() {
+            if pattern.is_match(line) {
+                matches.push(GrepMatch {
+                    snapshot: snapshot.clone(),
+                    path: path.to_owned(),
+                    line_number: i + 1,
+                    line: line.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::write, path::Path, sync::Arc, time::Duration};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{clock::FixedClock, test_util::test_repo, Repo};
+
+    #[test]
+    fn grep_snapshots() {
+        let temp_dir = tempdir().unwrap();
+        let (_git_repo, _config) = test_repo(temp_dir.path());
+        let repo = Repo::from_path(temp_dir.path()).unwrap();
+
+        write(temp_dir.path().join("a"), "fn old() {}\n").unwrap();
+        write(temp_dir.path().join("b"), "unrelated\n").unwrap();
+        repo.snapshot().unwrap();
+        write(temp_dir.path().join("a"), "fn old() {}\nfn lost() {}\n").unwrap();
+        repo.snapshot().unwrap();
+        write(temp_dir.path().join("a"), "fn old() {}\n").unwrap();
+        repo.snapshot().unwrap();
+
+        let matches = repo.grep(&Regex::new("fn lost").unwrap(), None).unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(Path::new("a"), matches[0].path);
+        assert_eq!(2, matches[0].line_number);
+        assert_eq!("fn lost() {}", matches[0].line);
+
+        // each version of a file is only reported by the snapshot that introduced it
+        let matches = repo.grep(&Regex::new("fn old").unwrap(), None).unwrap();
+        assert_eq!(3, matches.len());
+        let unrelated = repo.grep(&Regex::new("unrelated").unwrap(), None).unwrap();
+        assert_eq!(1, unrelated.len());
+    }
+
+    #[test]
+    fn grep_since_window_start() {
+        let temp_dir = tempdir().unwrap();
+        let (git_repo, _config) = test_repo(temp_dir.path());
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let clock = Arc::new(FixedClock::new(start));
+        let repo = Repo::new(git_repo).with_clock(clock.clone());
+
+        write(temp_dir.path().join("a"), "fn old() {}\n").unwrap();
+        repo.snapshot().unwrap();
+        let since = start + Duration::from_secs(60);
+        let old = Regex::new("fn old").unwrap();
+        let new = Regex::new("fn new").unwrap();
+
+        // the window holding no snapshots still searches the tree at its start
+        let matches = repo.grep(&old, Some(since)).unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(start, matches[0].snapshot.time);
+
+        clock.advance(Duration::from_secs(120));
+        write(temp_dir.path().join("b"), "fn new() {}\n").unwrap();
+        repo.snapshot().unwrap();
+
+        let matches = repo.grep(&old, Some(since)).unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(Path::new("a"), matches[0].path);
+        assert_eq!(start, matches[0].snapshot.time);
+        let matches = repo.grep(&new, Some(since)).unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(start + Duration::from_secs(120), matches[0].snapshot.time);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    env::var_os,
+    fmt::{self, Debug, Display},
+    fs::{create_dir_all, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use age::{secrecy::ExposeSecret, x25519, Decryptor, Encryptor, IdentityFile};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+const AGE_PREFIX: &str = "age:";
+const KEYRING_PREFIX: &str = "keyring:";
+const KEYRING_SERVICE: &str = "git-snapshot";
+
+/// A credential in a config. `age:<base64>` values are encrypted to the age identity and
+/// `keyring:<name>` values are stored in the OS keyring, both are only revealed when used.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Secret {
+    Plain(String),
+    Age(Vec<u8>),
+    Keyring(String),
+}
+
+impl Secret {
+    /// The plain value, decrypted with the age identity or read from the keyring
+    pub fn reveal(&self) -> Result<String, Error> {
+        match self {
+            Self::Plain(value) => Ok(value.clone()),
+            Self::Age(ciphertext) => decrypt(ciphertext, &identity_path()?),
+            Self::Keyring(name) => keyring::Entry::new(KEYRING_SERVICE, name)
+                .and_then(|entry| entry.get_password())
+                .map_err(secret_error),
+        }
+    }
+
+    /// Encrypt `value` to the age identity, which is generated when there's none yet
+    pub fn encrypt(value: &str) -> Result<Self, Error> {
+        encrypt(value, &identity_path()?).map(Self::Age)
+    }
+
+    /// Store `value` in the OS keyring under `name`
+    pub fn store(name: &str, value: &str) -> Result<Self, Error> {
+        keyring::Entry::new(KEYRING_SERVICE, name)
+            .and_then(|entry| entry.set_password(value))
+            .map_err(secret_error)?;
+        Ok(Self::Keyring(name.to_owned()))
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(ciphertext) = s.strip_prefix(AGE_PREFIX) {
+            return STANDARD
+                .decode(ciphertext)
+                .map(Self::Age)
+                .map_err(secret_error);
+        }
+        if let Some(name) = s.strip_prefix(KEYRING_PREFIX) {
+            return Ok(Self::Keyring(name.to_owned()));
+        }
+        Ok(Self::Plain(s.to_owned()))
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Secret> for String {
+    fn from(secret: Secret) -> Self {
+        secret.to_string()
+    }
+}
+
+/// The config value, plain secrets included
+impl Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain(value) => write!(f, "{}", value),
+            Self::Age(ciphertext) => write!(f, "{}{}", AGE_PREFIX, STANDARD.encode(ciphertext)),
+            Self::Keyring(name) => write!(f, "{}{}", KEYRING_PREFIX, name),
+        }
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain(_) => write!(f, "Plain(..)"),
+            Self::Age(_) => write!(f, "Age(..)"),
+            Self::Keyring(name) => f.debug_tuple("Keyring").field(name).finish(),
+        }
+    }
+}
+
+/// `GIT_SNAPSHOT_AGE_IDENTITY` or `~/.config/git-snapshot/identity.txt`
+pub fn identity_path() -> Result<PathBuf, Error> {
+    if let Some(path) = var_os("GIT_SNAPSHOT_AGE_IDENTITY") {
+        return Ok(PathBuf::from(path));
+    }
+    let home = dirs::home_dir().ok_or_else(|| secret_error("unable to get home directory"))?;
+    Ok(home.join(
+        [".config", "git-snapshot", "identity.txt"]
+            .iter()
+            .collect::<PathBuf>(),
+    ))
+}
+
+fn decrypt(ciphertext: &[u8], identity_path: &Path) -> Result<String, Error> {
+    let identities = IdentityFile::from_file(identity_path.to_string_lossy().into_owned())?
+        .into_identities()
+        .map_err(secret_error)?;
+    let mut reader = Decryptor::new(ciphertext)
+        .and_then(|decryptor| decryptor.decrypt(identities.iter().map(|i| i.as_ref() as _)))
+        .map_err(secret_error)?;
+    let mut value = String::new();
+    reader.read_to_string(&mut value)?;
+    Ok(value)
+}
+
+fn encrypt(value: &str, identity_path: &Path) -> Result<Vec<u8>, Error> {
+    if !identity_path.exists() {
+        generate_identity(identity_path)?;
+    }
+    let recipients = IdentityFile::from_file(identity_path.to_string_lossy().into_owned())?
+        .to_recipients()
+        .map_err(secret_error)?;
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))
+        .map_err(secret_error)?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(value.as_bytes())?;
+    writer.finish()?;
+    Ok(ciphertext)
+}
+
+// Only readable by the owner
+fn generate_identity(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let identity = x25519::Identity::generate();
+    writeln!(
+        options.open(path)?,
+        "# public key: {}\n{}",
+        identity.to_public(),
+        identity.to_string().expose_secret()
+    )?;
+    Ok(())
+}
+
+pub(crate) fn secret_error(err: impl Display) -> Error {
+    Error::Secret(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn age() {
+        let temp_dir = tempdir().unwrap();
+        let identity = temp_dir.path().join("identity.txt");
+
+        let ciphertext = encrypt("token", &identity).unwrap();
+        assert!(identity.exists());
+        assert_eq!("token", decrypt(&ciphertext, &identity).unwrap());
+
+        // a value encrypted to another identity can't be revealed
+        let other = temp_dir.path().join("other.txt");
+        assert!(decrypt(&encrypt("token", &other).unwrap(), &identity).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let plain: Secret = serde_json::from_str(r#""token""#).unwrap();
+        assert_eq!(Secret::Plain("token".to_owned()), plain);
+        assert_eq!("token", plain.reveal().unwrap());
+        assert_eq!("Plain(..)", format!("{:?}", plain));
+
+        let age = Secret::Age(vec![1, 2, 3]);
+        assert_eq!("age:AQID", age.to_string());
+        assert_eq!(age, "age:AQID".parse().unwrap());
+        assert_eq!(
+            Secret::Keyring("push".to_owned()),
+            "keyring:push".parse().unwrap()
+        );
+        assert!(matches!(
+            "age:not base64".parse::<Secret>(),
+            Err(Error::Secret(_))
+        ));
+    }
+}
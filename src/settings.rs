@@ -0,0 +1,245 @@
+use std::env::var;
+
+use git2::{Config, ConfigLevel};
+use serde::{Deserialize, Serialize};
+
+/// Environment variable setting the threads hashing files
+pub const THREADS_ENV: &str = "GIT_SNAPSHOT_THREADS";
+/// Environment variable logging snapshot timings when set to anything but `0`
+pub const TIMINGS_ENV: &str = "GIT_SNAPSHOT_TIMINGS";
+
+const DEFAULT_THREADS: usize = 1;
+
+/// Layer a setting was resolved from, highest precedence first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Flag,
+    Env,
+    /// The repo's entry of the watcher config
+    RepoWatchConfig,
+    WatchConfig,
+    /// The repo's own git config
+    RepoGitConfig,
+    /// Global or system git config
+    GlobalGitConfig,
+    Default,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Settings one layer sets, unset ones fall through to the layers below
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SettingOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<bool>,
+}
+
+/// The layers above the environment and git config
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SettingLayers {
+    pub flags: SettingOverrides,
+    pub repo: SettingOverrides,
+    pub watch: SettingOverrides,
+}
+
+/// Settings of a snapshot, resolved once from command line flags, then the environment, the
+/// repo's entry of the watcher config, the watcher config, the repo's git config, global git
+/// config and finally the defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Threads hashing files when the whole working tree is indexed, `snapshot.threads`
+    pub threads: Setting<usize>,
+    /// Log the duration of each snapshot phase, `snapshot.timings`
+    pub timings: Setting<bool>,
+}
+
+impl Settings {
+    pub fn resolve(layers: &SettingLayers, config: &Config) -> Self {
+        Self::resolve_with(layers, |name| var(name).ok(), config)
+    }
+
+    fn resolve_with(
+        layers: &SettingLayers,
+        env: impl Fn(&str) -> Option<String>,
+        config: &Config,
+    ) -> Self {
+        let threads = resolve(
+            layers,
+            |overrides| overrides.threads,
+            env(THREADS_ENV).and_then(|value| value.trim().parse().ok()),
+            git_value(config, "snapshot.threads", |config, key| {
+                config
+                    .get_i64(key)
+                    .map(|threads| usize::try_from(threads).unwrap_or(DEFAULT_THREADS))
+            }),
+            DEFAULT_THREADS,
+        );
+        let timings = resolve(
+            layers,
+            |overrides| overrides.timings,
+            env(TIMINGS_ENV).map(|value| !matches!(value.as_str(), "" | "0" | "false")),
+            git_value(config, "snapshot.timings", Config::get_bool),
+            false,
+        );
+        Self {
+            threads: Setting {
+                value: threads.value.max(1),
+                ..threads
+            },
+            timings,
+        }
+    }
+}
+
+// The value of the highest layer setting it
+fn resolve<T>(
+    layers: &SettingLayers,
+    get: impl Fn(&SettingOverrides) -> Option<T>,
+    env: Option<T>,
+    git: Option<Setting<T>>,
+    default_value: T,
+) -> Setting<T> {
+    [
+        (get(&layers.flags), Source::Flag),
+        (env, Source::Env),
+        (get(&layers.repo), Source::RepoWatchConfig),
+        (get(&layers.watch), Source::WatchConfig),
+    ]
+    .into_iter()
+    .find_map(|(value, source)| value.map(|value| Setting { value, source }))
+    .or(git)
+    .unwrap_or(Setting {
+        value: default_value,
+        source: Source::Default,
+    })
+}
+
+// A git config value, sourced from the repo when the winning entry is in its own config
+fn git_value<T>(
+    config: &Config,
+    key: &str,
+    get: impl Fn(&Config, &str) -> Result<T, git2::Error>,
+) -> Option<Setting<T>> {
+    let level = config.get_entry(key).ok()?.level();
+    let value = get(config, key).ok()?;
+    let source = match level {
+        ConfigLevel::Local | ConfigLevel::App => Source::RepoGitConfig,
+        _ => Source::GlobalGitConfig,
+    };
+    Some(Setting { value, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    // Config of a global and a repo file, without the user's own
+    fn layered_config(dir: &std::path::Path) -> Config {
+        let mut config = Config::new().unwrap();
+        config
+            .add_file(&dir.join("global"), ConfigLevel::Global, false)
+            .unwrap();
+        config
+            .add_file(&dir.join("local"), ConfigLevel::Local, false)
+            .unwrap();
+        config
+    }
+
+    #[test]
+    fn precedence() {
+        let temp = tempdir().unwrap();
+        let config = layered_config(temp.path());
+        let mut global = config.open_level(ConfigLevel::Global).unwrap();
+        let mut local = config.open_level(ConfigLevel::Local).unwrap();
+        global.set_i64("snapshot.threads", 6).unwrap();
+        global.set_bool("snapshot.timings", false).unwrap();
+        local.set_i64("snapshot.threads", 5).unwrap();
+        local.set_bool("snapshot.timings", true).unwrap();
+
+        let overrides = |threads, timings| SettingOverrides {
+            threads: Some(threads),
+            timings: Some(timings),
+        };
+        let mut layers = SettingLayers {
+            flags: overrides(1, true),
+            repo: overrides(3, true),
+            watch: overrides(4, false),
+        };
+        let mut env = HashMap::from([(THREADS_ENV, "2"), (TIMINGS_ENV, "0")]);
+        let resolve = |layers: &SettingLayers, env: &HashMap<&str, &str>| {
+            Settings::resolve_with(layers, |name| env.get(name).map(|v| v.to_string()), &config)
+        };
+        let expect = |threads, timings, source| Settings {
+            threads: Setting {
+                value: threads,
+                source,
+            },
+            timings: Setting {
+                value: timings,
+                source,
+            },
+        };
+
+        assert_eq!(expect(1, true, Source::Flag), resolve(&layers, &env));
+        layers.flags = SettingOverrides::default();
+        assert_eq!(expect(2, false, Source::Env), resolve(&layers, &env));
+        env.clear();
+        assert_eq!(
+            expect(3, true, Source::RepoWatchConfig),
+            resolve(&layers, &env)
+        );
+        layers.repo = SettingOverrides::default();
+        assert_eq!(
+            expect(4, false, Source::WatchConfig),
+            resolve(&layers, &env)
+        );
+        layers.watch = SettingOverrides::default();
+        assert_eq!(
+            expect(5, true, Source::RepoGitConfig),
+            resolve(&layers, &env)
+        );
+        local.remove("snapshot.threads").unwrap();
+        local.remove("snapshot.timings").unwrap();
+        assert_eq!(
+            expect(6, false, Source::GlobalGitConfig),
+            resolve(&layers, &env)
+        );
+        global.remove("snapshot.threads").unwrap();
+        global.remove("snapshot.timings").unwrap();
+        assert_eq!(expect(1, false, Source::Default), resolve(&layers, &env));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let temp = tempdir().unwrap();
+        let config = layered_config(temp.path());
+        let env = |name: &str| (name == THREADS_ENV).then(|| "many".to_owned());
+        let layers = SettingLayers {
+            watch: SettingOverrides {
+                threads: Some(0),
+                timings: None,
+            },
+            ..Default::default()
+        };
+
+        // an unparsable variable falls through, too few threads are raised to one
+        let settings = Settings::resolve_with(&layers, env, &config);
+        assert_eq!(
+            Setting {
+                value: 1,
+                source: Source::WatchConfig
+            },
+            settings.threads
+        );
+    }
+}
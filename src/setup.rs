@@ -0,0 +1,288 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::{ConfigLevel, Repository, RepositoryInitOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{ConfigSignature, SignatureProvider},
+    error::Error,
+    ConfigScope, Repo,
+};
+
+/// Git config set by `init` where the repo doesn't set the key already
+pub const RECOMMENDED_CONFIG: &[(&str, &str)] = &[
+    // chmod sweeps by build tools aren't worth a snapshot
+    ("snapshot.ignoremodechanges", "true"),
+];
+
+/// Set the recommended keys the repo's config leaves unset, returning the ones set
+pub fn apply_recommended_config(repo: &Repo) -> Result<Vec<&'static str>, Error> {
+    let config = repo.git_repo().config()?;
+    let mut applied = Vec::new();
+    for &(key, value) in RECOMMENDED_CONFIG {
+        if config.get_entry(key).is_err() {
+            repo.set_config(ConfigScope::Local, key, value)?;
+            applied.push(key);
+        }
+    }
+    Ok(applied)
+}
+
+/// Create a repo at `path` with an empty initial commit on `init.defaultBranch`, so it has a
+/// branch to snapshot from the first change
+pub fn create_repo(path: &Path) -> Result<Repo, Error> {
+    if Repository::open(path).is_ok() {
+        return Err(Error::RepoExists(path.to_owned()));
+    }
+    let git_repo = Repository::init_opts(path, RepositoryInitOptions::new().mkpath(true))?;
+    initial_commit(&git_repo)?;
+    Ok(Repo::new(git_repo))
+}
+
+/// Commit an empty tree to the unborn branch of a new repo
+pub(crate) fn initial_commit(git_repo: &Repository) -> Result<(), Error> {
+    let signature = ConfigSignature.signature(git_repo, SystemTime::now())?;
+    let tree = git_repo.treebuilder(None)?.write()?;
+    git_repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        "Initial commit",
+        &git_repo.find_tree(tree)?,
+        &[],
+    )?;
+    Ok(())
+}
+
+/// Git config applied to repos as they're added to the watcher config, the `defaults` of the
+/// watcher config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RepoDefaults {
+    /// `snapshot.snapshotbranch`, e.g. `wip/${BRANCH}`
+    #[serde(default)]
+    pub snapshot_branch: Option<String>,
+    /// `snapshot.snapshotmessage`
+    #[serde(default)]
+    pub snapshot_message: Option<String>,
+    /// Remotes snapshots are pushed to, where the repo has them
+    #[serde(default)]
+    pub remotes: Vec<String>,
+    /// Any other keys, e.g. `snapshot.squashsessions`
+    #[serde(default)]
+    pub git_config: BTreeMap<String, String>,
+}
+
+impl RepoDefaults {
+    fn entries(&self, repo: &Repository) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        if let Some(branch) = &self.snapshot_branch {
+            entries.push(("snapshot.snapshotbranch".to_owned(), branch.clone()));
+        }
+        if let Some(message) = &self.snapshot_message {
+            entries.push(("snapshot.snapshotmessage".to_owned(), message.clone()));
+        }
+        for remote in &self.remotes {
+            if repo.find_remote(remote).is_ok() {
+                entries.push((
+                    format!("remote.{}.snapshotenabled", remote),
+                    "true".to_owned(),
+                ));
+            }
+        }
+        entries.extend(self.git_config.clone());
+        entries
+    }
+}
+
+/// Set the keys of `defaults` the repo's own config leaves unset, returning the ones set
+pub fn apply_defaults(repo: &Repo, defaults: &RepoDefaults) -> Result<Vec<String>, Error> {
+    let config = repo.git_repo().config()?.open_level(ConfigLevel::Local)?;
+    let mut applied = Vec::new();
+    for (key, value) in defaults.entries(repo.git_repo()) {
+        if config.get_entry(&key).is_err() {
+            repo.set_config(ConfigScope::Local, &key, &value)?;
+            applied.push(key);
+        }
+    }
+    Ok(applied)
+}
+
+/// A user service running the watcher at login, a systemd unit on Linux and a launchd agent on
+/// macOS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceUnit {
+    pub path: PathBuf,
+    pub content: String,
+    /// Command loading the installed unit
+    pub enable_command: String,
+}
+
+impl ServiceUnit {
+    #[cfg(target_os = "linux")]
+    pub fn new(home: &Path, program: &Path, config: &Path) -> Result<Self, Error> {
+        let path = home.join(".config/systemd/user/git-snapshot.service");
+        let content = format!(
+            "[Unit]\n\
+             Description=git-snapshot watcher\n\
+             \n\
+             [Service]\n\
+             ExecStart=\"{}\" start-watcher --config \"{}\"\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            program.display(),
+            config.display()
+        );
+        Ok(Self {
+            path,
+            content,
+            enable_command: "systemctl --user enable --now git-snapshot".to_owned(),
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn new(home: &Path, program: &Path, config: &Path) -> Result<Self, Error> {
+        let path = home.join("Library/LaunchAgents/io.clynk.git-snapshot.plist");
+        let content = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>io.clynk.git-snapshot</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+        <string>start-watcher</string>
+        <string>--config</string>
+        <string>{}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+</dict>
+</plist>
+"#,
+            program.display(),
+            config.display()
+        );
+        let enable_command = format!("launchctl load -w {}", path.display());
+        Ok(Self {
+            path,
+            content,
+            enable_command,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn new(_home: &Path, _program: &Path, _config: &Path) -> Result<Self, Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "no service manager supported on this platform",
+        )
+        .into())
+    }
+
+    pub fn install(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&self.path, &self.content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn recommended_config() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config
+            .set_bool("snapshot.ignoremodechanges", false)
+            .unwrap();
+        let repo = Repo::new(repo);
+        assert!(apply_recommended_config(&repo).unwrap().is_empty());
+        assert!(!config.get_bool("snapshot.ignoremodechanges").unwrap());
+
+        config.remove("snapshot.ignoremodechanges").unwrap();
+        assert_eq!(
+            vec!["snapshot.ignoremodechanges"],
+            apply_recommended_config(&repo).unwrap()
+        );
+        assert!(repo
+            .git_repo()
+            .config()
+            .unwrap()
+            .get_bool("snapshot.ignoremodechanges")
+            .unwrap());
+    }
+
+    #[test]
+    fn defaults() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        config.set_str("snapshot.snapshotmessage", "Mine").unwrap();
+        let defaults = RepoDefaults {
+            snapshot_branch: Some("wip/${BRANCH}".to_owned()),
+            snapshot_message: Some("Snapshot".to_owned()),
+            remotes: vec!["origin".to_owned(), "backup".to_owned()],
+            git_config: BTreeMap::from([("snapshot.verify".to_owned(), "true".to_owned())]),
+        };
+        let repo = Repo::new(repo);
+        assert_eq!(
+            vec![
+                "snapshot.snapshotbranch",
+                "remote.origin.snapshotenabled",
+                "snapshot.verify"
+            ],
+            apply_defaults(&repo, &defaults).unwrap()
+        );
+        let config = repo.git_repo().config().unwrap().snapshot().unwrap();
+        assert_eq!("Mine", config.get_str("snapshot.snapshotmessage").unwrap());
+        assert!(config.get_bool("snapshot.verify").unwrap());
+        assert!(apply_defaults(&repo, &defaults).unwrap().is_empty());
+    }
+
+    #[test]
+    fn create() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("notes/scratch");
+        let repo = create_repo(&path).unwrap();
+        let head = repo.git_repo().head().unwrap();
+        assert!(head.is_branch());
+        assert_eq!(0, head.peel_to_commit().unwrap().tree().unwrap().len());
+        assert!(matches!(create_repo(&path), Err(Error::RepoExists(_))));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn service() {
+        let temp_dir = tempdir().unwrap();
+        let unit = ServiceUnit::new(
+            temp_dir.path(),
+            Path::new("/usr/bin/git-snapshot"),
+            Path::new("/home/me/config.json"),
+        )
+        .unwrap();
+        assert!(unit.path.starts_with(temp_dir.path()));
+        assert!(unit.content.contains("/usr/bin/git-snapshot"));
+        assert!(unit.content.contains("/home/me/config.json"));
+        unit.install().unwrap();
+        assert_eq!(unit.content, std::fs::read_to_string(&unit.path).unwrap());
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap},
+    fmt::{self, Display},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{self, Attributes, Record},
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+/// A span of a snapshot, push or watcher event once it has ended
+#[derive(Debug, Clone)]
+pub struct FinishedSpan {
+    pub name: &'static str,
+    pub target: &'static str,
+    /// Name of the repo the span is about, its own `repo` field or the closest parent's
+    pub repo: Option<String>,
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_id: Option<u64>,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl Display for FinishedSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} took {:.1?}", self.name, self.duration)?;
+        for (name, value) in &self.fields {
+            write!(f, " {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where ended spans are sent besides the log
+pub trait Export: Send + Sync {
+    fn export(&self, span: FinishedSpan);
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Vec<(&'static str, String)>,
+    repo: Option<String>,
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    started: Instant,
+    refs: usize,
+}
+
+thread_local! {
+    // ids of the spans entered on the thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Subscriber logging spans through `log` when they end, under the name of their repo like the
+/// rest of the repo's lines, and handing them to an exporter. Events of dependencies using
+/// `tracing` are passed on to `log` as well.
+pub struct SpanLog {
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+    export: Option<Box<dyn Export>>,
+}
+
+impl SpanLog {
+    pub fn new(export: Option<Box<dyn Export>>) -> Self {
+        Self {
+            spans: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            export,
+        }
+    }
+
+    fn current(&self) -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    fn finish(&self, span: FinishedSpan) {
+        let target = span.repo.as_deref().unwrap_or(span.target);
+        log::debug!(target: target, "{}", span);
+        if let Some(export) = &self.export {
+            export.export(span);
+        }
+    }
+}
+
+/// Log ended spans, exporting them over OTLP too when built with the `otel` feature and
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+pub fn install() {
+    #[cfg(feature = "otel")]
+    let export = crate::otel::OtlpExport::from_env().map(|export| Box::new(export) as _);
+    #[cfg(not(feature = "otel"))]
+    let export = None;
+    // only fails when one was installed already
+    let _ = tracing::subscriber::set_global_default(SpanLog::new(export));
+}
+
+impl Subscriber for SpanLog {
+    // the log level can change while running, so it's asked every time
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() || log_level(metadata.level()) <= log::max_level()
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Vec::new();
+        attributes.record(&mut Fields(&mut fields));
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => self.current(),
+            None => None,
+        };
+
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        let parent = parent.and_then(|parent| spans.get(&parent));
+        let repo = field(&fields, "repo").or_else(|| parent.and_then(|parent| parent.repo.clone()));
+        let data = SpanData {
+            metadata: attributes.metadata(),
+            repo,
+            trace_id: parent.map_or_else(
+                || (u128::from(random()) << 64) | u128::from(random()),
+                |parent| parent.trace_id,
+            ),
+            span_id: random(),
+            parent_id: parent.map(|parent| parent.span_id),
+            fields,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            refs: 1,
+        };
+        spans.insert(id, data);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(data) = spans.get_mut(&span.into_u64()) {
+            values.record(&mut Fields(&mut data.fields));
+            if let Some(repo) = field(&data.fields, "repo") {
+                data.repo = Some(repo);
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let mut fields = Vec::new();
+        event.record(&mut Fields(&mut fields));
+        let message = field(&fields, "message").unwrap_or_default();
+        let mut line = message;
+        for (name, value) in fields.iter().filter(|(name, _)| *name != "message") {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        log::logger().log(
+            &log::Record::builder()
+                .level(log_level(metadata.level()))
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!("{}", line))
+                .build(),
+        );
+    }
+
+    fn enter(&self, span: &span::Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(data) = spans.get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                let data = spans.remove(&id).unwrap();
+                // logged and exported without holding the lock, which spans of those would take
+                drop(spans);
+                self.finish(FinishedSpan {
+                    name: data.metadata.name(),
+                    target: data.metadata.target(),
+                    repo: data.repo,
+                    trace_id: data.trace_id,
+                    span_id: data.span_id,
+                    parent_id: data.parent_id,
+                    start: data.start,
+                    duration: data.started.elapsed(),
+                    fields: data.fields,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        let spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        match self
+            .current()
+            .and_then(|id| Some((id, spans.get(&id)?.metadata)))
+        {
+            Some((id, metadata)) => Current::new(span::Id::from_u64(id), metadata),
+            None => Current::none(),
+        }
+    }
+}
+
+// Field values of spans and events, set again when recorded later
+struct Fields<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Fields<'_> {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, current)) => *current = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), format!("{:?}", value));
+    }
+}
+
+fn field(fields: &[(&'static str, String)], name: &str) -> Option<String> {
+    fields
+        .iter()
+        .find(|(field, _)| *field == name)
+        .map(|(_, value)| value.clone())
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+// every RandomState is seeded anew
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tracing::{field, info_span};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect(Arc<Mutex<Vec<FinishedSpan>>>);
+
+    impl Export for Collect {
+        fn export(&self, span: FinishedSpan) {
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn finished_spans() {
+        let collect = Collect::default();
+        let finished = collect.0.clone();
+        let dispatch = tracing::Dispatch::new(SpanLog::new(Some(Box::new(collect))));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let event = info_span!("watch_event", repo = "a", changed = 2).entered();
+            let snapshot = info_span!("snapshot", commit = field::Empty);
+            snapshot.record("commit", "abc");
+            // carried over to a thread, as snapshots given a timeout are taken on one
+            let current = tracing::Span::current();
+            let thread_dispatch = dispatch.clone();
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&thread_dispatch, || {
+                    let _entered = current.enter();
+                    info_span!("push", remote = "origin").in_scope(|| {});
+                })
+            })
+            .join()
+            .unwrap();
+            drop(snapshot);
+            drop(event);
+        });
+
+        let finished = finished.lock().unwrap();
+        let names: Vec<_> = finished.iter().map(|span| span.name).collect();
+        assert_eq!(vec!["push", "snapshot", "watch_event"], names);
+        let (push, snapshot, event) = (&finished[0], &finished[1], &finished[2]);
+        assert!(finished
+            .iter()
+            .all(|span| span.repo.as_deref() == Some("a")));
+        assert!(finished.iter().all(|span| span.trace_id == event.trace_id));
+        assert_eq!(Some(event.span_id), push.parent_id);
+        assert_eq!(Some(event.span_id), snapshot.parent_id);
+        assert_eq!(None, event.parent_id);
+        assert_eq!(vec![("commit", "abc".to_owned())], snapshot.fields);
+        assert!(event.to_string().starts_with("watch_event took "));
+        assert!(event.to_string().ends_with(" repo=a changed=2"));
+    }
+}
@@ -0,0 +1,526 @@
+use std::{
+    fmt::{self, Display},
+    fs::{read, rename, write, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec_pretty};
+
+use crate::{error::Error, verify::Verification};
+
+// kept in the git dir next to the snapshot index
+const STATE_FILE: &str = "snapshot-state.json";
+// held while the state is read, changed and written back, the state file itself is replaced
+const LOCK_FILE: &str = "snapshot-state.lock";
+// long enough for any report period
+const PUSH_FAILURE_RETENTION: Duration = Duration::from_secs(31 * 24 * 60 * 60);
+
+/// Per repository state kept between runs
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    #[serde(default)]
+    pub last_restore: Option<RestoreState>,
+    /// Recent failed pushes of snapshots, for reports
+    #[serde(default)]
+    pub push_failures: Vec<PushFailure>,
+    /// Snapshots disabled through `disable`
+    #[serde(default)]
+    pub suppressions: Vec<Suppression>,
+    /// Check of the latest snapshot against the working tree, with `snapshot.verify` set
+    #[serde(default)]
+    pub last_verification: Option<Verification>,
+    /// Remotes snapshots may be pushed to, with `snapshot.confirmnewremotes` set
+    #[serde(default)]
+    pub approved_remotes: Vec<RemoteApproval>,
+    /// First pushes to remotes not approved yet
+    #[serde(default)]
+    pub pending_pushes: Vec<PendingPush>,
+    /// When the last snapshot was taken
+    #[serde(default, with = "humantime_serde")]
+    pub last_snapshot: Option<SystemTime>,
+    /// Snapshot branches and the branches they're taken of, to notice a branch being deleted
+    #[serde(default)]
+    pub snapshot_branches: Vec<TrackedBranch>,
+    /// Snapshot commits last pushed, so refs that didn't advance aren't pushed again
+    #[serde(default)]
+    pub pushed: Vec<PushedRef>,
+    /// Snapshot commits last written to bundles, so the next bundle for the same place only holds
+    /// what's new
+    #[serde(default)]
+    pub bundled: Vec<BundledRef>,
+}
+
+/// What the working tree looked like before the last restore
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreState {
+    /// Commit capturing the working tree before the restore
+    pub capture: String,
+    /// Commit that was restored
+    pub restored: String,
+    /// Paths the restore was limited to, empty for the whole working tree
+    pub paths: Vec<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushFailure {
+    pub error: String,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+/// Snapshots of the repo, or of one of its branches, disabled until re-enabled or a time passes
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Suppression {
+    /// Every branch when unset
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(with = "humantime_serde", default)]
+    pub until: Option<SystemTime>,
+}
+
+/// A remote approved for snapshots, for as long as its url stays the same
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteApproval {
+    pub remote: String,
+    pub url: String,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+/// Push of a snapshot branch held until its remote is approved
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingPush {
+    pub remote: String,
+    pub url: String,
+    /// Local snapshot ref
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub branch: String,
+    /// When the push was first held
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+}
+
+impl Display for PendingPush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): {} of {}, held since {}",
+            self.remote,
+            self.url,
+            self.ref_name,
+            self.branch,
+            humantime::format_rfc3339_seconds(self.time)
+        )
+    }
+}
+
+/// Snapshot commit a remote's ref was last updated to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushedRef {
+    pub remote: String,
+    pub url: String,
+    /// Snapshot ref on the remote
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String,
+    #[serde(with = "humantime_serde", default)]
+    pub time: Option<SystemTime>,
+    /// Commit pushed in place of `commit`, with its oversized blobs replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filtered: Option<String>,
+}
+
+/// Snapshot commit last bundled of a ref, for a place bundles are kept
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundledRef {
+    /// Where the bundles go, e.g. a bucket's url
+    pub target: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String,
+    #[serde(with = "humantime_serde", default)]
+    pub time: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedBranch {
+    pub branch: String,
+    pub snapshot_branch: String,
+    /// When the branch was first found deleted, while its snapshot branch is kept for the grace
+    /// period
+    #[serde(default, with = "humantime_serde")]
+    pub deleted: Option<SystemTime>,
+}
+
+impl Suppression {
+    pub fn applies(&self, branch: &str, now: SystemTime) -> bool {
+        self.branch.as_deref().is_none_or(|b| b == branch)
+            && self.until.is_none_or(|until| until > now)
+    }
+}
+
+impl State {
+    pub fn load(git_dir: &Path) -> Result<Self, Error> {
+        match read(git_dir.join(STATE_FILE)) {
+            Ok(data) => Ok(from_slice(&data)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Change the state with `f`, holding a lock so the watcher and the command line don't drop
+    /// each other's changes. Nothing is written when `f` fails or leaves the state as it was.
+    pub fn update<T>(
+        git_dir: &Path,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(git_dir.join(LOCK_FILE))?;
+        lock.lock()?;
+        let mut state = Self::load(git_dir)?;
+        let before = to_vec_pretty(&state)?;
+        let result = f(&mut state)?;
+        if to_vec_pretty(&state)? != before {
+            state.save(git_dir)?;
+        }
+        Ok(result)
+    }
+
+    /// Record a failed push, forgetting the ones past the retention
+    pub fn push_failed(&mut self, failure: PushFailure) {
+        self.push_failures
+            .retain(|f| f.time + PUSH_FAILURE_RETENTION >= failure.time);
+        self.push_failures.push(failure);
+    }
+
+    /// The suppression keeping `branch` from being snapshotted at `now`, if any
+    pub fn suppression(&self, branch: &str, now: SystemTime) -> Option<&Suppression> {
+        self.suppressions.iter().find(|s| s.applies(branch, now))
+    }
+
+    /// Replace the suppression of `branch`, all branches when unset, dropping expired ones
+    pub fn suppress(&mut self, suppression: Suppression, now: SystemTime) {
+        self.unsuppress(suppression.branch.as_deref(), now);
+        self.suppressions.push(suppression);
+    }
+
+    pub fn unsuppress(&mut self, branch: Option<&str>, now: SystemTime) {
+        self.suppressions
+            .retain(|s| s.branch.as_deref() != branch && s.until.is_none_or(|until| until > now));
+    }
+
+    pub fn is_approved(&self, remote: &str, url: &str) -> bool {
+        self.approved_remotes
+            .iter()
+            .any(|a| a.remote == remote && a.url == url)
+    }
+
+    /// Hold a push, keeping the time of an earlier one of the same ref to the same remote
+    pub fn hold(&mut self, push: PendingPush) {
+        match self
+            .pending_pushes
+            .iter_mut()
+            .find(|p| p.remote == push.remote && p.url == push.url && p.ref_name == push.ref_name)
+        {
+            Some(pending) => pending.branch = push.branch,
+            None => self.pending_pushes.push(push),
+        }
+    }
+
+    /// Approve `remote` at `url`, returning the pushes held for it
+    pub fn approve(&mut self, approval: RemoteApproval) -> Vec<PendingPush> {
+        let (held, pending) = self
+            .pending_pushes
+            .drain(..)
+            .partition(|p| p.remote == approval.remote && p.url == approval.url);
+        self.pending_pushes = pending;
+        self.approved_remotes
+            .retain(|a| a.remote != approval.remote);
+        self.approved_remotes.push(approval);
+        held
+    }
+
+    /// Track `snapshot_branch` as taken of `branch`
+    pub fn track(&mut self, branch: &str, snapshot_branch: &str) {
+        let tracked = TrackedBranch {
+            branch: branch.to_owned(),
+            snapshot_branch: snapshot_branch.to_owned(),
+            deleted: None,
+        };
+        match self
+            .snapshot_branches
+            .iter_mut()
+            .find(|t| t.snapshot_branch == snapshot_branch)
+        {
+            Some(t) => *t = tracked,
+            None => self.snapshot_branches.push(tracked),
+        }
+    }
+
+    /// Last push to `ref_name` on `remote`, as long as the remote's url stayed the same
+    pub fn pushed_ref(&self, remote: &str, url: &str, ref_name: &str) -> Option<&PushedRef> {
+        self.pushed
+            .iter()
+            .find(|p| p.remote == remote && p.url == url && p.ref_name == ref_name)
+    }
+
+    /// Commit last pushed to `ref_name` on `remote`, as long as the remote's url stayed the same
+    pub fn last_pushed(&self, remote: &str, url: &str, ref_name: &str) -> Option<&str> {
+        self.pushed_ref(remote, url, ref_name)
+            .map(|p| p.commit.as_str())
+    }
+
+    /// When anything was last pushed to `remote`
+    pub fn last_push_time(&self, remote: &str) -> Option<SystemTime> {
+        self.pushed
+            .iter()
+            .filter(|p| p.remote == remote)
+            .filter_map(|p| p.time)
+            .max()
+    }
+
+    pub fn record_push(&mut self, pushed: PushedRef) {
+        self.pushed
+            .retain(|p| p.remote != pushed.remote || p.ref_name != pushed.ref_name);
+        self.pushed.push(pushed);
+    }
+
+    /// Commits last bundled for `target`
+    pub fn last_bundled(&self, target: &str) -> Vec<&str> {
+        self.bundled
+            .iter()
+            .filter(|b| b.target == target)
+            .map(|b| b.commit.as_str())
+            .collect()
+    }
+
+    /// When a bundle was last written for `target`
+    pub fn last_bundle_time(&self, target: &str) -> Option<SystemTime> {
+        self.bundled
+            .iter()
+            .filter(|b| b.target == target)
+            .filter_map(|b| b.time)
+            .max()
+    }
+
+    pub fn record_bundle(&mut self, bundled: BundledRef) {
+        self.bundled
+            .retain(|b| b.target != bundled.target || b.ref_name != bundled.ref_name);
+        self.bundled.push(bundled);
+    }
+
+    /// Replace the state file, readers never see it half written
+    pub fn save(&self, git_dir: &Path) -> Result<(), Error> {
+        static SAVES: AtomicUsize = AtomicUsize::new(0);
+        let temp_path = git_dir.join(format!(
+            "{}.{}-{}.tmp",
+            STATE_FILE,
+            std::process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+        write(&temp_path, to_vec_pretty(self)?)?;
+        if let Err(err) = rename(&temp_path, git_dir.join(STATE_FILE)) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn load_save() {
+        let temp_dir = tempdir().unwrap();
+        assert!(State::load(temp_dir.path()).unwrap().last_restore.is_none());
+
+        let restore = RestoreState {
+            capture: "a".to_owned(),
+            restored: "b".to_owned(),
+            paths: vec![PathBuf::from("c")],
+            time: SystemTime::UNIX_EPOCH,
+        };
+        State {
+            last_restore: Some(restore.clone()),
+            ..Default::default()
+        }
+        .save(temp_dir.path())
+        .unwrap();
+        assert_eq!(
+            Some(restore),
+            State::load(temp_dir.path()).unwrap().last_restore
+        );
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let temp_dir = tempdir().unwrap();
+        let git_dir = temp_dir.path().to_owned();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let git_dir = git_dir.clone();
+                std::thread::spawn(move || {
+                    State::update(&git_dir, |state| {
+                        state.suppress(
+                            Suppression {
+                                branch: Some(i.to_string()),
+                                until: None,
+                            },
+                            SystemTime::UNIX_EPOCH,
+                        );
+                        Ok(())
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(8, State::load(&git_dir).unwrap().suppressions.len());
+
+        // a failed update leaves the state as it was
+        let err = State::update(&git_dir, |state| {
+            state.suppressions.clear();
+            Err::<(), _>(Error::SnapshotNotFound)
+        });
+        assert!(err.is_err());
+        assert_eq!(8, State::load(&git_dir).unwrap().suppressions.len());
+    }
+
+    #[test]
+    fn push_failed() {
+        let failure = |secs| PushFailure {
+            error: "auth".to_owned(),
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let mut state = State::default();
+        state.push_failed(failure(0));
+        state.push_failed(failure(60));
+        assert_eq!(2, state.push_failures.len());
+        state.push_failed(failure(PUSH_FAILURE_RETENTION.as_secs() + 30));
+        assert_eq!(
+            vec![failure(60), failure(PUSH_FAILURE_RETENTION.as_secs() + 30)],
+            state.push_failures
+        );
+    }
+
+    #[test]
+    fn approvals() {
+        let push = |ref_name: &str, url: &str| PendingPush {
+            remote: "origin".to_owned(),
+            url: url.to_owned(),
+            ref_name: ref_name.to_owned(),
+            branch: "main".to_owned(),
+            time: SystemTime::UNIX_EPOCH,
+        };
+        let mut state = State::default();
+        state.hold(push("refs/heads/snapshot/main", "a"));
+        state.hold(push("refs/heads/snapshot/main", "a"));
+        state.hold(push("refs/heads/snapshot/dev", "a"));
+        state.hold(push("refs/heads/snapshot/main", "b"));
+        assert_eq!(3, state.pending_pushes.len());
+        assert!(!state.is_approved("origin", "a"));
+
+        let held = state.approve(RemoteApproval {
+            remote: "origin".to_owned(),
+            url: "a".to_owned(),
+            time: SystemTime::UNIX_EPOCH,
+        });
+        assert_eq!(2, held.len());
+        assert_eq!(
+            vec![push("refs/heads/snapshot/main", "b")],
+            state.pending_pushes
+        );
+        assert!(state.is_approved("origin", "a"));
+        // a changed url needs approving again
+        assert!(!state.is_approved("origin", "b"));
+    }
+
+    #[test]
+    fn suppressions() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut state = State::default();
+        state.suppress(
+            Suppression {
+                branch: Some("main".to_owned()),
+                until: Some(at(60)),
+            },
+            at(0),
+        );
+        assert!(state.suppression("main", at(30)).is_some());
+        assert!(state.suppression("main", at(60)).is_none());
+        assert!(state.suppression("other", at(30)).is_none());
+
+        state.suppress(
+            Suppression {
+                branch: None,
+                until: None,
+            },
+            at(0),
+        );
+        assert!(state.suppression("other", at(1000)).is_some());
+        state.unsuppress(None, at(0));
+        assert_eq!(1, state.suppressions.len());
+        // expired ones go with the next change
+        state.unsuppress(None, at(100));
+        assert!(state.suppressions.is_empty());
+    }
+
+    #[test]
+    fn pushed() {
+        let pushed = |url: &str, commit: &str| PushedRef {
+            remote: "origin".to_owned(),
+            url: url.to_owned(),
+            ref_name: "refs/heads/snapshot/main".to_owned(),
+            commit: commit.to_owned(),
+            time: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(commit.parse().unwrap())),
+            filtered: None,
+        };
+        let mut state = State::default();
+        state.record_push(pushed("a", "1"));
+        state.record_push(pushed("a", "2"));
+        assert_eq!(1, state.pushed.len());
+        assert_eq!(
+            Some("2"),
+            state.last_pushed("origin", "a", "refs/heads/snapshot/main")
+        );
+        assert_eq!(
+            None,
+            state.last_pushed("origin", "a", "refs/heads/snapshot/dev")
+        );
+        // pushed anew after the url changed
+        assert_eq!(
+            None,
+            state.last_pushed("origin", "b", "refs/heads/snapshot/main")
+        );
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2)),
+            state.last_push_time("origin")
+        );
+        assert_eq!(None, state.last_push_time("other"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::{Component, Path, PathBuf};
+
+use git2::{FileMode, ObjectType, Repository, Tree};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, util::expand};
+
+/// A subdirectory of a monorepo snapshotted on a branch of its own, so the teams owning different
+/// subtrees get separate snapshot histories
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotStream {
+    pub name: String,
+    /// Relative to the working tree
+    pub path: PathBuf,
+    /// Snapshot branch template, `snapshot/<name>/${BRANCH}` by default
+    #[serde(default)]
+    pub branch: Option<String>,
+}
+
+impl SnapshotStream {
+    pub fn snapshot_branch(&self, current_branch: &str) -> String {
+        let template = self
+            .branch
+            .clone()
+            .unwrap_or_else(|| format!("snapshot/{}/${{BRANCH}}", self.name));
+        expand(&template, &[("BRANCH", current_branch)])
+    }
+
+    /// Pathspec of the stream's files
+    pub fn pathspec(&self) -> Result<String, Error> {
+        self.path
+            .to_str()
+            .map(|p| p.trim_end_matches('/').to_owned())
+            .ok_or_else(|| git2::Error::from_str("invalid stream path").into())
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+
+    /// `tree` with only the stream's subtree left in it, at the same path
+    pub fn limit_tree<'r>(&self, repo: &'r Repository, tree: &Tree) -> Result<Tree<'r>, Error> {
+        let mut id = match tree.get_path(&self.path) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Tree) => entry.id(),
+            // nothing of the stream in the working tree
+            _ => return Ok(repo.find_tree(repo.treebuilder(None)?.write()?)?),
+        };
+        let names = self.path.components().rev().filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        });
+        for name in names {
+            let mut builder = repo.treebuilder(None)?;
+            builder.insert(name, id, i32::from(FileMode::Tree))?;
+            id = builder.write()?;
+        }
+        Ok(repo.find_tree(id)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use git2::IndexAddOption;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    fn stream(path: &str) -> SnapshotStream {
+        SnapshotStream {
+            name: "a".to_owned(),
+            path: PathBuf::from(path),
+            branch: None,
+        }
+    }
+
+    #[test]
+    fn branch() {
+        assert_eq!(
+            "snapshot/a/main",
+            stream("services/a").snapshot_branch("main")
+        );
+        let custom = SnapshotStream {
+            branch: Some("wip/${BRANCH}/a".to_owned()),
+            ..stream("services/a")
+        };
+        assert_eq!("wip/main/a", custom.snapshot_branch("main"));
+    }
+
+    #[test]
+    fn limit_tree() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        for path in ["services/a/src/main.rs", "services/b/main.rs", "README"] {
+            let path = temp_dir.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, "x").unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let limited = stream("services/a/").limit_tree(&repo, &tree).unwrap();
+        assert!(limited
+            .get_path(Path::new("services/a/src/main.rs"))
+            .is_ok());
+        assert!(limited.get_path(Path::new("services/b")).is_err());
+        assert!(limited.get_path(Path::new("README")).is_err());
+
+        let missing = stream("services/c").limit_tree(&repo, &tree).unwrap();
+        assert_eq!(0, missing.len());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    env::{current_exe, var_os},
+    ffi::{CStr, CString},
+    fs::OpenOptions,
+    io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::from_reader;
+
+use crate::error::Error;
+
+// how often helpers are checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const PASSWD_BUFFER_LEN: usize = 16 * 1024;
+const MAX_GROUPS: usize = 1024;
+
+/// Config of the system-wide watcher, which runs a watcher per user under that user's id
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SystemConfig {
+    /// Users whose repos are watched
+    pub users: Vec<String>,
+    /// Each user's watcher config, relative to their home directory unless absolute
+    #[serde(default = "default_user_config")]
+    pub user_config: PathBuf,
+    /// Time before a user's watcher that exited is started again
+    #[serde(with = "humantime_serde", default = "default_restart_delay")]
+    pub restart_delay: Duration,
+}
+
+fn default_user_config() -> PathBuf {
+    [".config", "git-snapshot", "config.json"].iter().collect()
+}
+
+fn default_restart_delay() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// An account looked up in the user database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    /// Supplementary groups, including the primary group
+    pub groups: Vec<u32>,
+}
+
+impl User {
+    pub fn lookup(name: &str) -> Result<Self, Error> {
+        let unknown = || Error::UnknownUser(name.to_owned());
+        let c_name = CString::new(name).map_err(|_| unknown())?;
+
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; PASSWD_BUFFER_LEN];
+        let mut result = std::ptr::null_mut();
+        let code = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if code != 0 {
+            return Err(io::Error::from_raw_os_error(code).into());
+        }
+        if result.is_null() {
+            return Err(unknown());
+        }
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) }
+            .to_string_lossy()
+            .into_owned();
+
+        let mut groups = vec![0 as libc::gid_t; MAX_GROUPS];
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe {
+            libc::getgrouplist(
+                c_name.as_ptr(),
+                passwd.pw_gid as _,
+                groups.as_mut_ptr() as *mut _,
+                &mut count,
+            )
+        };
+        if found == -1 {
+            // more groups than fit, keep the ones that did
+            count = groups.len() as libc::c_int;
+        }
+        groups.truncate(count.max(0) as usize);
+
+        Ok(Self {
+            name: name.to_owned(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            home: PathBuf::from(home),
+            groups,
+        })
+    }
+}
+
+/// A user's watcher process
+struct Helper {
+    user: String,
+    child: Mutex<Option<Child>>,
+    starts: AtomicUsize,
+}
+
+/// Supervises a watcher process per configured user, restarting the ones that exit.
+/// The processes are stopped when this is dropped.
+pub struct SystemWatcher {
+    helpers: Vec<Arc<Helper>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl SystemWatcher {
+    pub fn new(config: SystemConfig) -> Result<Self, Error> {
+        Ok(Self::with_program(config, current_exe()?))
+    }
+
+    pub fn with_config(config_path: impl AsRef<Path>) -> Result<Self, Error> {
+        let f = OpenOptions::new().read(true).open(config_path)?;
+        Self::new(from_reader(f)?)
+    }
+
+    fn with_program(config: SystemConfig, program: PathBuf) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let helpers = config
+            .users
+            .iter()
+            .map(|user| {
+                let helper = Arc::new(Helper {
+                    user: user.clone(),
+                    child: Mutex::new(None),
+                    starts: AtomicUsize::new(0),
+                });
+                let supervised = helper.clone();
+                let program = program.clone();
+                let user_config = config.user_config.clone();
+                let restart_delay = config.restart_delay;
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    supervise(&supervised, &program, &user_config, restart_delay, &stop)
+                });
+                helper
+            })
+            .collect();
+        Self { helpers, stop }
+    }
+}
+
+impl Drop for SystemWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for helper in &self.helpers {
+            if let Some(child) = helper.child.lock().unwrap().as_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+fn supervise(
+    helper: &Helper,
+    program: &Path,
+    user_config: &Path,
+    restart_delay: Duration,
+    stop: &AtomicBool,
+) {
+    while !stop.load(Ordering::SeqCst) {
+        match start(helper, program, user_config) {
+            Ok(()) => {
+                helper.starts.fetch_add(1, Ordering::SeqCst);
+                info!("started watcher of user: {}", helper.user);
+                if let Some(status) = wait(helper, stop) {
+                    warn!("watcher of user {} exited: {}", helper.user, status);
+                }
+            }
+            Err(err) => error!("error starting watcher of user {}: {:?}", helper.user, err),
+        }
+        let restart = Instant::now() + restart_delay;
+        while !stop.load(Ordering::SeqCst) && Instant::now() < restart {
+            thread::sleep(POLL_INTERVAL.min(restart_delay));
+        }
+    }
+}
+
+fn start(helper: &Helper, program: &Path, user_config: &Path) -> Result<(), Error> {
+    let user = User::lookup(&helper.user)?;
+    let config = user.home.join(user_config);
+    // checked as the supervisor, which may see more than the user does
+    if !config.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no watcher config at {}", config.display()),
+        )
+        .into());
+    }
+    let child = helper_command(program, &user, &config).spawn()?;
+    *helper.child.lock().unwrap() = Some(child);
+    Ok(())
+}
+
+// The exit status once the helper exits, None when stopped
+fn wait(helper: &Helper, stop: &AtomicBool) -> Option<std::process::ExitStatus> {
+    while !stop.load(Ordering::SeqCst) {
+        let mut child = helper.child.lock().unwrap();
+        match child.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => {
+                *child = None;
+                return Some(status);
+            }
+            Some(Ok(None)) => {}
+            Some(Err(err)) => {
+                error!(
+                    "error waiting for watcher of user {}: {:?}",
+                    helper.user, err
+                );
+                return None;
+            }
+            None => return None,
+        }
+        drop(child);
+        thread::sleep(POLL_INTERVAL);
+    }
+    None
+}
+
+/// The watcher process of `user`, running with their ids when they aren't the current user
+fn helper_command(program: &Path, user: &User, config: &Path) -> Command {
+    let mut command = Command::new(program);
+    command
+        .arg("start-watcher")
+        .arg("--config")
+        .arg(config)
+        .env_clear()
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .current_dir(&user.home);
+    for key in ["PATH", "GIT_SNAPSHOT_LOG_LEVEL", "GIT_SNAPSHOT_THREADS"] {
+        if let Some(value) = var_os(key) {
+            command.env(key, value);
+        }
+    }
+
+    let (uid, gid, groups) = (user.uid, user.gid, user.groups.clone());
+    let switch_user = uid != unsafe { libc::geteuid() };
+    // only async-signal-safe calls are allowed between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // helpers don't outlive the supervisor
+            #[cfg(target_os = "linux")]
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if switch_user
+                && (libc::setgroups(groups.len() as _, groups.as_ptr()) == -1
+                    || libc::setgid(gid) == -1
+                    || libc::setuid(uid) == -1)
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn current_user() -> User {
+        let uid = unsafe { libc::geteuid() };
+        let passwd = unsafe { libc::getpwuid(uid) };
+        let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+        User::lookup(&name.to_string_lossy()).unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let user = current_user();
+        assert_eq!(unsafe { libc::geteuid() }, user.uid);
+        assert!(user.groups.contains(&user.gid));
+        assert!(matches!(
+            User::lookup("no-such-user-here"),
+            Err(Error::UnknownUser(_))
+        ));
+    }
+
+    #[test]
+    fn command() {
+        let user = current_user();
+        let command = helper_command(Path::new("git-snapshot"), &user, Path::new("/c.json"));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(vec!["start-watcher", "--config", "/c.json"], args);
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "HOME" && v == Some(user.home.as_os_str())));
+    }
+
+    #[test]
+    fn restarts_exited() {
+        let user = current_user();
+        let temp_dir = tempdir().unwrap();
+        let user_config = temp_dir.path().join("config.json");
+        write(&user_config, "{}").unwrap();
+
+        // exits right away, standing in for a watcher that crashes
+        let watcher = SystemWatcher::with_program(
+            SystemConfig {
+                users: vec![user.name.clone()],
+                user_config,
+                restart_delay: Duration::from_millis(10),
+            },
+            PathBuf::from("true"),
+        );
+        thread::sleep(Duration::from_secs(1));
+        let starts = watcher.helpers[0].starts.load(Ordering::SeqCst);
+        drop(watcher);
+        assert!(starts >= 2, "started {} times", starts);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    fs::{copy, create_dir_all, write},
+    io::{self, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    sync::Arc,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use git2::Repository;
+use tokio::sync::oneshot;
+
+use crate::error::Error;
+
+/// Environment variable with the url of an ssh remote for the ignored integration tests
+pub const SSH_URL_ENV: &str = "GIT_SNAPSHOT_TEST_SSH_URL";
+/// Environment variable with the private key authorized by the ssh remote
+pub const SSH_KEY_ENV: &str = "GIT_SNAPSHOT_TEST_SSH_KEY";
+/// Url of the repo served by the container `write_ssh_container` sets up, on port 2222
+pub const SSH_CONTAINER_URL: &str = "ssh://git@127.0.0.1:2222/home/git/remote.git";
+
+const REPO_NAME: &str = "remote.git";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SSH_DOCKERFILE: &str = r#"FROM alpine:3
+RUN apk add --no-cache git openssh \
+ && ssh-keygen -A \
+ && adduser -D -s /usr/bin/git-shell git \
+ && echo 'git:*' | chpasswd -e \
+ && echo /usr/bin/git-shell >> /etc/shells \
+ && git init --bare /home/git/remote.git \
+ && mkdir /home/git/.ssh
+COPY authorized_keys /home/git/.ssh/authorized_keys
+RUN chown -R git:git /home/git && chmod 700 /home/git/.ssh && chmod 600 /home/git/.ssh/authorized_keys
+EXPOSE 22
+CMD ["/usr/sbin/sshd", "-D", "-e"]
+"#;
+
+/// How a test remote is served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteKind {
+    /// `git daemon` over git://, without authentication
+    Daemon,
+    /// `git http-backend` behind basic authentication
+    Http,
+    /// sshd in a container, set up rather than started
+    Ssh,
+}
+
+impl FromStr for RemoteKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daemon" => Ok(Self::Daemon),
+            "http" => Ok(Self::Http),
+            "ssh" => Ok(Self::Ssh),
+            _ => Err(format!("invalid remote kind: {}", s)),
+        }
+    }
+}
+
+/// A bare repo served over the network for snapshots to be pushed to, stopped when dropped
+pub struct TestRemote {
+    /// Url to add the remote with
+    pub url: String,
+    /// The bare repo
+    pub path: PathBuf,
+    server: Server,
+}
+
+enum Server {
+    Daemon(Child),
+    Http {
+        shutdown: Option<oneshot::Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    },
+}
+
+impl TestRemote {
+    /// Serve a new bare repo in `dir` with `git daemon`, pushes enabled
+    pub fn daemon(dir: &Path) -> Result<Self, Error> {
+        let path = init_bare(dir)?;
+        let port = free_port()?;
+        // run directly, `git daemon` would leave it running when killed
+        let mut child = Command::new(exec_path()?.join("git-daemon"))
+            .args(["--reuseaddr", "--export-all", "--enable=receive-pack"])
+            .arg("--listen=127.0.0.1")
+            .arg(format!("--port={}", port))
+            .arg(format!("--base-path={}", dir.display()))
+            .arg(dir)
+            .stdin(Stdio::null())
+            .spawn()?;
+        if let Err(err) = wait_for_port(port, || Ok(child.try_wait()?.is_none())) {
+            let _ = child.kill();
+            return Err(err);
+        }
+        Ok(Self {
+            url: format!("git://127.0.0.1:{}/{}", port, REPO_NAME),
+            path,
+            server: Server::Daemon(child),
+        })
+    }
+
+    /// Serve a new bare repo in `dir` with `git http-backend`, only to `username` and `password`
+    pub fn http(dir: &Path, username: &str, password: &str) -> Result<Self, Error> {
+        let path = init_bare(dir)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let state = Arc::new(HttpState {
+            root: dir.to_owned(),
+            username: username.to_owned(),
+            authorization: format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            ),
+        });
+        let app = Router::new()
+            .fallback(http_backend)
+            .with_state(state)
+            .layer(DefaultBodyLimit::disable());
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        // a runtime of its own, tests pushing block the one they run on
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let thread = thread::spawn(move || {
+            runtime.block_on(async move {
+                let listener = match tokio::net::TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(_) => return,
+                };
+                tokio::select! {
+                    _ = axum::serve(listener, app) => {}
+                    _ = stopped => {}
+                }
+            })
+        });
+        Ok(Self {
+            url: format!("http://127.0.0.1:{}/{}", port, REPO_NAME),
+            path,
+            server: Server::Http {
+                shutdown: Some(shutdown),
+                thread: Some(thread),
+            },
+        })
+    }
+}
+
+impl Drop for TestRemote {
+    fn drop(&mut self) {
+        match &mut self.server {
+            Server::Daemon(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Server::Http { shutdown, thread } => {
+                if let Some(shutdown) = shutdown.take() {
+                    let _ = shutdown.send(());
+                }
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+/// Write a Dockerfile to `dir` of an sshd serving a bare repo at `SSH_CONTAINER_URL` to the
+/// owner of `public_key`
+pub fn write_ssh_container(dir: &Path, public_key: &Path) -> Result<(), Error> {
+    create_dir_all(dir)?;
+    copy(public_key, dir.join("authorized_keys"))?;
+    write(dir.join("Dockerfile"), SSH_DOCKERFILE)?;
+    Ok(())
+}
+
+// Directory of git's own commands
+fn exec_path() -> Result<PathBuf, Error> {
+    let output = Command::new("git").arg("--exec-path").output()?;
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
+
+fn init_bare(dir: &Path) -> Result<PathBuf, Error> {
+    let path = dir.join(REPO_NAME);
+    Repository::init_bare(&path)?;
+    Ok(path)
+}
+
+fn free_port() -> Result<u16, Error> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+// Wait until the server accepts connections, as long as it's running
+fn wait_for_port(port: u16, mut running: impl FnMut() -> Result<bool, Error>) -> Result<(), Error> {
+    let started = Instant::now();
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+        if !running()? {
+            return Err(io::Error::other("server exited").into());
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "server didn't start").into());
+        }
+        sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+struct HttpState {
+    root: PathBuf,
+    username: String,
+    authorization: String,
+}
+
+async fn http_backend(
+    State(state): State<Arc<HttpState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .map(|value| value.as_bytes() == state.authorization.as_bytes())
+        .unwrap_or(false);
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"git\"")],
+        )
+            .into_response();
+    }
+    let response =
+        tokio::task::spawn_blocking(move || run_cgi(&state, &method, &uri, &headers, &body)).await;
+    match response {
+        Ok(Ok(response)) => response,
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+// Run `git http-backend` as a CGI script for the request
+fn run_cgi(
+    state: &HttpState,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<Response, Error> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let mut child = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", &state.root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        // pushes are only accepted from an authenticated user
+        .env("REMOTE_USER", &state.username)
+        .env("REMOTE_ADDR", "127.0.0.1")
+        .env("REQUEST_METHOD", method.as_str())
+        .env("PATH_INFO", uri.path())
+        .env("QUERY_STRING", uri.query().unwrap_or_default())
+        .env("CONTENT_TYPE", header("content-type"))
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .env("HTTP_CONTENT_ENCODING", header("content-encoding"))
+        .env("GIT_PROTOCOL", header("git-protocol"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // written alongside reading the output, either may fill up its pipe
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let input = body.clone();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    let split = output
+        .stdout
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|at| (at, 4))
+        .or_else(|| {
+            output
+                .stdout
+                .windows(2)
+                .position(|window| window == b"\n\n")
+                .map(|at| (at, 2))
+        })
+        .ok_or_else(|| io::Error::other("no CGI headers"))?;
+    let (head, body) = output.stdout.split_at(split.0);
+    let mut response = Response::builder();
+    for line in String::from_utf8_lossy(head).lines() {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("status") {
+                let code = value.split_whitespace().next().unwrap_or_default();
+                response = response.status(code.parse::<u16>().unwrap_or(500));
+            } else {
+                response = response.header(name, value);
+            }
+        }
+    }
+    response
+        .body(Body::from(body[split.1..].to_vec()))
+        .map_err(|err| io::Error::other(err.to_string()).into())
+}
@@ -0,0 +1,84 @@
+use std::{path::Path, time::SystemTime};
+
+use git2::{Config, Index, IndexAddOption, Repository, Signature};
+use tempfile::NamedTempFile;
+
+use crate::Repo;
+
+/// Remote `test_repo_with_remote` adds, with snapshot pushes enabled
+pub const TEST_REMOTE_NAME: &str = "test";
+
+/// A new repo with a user to commit as
+pub fn test_repo(path: &Path) -> (Repository, Config) {
+    let repo = Repository::init(path).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Test").unwrap();
+    config.set_str("user.email", "test@test.test").unwrap();
+
+    (repo, config)
+}
+
+/// Adds an untracked file to a directory
+pub fn create_temp_file(p: &Path) {
+    NamedTempFile::new_in(p).unwrap().keep().unwrap();
+}
+
+/// A new repo with an untracked file
+pub fn test_repo_with_files(path: &Path) -> (Repository, Config) {
+    let (repo, config) = test_repo(path);
+    create_temp_file(path);
+    (repo, config)
+}
+
+/// A new repo with an untracked file and a bare repo as its `TEST_REMOTE_NAME` remote
+pub fn test_repo_with_remote(path: &Path, remote_path: &Path) -> (Repository, Repository, Config) {
+    let (repo, config) = test_repo_with_files(path);
+    let remote_repo = Repository::init_bare(remote_path).unwrap();
+    repo.remote(
+        TEST_REMOTE_NAME,
+        &format!("file://{}", remote_repo.path().to_str().unwrap()),
+    )
+    .unwrap();
+    repo.config()
+        .unwrap()
+        .set_bool(
+            &format!("remote.{}.snapshotenabled", TEST_REMOTE_NAME),
+            true,
+        )
+        .unwrap();
+    (repo, remote_repo, config)
+}
+
+/// Commits every file of the working tree to HEAD, without parents
+pub fn commit_all(repo: &Repository) {
+    let mut index = Index::new().unwrap();
+    repo.set_index(&mut index).unwrap();
+    index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+    let tree = index.write_tree().unwrap();
+    let tree = repo.find_tree(tree).unwrap();
+
+    let signature = Signature::now("test", "test").unwrap();
+
+    repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+        .unwrap();
+}
+
+/// Whether the current branch has a snapshot branch
+pub fn check_snapshot_exists(repo: &Repo) -> bool {
+    let config = repo.git_repo().config().unwrap();
+    let snapshot_branch =
+        Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), SystemTime::now());
+    repo.git_repo()
+        .resolve_reference_from_short_name(&snapshot_branch)
+        .is_ok()
+}
+
+/// Panics unless the current branch has a snapshot branch
+pub fn assert_snapshot_exists(repo: &Repo) {
+    assert!(
+        check_snapshot_exists(repo),
+        "no snapshot of {} in {}",
+        repo.current_branch().unwrap(),
+        repo.git_repo().path().display()
+    );
+}
@@ -0,0 +1,225 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{error::Error, units::HumanDuration, util::ConfigValue};
+use git2::{Config, ErrorClass, ErrorCode, RemoteCallbacks};
+
+const DEFAULT_CONNECT: Duration = Duration::from_secs(60);
+const DEFAULT_TRANSFER: Duration = Duration::from_secs(300);
+// how often a running operation is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a remote may take before an operation on it is given up, `0` never giving up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteTimeouts {
+    /// Until the remote first responds, `remote.<name>.snapshotConnectTimeout` or
+    /// `snapshot.connectTimeout`
+    pub connect: Duration,
+    /// Between two responses of the remote after that, `remote.<name>.snapshotTransferTimeout` or
+    /// `snapshot.transferTimeout`
+    pub transfer: Duration,
+}
+
+impl Default for RemoteTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT,
+            transfer: DEFAULT_TRANSFER,
+        }
+    }
+}
+
+impl RemoteTimeouts {
+    pub fn from_config(config: &Config, remote: &str) -> Self {
+        let duration = |key: &str, default: Duration| {
+            HumanDuration::from_config(
+                config,
+                &[
+                    &format!("remote.{}.snapshot{}", remote, key),
+                    &format!("snapshot.{}", key),
+                ],
+                default.into(),
+            )
+            .0
+        };
+        Self {
+            connect: duration("connecttimeout", DEFAULT_CONNECT),
+            transfer: duration("transfertimeout", DEFAULT_TRANSFER),
+        }
+    }
+
+    /// Run `op` with `remote` on a thread of its own, giving up on it when the remote doesn't
+    /// respond in time. libgit2 can't be interrupted while it waits on a socket, so the operation
+    /// is left to be cancelled by the next callback of its `Transfer` instead of waited for.
+    pub fn run<T, F>(&self, remote: &str, op: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(Transfer) -> Result<T, Error> + Send + 'static,
+    {
+        let transfer = Transfer::default();
+        let (sender, receiver) = channel();
+        let worker = transfer.clone();
+        thread::Builder::new()
+            .name(format!("remote {}", remote))
+            .spawn(move || {
+                // the receiver is gone when the operation was given up on
+                let _ = sender.send(op(worker));
+            })?;
+        let started = Instant::now();
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Panicked(format!("operation on remote {}", remote)))
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            let (waiting, timeout, since) = match transfer.last_response() {
+                None => ("connecting to", self.connect, started),
+                Some(response) => ("transferring with", self.transfer, response),
+            };
+            if !timeout.is_zero() && since.elapsed() >= timeout {
+                transfer.cancel();
+                return Err(git2::Error::new(
+                    ErrorCode::GenericError,
+                    ErrorClass::Net,
+                    format!(
+                        "timed out {} {} after {}",
+                        waiting,
+                        remote,
+                        HumanDuration(timeout)
+                    ),
+                )
+                .into());
+            }
+        }
+    }
+}
+
+/// A remote operation's responses, shared with its callbacks so they can cancel it
+#[derive(Debug, Clone, Default)]
+pub struct Transfer(Arc<TransferState>);
+
+#[derive(Debug, Default)]
+struct TransferState {
+    cancelled: AtomicBool,
+    last_response: Mutex<Option<Instant>>,
+}
+
+impl Transfer {
+    /// Records a response of the remote, false once the operation was given up on
+    pub fn respond(&self) -> bool {
+        *self.0.last_response.lock().unwrap() = Some(Instant::now());
+        !self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Progress callbacks recording the remote's responses and stopping the operation once it was
+    /// given up on
+    pub fn watch(&self, callbacks: &mut RemoteCallbacks<'_>) {
+        let transfer = self.clone();
+        callbacks.transfer_progress(move |_| transfer.respond());
+        let transfer = self.clone();
+        callbacks.sideband_progress(move |_| transfer.respond());
+        let transfer = self.clone();
+        callbacks.update_tips(move |_, _, _| transfer.respond());
+        // these can't cancel, the next callback that can will
+        let transfer = self.clone();
+        callbacks.pack_progress(move |_, _, _| {
+            transfer.respond();
+        });
+        let transfer = self.clone();
+        callbacks.push_transfer_progress(move |_, _, _| {
+            transfer.respond();
+        });
+    }
+
+    fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn last_response(&self) -> Option<Instant> {
+        *self.0.last_response.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn timeouts_from_config() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+        assert_eq!(
+            RemoteTimeouts::default(),
+            RemoteTimeouts::from_config(&config, "origin")
+        );
+        config.set_str("snapshot.connecttimeout", "10s").unwrap();
+        config
+            .set_str("remote.origin.snapshottransfertimeout", "0")
+            .unwrap();
+        assert_eq!(
+            RemoteTimeouts {
+                connect: Duration::from_secs(10),
+                transfer: Duration::ZERO,
+            },
+            RemoteTimeouts::from_config(&config, "origin")
+        );
+    }
+
+    #[test]
+    fn run() {
+        let timeouts = RemoteTimeouts {
+            connect: Duration::from_millis(200),
+            transfer: Duration::from_millis(200),
+        };
+        assert_eq!(7, timeouts.run("origin", |_| Ok(7)).unwrap());
+
+        // responding in time
+        let responses = timeouts
+            .run("origin", |transfer| {
+                for _ in 0..5 {
+                    thread::sleep(Duration::from_millis(100));
+                    transfer.respond();
+                }
+                Ok(5)
+            })
+            .unwrap();
+        assert_eq!(5, responses);
+
+        // never responding, cancelled when it does after all
+        let (sender, receiver) = channel();
+        let err = timeouts
+            .run("origin", move |transfer| {
+                thread::sleep(Duration::from_millis(500));
+                sender.send(transfer.respond()).unwrap();
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(crate::ErrorCode::Network, err.code());
+        assert!(err.to_string().contains("timed out connecting to origin"));
+        assert!(!receiver.recv().unwrap());
+
+        // stalling after the first response
+        let err = timeouts
+            .run("origin", |transfer| {
+                transfer.respond();
+                thread::sleep(Duration::from_millis(500));
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("timed out transferring with origin"));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+use git2::Config;
+use log::error;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::util::ConfigValue;
+
+// suffixes of byte sizes, single letters are binary like git's `k`, `m` and `g`
+const SIZE_UNITS: [(&str, u64); 13] = [
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kib", 1 << 10),
+    ("kb", 1000),
+    ("m", 1 << 20),
+    ("mib", 1 << 20),
+    ("mb", 1000 * 1000),
+    ("g", 1 << 30),
+    ("gib", 1 << 30),
+    ("gb", 1000 * 1000 * 1000),
+    ("t", 1 << 40),
+    ("tib", 1 << 40),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+];
+
+/// A duration written like `90s` or `1h 30m` in configs and flags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+impl FromStr for HumanDuration {
+    type Err = humantime::DurationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        humantime::parse_duration(s.trim()).map(Self)
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        humantime::format_duration(self.0).fmt(f)
+    }
+}
+
+impl From<Duration> for HumanDuration {
+    fn from(duration: Duration) -> Self {
+        Self(duration)
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(duration: HumanDuration) -> Self {
+        duration.0
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl ConfigValue for HumanDuration {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self {
+        parse_from_config(config, keys, default_value)
+    }
+}
+
+/// A number of bytes written like `5MB`, `512 KiB` or git's `1g`, plain numbers are bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteSizeError(String);
+
+impl Display for ByteSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid size: {}", self.0)
+    }
+}
+
+impl std::error::Error for ByteSizeError {}
+
+impl FromStr for ByteSize {
+    type Err = ByteSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ByteSizeError(s.to_owned());
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit = unit.trim().to_ascii_lowercase();
+        let multiplier = match unit.as_str() {
+            "" => 1,
+            unit => {
+                SIZE_UNITS
+                    .iter()
+                    .find(|(suffix, _)| *suffix == unit)
+                    .ok_or_else(invalid)?
+                    .1
+            }
+        };
+        number.checked_mul(multiplier).map(Self).ok_or_else(invalid)
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the largest binary unit the size is a whole number of
+        for (unit, size) in [
+            ("TiB", 1 << 40),
+            ("GiB", 1 << 30),
+            ("MiB", 1 << 20),
+            ("KiB", 1 << 10),
+        ] {
+            if self.0 >= size && self.0.is_multiple_of(size) {
+                return write!(f, "{}{}", self.0 / size, unit);
+            }
+        }
+        write!(f, "{}B", self.0)
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // plain numbers of bytes too, like the sizes of older configs
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(Self(bytes)),
+            Raw::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+impl ConfigValue for ByteSize {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self {
+        parse_from_config(config, keys, default_value)
+    }
+}
+
+// The first key that's set, an invalid value is logged and falls back to the default
+fn parse_from_config<T: FromStr>(config: &Config, keys: &[&str], default_value: T) -> T
+where
+    T::Err: Display,
+{
+    for &key in keys {
+        if let Ok(value) = config.get_string(key) {
+            return match value.parse() {
+                Ok(value) => value,
+                Err(err) => {
+                    error!("invalid {} {}: {}", key, value, err);
+                    default_value
+                }
+            };
+        }
+    }
+    default_value
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_str, to_string};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn durations() {
+        let duration: HumanDuration = "1h 30m".parse().unwrap();
+        assert_eq!(Duration::from_secs(90 * 60), duration.0);
+        assert_eq!("1h 30m", duration.to_string());
+        assert!("90".parse::<HumanDuration>().is_err());
+
+        assert_eq!(
+            "\"5m\"",
+            to_string(&HumanDuration(Duration::from_secs(300))).unwrap()
+        );
+        assert_eq!(duration, from_str("\"90m\"").unwrap());
+        assert!(from_str::<HumanDuration>("90").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(ByteSize(5_000_000), "5MB".parse().unwrap());
+        assert_eq!(ByteSize(512 << 10), "512 KiB".parse().unwrap());
+        assert_eq!(ByteSize(1 << 30), "1g".parse().unwrap());
+        assert_eq!(ByteSize(100), "100".parse().unwrap());
+        assert!("5 parsecs".parse::<ByteSize>().is_err());
+        assert!("MB".parse::<ByteSize>().is_err());
+        assert!("100000000000t".parse::<ByteSize>().is_err());
+
+        assert_eq!("5MiB", ByteSize(5 << 20).to_string());
+        assert_eq!("1500B", ByteSize(1500).to_string());
+        assert_eq!("\"2KiB\"", to_string(&ByteSize(2048)).unwrap());
+        assert_eq!(ByteSize(2048), from_str("2048").unwrap());
+        assert_eq!(ByteSize(2000), from_str("\"2kb\"").unwrap());
+    }
+
+    #[test]
+    fn from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config.set_str("test.size", "5MB").unwrap();
+        config.set_str("test.gap", "soon").unwrap();
+        config.set_str("test.interval", "10m").unwrap();
+
+        assert_eq!(
+            ByteSize(5_000_000),
+            ByteSize::from_config(&config, &["test.unset", "test.size"], ByteSize(1))
+        );
+        // invalid values fall back to the default rather than the next key
+        assert_eq!(
+            HumanDuration(Duration::from_secs(1)),
+            HumanDuration::from_config(
+                &config,
+                &["test.gap", "test.interval"],
+                HumanDuration(Duration::from_secs(1))
+            )
+        );
+        assert_eq!(
+            HumanDuration(Duration::from_secs(600)),
+            HumanDuration::from_config(&config, &["test.interval"], HumanDuration::default())
+        );
+    }
+}
@@ -1,9 +1,25 @@
+use std::any::Any;
 use std::env::var;
+use std::fmt::Write as _;
+use std::fs::canonicalize;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, TimeZone,
+};
 use git2::Config;
+use log::warn;
+use regex::Regex;
 use shellexpand::env_with_context_no_errors;
+use tokio::{runtime::RuntimeFlavor, task::block_in_place};
 
-pub const BRANCH_REF_PREFIX: &'static str = "refs/heads/";
+use crate::error::Error;
+
+pub const BRANCH_REF_PREFIX: &str = "refs/heads/";
 
 fn get_value<T>(
     config: &Config,
@@ -16,7 +32,7 @@ fn get_value<T>(
             return value;
         }
     }
-    return default_value;
+    default_value
 }
 
 // trait to easily find the first populated key in git config
@@ -44,6 +60,58 @@ impl ConfigValue for bool {
     }
 }
 
+impl ConfigValue for i64 {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        get_value(config, &mut Config::get_i64, keys, default_value)
+    }
+}
+
+/// Every value of the first key with any, e.g. a list set with `git config --add`
+impl ConfigValue for Vec<String> {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        keys.iter()
+            .map(|key| config_values(config, key))
+            .find(|values| !values.is_empty())
+            .unwrap_or(default_value)
+    }
+}
+
+/// A path with `~` and environment variables expanded, empty values are unset
+impl ConfigValue for PathBuf {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        let mut getter = |config: &Config, key: &str| match config.get_string(key) {
+            Ok(value) if value.is_empty() => Err(git2::Error::from_str("empty path")),
+            Ok(value) => Ok(PathBuf::from(
+                shellexpand::tilde(&expand(&value, &[])).as_ref(),
+            )),
+            Err(err) => Err(err),
+        };
+        get_value(config, &mut getter, keys, default_value)
+    }
+}
+
+/// Every value of a multi-valued key, e.g. set with `git config --add`
+pub fn config_values(config: &Config, key: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Ok(entries) = config.multivar(key, None) {
+        for entry in &entries {
+            if let Some(value) = entry.ok().and_then(|e| e.value().map(str::to_owned)) {
+                values.push(value);
+            }
+        }
+    }
+    values
+}
+
 pub fn expand(input: &str, context: &[(&str, &str)]) -> String {
     env_with_context_no_errors(input, |name| {
         for &(key, val) in context {
@@ -56,31 +124,320 @@ pub fn expand(input: &str, context: &[(&str, &str)]) -> String {
     .to_string()
 }
 
+// formats of `${DATE}` and `${TIME}` without one
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+const DEFAULT_TIME_FORMAT: &str = "%H%M%S";
+
+/// `expand_at` after also formatting strftime-style specifiers outside of variables, like the
+/// `%Y-%m-%d` of `snapshot/${BRANCH}/%Y-%m-%d`
+pub fn expand_dated<Tz: TimeZone>(
+    input: &str,
+    context: &[(&str, &str)],
+    time: &DateTime<Tz>,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    expand_time(input, context, time, true)
+}
+
+/// `expand` with `${DATE:<format>}` and `${TIME:<format>}` tokens formatted with `time`. Tokens
+/// with an invalid format are left as they are.
+pub fn expand_at<Tz: TimeZone>(input: &str, context: &[(&str, &str)], time: &DateTime<Tz>) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    expand_time(input, context, time, false)
+}
+
+fn expand_time<Tz: TimeZone>(
+    input: &str,
+    context: &[(&str, &str)],
+    time: &DateTime<Tz>,
+    specifiers: bool,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let text = |text: &str| match specifiers {
+        true => format_time(text, time).unwrap_or_else(|| text.to_owned()),
+        false => text.to_owned(),
+    };
+
+    // formatted before the variables are expanded so `%` in their values isn't taken for a
+    // specifier
+    let mut formatted = String::new();
+    let mut end = 0;
+    for captures in time_token().captures_iter(input) {
+        let whole = captures.get(0).unwrap();
+        formatted.push_str(&text(&input[end..whole.start()]));
+        let format = match (captures.get(2), &captures[1]) {
+            (Some(format), _) => format.as_str(),
+            (None, "DATE") => DEFAULT_DATE_FORMAT,
+            (None, _) => DEFAULT_TIME_FORMAT,
+        };
+        match format_time(format, time) {
+            Some(time) => formatted.push_str(&time),
+            None => {
+                warn!("invalid date format: {}", format);
+                formatted.push_str(whole.as_str());
+            }
+        }
+        end = whole.end();
+    }
+    formatted.push_str(&text(&input[end..]));
+    expand(&formatted, context)
+}
+
+fn time_token() -> &'static Regex {
+    static TOKEN: OnceLock<Regex> = OnceLock::new();
+    TOKEN.get_or_init(|| Regex::new(r"\$\{(DATE|TIME)(?::([^}]*))?\}").unwrap())
+}
+
+/// `input` with a date specifier in place of each date and time token, for matching with
+/// `date_pattern`
+pub fn mask_time_tokens(input: &str) -> String {
+    time_token().replace_all(input, "%c").into_owned()
+}
+
+/// `time` formatted with strftime-style specifiers, None for an invalid format
+fn format_time<Tz: TimeZone>(format: &str, time: &DateTime<Tz>) -> Option<String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return None;
+    }
+    let mut formatted = String::new();
+    write!(formatted, "{}", time.format_with_items(items.into_iter())).ok()?;
+    Some(formatted)
+}
+
+/// Regex source matching `input` with any text in place of its date specifiers
+pub fn date_pattern(input: &str) -> String {
+    let mut pattern = String::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            pattern.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+        // padding and width modifiers, e.g. `%-d` or `%3f`
+        while chars.next_if(|c| "-_0123456789.:#".contains(*c)).is_some() {}
+        match chars.next() {
+            Some('%') => pattern.push('%'),
+            Some(_) => pattern.push_str(".+"),
+            None => pattern.push('%'),
+        }
+    }
+    pattern
+}
+
+pub fn is_valid_branch_name(name: &str) -> bool {
+    git2::Reference::is_valid_name(&[BRANCH_REF_PREFIX, name].concat())
+}
+
+/// `name` with what git doesn't allow in branch names replaced, e.g. the spaces and colons of a
+/// value from an environment variable
+pub fn sanitize_branch_name(name: &str) -> String {
+    if is_valid_branch_name(name) {
+        return name.to_owned();
+    }
+    let components: Vec<String> = name
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(|component| {
+            let mut component: String = component
+                .chars()
+                .map(|c| match c {
+                    c if c.is_ascii_control() => '-',
+                    ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\' => '-',
+                    c => c,
+                })
+                .collect();
+            while component.contains("..") {
+                component = component.replace("..", ".");
+            }
+            component = component.replace("@{", "@-");
+            if let Some(stripped) = component.strip_suffix(".lock") {
+                component = format!("{}-lock", stripped);
+            }
+            component.trim_start_matches('.').to_owned()
+        })
+        .filter(|c| !c.is_empty())
+        .collect();
+    components.join("/").trim_end_matches('.').to_owned()
+}
+
 pub fn branch_ref_shorthand(ref_name: &str) -> &str {
     ref_name.trim_start_matches(BRANCH_REF_PREFIX)
 }
 
-#[cfg(test)]
-pub mod tests {
-    use std::path::Path;
+// default filesystems on macOS and Windows don't distinguish case
+const CASE_INSENSITIVE_FS: bool = cfg!(any(target_os = "macos", target_os = "windows"));
 
-    use super::*;
-    use git2::Repository;
-    use tempfile::{tempdir, NamedTempFile};
+fn components_eq(a: Component, b: Component, case_insensitive: bool) -> bool {
+    if !case_insensitive {
+        return a == b;
+    }
+    a.as_os_str().to_string_lossy().to_lowercase() == b.as_os_str().to_string_lossy().to_lowercase()
+}
+
+fn strip_prefix_with(path: &Path, prefix: &Path, case_insensitive: bool) -> Option<PathBuf> {
+    let mut components = path.components();
+    for prefix_component in prefix.components() {
+        if !components_eq(components.next()?, prefix_component, case_insensitive) {
+            return None;
+        }
+    }
+    Some(components.as_path().to_owned())
+}
+
+/// `Path::strip_prefix` that ignores case on case insensitive filesystems, keeping the casing of `path`
+pub fn strip_path_prefix(path: &Path, prefix: &Path) -> Option<PathBuf> {
+    strip_prefix_with(path, prefix, CASE_INSENSITIVE_FS)
+}
+
+/// The physical repo a path leads to, the same through bind mounts and symlinks
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RepoId {
+    #[cfg(unix)]
+    git_dir: (u64, u64),
+    #[cfg(not(unix))]
+    git_dir: PathBuf,
+}
+
+/// Identify the repo at `path` by its git dir's device and inode, or canonical path where there
+/// are none
+pub fn repo_id(path: &Path) -> Option<RepoId> {
+    let repo = git2::Repository::discover(path).ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(repo.path()).ok()?;
+        Some(RepoId {
+            git_dir: (metadata.dev(), metadata.ino()),
+        })
+    }
+    #[cfg(not(unix))]
+    Some(RepoId {
+        git_dir: canonicalize(repo.path()).ok()?,
+    })
+}
+
+/// `Path::starts_with` that ignores case on case insensitive filesystems
+pub fn path_starts_with(path: &Path, prefix: &Path) -> bool {
+    strip_path_prefix(path, prefix).is_some()
+}
+
+/// Resolve symlinks in `path` like `fs::canonicalize`, but also for paths that no longer exist by
+/// resolving their closest existing ancestor
+pub fn canonicalize_existing(path: &Path) -> PathBuf {
+    if let Ok(path) = canonicalize(path) {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => canonicalize_existing(parent).join(name),
+        _ => path.to_owned(),
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`, None where it can't be told
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn available_space(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // the block counts and sizes differ in width between platforms
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Run `f`, returning a panic as an error so a bug hit by one repo doesn't take down the watcher
+pub fn catch_panic<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(Error::Panicked(panic_message(&*payload))))
+}
+
+/// Run blocking work, like a snapshot waiting to retry, from a task on a runtime worker thread,
+/// handing the worker's other tasks to the rest of the runtime meanwhile. A current thread runtime
+/// has nowhere to hand them, `f` just runs there and off the runtime.
+pub fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Message a panic was raised with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => (*message).to_owned(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_owned(),
+    }
+}
 
-    pub fn test_repo(path: &Path) -> (Repository, Config) {
-        let repo = Repository::init(path).unwrap();
-        let mut config = repo.config().unwrap();
-        config.set_str("user.name", "Test").unwrap();
-        config.set_str("user.email", "test@test.test").unwrap();
+/// Durations of consecutive phases of an operation
+pub struct Timings {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
 
-        (repo, config)
+impl Timings {
+    pub fn new() -> Self {
+        Timings {
+            last: Instant::now(),
+            phases: Vec::new(),
+        }
     }
 
-    pub fn create_temp_file(p: &Path) {
-        NamedTempFile::new_in(p).unwrap().keep().unwrap();
+    /// Record the time since the previous phase ended as the duration of `phase`
+    pub fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
     }
 
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, d)| *d).sum()
+    }
+}
+
+impl std::fmt::Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (phase, duration) in &self.phases {
+            write!(f, "{}: {:?}, ", phase, duration)?;
+        }
+        write!(f, "total: {:?}", self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::test_util::test_repo;
+    use tempfile::tempdir;
+
     #[test]
     fn string_from_config() {
         let temp = tempdir().unwrap();
@@ -119,6 +476,205 @@ pub mod tests {
         assert_eq!(default_value, result);
     }
 
+    #[test]
+    fn expand_date() {
+        let time = chrono::Utc.with_ymd_and_hms(2022, 6, 1, 9, 30, 0).unwrap();
+        assert_eq!(
+            "snapshot/main/2022-06-01",
+            expand_dated("snapshot/${BRANCH}/%Y-%m-%d", &[("BRANCH", "main")], &time)
+        );
+        assert_eq!(
+            "snapshot/100%/0930",
+            expand_dated("snapshot/${BRANCH}/%H%M", &[("BRANCH", "100%")], &time)
+        );
+        // not a specifier chrono knows
+        assert_eq!(
+            "snapshot/main/%Q",
+            expand_dated("snapshot/${BRANCH}/%Q", &[("BRANCH", "main")], &time)
+        );
+        assert_eq!(
+            "snapshot/main/2022-06-01/%Y",
+            expand_dated(
+                "snapshot/${BRANCH}/${DATE}/%%Y",
+                &[("BRANCH", "main")],
+                &time
+            )
+        );
+
+        let pattern = Regex::new(&format!("^{}$", date_pattern("a.b/%Y-%-m/%%"))).unwrap();
+        assert!(pattern.is_match("a.b/2022-6/%"));
+        assert!(!pattern.is_match("axb/2022-6/%"));
+        let pattern = Regex::new(&format!(
+            "^{}$",
+            date_pattern(&mask_time_tokens("a/${DATE}-${TIME:%H}"))
+        ))
+        .unwrap();
+        assert!(pattern.is_match("a/2022-06-01-09"));
+    }
+
+    #[test]
+    fn expand_time_tokens() {
+        let time = chrono::Utc.with_ymd_and_hms(2022, 6, 1, 9, 30, 5).unwrap();
+        assert_eq!(
+            "Snapshot of main on 2022-06-01 at 09:30, 100%",
+            expand_at(
+                "Snapshot of ${BRANCH} on ${DATE} at ${TIME:%H:%M}, 100%",
+                &[("BRANCH", "main")],
+                &time
+            )
+        );
+        assert_eq!("093005 22", expand_at("${TIME} ${DATE:%y}", &[], &time));
+        // invalid formats are kept
+        assert_eq!(
+            "${DATE:%Q} 2022",
+            expand_at("${DATE:%Q} ${DATE:%Y}", &[], &time)
+        );
+        assert_eq!("${TIME:%}", expand_at("${TIME:%}", &[], &time));
+    }
+
+    #[test]
+    fn sanitize_branch() {
+        assert_eq!("snapshot/main", sanitize_branch_name("snapshot/main"));
+        assert_eq!(
+            "snapshot/John-Doe/feature-x",
+            sanitize_branch_name("snapshot/John Doe//feature:x/")
+        );
+        assert_eq!(
+            "snapshot/a.b/c-lock/d@-1}",
+            sanitize_branch_name("snapshot/a..b/c.lock/.d@{1}.")
+        );
+        assert_eq!("-", sanitize_branch_name("/~/"));
+        assert_eq!("", sanitize_branch_name("/./"));
+        assert!(is_valid_branch_name(&sanitize_branch_name(
+            "snap\tshot/[x]?*^\\"
+        )));
+        assert!(!is_valid_branch_name(""));
+    }
+
+    #[test]
+    fn catch_panics() {
+        assert_eq!(1, catch_panic(|| Ok(1)).unwrap());
+        let panicked = |f: fn() -> Result<(), Error>| match catch_panic(f) {
+            Err(Error::Panicked(message)) => message,
+            result => panic!("no panic: {:?}", result),
+        };
+        assert_eq!("boom", panicked(|| panic!("boom")));
+        assert_eq!("boom 2", panicked(|| panic!("boom {}", 2)));
+        assert!(matches!(
+            catch_panic(|| -> Result<(), Error> { Err(Error::InvalidHead) }),
+            Err(Error::InvalidHead)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn run_blocking_frees_worker() {
+        let start = Instant::now();
+        let ticked = tokio::spawn(async { Instant::now() });
+        run_blocking(|| std::thread::sleep(Duration::from_millis(500)));
+        // the only worker ran the task while blocked
+        assert!(ticked.await.unwrap() < start + Duration::from_millis(400));
+        // nowhere to hand tasks to outside a runtime
+        assert_eq!(1, std::thread::spawn(|| run_blocking(|| 1)).join().unwrap());
+    }
+
+    #[test]
+    fn timings() {
+        let mut timings = Timings::new();
+        timings.lap("first");
+        timings.lap("second");
+
+        let phases: Vec<&str> = timings.phases().iter().map(|(p, _)| *p).collect();
+        assert_eq!(vec!["first", "second"], phases);
+        assert!(timings.to_string().starts_with("first: "));
+    }
+
+    #[test]
+    fn strip_prefix_case_insensitive() {
+        let path = Path::new("/Users/Test/MyRepo/src/Main.rs");
+        assert_eq!(
+            Some(PathBuf::from("src/Main.rs")),
+            strip_prefix_with(path, Path::new("/users/test/myrepo"), true)
+        );
+        assert_eq!(
+            None,
+            strip_prefix_with(path, Path::new("/users/test/myrepo"), false)
+        );
+        assert_eq!(
+            None,
+            strip_prefix_with(path, Path::new("/users/test/myrepo2"), true)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonicalize_existing_symlink() {
+        let temp = tempdir().unwrap();
+        let real = canonicalize(temp.path()).unwrap().join("real");
+        std::fs::create_dir(&real).unwrap();
+        let link = temp.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        assert_eq!(real.join("a"), canonicalize_existing(&link.join("a")));
+        assert_eq!(
+            real.join("a").join("b"),
+            canonicalize_existing(&link.join("a").join("b"))
+        );
+    }
+
+    #[test]
+    fn i64_from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config.set_str("test.key", "2k").unwrap();
+        config.set_str("test.invalid", "many").unwrap();
+
+        assert_eq!(2048, i64::from_config(&config, &["test.key"], 0));
+        assert_eq!(-1, i64::from_config(&config, &["test.invalid"], -1));
+    }
+
+    #[test]
+    fn list_from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config.set_multivar("test.list", "^$", "a").unwrap();
+        config.set_multivar("test.list", "^$", "b").unwrap();
+        config.set_str("test.other", "c").unwrap();
+
+        assert_eq!(
+            vec!["a", "b"],
+            Vec::<String>::from_config(&config, &["test.unset", "test.list", "test.other"], vec![])
+        );
+        assert_eq!(
+            vec!["d"],
+            Vec::<String>::from_config(&config, &["test.unset"], vec!["d".to_owned()])
+        );
+    }
+
+    #[test]
+    fn path_from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        std::env::set_var("GIT_SNAPSHOT_TEST_DIR", "/tmp/snapshots");
+        config.set_str("test.home", "~/keys/id").unwrap();
+        config
+            .set_str("test.var", "${GIT_SNAPSHOT_TEST_DIR}/objects")
+            .unwrap();
+        config.set_str("test.empty", "").unwrap();
+
+        assert_eq!(
+            dirs::home_dir().unwrap().join("keys/id"),
+            PathBuf::from_config(&config, &["test.home"], PathBuf::new())
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/snapshots/objects"),
+            PathBuf::from_config(&config, &["test.empty", "test.var"], PathBuf::new())
+        );
+        assert_eq!(
+            PathBuf::new(),
+            PathBuf::from_config(&config, &["test.empty"], PathBuf::new())
+        );
+    }
+
     #[test]
     fn mutliple_keys() {
         let temp = tempdir().unwrap();
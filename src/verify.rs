@@ -0,0 +1,344 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    fs::{read, read_link},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::{Commit, FileMode, ObjectType, Odb, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Outcome of checking a snapshot against the working tree it was taken of
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Verification {
+    pub commit: String,
+    #[serde(with = "humantime_serde")]
+    pub time: SystemTime,
+    /// Files compared
+    pub checked: usize,
+    /// Files whose blob couldn't be read or differs from the working tree
+    pub mismatched: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let short = self.commit.get(..7).unwrap_or(&self.commit);
+        match self.is_ok() {
+            true => write!(f, "{} ok, {} files checked", short, self.checked),
+            false => write!(
+                f,
+                "{} failed, {} of {} files differ",
+                short,
+                self.mismatched.len(),
+                self.checked
+            ),
+        }
+    }
+}
+
+/// Read back the blobs of `commit` and compare their hashes with the working tree's files, all of
+/// them or `sample` spread over the tree. Contents are hashed as they are on disk, without the
+/// line ending conversions git may apply.
+pub fn verify_worktree(
+    repo: &Repository,
+    commit: &Commit,
+    sample: Option<usize>,
+) -> Result<Verification, Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("no working tree to verify against"))?;
+    let mut files = Vec::new();
+    commit.tree()?.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                files.push((PathBuf::from(root).join(name), entry.id(), entry.filemode()));
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    if let Some(sample) = sample.filter(|&sample| sample > 0 && sample < files.len()) {
+        let step = files.len() / sample;
+        files = files.into_iter().step_by(step).take(sample).collect();
+    }
+
+    let mut mismatched = Vec::new();
+    for (path, id, mode) in &files {
+        if !matches_blob(repo, &workdir.join(path), *id, *mode) {
+            mismatched.push(path.clone());
+        }
+    }
+    Ok(Verification {
+        commit: commit.id().to_string(),
+        time: SystemTime::now(),
+        checked: files.len(),
+        mismatched,
+    })
+}
+
+fn matches_blob(repo: &Repository, path: &Path, id: Oid, mode: i32) -> bool {
+    if repo.find_blob(id).is_err() {
+        return false;
+    }
+    let content = match mode == i32::from(FileMode::Link) {
+        true => read_link(path).map(|target| target.to_string_lossy().into_owned().into_bytes()),
+        false => read(path),
+    };
+    content
+        .ok()
+        .and_then(|content| Oid::hash_object(ObjectType::Blob, &content).ok())
+        .is_some_and(|hash| hash == id)
+}
+
+/// Objects of a snapshot branch read back from the object database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub commits: usize,
+    /// Readable objects, commits included
+    pub objects: usize,
+    pub unreadable: Vec<Oid>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreadable.is_empty()
+    }
+}
+
+impl Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} snapshots, {} objects readable",
+            self.commits, self.objects
+        )?;
+        if !self.is_ok() {
+            write!(f, ", {} unreadable:", self.unreadable.len())?;
+            for id in &self.unreadable {
+                write!(f, " {}", id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read every commit reachable from `tip` along with the trees and blobs they hold, a lighter
+/// `git fsck` of the snapshots
+pub fn check_objects(repo: &Repository, tip: Oid) -> Result<IntegrityReport, Error> {
+    let odb = repo.odb()?;
+    let mut report = IntegrityReport::default();
+    let mut seen = HashSet::new();
+    let mut pending = vec![tip];
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if odb.read(id).is_err() {
+            report.unreadable.push(id);
+            continue;
+        }
+        report.commits += 1;
+        report.objects += 1;
+        let commit = repo.find_commit(id)?;
+        pending.extend(commit.parent_ids());
+        check_tree(repo, &odb, commit.tree_id(), &mut seen, &mut report)?;
+    }
+    Ok(report)
+}
+
+// Trees already seen are shared with an earlier snapshot and skipped
+fn check_tree(
+    repo: &Repository,
+    odb: &Odb,
+    id: Oid,
+    seen: &mut HashSet<Oid>,
+    report: &mut IntegrityReport,
+) -> Result<(), Error> {
+    if !seen.insert(id) {
+        return Ok(());
+    }
+    if odb.read(id).is_err() {
+        report.unreadable.push(id);
+        return Ok(());
+    }
+    report.objects += 1;
+    for entry in repo.find_tree(id)?.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => check_tree(repo, odb, entry.id(), seen, report)?,
+            Some(ObjectType::Blob) if seen.insert(entry.id()) => match odb.read(entry.id()) {
+                Ok(_) => report.objects += 1,
+                Err(_) => report.unreadable.push(entry.id()),
+            },
+            // submodule commits live in another repo
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// How a remote's snapshot branch compares with the local one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    InSync,
+    /// Local snapshots not pushed yet
+    Ahead(usize),
+    /// Snapshots only the remote has
+    Behind(usize),
+    /// Both have snapshots the other lacks, or the remote's aren't known locally
+    Diverged,
+    /// No snapshot branch on the remote
+    Missing,
+}
+
+impl Parity {
+    pub fn new(repo: &Repository, local: Oid, remote: Option<Oid>) -> Result<Self, Error> {
+        let remote = match remote {
+            Some(remote) if remote == local => return Ok(Self::InSync),
+            Some(remote) => remote,
+            None => return Ok(Self::Missing),
+        };
+        if repo.find_commit(remote).is_err() {
+            return Ok(Self::Diverged);
+        }
+        Ok(match repo.graph_ahead_behind(local, remote)? {
+            (ahead, 0) => Self::Ahead(ahead),
+            (0, behind) => Self::Behind(behind),
+            _ => Self::Diverged,
+        })
+    }
+}
+
+impl Display for Parity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InSync => write!(f, "in sync"),
+            Self::Ahead(n) => write!(f, "{} snapshot(s) not pushed", n),
+            Self::Behind(n) => write!(f, "{} snapshot(s) only on the remote", n),
+            Self::Diverged => write!(f, "diverged"),
+            Self::Missing => write!(f, "no snapshots on the remote"),
+        }
+    }
+}
+
+/// A remote's snapshot branch checked against the local one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteCheck {
+    pub remote: String,
+    /// Snapshot ref on the remote
+    pub ref_name: String,
+    pub parity: Parity,
+}
+
+impl Display for RemoteCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.remote, self.ref_name, self.parity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use git2::{IndexAddOption, Signature};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::test_util::test_repo;
+
+    #[test]
+    fn worktree() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        for name in ["a", "b", "c", "d"] {
+            write(temp_dir.path().join(name), name).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test").unwrap();
+        let id = repo
+            .commit(None, &signature, &signature, "snapshot", &tree, &[])
+            .unwrap();
+        let commit = repo.find_commit(id).unwrap();
+
+        let verification = verify_worktree(&repo, &commit, None).unwrap();
+        assert!(verification.is_ok());
+        assert_eq!(4, verification.checked);
+        assert_eq!(2, verify_worktree(&repo, &commit, Some(2)).unwrap().checked);
+
+        write(temp_dir.path().join("b"), "changed").unwrap();
+        let verification = verify_worktree(&repo, &commit, None).unwrap();
+        assert_eq!(vec![PathBuf::from("b")], verification.mismatched);
+    }
+
+    #[test]
+    fn objects() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        write(temp_dir.path().join("a"), "a").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test").unwrap();
+        let first = repo
+            .commit(None, &signature, &signature, "first", &tree, &[])
+            .unwrap();
+        let first = repo.find_commit(first).unwrap();
+        let second = repo
+            .commit(None, &signature, &signature, "second", &tree, &[&first])
+            .unwrap();
+
+        let report = check_objects(&repo, second).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(2, report.commits);
+        // two commits sharing a tree with one blob
+        assert_eq!(4, report.objects);
+
+        // a lost blob
+        let blob = tree.get_name("a").unwrap().id();
+        let blob_path = temp_dir
+            .path()
+            .join(".git/objects")
+            .join(&blob.to_string()[..2]);
+        std::fs::remove_file(blob_path.join(&blob.to_string()[2..])).unwrap();
+        assert_eq!(vec![blob], check_objects(&repo, second).unwrap().unreadable);
+    }
+
+    #[test]
+    fn parity() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let signature = Signature::now("test", "test").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let commit = |message: &str, parents: &[&Commit]| {
+            let id = repo
+                .commit(None, &signature, &signature, message, &tree, parents)
+                .unwrap();
+            repo.find_commit(id).unwrap()
+        };
+        let first = commit("first", &[]);
+        let second = commit("second", &[&first]);
+        let other = commit("other", &[&first]);
+        let parity =
+            |local: &Commit, remote: Option<Oid>| Parity::new(&repo, local.id(), remote).unwrap();
+
+        assert_eq!(Parity::InSync, parity(&second, Some(second.id())));
+        assert_eq!(Parity::Ahead(1), parity(&second, Some(first.id())));
+        assert_eq!(Parity::Behind(1), parity(&first, Some(second.id())));
+        assert_eq!(Parity::Diverged, parity(&second, Some(other.id())));
+        assert_eq!(Parity::Diverged, parity(&second, Some(Oid::zero())));
+        assert_eq!(Parity::Missing, parity(&second, None));
+    }
+}
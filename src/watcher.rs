@@ -1,22 +1,35 @@
+use log::{error, warn};
 use notify::{
+    event::{MetadataKind, ModifyKind},
     Config, Event, EventHandler, PollWatcher, RecommendedWatcher, Watcher as NotifyWatcher,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    filter::EventFilter,
+    recorder::{self, Record},
+    util::{canonicalize_existing, panic_message, path_starts_with},
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
     fs::canonicalize,
+    panic::{catch_unwind, AssertUnwindSafe},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use tokio::{sync::mpsc::unbounded_channel, time::sleep};
+use tokio::{sync::mpsc::unbounded_channel, task::JoinHandle, time::sleep};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "mode", content = "mode_config")]
 pub enum WatchMode {
+    #[default]
     Event,
     Poll {
         #[serde(with = "humantime_serde")]
@@ -25,19 +38,113 @@ pub enum WatchMode {
 }
 
 pub trait Handler {
-    fn handle(&mut self, path: PathBuf);
+    /// Called with the watched root and the paths that changed beneath it during the debounce period
+    fn handle(&mut self, path: PathBuf, changed_paths: Vec<PathBuf>);
 }
 
-impl<F: FnMut(PathBuf) -> ()> Handler for F {
-    fn handle(&mut self, path: PathBuf) {
-        (self)(path);
+impl<F: FnMut(PathBuf, Vec<PathBuf>)> Handler for F {
+    fn handle(&mut self, path: PathBuf, changed_paths: Vec<PathBuf>) {
+        (self)(path, changed_paths);
     }
 }
 type BoxedNotifyWatcher = Box<dyn NotifyWatcher + Send + Sync>;
 
+/// Given the paths that changed beneath a root, returns the directories to watch for it from now on,
+/// or `None` to keep watching the current ones
+pub type DirsProvider = Box<dyn Fn(&[PathBuf]) -> Option<Vec<PathBuf>> + Send + Sync>;
+
+/// How the directories of a watched root are registered with the OS
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WatchStrategy {
+    /// One recursive watch on the root
+    #[default]
+    Recursive,
+    /// Non-recursive watches on only the directories containing tracked files
+    TrackedDirs,
+}
+
+/// Kinds of filesystem events that can be configured to trigger snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    Create,
+    /// Content changes, including a newer mtime which is how poll mode reports writes
+    Modify,
+    /// Both the old and new path of a rename or move
+    Rename,
+    Remove,
+    /// Permission, ownership, timestamp and extended attribute changes
+    Metadata,
+    Access,
+}
+
+/// Event kinds triggering snapshots unless configured otherwise
+pub const DEFAULT_EVENT_KINDS: &[EventKind] = &[
+    EventKind::Create,
+    EventKind::Modify,
+    EventKind::Rename,
+    EventKind::Remove,
+];
+
+impl EventKind {
+    fn from_notify(kind: &notify::EventKind) -> Self {
+        match kind {
+            notify::EventKind::Create(_) => Self::Create,
+            notify::EventKind::Modify(ModifyKind::Name(_)) => Self::Rename,
+            notify::EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)) => {
+                Self::Modify
+            }
+            notify::EventKind::Modify(ModifyKind::Metadata(_)) => Self::Metadata,
+            notify::EventKind::Remove(_) => Self::Remove,
+            notify::EventKind::Access(_) => Self::Access,
+            _ => Self::Modify,
+        }
+    }
+}
+
+// Directories watched non-recursively for a root
+struct WatchedDirs {
+    dirs: HashSet<PathBuf>,
+    provider: DirsProvider,
+}
+
+/// Why a watcher no longer delivers events and has to be rebuilt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The task dispatching events to handlers ended, e.g. after the backend closed its channel
+    DispatcherEnded,
+    /// A handler has been running for longer than the stall timeout, holding up every root
+    Stalled { root: PathBuf, running: Duration },
+    /// Errors of the notify backend itself since the last check, rather than of a watched path
+    BackendErrors(u64),
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DispatcherEnded => write!(f, "the event dispatcher ended"),
+            Self::Stalled { root, running } => write!(
+                f,
+                "the handler of {} has been running for {}",
+                root.display(),
+                humantime::format_duration(*running)
+            ),
+            Self::BackendErrors(errors) => {
+                write!(f, "the watch backend reported {} error(s)", errors)
+            }
+        }
+    }
+}
+
 pub struct Watcher {
-    notify_watcher: BoxedNotifyWatcher,
+    notify_watcher: Arc<Mutex<BoxedNotifyWatcher>>,
     handlers: Arc<Mutex<HashMap<PathBuf, Box<dyn Handler + Send + Sync>>>>,
+    watched_dirs: Arc<Mutex<HashMap<PathBuf, WatchedDirs>>>,
+    dispatcher: JoinHandle<()>,
+    // root whose handler is running and since when
+    running: Arc<Mutex<Option<(PathBuf, Instant)>>>,
+    backend_errors: Arc<AtomicU64>,
 }
 
 impl Watcher {
@@ -51,70 +158,228 @@ impl Watcher {
                 Box::new(watcher)
             }
             WatchMode::Poll { interval } => {
-                let watcher = PollWatcher::new(
-                    handler,
-                    Config::default().with_poll_interval(interval.clone()),
-                )?;
+                let watcher =
+                    PollWatcher::new(handler, Config::default().with_poll_interval(*interval))?;
                 Box::new(watcher)
             }
         };
         Ok(watcher)
     }
 
-    pub fn new(mode: &WatchMode, debounce_period: Duration) -> Result<Self, Error> {
+    pub fn new(
+        mode: &WatchMode,
+        debounce_period: Duration,
+        filter: EventFilter,
+    ) -> Result<Self, Error> {
         let handlers: Arc<Mutex<HashMap<PathBuf, Box<dyn Handler + Send + Sync>>>> =
             Arc::new(Mutex::new(HashMap::new()));
-        let (tx, mut rx) = unbounded_channel::<PathBuf>();
+        let (tx, mut rx) = unbounded_channel::<(EventKind, PathBuf)>();
+        let backend_errors = Arc::new(AtomicU64::new(0));
+        let errors = backend_errors.clone();
         let handler = move |event: Result<Event, notify::Error>| -> () {
-            if let Ok(event) = event {
-                if !event.kind.is_create() && !event.kind.is_modify() && !event.kind.is_remove() {
-                    return;
+            match event {
+                Ok(event) => {
+                    let kind = EventKind::from_notify(&event.kind);
+                    // renames carry the old and new path, either can be the only one inside a root
+                    for event_path in &event.paths {
+                        recorder::record(|| Record::Event {
+                            kind,
+                            path: event_path.clone(),
+                        });
+                        if filter.is_match(kind, event_path) {
+                            let _ = tx.send((kind, event_path.clone()));
+                        } else {
+                            recorder::record(|| Record::Filtered {
+                                kind,
+                                path: event_path.clone(),
+                            });
+                        }
+                    }
                 }
-
-                for event_path in &event.paths {
-                    let _ = tx.send(event_path.clone());
+                // errors of a single path, like an unreadable file while polling, carry the path
+                Err(err) if !err.paths.is_empty() => warn!("watch error: {}", err),
+                Err(err) => {
+                    warn!("watch backend error: {}", err);
+                    errors.fetch_add(1, Ordering::Relaxed);
                 }
             }
         };
 
+        let notify_watcher = Arc::new(Mutex::new(Self::notify_watcher(mode, handler)?));
+        let watched_dirs: Arc<Mutex<HashMap<PathBuf, WatchedDirs>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
         let handlers_clone = handlers.clone();
+        // weak so dropping the watcher drops the notify watcher, closing the channel and ending the task
+        let notify_watcher_weak = Arc::downgrade(&notify_watcher);
+        let watched_dirs_clone = watched_dirs.clone();
 
-        tokio::spawn(async move {
-            while let Some(event_path) = rx.recv().await {
-                let handlers = handlers_clone.lock().unwrap();
-                let mut debouncers = HashMap::new();
-
-                for p in handlers.keys() {
-                    if event_path.starts_with(p.as_path()) {
-                        let handler_path = p.clone();
-                        let handlers = handlers_clone.clone();
-                        let debounce_period = debounce_period.clone();
-
-                        let join_handle = tokio::spawn(async move {
-                            sleep(debounce_period).await;
-                            if let Some(handler) = handlers.lock().unwrap().get_mut(&handler_path) {
-                                handler.handle(handler_path);
-                            }
-                        });
+        // changed paths collected per watched root while its debounce period is running
+        let pending: Arc<Mutex<HashMap<PathBuf, BTreeSet<PathBuf>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let running: Arc<Mutex<Option<(PathBuf, Instant)>>> = Arc::default();
+        let running_clone = running.clone();
+
+        let dispatcher = tokio::spawn(async move {
+            let mut debouncers: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+            while let Some((kind, event_path)) = rx.recv().await {
+                // events can arrive through symlinks or aliased mounts, watched roots are canonical
+                let event_path = canonicalize_existing(&event_path);
+                let handler_path = match handlers_clone
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .filter(|p| path_starts_with(&event_path, p))
+                    // roots can be nested, e.g. a worktree inside the repo, the innermost one wins
+                    .max_by_key(|p| p.components().count())
+                {
+                    Some(p) => p.clone(),
+                    None => {
+                        recorder::record(|| Record::Unwatched { path: event_path });
+                        continue;
+                    }
+                };
+
+                // the root itself was moved away, its handler would only see a missing path
+                if kind == EventKind::Rename && event_path == handler_path && !handler_path.exists()
+                {
+                    warn!(
+                        "{} was renamed or moved, no longer watching it",
+                        handler_path.display()
+                    );
+                    if let Some(old_handle) = debouncers.remove(&handler_path) {
+                        old_handle.abort();
+                    }
+                    pending.lock().unwrap().remove(&handler_path);
+                    if let Some(notify_watcher) = notify_watcher_weak.upgrade() {
+                        let _ = Self::unwatch(
+                            &notify_watcher,
+                            &handlers_clone,
+                            &watched_dirs_clone,
+                            &handler_path,
+                        );
+                    }
+                    continue;
+                }
 
-                        // abort the existing handle for debouncing
-                        if let Some(old_handle) = debouncers.insert(p.clone(), join_handle) {
-                            old_handle.abort();
+                let handlers = handlers_clone.clone();
+                let pending = pending.clone();
+                let notify_watcher = notify_watcher_weak.clone();
+                let watched_dirs = watched_dirs_clone.clone();
+                let running = running_clone.clone();
+
+                pending
+                    .lock()
+                    .unwrap()
+                    .entry(handler_path.clone())
+                    .or_default()
+                    .insert(event_path);
+
+                let root = handler_path.clone();
+                let join_handle = tokio::spawn(async move {
+                    sleep(debounce_period).await;
+                    let changed_paths = pending
+                        .lock()
+                        .unwrap()
+                        .remove(&handler_path)
+                        .unwrap_or_default();
+                    let changed_paths: Vec<PathBuf> = changed_paths.into_iter().collect();
+                    if let (Some(watched), Some(notify_watcher)) = (
+                        watched_dirs.lock().unwrap().get_mut(&handler_path),
+                        notify_watcher.upgrade(),
+                    ) {
+                        if let Some(dirs) = (watched.provider)(&changed_paths) {
+                            Self::set_dirs(&notify_watcher, watched, dirs);
+                        }
+                    }
+                    recorder::record(|| Record::Debounced {
+                        root: handler_path.clone(),
+                        changed_paths: changed_paths.clone(),
+                    });
+                    if let Some(handler) = handlers.lock().unwrap().get_mut(&handler_path) {
+                        *running.lock().unwrap() = Some((handler_path.clone(), Instant::now()));
+                        // caught while the lock is held so it isn't poisoned for every other root
+                        let handled = catch_unwind(AssertUnwindSafe(|| {
+                            handler.handle(handler_path.clone(), changed_paths)
+                        }));
+                        *running.lock().unwrap() = None;
+                        if let Err(payload) = handled {
+                            error!(
+                                "handler of {} panicked: {}",
+                                handler_path.display(),
+                                panic_message(&*payload)
+                            );
                         }
-                        break;
                     }
+                });
+
+                // abort the existing handle for debouncing
+                if let Some(old_handle) = debouncers.insert(root, join_handle) {
+                    old_handle.abort();
                 }
             }
         });
 
-        let notify_watcher = Self::notify_watcher(&mode, handler)?;
-
         Ok(Self {
             notify_watcher,
             handlers,
+            watched_dirs,
+            dispatcher,
+            running,
+            backend_errors,
         })
     }
 
+    /// Why the watcher stopped delivering events, if it has, resetting the backend error count
+    pub fn check(&self, stall_timeout: Duration) -> Option<Fault> {
+        if self.dispatcher.is_finished() {
+            return Some(Fault::DispatcherEnded);
+        }
+        if let Some((root, since)) = &*self.running.lock().unwrap() {
+            let running = since.elapsed();
+            if running > stall_timeout {
+                return Some(Fault::Stalled {
+                    root: root.clone(),
+                    running,
+                });
+            }
+        }
+        match self.backend_errors.swap(0, Ordering::Relaxed) {
+            0 => None,
+            errors => Some(Fault::BackendErrors(errors)),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn abort_dispatcher(&self) {
+        self.dispatcher.abort();
+    }
+
+    // Watch the new directories and unwatch the ones no longer in `dirs`
+    fn set_dirs(
+        notify_watcher: &Mutex<BoxedNotifyWatcher>,
+        watched: &mut WatchedDirs,
+        dirs: impl IntoIterator<Item = PathBuf>,
+    ) {
+        let mut notify_watcher = notify_watcher.lock().unwrap();
+        let dirs: HashSet<PathBuf> = dirs.into_iter().collect();
+        for dir in watched.dirs.difference(&dirs) {
+            let _ = notify_watcher.unwatch(dir);
+        }
+        let mut new_dirs = HashSet::new();
+        for dir in dirs {
+            // directories that can't be watched, e.g. deleted ones, are retried on the next refresh
+            if watched.dirs.contains(&dir)
+                || notify_watcher
+                    .watch(&dir, notify::RecursiveMode::NonRecursive)
+                    .is_ok()
+            {
+                new_dirs.insert(dir);
+            }
+        }
+        watched.dirs = new_dirs;
+    }
+
     pub fn watch_path(
         &mut self,
         path: impl AsRef<Path>,
@@ -122,6 +387,8 @@ impl Watcher {
     ) -> Result<(), Error> {
         let path = canonicalize(path)?;
         self.notify_watcher
+            .lock()
+            .unwrap()
             .watch(&path, notify::RecursiveMode::Recursive)?;
 
         self.handlers.lock().unwrap().insert(path, handler);
@@ -129,10 +396,49 @@ impl Watcher {
         Ok(())
     }
 
+    /// Watch `dirs` non-recursively, routing their events to `handler` for `path`. After each
+    /// debounce period `provider` is asked whether the set of directories should change.
+    pub fn watch_dirs(
+        &mut self,
+        path: impl AsRef<Path>,
+        dirs: impl IntoIterator<Item = PathBuf>,
+        provider: DirsProvider,
+        handler: Box<dyn Handler + Send + Sync>,
+    ) -> Result<(), Error> {
+        let path = canonicalize(path)?;
+        self.handlers.lock().unwrap().insert(path.clone(), handler);
+
+        let mut watched = WatchedDirs {
+            dirs: HashSet::new(),
+            provider,
+        };
+        Self::set_dirs(&self.notify_watcher, &mut watched, dirs);
+        self.watched_dirs.lock().unwrap().insert(path, watched);
+
+        Ok(())
+    }
+
     pub fn unwatch_path(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
-        let path = canonicalize(path).unwrap();
-        self.notify_watcher.unwatch(&path)?;
-        self.handlers.lock().unwrap().remove(&path);
+        let path = canonicalize_existing(path.as_ref());
+        Self::unwatch(
+            &self.notify_watcher,
+            &self.handlers,
+            &self.watched_dirs,
+            &path,
+        )
+    }
+
+    fn unwatch(
+        notify_watcher: &Mutex<BoxedNotifyWatcher>,
+        handlers: &Mutex<HashMap<PathBuf, Box<dyn Handler + Send + Sync>>>,
+        watched_dirs: &Mutex<HashMap<PathBuf, WatchedDirs>>,
+        path: &Path,
+    ) -> Result<(), Error> {
+        handlers.lock().unwrap().remove(path);
+        match watched_dirs.lock().unwrap().remove(path) {
+            Some(mut watched) => Self::set_dirs(notify_watcher, &mut watched, []),
+            None => notify_watcher.lock().unwrap().unwatch(path)?,
+        }
         Ok(())
     }
 }
@@ -143,14 +449,16 @@ mod tests {
     use tokio::sync::mpsc::UnboundedReceiver;
 
     use super::*;
+    use crate::filter::DEFAULT_IGNORE_PATTERNS;
 
     fn test_watcher(path: &Path, mode: &WatchMode) -> (Watcher, UnboundedReceiver<PathBuf>) {
-        let mut watcher = Watcher::new(mode, Duration::from_millis(100)).unwrap();
+        let mut watcher =
+            Watcher::new(mode, Duration::from_millis(100), EventFilter::default()).unwrap();
         let (tx, rx) = unbounded_channel();
         watcher
             .watch_path(
                 path,
-                Box::new(move |p: PathBuf| {
+                Box::new(move |p: PathBuf, _| {
                     let _ = tx.send(p);
                 }),
             )
@@ -206,6 +514,281 @@ mod tests {
         assert_eq!(item.unwrap(), root_path);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn panicking_handler() {
+        let root = tempdir().unwrap();
+        let other = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::default(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        let mut calls = 0;
+        watcher
+            .watch_path(
+                root.path(),
+                Box::new(move |p: PathBuf, _| {
+                    calls += 1;
+                    if calls == 1 {
+                        panic!("injected");
+                    }
+                    let _ = tx.send(p);
+                }),
+            )
+            .unwrap();
+
+        NamedTempFile::new_in(root.path()).unwrap().keep().unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+
+        // the panic neither ended the task nor poisoned the handlers
+        NamedTempFile::new_in(root.path()).unwrap().keep().unwrap();
+        assert_eq!(root_path, rx.recv().await.unwrap());
+        let (other_tx, mut other_rx) = unbounded_channel();
+        watcher
+            .watch_path(
+                other.path(),
+                Box::new(move |p: PathBuf, _| {
+                    let _ = other_tx.send(p);
+                }),
+            )
+            .unwrap();
+        NamedTempFile::new_in(other.path()).unwrap().keep().unwrap();
+        assert!(other_rx.recv().await.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn faults() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::default(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        watcher
+            .watch_path(
+                root.path(),
+                Box::new(move |p: PathBuf, _| {
+                    let _ = tx.send(p);
+                    std::thread::sleep(Duration::from_millis(500));
+                }),
+            )
+            .unwrap();
+        let stall_timeout = Duration::from_millis(200);
+        assert_eq!(None, watcher.check(stall_timeout));
+
+        NamedTempFile::new_in(root.path()).unwrap().keep().unwrap();
+        rx.recv().await.unwrap();
+        // blocking, the handler may hold the runtime's only worker
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            watcher.check(stall_timeout),
+            Some(Fault::Stalled { root, .. }) if root == root_path
+        ));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(None, watcher.check(stall_timeout));
+
+        watcher.backend_errors.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(Some(Fault::BackendErrors(2)), watcher.check(stall_timeout));
+        assert_eq!(None, watcher.check(stall_timeout));
+
+        watcher.abort_dispatcher();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(Some(Fault::DispatcherEnded), watcher.check(stall_timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changed_paths() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::default(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        watcher
+            .watch_path(
+                root.path(),
+                Box::new(move |_, changed_paths: Vec<PathBuf>| {
+                    let _ = tx.send(changed_paths);
+                }),
+            )
+            .unwrap();
+
+        let (_, file_path) = NamedTempFile::new_in(root.path()).unwrap().keep().unwrap();
+
+        let changed_paths = rx.recv().await.unwrap();
+        assert!(changed_paths.contains(&root_path.join(file_path.file_name().unwrap())));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn symlinked_root() {
+        let root = tempdir().unwrap();
+        let real = canonicalize(root.path()).unwrap().join("real");
+        std::fs::create_dir(&real).unwrap();
+        let link = root.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let (_watcher, mut rx) = test_watcher(&link, &WatchMode::Event);
+        NamedTempFile::new_in(&link).unwrap().keep().unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), real);
+    }
+
+    // /tmp is a symlink to /private/tmp on macOS
+    #[cfg(target_os = "macos")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn private_tmp() {
+        let root = tempfile::Builder::new().tempdir_in("/tmp").unwrap();
+        let (_watcher, mut rx) = test_watcher(root.path(), &WatchMode::Event);
+        NamedTempFile::new_in(root.path()).unwrap().keep().unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), canonicalize(root.path()).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_dirs() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let watched = root_path.join("watched");
+        let unwatched = root_path.join("unwatched");
+        std::fs::create_dir(&watched).unwrap();
+        std::fs::create_dir(&unwatched).unwrap();
+
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::default(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        let refreshed = watched.clone();
+        watcher
+            .watch_dirs(
+                &root_path,
+                vec![unwatched.clone()],
+                Box::new(move |_| Some(vec![refreshed.clone()])),
+                Box::new(move |_, changed_paths: Vec<PathBuf>| {
+                    let _ = tx.send(changed_paths);
+                }),
+            )
+            .unwrap();
+
+        // the provider swaps the watch to the other directory after the first event
+        NamedTempFile::new_in(&unwatched).unwrap().keep().unwrap();
+        assert!(rx.recv().await.is_some());
+
+        NamedTempFile::new_in(&unwatched).unwrap().keep().unwrap();
+        let (_, file_path) = NamedTempFile::new_in(&watched).unwrap().keep().unwrap();
+        let changed_paths = rx.recv().await.unwrap();
+        assert_eq!(vec![file_path], changed_paths);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rename() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let old = root_path.join("old");
+        let new = root_path.join("new");
+        std::fs::write(&old, "old").unwrap();
+
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::default(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        watcher
+            .watch_path(
+                &root_path,
+                Box::new(move |_, changed_paths: Vec<PathBuf>| {
+                    let _ = tx.send(changed_paths);
+                }),
+            )
+            .unwrap();
+
+        std::fs::rename(&old, &new).unwrap();
+
+        let changed_paths = rx.recv().await.unwrap();
+        assert!(changed_paths.contains(&old));
+        assert!(changed_paths.contains(&new));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ignore_patterns() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let (_watcher, mut rx) = test_watcher(&root_path, &WatchMode::Event);
+
+        std::fs::write(root_path.join(".file.swp"), "swap").unwrap();
+        std::fs::write(root_path.join("file~"), "backup").unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+
+        std::fs::write(root_path.join("file"), "file").unwrap();
+        assert_eq!(rx.recv().await.unwrap(), root_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn root_renamed() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let watched = root_path.join("watched");
+        std::fs::create_dir(&watched).unwrap();
+        let (watcher, mut rx) = test_watcher(&watched, &WatchMode::Event);
+
+        std::fs::rename(&watched, root_path.join("moved")).unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(rx.try_recv().is_err());
+        assert!(watcher.handlers.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metadata_kind() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let file_path = root_path.join("file");
+        std::fs::write(&file_path, "file").unwrap();
+        let (_watcher, mut rx) = test_watcher(&root_path, &WatchMode::Event);
+
+        // metadata only changes don't trigger by default
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+
+        let mut watcher = Watcher::new(
+            &WatchMode::Event,
+            Duration::from_millis(50),
+            EventFilter::new(&[EventKind::Metadata], DEFAULT_IGNORE_PATTERNS).unwrap(),
+        )
+        .unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        watcher
+            .watch_path(
+                &root_path,
+                Box::new(move |p: PathBuf, _| {
+                    let _ = tx.send(p);
+                }),
+            )
+            .unwrap();
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(rx.recv().await.unwrap(), root_path);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn unwatch() {
         let root = tempdir().unwrap();
@@ -0,0 +1,131 @@
+use std::{
+    fs::{read_to_string, write},
+    io::Cursor,
+    path::Path,
+};
+
+use git_snapshot::{
+    ipc::{
+        read_message, serve, write_message, Response, SnapshotInfo, SnapshotResult, StatusResult,
+        METHOD_NOT_FOUND, SNAPSHOT_ERROR,
+    },
+    test_util::test_repo,
+};
+use serde_json::{from_slice, from_value, json, to_vec, Value};
+use tempfile::tempdir;
+
+// Serve the requests in one session, returning the responses in order
+fn session(cwd: &Path, requests: &[Value]) -> Vec<Response> {
+    let mut input = Vec::new();
+    for request in requests {
+        write_message(&mut input, &to_vec(request).unwrap()).unwrap();
+    }
+    let mut output = Vec::new();
+    serve(Cursor::new(input), &mut output, cwd).unwrap();
+
+    let mut output = Cursor::new(output);
+    let mut responses = Vec::new();
+    while let Some(message) = read_message(&mut output).unwrap() {
+        responses.push(from_slice(&message).unwrap());
+    }
+    responses
+}
+
+fn request(id: u64, method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+}
+
+fn result<T: serde::de::DeserializeOwned>(response: &Response) -> T {
+    assert!(response.error.is_none(), "{:?}", response.error);
+    from_value(response.result.clone().unwrap()).unwrap()
+}
+
+#[test]
+fn snapshot_status_list() {
+    let temp_dir = tempdir().unwrap();
+    test_repo(temp_dir.path());
+    write(temp_dir.path().join("a"), "1").unwrap();
+
+    let responses = session(
+        temp_dir.path(),
+        &[
+            request(1, "snapshot", Value::Null),
+            // nothing changed since
+            request(2, "snapshot", json!({})),
+            request(3, "snapshot", json!({"message": "before refactor"})),
+            request(4, "status", json!({})),
+            request(5, "list", json!({"limit": 1})),
+        ],
+    );
+    assert_eq!(5, responses.len());
+
+    let first: SnapshotResult = result(&responses[0]);
+    assert!(first.snapshot.is_some());
+    let unchanged: SnapshotResult = result(&responses[1]);
+    assert!(unchanged.snapshot.is_none());
+    let checkpoint = result::<SnapshotResult>(&responses[2]).snapshot.unwrap();
+    assert!(checkpoint.checkpoint);
+    assert_eq!("before refactor", checkpoint.summary);
+
+    let status: StatusResult = result(&responses[3]);
+    assert_eq!(
+        format!("snapshot/{}", status.branch),
+        status.snapshot_branch
+    );
+    assert_eq!(Some(checkpoint.clone()), status.last_snapshot);
+    assert_eq!(None, status.disabled);
+
+    let listed: Vec<SnapshotInfo> = result(&responses[4]);
+    assert_eq!(vec![checkpoint], listed);
+}
+
+#[test]
+fn restore_file() {
+    let temp_dir = tempdir().unwrap();
+    test_repo(temp_dir.path());
+    let file = temp_dir.path().join("a");
+    write(&file, "1").unwrap();
+
+    let responses = session(temp_dir.path(), &[request(1, "snapshot", Value::Null)]);
+    let snapshot = result::<SnapshotResult>(&responses[0]).snapshot.unwrap();
+
+    write(&file, "2").unwrap();
+    let responses = session(
+        temp_dir.path(),
+        &[
+            request(
+                1,
+                "restoreFile",
+                json!({"file": file.canonicalize().unwrap(), "snapshot": snapshot.id}),
+            ),
+            request(2, "restoreFile", json!({"file": "a", "snapshot": "nope"})),
+        ],
+    );
+    let restored = result::<SnapshotResult>(&responses[0]).snapshot.unwrap();
+    assert_eq!(snapshot.id, restored.id);
+    assert_eq!("1", read_to_string(&file).unwrap());
+    assert_eq!(SNAPSHOT_ERROR, responses[1].error.as_ref().unwrap().code);
+}
+
+#[test]
+fn protocol_errors() {
+    let temp_dir = tempdir().unwrap();
+    test_repo(temp_dir.path());
+
+    let responses = session(
+        temp_dir.path(),
+        &[
+            // notifications get no response
+            json!({"jsonrpc": "2.0", "method": "status"}),
+            request(1, "rename", Value::Null),
+            request(2, "shutdown", Value::Null),
+            request(3, "status", Value::Null),
+        ],
+    );
+    assert_eq!(2, responses.len());
+    assert_eq!(json!(1), responses[0].id);
+    assert_eq!(METHOD_NOT_FOUND, responses[0].error.as_ref().unwrap().code);
+    // nothing is answered after shutdown
+    assert_eq!(json!(2), responses[1].id);
+    assert!(responses[1].error.is_none());
+}
@@ -0,0 +1,140 @@
+// Pushes to remotes served over the network, ignored by default as they need `git` on the path
+// or a remote set up with `git snapshot dev test-remote`: `cargo test --test remote -- --ignored`
+
+use std::{env::var, path::Path};
+
+use git2::Repository;
+use git_snapshot::{
+    test_remote::{TestRemote, SSH_KEY_ENV, SSH_URL_ENV},
+    test_util::{test_repo_with_files, TEST_REMOTE_NAME},
+    verify::Parity,
+    ErrorCode, Repo,
+};
+use tempfile::tempdir;
+
+const USERNAME: &str = "snapshot";
+const PASSWORD: &str = "secret";
+
+fn repo_with_remote(path: &Path, url: &str, config: &[(&str, &str)]) -> Repo {
+    let (repo, mut git_config) = test_repo_with_files(path);
+    repo.remote(TEST_REMOTE_NAME, url).unwrap();
+    git_config
+        .set_bool(
+            &format!("remote.{}.snapshotenabled", TEST_REMOTE_NAME),
+            true,
+        )
+        .unwrap();
+    for (key, value) in config {
+        git_config
+            .set_str(&format!("remote.{}.{}", TEST_REMOTE_NAME, key), value)
+            .unwrap();
+    }
+    Repo::new(repo)
+}
+
+// Snapshot, then fetch the remote's snapshot branch back to compare
+fn assert_pushed(repo: &Repo) {
+    repo.snapshot().unwrap();
+    let parities: Vec<Parity> = repo
+        .verify_remotes(None)
+        .unwrap()
+        .into_iter()
+        .map(|check| check.parity)
+        .collect();
+    assert_eq!(vec![Parity::InSync], parities);
+}
+
+#[test]
+#[ignore = "serves a remote with git daemon"]
+fn git_daemon() {
+    let temp = tempdir().unwrap();
+    let remote = TestRemote::daemon(&temp.path().join("remote")).unwrap();
+    let repo = repo_with_remote(&temp.path().join("repo"), &remote.url, &[]);
+
+    assert_pushed(&repo);
+    let remote_repo = Repository::open_bare(&remote.path).unwrap();
+    assert!(remote_repo
+        .find_reference("refs/heads/snapshot/master")
+        .is_ok());
+}
+
+#[test]
+#[ignore = "serves a remote with git http-backend"]
+fn http_credentials() {
+    let temp = tempdir().unwrap();
+    let remote = TestRemote::http(&temp.path().join("remote"), USERNAME, PASSWORD).unwrap();
+
+    let repo = repo_with_remote(
+        &temp.path().join("repo"),
+        &remote.url,
+        &[
+            ("snapshotusername", USERNAME),
+            ("snapshotpassword", PASSWORD),
+        ],
+    );
+    assert_pushed(&repo);
+
+    let rejected = repo_with_remote(
+        &temp.path().join("rejected"),
+        &remote.url,
+        &[
+            ("snapshotusername", USERNAME),
+            ("snapshotpassword", "wrong"),
+        ],
+    );
+    assert_eq!(ErrorCode::Auth, rejected.snapshot().unwrap_err().code());
+}
+
+#[test]
+#[ignore = "serves a remote with git http-backend"]
+fn http_git_cli() {
+    let temp = tempdir().unwrap();
+    let remote = TestRemote::http(&temp.path().join("remote"), USERNAME, PASSWORD).unwrap();
+    // credentials only git's own helpers know about
+    let credentials = temp.path().join("credentials");
+    std::fs::write(
+        &credentials,
+        remote
+            .url
+            .replace("http://", &format!("http://{}:{}@", USERNAME, PASSWORD)),
+    )
+    .unwrap();
+    let helper = format!("store --file={}", credentials.display());
+
+    let repo = repo_with_remote(
+        &temp.path().join("repo"),
+        &remote.url,
+        &[("snapshotpushbackend", "git-cli")],
+    );
+    repo.git_repo()
+        .config()
+        .unwrap()
+        .set_str("credential.helper", &helper)
+        .unwrap();
+    repo.snapshot().unwrap();
+    // fetching it back would need the credentials too, the bare repo is read instead
+    let remote_repo = Repository::open_bare(&remote.path).unwrap();
+    assert_eq!(
+        repo.find_snapshot(None).unwrap().id(),
+        remote_repo
+            .refname_to_id("refs/heads/snapshot/master")
+            .unwrap()
+    );
+}
+
+#[test]
+#[ignore = "needs an ssh remote, see `git snapshot dev test-remote --kind ssh`"]
+fn ssh_key() {
+    let url = var(SSH_URL_ENV).unwrap_or_else(|_| panic!("{} isn't set", SSH_URL_ENV));
+    let key = var(SSH_KEY_ENV).unwrap_or_else(|_| panic!("{} isn't set", SSH_KEY_ENV));
+    let temp = tempdir().unwrap();
+    // the same remote is pushed to on every run, a branch of its own keeps them apart
+    let branch = format!("ssh-{}", std::process::id());
+    let repo = repo_with_remote(
+        temp.path(),
+        &url,
+        &[("snapshotsshkey", &key), ("snapshotbranch", &branch)],
+    );
+
+    assert_pushed(&repo);
+}